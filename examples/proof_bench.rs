@@ -0,0 +1,35 @@
+//! Compare `verify_proof` against a serial loop over the proof window.
+//!
+//! Run with `cargo run --release --example proof_bench --features parallel-proof`
+//! to measure the parallel path.
+
+extern crate myodine;
+
+use std::time::Instant;
+
+use myodine::myo_proto::establish::{password_proof, verify_proof};
+
+const ITERATIONS: u32 = 200;
+
+fn main() {
+    let cur_time = 1_500_000_000;
+    for &window in &[30u64, 300] {
+        // A proof that never matches forces a scan of the whole window.
+        let proof = password_proof("wrong", cur_time);
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            assert!(!((cur_time - window)..(cur_time + window))
+                .any(|i| proof == password_proof("password", i)));
+        }
+        let serial = start.elapsed() / ITERATIONS;
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            assert!(!verify_proof(proof, "password", cur_time, window));
+        }
+        let actual = start.elapsed() / ITERATIONS;
+
+        println!("window={}: serial={:?} verify_proof={:?}", window, serial, actual);
+    }
+}
@@ -0,0 +1,109 @@
+// Open a session with a myodine server from JavaScript, send a line to the
+// destination, and print what comes back.
+//
+// Build the bindings from the repository root with:
+//
+//     cargo rustc --lib --release --target wasm32-unknown-unknown \
+//         --no-default-features --features wasm --crate-type cdylib
+//     wasm-bindgen --target nodejs --out-dir examples/wasm/pkg \
+//         target/wasm32-unknown-unknown/release/myodine.wasm
+//
+// Then run:
+//
+//     node examples/wasm/handshake.js RESOLVER HOST REMOTE_HOST REMOTE_PORT [PASSWORD]
+//
+// where RESOLVER is an "IP:port" address to send UDP queries to, or a DoH
+// URL starting with "https://". Without a password, the session asks for no
+// authentication.
+
+const crypto = require('crypto');
+const dgram = require('dgram');
+const { Handshake } = require('./pkg/myodine.js');
+
+const QUERY_TIMEOUT_MS = 2000;
+const QUERY_TRIES = 5;
+const TRANSFER_QUERIES = 100;
+
+async function main() {
+  const [resolver, host, remoteHost, remotePort, password] = process.argv.slice(2);
+  if (!remotePort) {
+    console.error('usage: handshake.js RESOLVER HOST REMOTE_HOST REMOTE_PORT [PASSWORD]');
+    process.exit(1);
+  }
+  const send = resolver.startsWith('https://') ? dohSender(resolver) : udpSender(resolver);
+
+  const epoch = Math.floor(Date.now() / 1000);
+  const nonce = crypto.randomBytes(4).readUInt32LE(0);
+  const handshake = new Handshake(host, remoteHost, parseInt(remotePort), password, epoch,
+    nonce);
+  let transfer;
+  while (!transfer) {
+    // A split handshake takes a second query for the destination.
+    transfer = handshake.handle_response(await send(handshake.query()));
+  }
+  console.error(`opened session ${transfer.session_id()}`);
+
+  transfer.write(Buffer.from('hello from wasm\n'));
+  for (let i = 0; i < TRANSFER_QUERIES && !transfer.is_finished(); i++) {
+    transfer.handle_response(await send(transfer.next_query()));
+    const data = transfer.read();
+    if (data.length > 0) {
+      process.stdout.write(Buffer.from(data));
+      transfer.close();
+    }
+  }
+  send.close();
+}
+
+// Send DNS messages over UDP, retrying queries that get no response.
+function udpSender(address) {
+  const [ip, port] = address.split(':');
+  const socket = dgram.createSocket('udp4');
+  let waiter = null;
+  socket.on('message', (msg) => {
+    if (waiter) {
+      waiter(msg);
+    }
+  });
+  const send = async (query) => {
+    for (let i = 0; i < QUERY_TRIES; i++) {
+      const response = await new Promise((resolve) => {
+        const timer = setTimeout(() => resolve(null), QUERY_TIMEOUT_MS);
+        waiter = (msg) => {
+          clearTimeout(timer);
+          resolve(msg);
+        };
+        socket.send(query, parseInt(port), ip);
+      });
+      waiter = null;
+      if (response) {
+        return response;
+      }
+    }
+    throw new Error('no response from ' + address);
+  };
+  send.close = () => socket.close();
+  return send;
+}
+
+// Send DNS messages to a DoH endpoint (RFC 8484).
+function dohSender(url) {
+  const send = async (query) => {
+    const response = await fetch(url, {
+      method: 'POST',
+      headers: { 'content-type': 'application/dns-message' },
+      body: query
+    });
+    if (!response.ok) {
+      throw new Error(`DoH request failed: ${response.status}`);
+    }
+    return new Uint8Array(await response.arrayBuffer());
+  };
+  send.close = () => {};
+  return send;
+}
+
+main().catch((err) => {
+  console.error('error:', err);
+  process.exit(1);
+});
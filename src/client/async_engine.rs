@@ -0,0 +1,633 @@
+extern crate tokio;
+use self::tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use self::tokio::net::{TcpListener, TcpStream, UdpSocket};
+use self::tokio::runtime::{Builder, Runtime};
+use self::tokio::time::{Sleep, sleep};
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::mem;
+use std::net::{self, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use conn::{ChunkSink, ChunkSource};
+use dns_coding::{dns_decode, dns_encode};
+use dns_proto::{Message, Question};
+use myo_proto::establish::{DestinationQuery, EstablishQuery, EstablishResponse};
+use myo_proto::record_code::decode_answers;
+use myo_proto::util::{payload_answer, payload_answers};
+use myo_proto::xfer::{Packet, WwrState, handle_packet_in, next_packet_out, unwrap_packet,
+    wrap_packet};
+
+use super::config::ClientConfig;
+use super::discovery::{Features, discover_features};
+use super::engine::dial_resolvers;
+use super::establish::{Establishment, establish_query, finish_establish,
+    next_establish_timeout};
+use super::logger::RawLogger;
+use super::poll_backoff::PollBackoff;
+
+/// How often a lane that is held back from polling checks for outgoing data.
+const PARKED_LANE_MS: u64 = 20;
+
+/// The most incoming bytes to hold while the local connection is slow.
+const MAX_LOCAL_BUFFER: usize = 65536;
+
+/// Listen for local connections and proxy each one over a new session, like
+/// `run`, with every session on a single tokio thread.
+///
+/// Datagram sessions, early data, MTU probing, case randomization, resuming,
+/// and the health socket aren't supported by this engine.
+pub fn run_async(config: ClientConfig) -> Result<(), String> {
+    if config.udp {
+        return Err("the async engine doesn't support datagram sessions".to_owned());
+    }
+    let listener = net::TcpListener::bind((config.listen_addr, config.listen_port))
+        .and_then(|x| x.set_nonblocking(true).map(|_| x))
+        .map_err(|e| format!("listen error: {}", e))?;
+    let logger = RawLogger::new();
+    logger.log("listening for connections...".to_owned());
+    new_runtime()?.block_on(AcceptLoop{
+        config,
+        logger,
+        std_listener: Some(listener),
+        listener: None,
+        connections: Vec::new()
+    })
+}
+
+/// Proxy a single local connection over a new session, like
+/// `handle_connection`, on a new tokio runtime.
+pub fn handle_connection_async(
+    config: ClientConfig,
+    conn: net::TcpStream,
+    logger: &RawLogger
+) -> Result<(), String> {
+    conn.set_nonblocking(true).map_err(|e| format!("{}", e))?;
+    let runtime = new_runtime()?;
+    let conn = {
+        let _guard = runtime.enter();
+        TcpStream::from_std(conn).map_err(|e| format!("{}", e))?
+    };
+    runtime.block_on(AsyncConnection::new(config, conn, logger.clone()))
+}
+
+fn new_runtime() -> Result<Runtime, String> {
+    Builder::new_current_thread().enable_all().build()
+        .map_err(|e| format!("error starting runtime: {}", e))
+}
+
+/// Accepts local connections and drives their sessions.
+struct AcceptLoop {
+    config: ClientConfig,
+    logger: RawLogger,
+    /// The listener before it is moved onto the runtime.
+    std_listener: Option<net::TcpListener>,
+    listener: Option<TcpListener>,
+    connections: Vec<(SocketAddr, AsyncConnection)>
+}
+
+impl Future for AcceptLoop {
+    type Output = Result<(), String>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), String>> {
+        let this = &mut *self;
+        if let Some(listener) = this.std_listener.take() {
+            this.listener = Some(TcpListener::from_std(listener)
+                .map_err(|e| format!("listen error: {}", e))?);
+        }
+        while let Poll::Ready(res) = this.listener.as_ref().unwrap().poll_accept(cx) {
+            let (conn, addr) = res.map_err(|e| format!("accept error: {}", e))?;
+            this.logger.log(format!("new connection from {}", addr));
+            if this.config.tcp_nodelay {
+                if let Err(e) = conn.set_nodelay(true) {
+                    this.logger.log(format!("error setting nodelay for {}: {}", addr, e));
+                }
+            }
+            let connection = AsyncConnection::new(this.config.clone(), conn, this.logger.clone());
+            this.connections.push((addr, connection));
+        }
+        let logger = &this.logger;
+        this.connections.retain_mut(|&mut (addr, ref mut connection)| {
+            match Pin::new(connection).poll(cx) {
+                Poll::Pending => true,
+                Poll::Ready(Ok(())) => {
+                    logger.log(format!("session ended for {}", addr));
+                    false
+                },
+                Poll::Ready(Err(msg)) => {
+                    logger.log(format!("error for {}: {}", addr, msg));
+                    false
+                }
+            }
+        });
+        Poll::Pending
+    }
+}
+
+/// A future that proxies one local connection over a new session.
+///
+/// Each of the `config.concurrency` query lanes is a UDP socket with at most
+/// one query in flight, and the lanes are polled together instead of running
+/// on their own threads. The future must be polled on a tokio runtime with
+/// I/O and timers enabled.
+pub struct AsyncConnection {
+    config: ClientConfig,
+    logger: RawLogger,
+    local: Local,
+    phase: Phase
+}
+
+enum Phase {
+    /// Nothing has been sent yet.
+    Start,
+    Establishing(Box<Establishing>),
+    Transferring(Box<Transfer>),
+    Finished
+}
+
+impl AsyncConnection {
+    pub fn new(config: ClientConfig, conn: TcpStream, logger: RawLogger) -> AsyncConnection {
+        AsyncConnection{
+            config,
+            logger,
+            local: Local{
+                stream: conn,
+                outgoing: VecDeque::new(),
+                incoming: VecDeque::new(),
+                read_eof: false,
+                finished: false,
+                shut_down: false
+            },
+            phase: Phase::Start
+        }
+    }
+
+    fn start(&self) -> Result<Box<Establishing>, String> {
+        self.logger.log(format!("discovering features @{} for {}...", self.config.host,
+            self.config.addr));
+        let features = discover_features(&self.config)
+            .map_err(|e| format!("failed to discover features: {}", e))?;
+        let mut lanes = Vec::new();
+        for socket in dial_resolvers(&self.config, &self.logger)? {
+            socket.set_nonblocking(true).map_err(|e| format!("{}", e))?;
+            lanes.push(Lane::new(UdpSocket::from_std(socket).map_err(|e| format!("{}", e))?));
+        }
+        self.logger.log("establishing session...".to_owned());
+        let epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let query = establish_query(&self.config, &features, epoch);
+        let mut establishing = Establishing{
+            lanes,
+            features: Some(features),
+            query,
+            epoch,
+            tries: 0,
+            timeout: self.config.establish_timeout,
+            following_up: false
+        };
+        establishing.send(&self.config)?;
+        Ok(Box::new(establishing))
+    }
+}
+
+impl Future for AsyncConnection {
+    type Output = Result<(), String>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), String>> {
+        let this = &mut *self;
+        loop {
+            match mem::replace(&mut this.phase, Phase::Finished) {
+                Phase::Start => this.phase = Phase::Establishing(this.start()?),
+                Phase::Establishing(mut establishing) => {
+                    match establishing.poll(&this.config, cx)? {
+                        Poll::Ready(info) => {
+                            this.logger.log(format!("query MTU: {}, response MTU: {}",
+                                info.query_mtu, info.response_mtu));
+                            this.logger.log("running session...".to_owned());
+                            let lanes = establishing.lanes;
+                            this.phase = Phase::Transferring(Box::new(Transfer::new(&this.config,
+                                info, lanes)));
+                        },
+                        Poll::Pending => {
+                            this.phase = Phase::Establishing(establishing);
+                            return Poll::Pending;
+                        }
+                    }
+                },
+                Phase::Transferring(mut transfer) => {
+                    let result = transfer.poll(&this.config, &mut this.local, cx)?;
+                    if result.is_pending() {
+                        this.phase = Phase::Transferring(transfer);
+                    }
+                    return result.map(Ok);
+                },
+                Phase::Finished => return Poll::Ready(Ok(()))
+            }
+        }
+    }
+}
+
+/// A session that is waiting for the response to its establishment query,
+/// which is sent on the first lane.
+struct Establishing {
+    lanes: Vec<Lane>,
+    /// The codecs, until they go into the `Establishment`.
+    features: Option<Features>,
+    query: EstablishQuery,
+    epoch: u64,
+    tries: usize,
+    timeout: Duration,
+    /// Whether the query was split, and the server is waiting for its
+    /// destination.
+    following_up: bool
+}
+
+impl Establishing {
+    fn send(&mut self, config: &ClientConfig) -> Result<(), String> {
+        let domain = if self.following_up {
+            let host = self.query.host.clone();
+            DestinationQuery{nonce: self.query.nonce.unwrap(), host}
+                .to_domain(&config.host, &config.api_chars)?
+        } else {
+            self.query.to_domain(&config.host, &config.api_chars)?
+        };
+        let mut message = Message::new_query(Question{
+            domain,
+            record_type: self.features.as_ref().unwrap().record_type,
+            record_class: config.record_class
+        });
+        message.header.recursion_desired = config.recursion_desired;
+        self.tries += 1;
+        self.lanes[0].send(message, self.timeout)
+    }
+
+    fn poll(
+        &mut self,
+        config: &ClientConfig,
+        cx: &mut Context
+    ) -> Result<Poll<Establishment>, String> {
+        loop {
+            let response = match self.lanes[0].poll_response(cx)? {
+                Poll::Ready(Some(response)) => response,
+                Poll::Ready(None) if self.tries < config.establish_tries => {
+                    self.timeout = next_establish_timeout(self.timeout);
+                    self.send(config)?;
+                    continue;
+                },
+                Poll::Ready(None) => return Err("no establishment response".to_owned()),
+                Poll::Pending => return Ok(Poll::Pending)
+            };
+            let raw_data = self.features.as_ref().unwrap().record_code
+                .decode_body(payload_answer(&response)?)?;
+            let response: EstablishResponse = dns_decode(raw_data)?;
+            if self.query.split && !self.following_up && response == EstablishResponse::Pending {
+                self.following_up = true;
+                self.tries = 0;
+                self.timeout = config.establish_timeout;
+                self.send(config)?;
+                continue;
+            }
+            let features = self.features.take().unwrap();
+            let query = self.query.clone();
+            return finish_establish(config, features, query, response, self.epoch)
+                .map(Poll::Ready);
+        }
+    }
+}
+
+/// A session that is moving data.
+struct Transfer {
+    info: Establishment,
+    lanes: Vec<Lane>,
+    state: WwrState,
+    poll_backoff: PollBackoff,
+    /// The number of queries in a row that got no usable response.
+    failures: usize
+}
+
+impl Transfer {
+    fn new(config: &ClientConfig, info: Establishment, lanes: Vec<Lane>) -> Transfer {
+        let mut state = WwrState::new(info.response_window, info.query_window, info.seq_start);
+        state.set_send_byte_limit(config.max_inflight_bytes);
+        state.set_recv_chunk_limit(Some(info.response_mtu as usize));
+        Transfer{
+            info,
+            lanes,
+            state,
+            poll_backoff: PollBackoff::new(config.max_poll_delay),
+            failures: 0
+        }
+    }
+
+    /// Move data until both sides of the stream are done.
+    fn poll(
+        &mut self,
+        config: &ClientConfig,
+        local: &mut Local,
+        cx: &mut Context
+    ) -> Result<Poll<()>, String> {
+        loop {
+            let mut progress = local.poll_read(cx, self.info.query_mtu as usize,
+                self.info.query_window as usize)?;
+            progress |= local.poll_write(cx)?;
+            for lane in 0..self.lanes.len() {
+                progress |= self.poll_lane(config, lane, local, cx)?;
+            }
+            if self.state.is_done() && local.is_flushed() {
+                return Ok(Poll::Ready(()));
+            } else if !progress {
+                return Ok(Poll::Pending);
+            }
+        }
+    }
+
+    /// Handle the response on a lane, if there is one, and send the next
+    /// query once the lane is free.
+    ///
+    /// Returns whether anything happened.
+    fn poll_lane(
+        &mut self,
+        config: &ClientConfig,
+        lane: usize,
+        local: &mut Local,
+        cx: &mut Context
+    ) -> Result<bool, String> {
+        if self.lanes[lane].is_busy() {
+            match self.lanes[lane].poll_response(cx)? {
+                Poll::Ready(Some(response)) => {
+                    let polled = self.lanes[lane].polled;
+                    self.handle_response(response, polled, local);
+                },
+                Poll::Ready(None) => self.record_failure(config)?,
+                Poll::Pending => return Ok(false)
+            }
+        }
+        if let Some(ref mut parked) = self.lanes[lane].parked {
+            if parked.as_mut().poll(cx).is_pending() {
+                return Ok(false);
+            }
+        }
+        self.lanes[lane].parked = None;
+        if self.state.is_done() {
+            return Ok(false);
+        }
+        let (packet, _) = next_packet_out(&mut self.state, local);
+        let pull = packet.chunk.is_none();
+        let now = Instant::now();
+        let polling = self.lanes.iter().any(|x| x.is_busy() && x.polled);
+        if pull && (polling || self.poll_backoff.wait_time(now).is_some()) {
+            // Only one lane polls at a time, so idle sessions don't flood the
+            // server.
+            let delay = self.poll_backoff.wait_time(now)
+                .unwrap_or(Duration::from_millis(PARKED_LANE_MS));
+            let mut parked = Box::pin(sleep(delay));
+            // Register for a wakeup.
+            let _ = parked.as_mut().poll(cx);
+            self.lanes[lane].parked = Some(parked);
+            return Ok(true);
+        }
+        if pull {
+            self.poll_backoff.record_poll(now);
+        } else {
+            self.poll_backoff.record_chunk_sent(now);
+        }
+        let (api_code, data) = packet.encode_query(&config.api_chars)?;
+        let padded_size = if self.info.query.padded {
+            Some(Packet::padded_size(self.info.response_window, self.info.query_mtu))
+        } else {
+            None
+        };
+        let data = wrap_packet(data, padded_size, self.info.query.framed)?;
+        let domain = config.name_template.encode(&*self.info.name_code, api_code,
+            self.info.session_id, &data, &config.host, config.decoy_labels)?;
+        let mut message = Message::new_query(Question{
+            domain,
+            record_type: self.info.record_type,
+            record_class: config.record_class
+        });
+        message.header.recursion_desired = config.recursion_desired;
+        self.lanes[lane].polled = pull;
+        self.lanes[lane].send(message, config.query_max_time)?;
+        Ok(true)
+    }
+
+    fn handle_response(&mut self, response: Message, polled: bool, local: &mut Local) {
+        let answers = if response.header.truncated {
+            Err("truncated".to_owned())
+        } else if self.info.query.answers == 1 {
+            payload_answer(&response).map(|x| vec![x])
+        } else {
+            payload_answers(&response)
+        };
+        let data = answers.and_then(|x| decode_answers(&*self.info.record_code, &x,
+            self.info.query.answers));
+        let body = data.and_then(|x| unwrap_packet(x, self.info.query.padded,
+            self.info.query.framed));
+        match body.and_then(|x| Packet::decode_response(&x, self.info.query_window)) {
+            Ok(packet) => {
+                self.failures = 0;
+                let got_chunk = packet.chunk.is_some();
+                self.poll_backoff.record_response(Instant::now(), polled, got_chunk);
+                handle_packet_in(packet, &mut self.state, local);
+            },
+            Err(_) => self.failures += 1
+        }
+    }
+
+    fn record_failure(&mut self, config: &ClientConfig) -> Result<(), String> {
+        self.failures += 1;
+        if self.failures > config.max_server_failures {
+            Err(format!("{} queries in a row failed", self.failures))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A UDP socket with at most one query in flight.
+struct Lane {
+    socket: UdpSocket,
+    next_id: u16,
+    outstanding: Option<Outstanding>,
+    /// Whether the outstanding query only polls for incoming data.
+    polled: bool,
+    /// The wait before a lane that was held back tries again.
+    parked: Option<Pin<Box<Sleep>>>
+}
+
+struct Outstanding {
+    data: Vec<u8>,
+    identifier: u16,
+    questions: Vec<Question>,
+    sent: bool,
+    deadline: Pin<Box<Sleep>>
+}
+
+impl Lane {
+    fn new(socket: UdpSocket) -> Lane {
+        Lane{socket, next_id: 0, outstanding: None, polled: false, parked: None}
+    }
+
+    fn is_busy(&self) -> bool {
+        self.outstanding.is_some()
+    }
+
+    /// Start a query, which is given up on after `timeout`.
+    fn send(&mut self, mut message: Message, timeout: Duration) -> Result<(), String> {
+        message.header.identifier = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.outstanding = Some(Outstanding{
+            data: dns_encode(&message)?,
+            identifier: message.header.identifier,
+            questions: message.questions,
+            sent: false,
+            deadline: Box::pin(sleep(timeout))
+        });
+        Ok(())
+    }
+
+    /// Wait for the response to the outstanding query.
+    ///
+    /// Returns None once the query times out. Late responses to earlier
+    /// queries are dropped.
+    fn poll_response(&mut self, cx: &mut Context) -> Result<Poll<Option<Message>>, String> {
+        let result = self.poll_outstanding(cx);
+        if let Ok(Poll::Ready(_)) = result {
+            self.outstanding = None;
+        }
+        result
+    }
+
+    fn poll_outstanding(&mut self, cx: &mut Context) -> Result<Poll<Option<Message>>, String> {
+        let outstanding = match self.outstanding {
+            Some(ref mut x) => x,
+            None => return Ok(Poll::Ready(None))
+        };
+        if !outstanding.sent {
+            match self.socket.poll_send(cx, &outstanding.data) {
+                Poll::Ready(res) => {
+                    res.map_err(|e| format!("error sending datagram: {}", e))?;
+                    outstanding.sent = true;
+                },
+                Poll::Pending => return Ok(Poll::Pending)
+            }
+        }
+        let mut buffer = [0u8; 2048];
+        loop {
+            let mut read_buf = ReadBuf::new(&mut buffer);
+            match self.socket.poll_recv(cx, &mut read_buf) {
+                Poll::Ready(Ok(())) => {
+                    let response = match dns_decode::<Message>(read_buf.filled().to_vec()) {
+                        Ok(x) => x,
+                        Err(_) => continue
+                    };
+                    if response.header.identifier == outstanding.identifier &&
+                        response.questions == outstanding.questions
+                    {
+                        return Ok(Poll::Ready(Some(response)));
+                    }
+                },
+                // Errors such as ICMP port unreachable count as a lost query.
+                Poll::Ready(Err(_)) => break,
+                Poll::Pending => break
+            }
+        }
+        Ok(outstanding.deadline.as_mut().poll(cx).map(|_| None))
+    }
+}
+
+/// The local connection, with the data moving through it.
+struct Local {
+    stream: TcpStream,
+    /// Chunks read from the stream, waiting to go into the session.
+    outgoing: VecDeque<Vec<u8>>,
+    /// Data from the session, waiting to be written to the stream.
+    incoming: VecDeque<u8>,
+    read_eof: bool,
+    /// Whether the session's stream ended.
+    finished: bool,
+    shut_down: bool
+}
+
+impl Local {
+    /// Read a chunk of up to `mtu` bytes, unless `max_chunks` are already
+    /// waiting.
+    ///
+    /// Returns whether anything was read.
+    fn poll_read(
+        &mut self,
+        cx: &mut Context,
+        mtu: usize,
+        max_chunks: usize
+    ) -> Result<bool, String> {
+        if self.read_eof || self.outgoing.len() >= max_chunks {
+            return Ok(false);
+        }
+        let mut buffer = vec![0u8; mtu];
+        let mut read_buf = ReadBuf::new(&mut buffer);
+        match Pin::new(&mut self.stream).poll_read(cx, &mut read_buf) {
+            Poll::Ready(Ok(())) => {
+                let data = read_buf.filled().to_vec();
+                self.read_eof = data.is_empty();
+                self.outgoing.push_back(data);
+                Ok(true)
+            },
+            Poll::Ready(Err(e)) => Err(format!("error reading from connection: {}", e)),
+            Poll::Pending => Ok(false)
+        }
+    }
+
+    /// Write incoming data, and shut down the write half of the stream once
+    /// the session's stream has ended.
+    ///
+    /// Returns whether anything was written.
+    fn poll_write(&mut self, cx: &mut Context) -> Result<bool, String> {
+        if !self.incoming.is_empty() {
+            let data = self.incoming.as_slices().0;
+            match Pin::new(&mut self.stream).poll_write(cx, data) {
+                Poll::Ready(Ok(size)) => {
+                    self.incoming.drain(..size);
+                    Ok(true)
+                },
+                Poll::Ready(Err(e)) => Err(format!("error writing to connection: {}", e)),
+                Poll::Pending => Ok(false)
+            }
+        } else if self.finished && !self.shut_down {
+            match Pin::new(&mut self.stream).poll_shutdown(cx) {
+                Poll::Ready(_) => {
+                    self.shut_down = true;
+                    Ok(true)
+                },
+                Poll::Pending => Ok(false)
+            }
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn is_flushed(&self) -> bool {
+        self.incoming.is_empty() && (!self.finished || self.shut_down)
+    }
+}
+
+impl ChunkSource for Local {
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.outgoing.pop_front()
+    }
+}
+
+impl ChunkSink for Local {
+    fn can_send(&mut self) -> bool {
+        self.incoming.len() < MAX_LOCAL_BUFFER
+    }
+
+    fn send(&mut self, chunk: Vec<u8>) {
+        self.incoming.extend(chunk);
+    }
+
+    fn send_finished(&mut self) {
+        self.finished = true;
+    }
+}
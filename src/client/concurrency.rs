@@ -0,0 +1,82 @@
+use std::time::{Duration, Instant};
+
+/// How long after cutting the limit to ignore further failures.
+///
+/// Queries that were already in flight when the limit was cut tend to fail
+/// together, and they shouldn't cut it again.
+const DECREASE_COOLDOWN_MS: u64 = 1000;
+
+/// Limits how many transfer queries are in flight, backing off when the
+/// resolver starts failing them.
+///
+/// This is additive-increase, multiplicative-decrease: a server failure or
+/// timeout halves the limit, and every successful query raises it by one
+/// over the current limit, so it grows by about one per round of queries.
+/// The limit never goes below one or above the number of lanes.
+pub struct ConcurrencyLimit {
+    max: usize,
+    limit: f64,
+    last_decrease: Option<Instant>
+}
+
+impl ConcurrencyLimit {
+    /// Create a limit that starts at, and never exceeds, `max` queries.
+    pub fn new(max: usize) -> ConcurrencyLimit {
+        ConcurrencyLimit{max, limit: max as f64, last_decrease: None}
+    }
+
+    /// Get the current number of queries that may be in flight.
+    pub fn limit(&self) -> usize {
+        (self.limit as usize).max(1)
+    }
+
+    /// Record a transfer query that got a valid response.
+    pub fn record_success(&mut self) {
+        self.limit = (self.limit + 1.0 / self.limit).min(self.max as f64);
+    }
+
+    /// Record a transfer query that timed out or got a server failure.
+    pub fn record_failure(&mut self, now: Instant) {
+        let cooldown = Duration::from_millis(DECREASE_COOLDOWN_MS);
+        if self.last_decrease.map(|x| now - x < cooldown).unwrap_or(false) {
+            return;
+        }
+        self.last_decrease = Some(now);
+        self.limit = (self.limit / 2.0).max(1.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn servfail_burst() {
+        let start = Instant::now();
+        let mut limit = ConcurrencyLimit::new(16);
+        assert_eq!(limit.limit(), 16);
+
+        // A burst of failures only counts once.
+        for _ in 0..8 {
+            limit.record_failure(start);
+        }
+        assert_eq!(limit.limit(), 8);
+
+        let mut time = start;
+        for _ in 0..5 {
+            time += Duration::from_millis(DECREASE_COOLDOWN_MS);
+            limit.record_failure(time);
+        }
+        assert_eq!(limit.limit(), 1);
+
+        // Clean responses raise the limit about once per round of queries.
+        limit.record_success();
+        assert_eq!(limit.limit(), 2);
+        limit.record_success();
+        assert_eq!(limit.limit(), 2);
+        for _ in 0..1000 {
+            limit.record_success();
+        }
+        assert_eq!(limit.limit(), 16);
+    }
+}
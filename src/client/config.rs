@@ -0,0 +1,293 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use dns_proto::{Domain, RecordClass};
+use myo_proto::api_chars::ApiChars;
+use myo_proto::name_code::MAX_LABELS;
+use myo_proto::name_template::NameTemplate;
+use myo_proto::record_code::MAX_ANSWERS;
+
+use super::resolvers::Resolver;
+
+/// Configuration for running a client.
+#[derive(Clone)]
+pub struct ClientConfig {
+    pub addr: String,
+    pub host: Domain,
+    pub concurrency: usize,
+    pub read_ahead: Option<usize>,
+    pub query_window: u16,
+    pub response_window: u16,
+    pub push_only: bool,
+    pub early_data: bool,
+    pub pad_packets: bool,
+    pub frame_packets: bool,
+    /// The number of answers to split each transfer response across.
+    pub answers: usize,
+    pub tcp_nodelay: bool,
+    pub udp: bool,
+    pub udp_idle_timeout: Duration,
+    pub source_port: Option<u16>,
+    pub password: String,
+    pub remote_host: Domain,
+    pub remote_port: u16,
+    /// The name of a Unix socket on the server to proxy to instead of
+    /// `remote_host` and `remote_port`.
+    pub remote_unix: Option<Domain>,
+    pub listen_addr: IpAddr,
+    pub listen_port: u16,
+    pub query_min_time: Duration,
+    pub query_max_time: Duration,
+    pub query_response_time: Option<Duration>,
+    pub max_poll_delay: Duration,
+    pub query_mtu: Option<u16>,
+    pub response_mtu: Option<u16>,
+    pub min_mtu: Option<u16>,
+    pub probe_mtu: bool,
+    pub recursion_desired: bool,
+    pub max_server_failures: usize,
+    pub establish_tries: usize,
+    pub establish_timeout: Duration,
+    pub loss_warning: u32,
+    pub max_inflight_bytes: Option<usize>,
+    pub response_codecs: Vec<String>,
+    pub no_auth: bool,
+    pub api_chars: ApiChars,
+    pub extra_resolvers: Vec<Resolver>,
+    pub case_randomization: bool,
+    pub decoy_labels: usize,
+    pub name_template: NameTemplate,
+    pub verbose: bool,
+    pub health_socket: Option<String>,
+    pub shutdown_grace: Duration,
+    pub max_data_labels: Option<usize>,
+    pub record_class: RecordClass
+}
+
+impl ClientConfig {
+    /// Create a configuration with default settings.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The "IP:port" address of the DNS server to query.
+    /// * `host` - The root domain name of the proxy.
+    pub fn new(addr: &str, host: Domain) -> ClientConfig {
+        ClientConfig{
+            addr: addr.to_owned(),
+            host,
+            concurrency: 2,
+            read_ahead: None,
+            query_window: 4,
+            response_window: 4,
+            push_only: false,
+            early_data: false,
+            pad_packets: false,
+            frame_packets: false,
+            answers: 1,
+            tcp_nodelay: false,
+            udp: false,
+            udp_idle_timeout: Duration::from_secs(60),
+            source_port: None,
+            password: String::new(),
+            remote_host: "127.0.0.1".parse().unwrap(),
+            remote_port: 22,
+            remote_unix: None,
+            listen_addr: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            listen_port: 2222,
+            query_min_time: Duration::from_millis(50),
+            query_max_time: Duration::from_millis(5000),
+            query_response_time: None,
+            max_poll_delay: Duration::from_millis(1000),
+            query_mtu: None,
+            response_mtu: None,
+            min_mtu: None,
+            probe_mtu: false,
+            recursion_desired: true,
+            max_server_failures: 32,
+            establish_tries: 5,
+            establish_timeout: Duration::from_secs(5),
+            loss_warning: 20,
+            max_inflight_bytes: None,
+            response_codecs: vec!["txt".to_owned()],
+            no_auth: false,
+            api_chars: ApiChars::default(),
+            extra_resolvers: Vec::new(),
+            case_randomization: true,
+            decoy_labels: 0,
+            name_template: NameTemplate::default(),
+            verbose: false,
+            health_socket: None,
+            shutdown_grace: Duration::from_secs(5),
+            max_data_labels: None,
+            record_class: RecordClass::IN
+        }
+    }
+
+    /// Get the response window to negotiate, which is 0 for push-only
+    /// sessions.
+    pub fn negotiated_response_window(&self) -> u16 {
+        if self.push_only {
+            0
+        } else {
+            self.response_window
+        }
+    }
+
+    /// Check for settings that would stall the tunnel.
+    ///
+    /// A zero response window is rejected even though push-only sessions
+    /// negotiate one; `push_only` is the way to ask for that.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.query_window == 0 {
+            Err("query window must be at least 1".to_owned())
+        } else if self.response_window == 0 {
+            Err("response window must be at least 1".to_owned())
+        } else if self.concurrency == 0 {
+            Err("concurrency must be at least 1".to_owned())
+        } else if self.read_ahead == Some(0) {
+            Err("read ahead must be at least 1".to_owned())
+        } else if self.source_port == Some(0) {
+            Err("source port must not be 0".to_owned())
+        } else if self.source_port.map(|x| x as usize + self.concurrency > 0x10000)
+            .unwrap_or(false)
+        {
+            Err("source port leaves too few ports for every lane".to_owned())
+        } else if self.remote_port == 0 {
+            Err("remote port must not be 0".to_owned())
+        } else if self.remote_host.to_string().parse::<Ipv4Addr>().is_err() {
+            Err(format!("remote host must be an IPv4 address: {}", self.remote_host))
+        } else if self.establish_tries == 0 {
+            Err("establish tries must be at least 1".to_owned())
+        } else if self.establish_timeout < Duration::from_secs(1) {
+            Err("establish timeout must be at least 1 second".to_owned())
+        } else if self.loss_warning > 100 {
+            Err("loss warning must be a percentage from 0 to 100".to_owned())
+        } else if self.max_data_labels == Some(0) || self.max_data_labels > Some(MAX_LABELS) {
+            Err(format!("max data labels must be from 1 to {}", MAX_LABELS))
+        } else if self.query_response_time == Some(Duration::from_millis(0)) {
+            Err("query response time must be at least 1 ms".to_owned())
+        } else if self.answers == 0 || self.answers > MAX_ANSWERS {
+            Err(format!("answers must be from 1 to {}", MAX_ANSWERS))
+        } else if self.udp && self.remote_unix.is_some() {
+            Err("UDP tunnels cannot use a Unix destination".to_owned())
+        } else if self.udp && self.early_data {
+            Err("early data is not supported for UDP".to_owned())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Parse the address of the forward target.
+///
+/// The server connects to the address without resolving it, so it must be
+/// an IPv4 address. "localhost" is accepted as 127.0.0.1.
+pub fn parse_remote_host(host: &str) -> Result<Domain, String> {
+    let addr: Ipv4Addr = if host.eq_ignore_ascii_case("localhost") {
+        Ipv4Addr::new(127, 0, 0, 1)
+    } else {
+        host.parse().map_err(|_| format!("not an IPv4 address: {}", host))?
+    };
+    addr.to_string().parse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> ClientConfig {
+        ClientConfig::new("127.0.0.1:53", "proxy.com".parse().unwrap())
+    }
+
+    #[test]
+    fn validate_rejects_zero() {
+        let config = test_config();
+        assert!(config.validate().is_ok());
+        let mut bad = config.clone();
+        bad.query_window = 0;
+        assert!(bad.validate().is_err());
+        let mut bad = config.clone();
+        bad.response_window = 0;
+        assert!(bad.validate().is_err());
+        let mut bad = config.clone();
+        bad.concurrency = 0;
+        assert!(bad.validate().is_err());
+        let mut bad = config.clone();
+        bad.read_ahead = Some(0);
+        assert!(bad.validate().is_err());
+        let mut bad = config.clone();
+        bad.remote_port = 0;
+        assert!(bad.validate().is_err());
+    }
+
+    #[test]
+    fn validate_remote_host_ipv4() {
+        let mut config = test_config();
+        config.remote_host = "example.com".parse().unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn validate_unix_not_udp() {
+        let mut config = test_config();
+        config.remote_unix = Some("db".parse().unwrap());
+        assert!(config.validate().is_ok());
+        config.udp = true;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn validate_establish_retries() {
+        let mut config = test_config();
+        config.establish_tries = 0;
+        assert!(config.validate().is_err());
+        let mut config = test_config();
+        config.establish_timeout = Duration::from_millis(500);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn validate_source_port() {
+        let mut config = test_config();
+        config.source_port = Some(0);
+        assert!(config.validate().is_err());
+        // A fixed source port only works with one socket.
+        config.source_port = Some(65535);
+        assert!(config.validate().is_err());
+        config.concurrency = 1;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_answers() {
+        let mut config = test_config();
+        config.answers = 0;
+        assert!(config.validate().is_err());
+        config.answers = 17;
+        assert!(config.validate().is_err());
+        config.answers = 16;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_max_data_labels() {
+        let mut config = test_config();
+        config.max_data_labels = Some(0);
+        assert!(config.validate().is_err());
+        config.max_data_labels = Some(MAX_LABELS + 1);
+        assert!(config.validate().is_err());
+        config.max_data_labels = Some(usize::MAX);
+        assert!(config.validate().is_err());
+        config.max_data_labels = Some(MAX_LABELS);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn remote_host_parsing() {
+        assert_eq!(parse_remote_host("10.0.0.7").unwrap().to_string(), "10.0.0.7");
+        assert_eq!(parse_remote_host("LocalHost").unwrap().to_string(), "127.0.0.1");
+        assert!(parse_remote_host("example.com").is_err());
+        assert!(parse_remote_host("10.0.0.256").is_err());
+        assert!(parse_remote_host("").is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use std::fmt::Write;
+use std::io::{Read, stdin};
+use std::str;
+
+use dns_coding::{dns_decode, hex_dump};
+use dns_proto::{Message, Record, RecordBody};
+
+/// Read a DNS message from stdin as hex or base64, and print it section by
+/// section.
+///
+/// Hex may be split up by whitespace or colons, and may be a `hex_dump`
+/// (the offset before each line is skipped). Anything else is treated as
+/// base64. Both queries and responses are supported.
+pub fn run_decode() -> Result<(), String> {
+    let mut text = String::new();
+    stdin().read_to_string(&mut text).map_err(|e| format!("read stdin: {}", e))?;
+    let data = parse_packet_text(&text)?;
+    let message: Message = dns_decode(data.clone())
+        .map_err(|e| format!("malformed message ({} bytes): {}\n{}", data.len(), e,
+            hex_dump(&data)))?;
+    print!("{}", describe_message(&message));
+    Ok(())
+}
+
+/// Turn the hex or base64 text of a packet into bytes.
+pub fn parse_packet_text(text: &str) -> Result<Vec<u8>, String> {
+    let hex: String = text.lines()
+        .map(|line| line.find(": ").map(|i| &line[i + 2..]).unwrap_or(line))
+        .flat_map(|line| line.chars())
+        .filter(|&c| !c.is_whitespace() && c != ':')
+        .collect();
+    let compact: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    if compact.is_empty() {
+        Err("no packet given".to_owned())
+    } else if hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        let pairs: Vec<_> = hex.as_bytes().chunks(2).collect();
+        if pairs.last().unwrap().len() != 2 {
+            return Err("odd number of hex digits".to_owned());
+        }
+        Ok(pairs.into_iter()
+            .map(|x| u8::from_str_radix(str::from_utf8(x).unwrap(), 16).unwrap())
+            .collect())
+    } else {
+        decode_base64(&compact)
+    }
+}
+
+/// Decode standard or URL-safe base64, with or without padding.
+fn decode_base64(text: &str) -> Result<Vec<u8>, String> {
+    let mut result = Vec::new();
+    let mut bits = 0u32;
+    let mut num_bits = 0;
+    for c in text.trim_end_matches('=').chars() {
+        let value = match c {
+            'A'..='Z' => c as u32 - 'A' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 26,
+            '0'..='9' => c as u32 - '0' as u32 + 52,
+            '+' | '-' => 62,
+            '/' | '_' => 63,
+            _ => return Err(format!("packet is neither hex nor base64 (found {:?})", c))
+        };
+        bits = (bits << 6) | value;
+        num_bits += 6;
+        if num_bits >= 8 {
+            num_bits -= 8;
+            result.push((bits >> num_bits) as u8);
+            bits &= (1 << num_bits) - 1;
+        }
+    }
+    if num_bits >= 6 {
+        return Err("truncated base64".to_owned());
+    }
+    Ok(result)
+}
+
+/// Describe every part of a message, one line per header, question, or
+/// record.
+pub fn describe_message(message: &Message) -> String {
+    let header = &message.header;
+    let mut flags = Vec::new();
+    for &(set, name) in &[(header.authoritative, "aa"), (header.truncated, "tc"),
+        (header.recursion_desired, "rd"), (header.recursion_available, "ra")]
+    {
+        if set {
+            flags.push(name);
+        }
+    }
+    let mut result = String::new();
+    writeln!(result, "{} id={} opcode={:?} rcode={:?} flags=[{}]",
+        if header.is_response { "response" } else { "query" }, header.identifier,
+        header.opcode, header.response_code, flags.join(" ")).unwrap();
+    writeln!(result, "counts: {} questions, {} answers, {} authorities, {} additional",
+        header.question_count, header.answer_count, header.authority_count,
+        header.additional_count).unwrap();
+    for question in &message.questions {
+        writeln!(result, "question: {} {:?} {:?}", question.domain, question.record_type,
+            question.record_class).unwrap();
+    }
+    for &(section, records) in &[("answer", &message.answers),
+        ("authority", &message.authorities), ("additional", &message.additional)]
+    {
+        for record in records.iter() {
+            writeln!(result, "{}: {}", section, describe_record(record)).unwrap();
+        }
+    }
+    result
+}
+
+fn describe_record(record: &Record) -> String {
+    let header = &record.header;
+    let body = match record.body {
+        RecordBody::A(ref addr) => format!("{}", addr),
+        RecordBody::AAAA(ref addr) => format!("{}", addr),
+        RecordBody::Domain(ref domain) => format!("{}", domain),
+        RecordBody::SOA(ref soa) => format!("{:?}", soa),
+        RecordBody::Unknown(ref data) => format!("{} bytes\n{}", data.len(), hex_dump(data))
+    };
+    format!("{} {:?} {:?} ttl={} {}", header.domain, header.record_type, header.record_class,
+        header.ttl, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use dns_coding::dns_encode;
+    use dns_proto::{Question, RecordClass, RecordHeader, RecordType};
+
+    #[test]
+    fn packet_text_formats() {
+        let data = vec![0xde, 0xad, 0xbe, 0xef, 0x00, 0xff];
+        assert_eq!(parse_packet_text("deadbeef00ff\n").unwrap(), data);
+        assert_eq!(parse_packet_text("de:ad:be:ef:00:ff").unwrap(), data);
+        assert_eq!(parse_packet_text(&hex_dump(&data)).unwrap(), data);
+        assert_eq!(parse_packet_text("3q2+7wD/").unwrap(), data);
+        assert_eq!(parse_packet_text("3q2-7wD_").unwrap(), data);
+        assert_eq!(parse_packet_text("3q2+7w==").unwrap(), &data[..4]);
+        assert!(parse_packet_text(" \n").is_err());
+        assert!(parse_packet_text("3q2+7wD/!").is_err());
+        assert!(parse_packet_text("3q2+7").is_err());
+        assert!(parse_packet_text("deadb").is_err());
+    }
+
+    #[test]
+    fn describe_response() {
+        let domain = "foo.com".parse().unwrap();
+        let mut message = Message::new_query(Question{
+            domain: "foo.com".parse().unwrap(),
+            record_type: RecordType::A,
+            record_class: RecordClass::IN
+        });
+        message.header.identifier = 1337;
+        message.header.is_response = true;
+        message.header.answer_count = 1;
+        message.answers.push(Record{
+            header: RecordHeader{
+                domain,
+                record_type: RecordType::A,
+                record_class: RecordClass::IN,
+                ttl: 5
+            },
+            body: RecordBody::A("1.2.3.4".parse().unwrap())
+        });
+        let data = dns_encode(&message).unwrap();
+        let decoded: Message = dns_decode(data).unwrap();
+        assert_eq!(describe_message(&decoded), "response id=1337 opcode=Query \
+            rcode=NoError flags=[rd]\n\
+            counts: 1 questions, 1 answers, 0 authorities, 0 additional\n\
+            question: foo.com A IN\n\
+            answer: foo.com A IN ttl=5 1.2.3.4\n");
+    }
+}
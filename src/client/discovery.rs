@@ -1,8 +1,11 @@
-use myodine::dns_proto::RecordType;
-use myodine::myo_proto::name_code::{NameCode, get_name_code};
-use myodine::myo_proto::record_code::{RecordCode, get_record_code};
+use std::cmp::min;
 
-use flags::Flags;
+use dns_proto::RecordType;
+use myo_proto::name_code::{NameCode, get_name_code, supported_name_encodings};
+use myo_proto::record_code::{RecordCode, get_record_code, supported_record_codes};
+use myo_proto::xfer::{FRAME_OVERHEAD, PADDING_OVERHEAD, Packet};
+
+use super::config::ClientConfig;
 
 /// Information about the optimal transport parameters
 /// supported by a server.
@@ -16,17 +19,224 @@ pub struct Features {
     pub record_code: Box<RecordCode>
 }
 
+/// The downstream codecs the client knows how to use, as tuples of the form
+/// (name, record type, response encoding).
+const DOWNSTREAM_CODECS: [(&str, RecordType, &str); 1] = [("txt", RecordType::TXT, "raw")];
+
 /// Figure out the optimal transport parameters that the
-/// server supports.
-pub fn discover_features(flags: &Flags) -> Result<Features, String> {
-    // TODO: perform feature discovery here.
+/// server supports, without sending any queries.
+///
+/// The most preferred response codec that the client supports is used.
+pub fn discover_features(config: &ClientConfig) -> Result<Features, String> {
+    discover_features_with(config, |_, _| true)
+}
+
+/// Like `discover_features`, but only use a response codec if `probe`
+/// passes for its record type and encoding.
+///
+/// Codecs are probed in order of preference, and the first one that passes
+/// is used, so a codec that the server or the path doesn't support falls
+/// back to the next one.
+pub fn discover_features_with<F>(config: &ClientConfig, mut probe: F) -> Result<Features, String>
+    where F: FnMut(RecordType, &str) -> bool
+{
+    let supported: Vec<String> = DOWNSTREAM_CODECS.iter()
+        .filter(|x| supported_record_codes(x.1).contains(&x.2))
+        .map(|x| x.0.to_owned())
+        .collect();
+    let codec = select_codec(&config.response_codecs, &supported, |name| {
+        let &(_, record_type, encoding) = find_codec(name).unwrap();
+        probe(record_type, encoding)
+    }).ok_or("none of the preferred response codecs are usable".to_owned())?;
+    let &(_, record_type, response_encoding) = find_codec(&codec).unwrap();
+    let name_encoding = supported_name_encodings()[0];
+    let max_mtu = max_query_mtu(config, name_encoding)?;
+    if max_mtu == 0 {
+        return Err(format!("host name {} leaves no room for data", config.host));
+    }
     Ok(Features{
-        record_type: RecordType::TXT,
-        response_encoding: "raw".to_owned(),
-        response_mtu: flags.response_mtu.unwrap_or(64),
-        name_encoding: "b16".to_owned(),
-        query_mtu: flags.query_mtu.unwrap_or(64),
-        name_code: get_name_code("b16").unwrap(),
-        record_code: get_record_code(RecordType::TXT, "raw").unwrap()
+        record_type,
+        response_encoding: response_encoding.to_owned(),
+        response_mtu: min(config.response_mtu.unwrap_or(64) as usize,
+            max_response_mtu(config, record_type, response_encoding)) as u16,
+        name_encoding: name_encoding.to_owned(),
+        query_mtu: min(config.query_mtu.unwrap_or(64) as usize, max_mtu) as u16,
+        name_code: get_name_code(name_encoding).unwrap(),
+        record_code: get_record_code(record_type, response_encoding).unwrap()
     })
 }
+
+fn find_codec(name: &str) -> Option<&'static (&'static str, RecordType, &'static str)> {
+    DOWNSTREAM_CODECS.iter().find(|x| x.0 == name)
+}
+
+/// Get the largest query MTU (chunk size) for which a transfer query still
+/// fits in a single domain name.
+///
+/// This depends on the length of the host domain, the name encoding, and the
+/// size of the acknowledgement that rides along with each chunk.
+pub fn max_query_mtu(config: &ClientConfig, name_encoding: &str) -> Result<usize, String> {
+    let name_code = get_name_code(name_encoding)
+        .ok_or(format!("unknown name encoding: {}", name_encoding))?;
+    let overhead = query_overhead(config);
+    let mut budget = config.name_template.max_data(&*name_code, config.api_chars.transfer,
+        &config.host, config.decoy_labels);
+    if let Some(labels) = config.max_data_labels {
+        budget = min(budget, name_code.max_label_data(labels));
+    }
+    Ok(budget.saturating_sub(overhead))
+}
+
+/// Get the number of bytes that a transfer query carries on top of its chunk.
+pub fn query_overhead(config: &ClientConfig) -> usize {
+    packet_overhead(config, config.negotiated_response_window())
+}
+
+/// Get the number of bytes that a transfer response carries on top of its
+/// chunk.
+pub fn response_overhead(config: &ClientConfig) -> usize {
+    packet_overhead(config, config.query_window)
+}
+
+/// Get the overhead of a transfer packet whose acknowledgement covers a
+/// window of `window_size` chunks.
+fn packet_overhead(config: &ClientConfig, window_size: u16) -> usize {
+    let mut overhead = Packet::query_overhead(window_size);
+    if config.pad_packets {
+        overhead += PADDING_OVERHEAD;
+    }
+    if config.frame_packets {
+        overhead += FRAME_OVERHEAD;
+    }
+    overhead
+}
+
+/// Get the largest response MTU (chunk size) for which a transfer response
+/// still fits in a DNS message.
+pub fn max_response_mtu(
+    config: &ClientConfig,
+    record_type: RecordType,
+    response_encoding: &str
+) -> usize {
+    Packet::max_response_mtu(record_type, response_encoding, config.query_window,
+        config.pad_packets, config.frame_packets, config.answers)
+}
+
+/// Parse a comma-separated list of codec names, in order of preference.
+///
+/// Names are case-insensitive and are returned in lowercase.
+pub fn parse_codec_list(list: &str) -> Result<Vec<String>, String> {
+    let mut result: Vec<String> = Vec::new();
+    for name in list.split(',').map(|x| x.trim().to_lowercase()) {
+        if name.is_empty() {
+            return Err(format!("empty codec name in list: {}", list));
+        } else if result.contains(&name) {
+            return Err(format!("duplicate codec name: {}", name));
+        }
+        result.push(name);
+    }
+    Ok(result)
+}
+
+/// Pick the most preferred codec that the server supports and that passes a
+/// probe.
+///
+/// The probe is only run on codecs that the server supports, in order of
+/// preference, until one succeeds.
+pub fn select_codec<F>(preferences: &[String], supported: &[String], mut probe: F) -> Option<String>
+    where F: FnMut(&str) -> bool
+{
+    preferences.iter()
+        .filter(|x| supported.contains(x))
+        .find(|x| probe(x))
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downstream_codecs_registered() {
+        for &(_, record_type, encoding) in &DOWNSTREAM_CODECS {
+            assert!(supported_record_codes(record_type).contains(&encoding));
+        }
+        assert!(supported_name_encodings().contains(&"b16"));
+    }
+
+    #[test]
+    fn long_host_query_mtu() {
+        let config = ClientConfig::new("127.0.0.1:53", "t.co".parse().unwrap());
+        let short_mtu = max_query_mtu(&config, "b16").unwrap();
+        assert!(short_mtu > 64);
+
+        let long_host = format!("{}.{}.{}.com", "a".repeat(63), "b".repeat(63), "c".repeat(40));
+        let config = ClientConfig::new("127.0.0.1:53", long_host.parse().unwrap());
+        let long_mtu = max_query_mtu(&config, "b16").unwrap();
+        // 255 - 174 (host) - 7 (session label) leaves 74 bytes, which holds 72
+        // hex digits in two labels. That is 36 bytes, minus 13 for the packet.
+        assert_eq!(long_mtu, 23);
+        assert_eq!(discover_features(&config).unwrap().query_mtu as usize, long_mtu);
+
+        let mut config = ClientConfig::new("127.0.0.1:53", "t.co".parse().unwrap());
+        config.max_data_labels = Some(1);
+        // One label holds 31 bytes, minus 13 for the packet.
+        assert_eq!(max_query_mtu(&config, "b16").unwrap(), 18);
+    }
+
+    #[test]
+    fn oversized_response_mtu() {
+        let mut config = ClientConfig::new("127.0.0.1:53", "t.co".parse().unwrap());
+        config.response_mtu = Some(65535);
+        let limit = max_response_mtu(&config, RecordType::TXT, "raw");
+        assert!(limit > 60000 && limit < 65535);
+        assert_eq!(discover_features(&config).unwrap().response_mtu as usize, limit);
+
+        config.pad_packets = true;
+        assert_eq!(max_response_mtu(&config, RecordType::TXT, "raw"), limit - PADDING_OVERHEAD);
+    }
+
+    #[test]
+    fn codec_list_parsing() {
+        assert_eq!(parse_codec_list("null, TXT,aaaa").unwrap(),
+            vec!["null".to_owned(), "txt".to_owned(), "aaaa".to_owned()]);
+        assert!(parse_codec_list("txt,,a").is_err());
+        assert!(parse_codec_list("txt,a,TXT").is_err());
+    }
+
+    #[test]
+    fn codec_selection() {
+        let prefs = parse_codec_list("null,txt,aaaa,cname,a").unwrap();
+        let supported = vec!["a".to_owned(), "aaaa".to_owned(), "txt".to_owned()];
+        assert_eq!(select_codec(&prefs, &supported, |_| true), Some("txt".to_owned()));
+
+        let mut probed = Vec::new();
+        let result = select_codec(&prefs, &supported, |x| {
+            probed.push(x.to_owned());
+            x == "a"
+        });
+        assert_eq!(result, Some("a".to_owned()));
+        assert_eq!(probed, vec!["txt".to_owned(), "aaaa".to_owned(), "a".to_owned()]);
+
+        assert_eq!(select_codec(&prefs, &supported, |_| false), None);
+    }
+
+    #[test]
+    fn codec_probe_fallback() {
+        let mut config = ClientConfig::new("127.0.0.1:53", "t.co".parse().unwrap());
+        config.response_codecs = parse_codec_list("null,txt").unwrap();
+        let mut probed = Vec::new();
+        let features = discover_features_with(&config, |record_type, encoding| {
+            probed.push((record_type, encoding.to_owned()));
+            true
+        }).unwrap();
+        // The client can't decode NULL records, so it never probes them.
+        assert_eq!(probed, vec![(RecordType::TXT, "raw".to_owned())]);
+        assert_eq!(features.record_type, RecordType::TXT);
+        assert_eq!(features.response_encoding, "raw");
+
+        assert!(discover_features_with(&config, |_, _| false).is_err());
+        config.response_codecs = parse_codec_list("null").unwrap();
+        assert!(discover_features(&config).is_err());
+    }
+}
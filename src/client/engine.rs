@@ -0,0 +1,419 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError, channel, sync_channel};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use conn::{DATAGRAM_BUFFER, TcpChunker, UdpChunker, dial_udp_from, forward_datagram};
+use myo_proto::name_code::supported_name_encodings;
+use myo_proto::record_code::{supported_record_codes, supported_record_types};
+
+use super::config::ClientConfig;
+use super::discovery::{Features, discover_features_with, max_query_mtu, max_response_mtu};
+use super::establish::{Establishment, establish};
+use super::health::{Health, listen as listen_health};
+use super::logger::RawLogger;
+use super::probe::{probe, probe_codec, probe_mtus};
+use super::resolvers::assign_lanes;
+use super::session::run_session;
+use super::shutdown::{install_handler as install_shutdown_handler,
+    requested as shutdown_requested};
+
+/// How often to check whether the client has been asked to exit.
+const SHUTDOWN_POLL_MS: u64 = 100;
+
+/// The largest possible UDP datagram.
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// Listen for local connections and proxy each one over a new session.
+///
+/// This returns an error if the listener fails. On SIGINT or SIGTERM, it stops
+/// accepting connections and returns once every session has flushed its
+/// outgoing data, or once `config.shutdown_grace` has passed.
+pub fn run(config: ClientConfig) -> Result<(), String> {
+    let listen_addr = (config.listen_addr, config.listen_port);
+    let (listener, udp_listener) = if config.udp {
+        (None, Some(UdpSocket::bind(listen_addr).map_err(|e| format!("listen error: {}", e))?))
+    } else {
+        (Some(TcpListener::bind(listen_addr).map_err(|e| format!("listen error: {}", e))?), None)
+    };
+    install_shutdown_handler()?;
+
+    let logger = RawLogger::new();
+    let health = Health::new();
+    if let Some(ref path) = config.health_socket {
+        listen_health(path, health.clone())?;
+        logger.log(format!("serving health status on {}", path));
+    }
+    let active = Arc::new(AtomicUsize::new(0));
+    let (error_sender, errors) = channel();
+    let (accept_config, accept_logger, accept_active) =
+        (config.clone(), logger.clone(), active.clone());
+    if let Some(socket) = udp_listener {
+        logger.log("listening for datagrams...".to_owned());
+        spawn(move || {
+            let err = datagram_loop(accept_config, socket, &accept_logger, health, accept_active);
+            error_sender.send(err).ok();
+        });
+    } else {
+        logger.log("listening for connections...".to_owned());
+        let listener = listener.unwrap();
+        spawn(move || {
+            let err = accept_loop(accept_config, listener, &accept_logger, health, accept_active);
+            error_sender.send(err).ok();
+        });
+    }
+    while !shutdown_requested() {
+        match errors.recv_timeout(Duration::from_millis(SHUTDOWN_POLL_MS)) {
+            Ok(err) => return Err(err),
+            Err(_) => continue
+        }
+    }
+
+    // Sessions give up on their own when the grace period runs out, so the
+    // extra second only covers sessions that are still being established.
+    let deadline = Instant::now() + config.shutdown_grace + Duration::from_secs(1);
+    logger.log(format!("exiting after {} sessions finish...", active.load(Ordering::SeqCst)));
+    while active.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        sleep(Duration::from_millis(SHUTDOWN_POLL_MS));
+    }
+    Ok(())
+}
+
+fn accept_loop(
+    config: ClientConfig,
+    listener: TcpListener,
+    logger: &RawLogger,
+    health: Health,
+    active: Arc<AtomicUsize>
+) -> String {
+    loop {
+        let (conn, addr) = match listener.accept() {
+            Ok(x) => x,
+            Err(e) => return format!("accept error: {}", e)
+        };
+        if shutdown_requested() {
+            // Refuse new connections while the existing ones are flushed.
+            continue;
+        }
+        logger.log(format!("new connection from {}", addr));
+        if config.tcp_nodelay {
+            if let Err(e) = conn.set_nodelay(true) {
+                logger.log(format!("error setting nodelay for {}: {}", addr, e));
+            }
+        }
+        let local_config = config.clone();
+        let local_logger = logger.clone();
+        let local_health = health.clone();
+        let local_active = active.clone();
+        local_active.fetch_add(1, Ordering::SeqCst);
+        spawn(move || {
+            if let Err(msg) = handle_connection(local_config, conn, &local_logger, &local_health) {
+                local_logger.log(format!("error for {}: {}", addr, msg));
+            } else {
+                local_logger.log(format!("session ended for {}", addr));
+            }
+            local_active.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Read datagrams from the local socket and proxy the datagrams of each peer
+/// address over its own session.
+///
+/// A peer's session ends after `config.udp_idle_timeout` without datagrams in
+/// either direction. Its next datagram starts a new session.
+fn datagram_loop(
+    config: ClientConfig,
+    socket: UdpSocket,
+    logger: &RawLogger,
+    health: Health,
+    active: Arc<AtomicUsize>
+) -> String {
+    let mut peers: HashMap<SocketAddr, SyncSender<Vec<u8>>> = HashMap::new();
+    let mut data = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let (size, addr) = match socket.recv_from(&mut data) {
+            Ok(x) => x,
+            Err(e) => return format!("receive error: {}", e)
+        };
+        if shutdown_requested() {
+            // Drop new datagrams while the existing sessions are flushed.
+            continue;
+        }
+        let mut datagram = data[0..size].to_vec();
+        if let Some(sender) = peers.get(&addr) {
+            match forward_datagram(sender, datagram) {
+                Ok(_) => continue,
+                Err(TrySendError::Disconnected(x)) | Err(TrySendError::Full(x)) => datagram = x
+            }
+        }
+        let reply_socket = match socket.try_clone() {
+            Ok(x) => x,
+            Err(e) => return format!("socket error: {}", e)
+        };
+        logger.log(format!("new datagram session for {}", addr));
+        let (sender, receiver) = sync_channel(DATAGRAM_BUFFER);
+        forward_datagram(&sender, datagram).ok();
+        peers.insert(addr, sender);
+        let local_config = config.clone();
+        let local_logger = logger.clone();
+        let local_health = health.clone();
+        let local_active = active.clone();
+        local_active.fetch_add(1, Ordering::SeqCst);
+        spawn(move || {
+            let result = handle_datagrams(local_config, reply_socket, addr, receiver,
+                &local_logger, &local_health);
+            if let Err(msg) = result {
+                local_logger.log(format!("error for {}: {}", addr, msg));
+            } else {
+                local_logger.log(format!("session ended for {}", addr));
+            }
+            local_active.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Proxy a single local connection over a new session.
+///
+/// The concurrent queries are spread across `config.addr` and any extra
+/// resolvers, subject to their concurrency caps. The session reports the
+/// acknowledgements it receives to `health`.
+pub fn handle_connection(
+    config: ClientConfig,
+    conn: TcpStream,
+    logger: &RawLogger,
+    health: &Health
+) -> Result<(), String> {
+    let sockets = dial_resolvers(&config, logger)?;
+    handle_connection_with_sockets(config, conn, sockets, logger, health)
+}
+
+/// Proxy a single local connection over a new session, using existing UDP
+/// sockets that are already connected to the DNS server.
+///
+/// One query is in flight per socket at a time, so the number of sockets
+/// takes the place of `config.concurrency`. This makes it possible to create
+/// sockets before dropping privileges, or to use sockets handed to the
+/// process by a supervisor.
+pub fn handle_connection_with_sockets(
+    config: ClientConfig,
+    conn: TcpStream,
+    sockets: Vec<UdpSocket>,
+    logger: &RawLogger,
+    health: &Health
+) -> Result<(), String> {
+    if sockets.is_empty() {
+        return Err("no UDP sockets provided".to_owned());
+    }
+    let early_source = if config.early_data { Some(&conn) } else { None };
+    let (info, case_lanes) = open_session(&config, &sockets, logger, early_source)?;
+    let conn = TcpChunker::new(
+        conn,
+        info.query_mtu as usize,
+        info.query_window as usize,
+        info.response_window as usize
+    ).map_err(|e| format!("error creating chunker: {}", e))?;
+    run_session(config, Box::new(conn), info, sockets, case_lanes, logger, health.register())
+}
+
+/// Proxy the datagrams from one peer of the local UDP socket over a new
+/// session.
+fn handle_datagrams(
+    config: ClientConfig,
+    socket: UdpSocket,
+    peer: SocketAddr,
+    incoming: Receiver<Vec<u8>>,
+    logger: &RawLogger,
+    health: &Health
+) -> Result<(), String> {
+    let sockets = dial_resolvers(&config, logger)?;
+    let (info, case_lanes) = open_session(&config, &sockets, logger, None)?;
+    let mut conn = UdpChunker::with_peer(socket, peer, incoming, info.query_mtu as usize);
+    conn.set_idle_timeout(Some(config.udp_idle_timeout));
+    run_session(config, Box::new(conn), info, sockets, case_lanes, logger, health.register())
+}
+
+/// Create a socket for each query lane, spread across `config.addr` and any
+/// extra resolvers, subject to their concurrency caps.
+///
+/// With `config.source_port`, lane `i` sends from that port plus `i`. If
+/// that port is taken, e.g. by another session that is still open, the lane
+/// sends from a random port instead, with a warning.
+pub fn dial_resolvers(config: &ClientConfig, logger: &RawLogger) -> Result<Vec<UdpSocket>, String> {
+    let (addrs, caps) = resolver_addrs(config);
+    let mut sockets = Vec::new();
+    for (lane, index) in assign_lanes(config.concurrency, &caps).into_iter().enumerate() {
+        let addr = &addrs[index];
+        let port = config.source_port.map(|x| x + lane as u16);
+        let socket = match dial_udp_from(addr, port) {
+            Err(ref e) if port.is_some() && e.kind() == ErrorKind::AddrInUse => {
+                logger.log(format!("lane {}: {}; using a random source port", lane, e));
+                dial_udp_from(addr, None)
+            },
+            x => x
+        };
+        sockets.push(socket.map_err(|e| format!("dial {}: {}", addr, e))?);
+    }
+    Ok(sockets)
+}
+
+/// Discover the server's features, probe each lane for 0x20 support, and
+/// establish a session.
+///
+/// Returns the session and whether each lane uses case randomization.
+fn open_session(
+    config: &ClientConfig,
+    sockets: &[UdpSocket],
+    logger: &RawLogger,
+    early_source: Option<&TcpStream>
+) -> Result<(Establishment, Vec<bool>), String> {
+    logger.log(format!("discovering features @{} for {}...", config.host, config.addr));
+    let mut features = discover_features_with(config, |record_type, encoding| {
+        let passed = probe_codec(config, &sockets[0], record_type, encoding);
+        if !passed {
+            logger.log(format!("{:?}/{} responses failed the probe", record_type, encoding));
+        }
+        passed
+    }).map_err(|e| format!("failed to discover features: {}", e))?;
+    if config.probe_mtu {
+        logger.log("probing MTUs...".to_owned());
+        apply_probed_mtus(config, &mut features, &sockets[0], |x| logger.log(x))?;
+    }
+    let mut case_lanes = Vec::new();
+    for (lane, socket) in sockets.iter().enumerate() {
+        if !config.case_randomization {
+            case_lanes.push(false);
+            continue;
+        }
+        match probe(config, socket) {
+            Ok(result) => {
+                logger.log(format!("lane {}: {}", lane,
+                    result.describe_case_randomization(config)));
+                case_lanes.push(result.use_case_randomization(config));
+            },
+            Err(e) => {
+                logger.log(format!("lane {}: 0x20 disabled (probe failed: {})", lane, e));
+                case_lanes.push(false);
+            }
+        }
+    }
+    logger.log("establishing session...".to_owned());
+    let establishment = establish(config, features, &sockets[0], early_source)?;
+    if !establishment.early_data.is_empty() {
+        logger.log(format!("sent {} bytes of early data", establishment.early_data.len()));
+    }
+    logger.log(format!("query MTU: requested {}, using {}",
+        describe_mtu(config.query_mtu), establishment.query_mtu));
+    logger.log(format!("response MTU: requested {}, using {}",
+        describe_mtu(config.response_mtu), establishment.response_mtu));
+    logger.log("running session...".to_owned());
+    Ok((establishment, case_lanes))
+}
+
+/// Probe each resolver and print the results, without opening a session.
+pub fn run_test(config: ClientConfig) -> Result<(), String> {
+    let features = {
+        // The socket is closed before the resolvers are dialed below, which
+        // may use the same source port.
+        let socket = dial_udp_from(&config.addr, config.source_port)
+            .map_err(|e| format!("dial {}: {}", config.addr, e))?;
+        let mut features = discover_features_with(&config, |record_type, encoding| {
+            let passed = probe_codec(&config, &socket, record_type, encoding);
+            println!("{:?}/{} responses: {}", record_type, encoding,
+                if passed { "probe passed" } else { "probe failed" });
+            passed
+        })?;
+        if config.probe_mtu {
+            apply_probed_mtus(&config, &mut features, &socket, |x| println!("{}", x))?;
+        }
+        features
+    };
+    let record_codes: Vec<String> = supported_record_types().into_iter()
+        .map(|x| format!("{:?}/{}", x, supported_record_codes(x).join(",")))
+        .collect();
+    println!("supported record codes: {}", record_codes.join(" "));
+    println!("supported name encodings: {}", supported_name_encodings().join(" "));
+    println!("upstream budget: {} bytes per query (max {} for {})", features.query_mtu,
+        max_query_mtu(&config, &features.name_encoding)?, config.host);
+    println!("downstream budget: {} bytes per response (max {} for {:?} records)",
+        features.response_mtu, max_response_mtu(&config, features.record_type,
+        &features.response_encoding), features.record_type);
+    for addr in resolver_addrs(&config).0 {
+        let socket = dial_udp_from(&addr, config.source_port)
+            .map_err(|e| format!("dial {}: {}", addr, e))?;
+        match probe(&config, &socket) {
+            Ok(result) => println!("{}: round trip {} ms, {}", addr,
+                result.round_trip.as_millis(), result.describe_case_randomization(&config)),
+            Err(e) => println!("{}: probe failed: {}", addr, e)
+        }
+    }
+    Ok(())
+}
+
+/// Probe the query and response MTUs that aren't set by flag, and use the
+/// results in place of the defaults.
+fn apply_probed_mtus<F: Fn(String)>(
+    config: &ClientConfig,
+    features: &mut Features,
+    socket: &UdpSocket,
+    log: F
+) -> Result<(), String> {
+    let (query_mtu, response_mtu) = probe_mtus(config, features, socket)?;
+    if config.query_mtu.is_none() {
+        let mtu = query_mtu.ok_or("no query got through the MTU probe")?;
+        log(format!("probed query MTU: {}", mtu));
+        features.query_mtu = mtu;
+    }
+    if config.response_mtu.is_none() {
+        let mtu = response_mtu.ok_or("no response got through the MTU probe")?;
+        log(format!("probed response MTU: {}", mtu));
+        features.response_mtu = mtu;
+    }
+    Ok(())
+}
+
+/// Get the address and concurrency cap of every resolver, starting with the
+/// main one.
+fn resolver_addrs(config: &ClientConfig) -> (Vec<String>, Vec<Option<usize>>) {
+    let mut addrs = vec![config.addr.clone()];
+    let mut caps = vec![None];
+    for resolver in &config.extra_resolvers {
+        addrs.push(resolver.addr.clone());
+        caps.push(resolver.max_concurrency);
+    }
+    (addrs, caps)
+}
+
+fn describe_mtu(mtu: Option<u16>) -> String {
+    match mtu {
+        Some(x) => format!("{}", x),
+        None => "auto".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simultaneous_fixed_port_dials() {
+        let resolver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let free_port = UdpSocket::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+        let mut config = ClientConfig::new(&resolver.local_addr().unwrap().to_string(),
+            "proxy.com".parse().unwrap());
+        config.concurrency = 1;
+        config.source_port = Some(free_port);
+        let logger = RawLogger::new();
+
+        let first = dial_resolvers(&config, &logger).unwrap();
+        assert_eq!(first[0].local_addr().unwrap().port(), free_port);
+        // The port is still held by the first session, so the second one
+        // falls back to a random port rather than failing.
+        let second = dial_resolvers(&config, &logger).unwrap();
+        assert_ne!(second[0].local_addr().unwrap().port(), free_port);
+    }
+}
@@ -1,15 +1,27 @@
-use std::net::UdpSocket;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::cmp;
+use std::io::{ErrorKind, Read};
+use std::net::{TcpStream, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use myodine::conn::dial_udp;
-use myodine::dns_coding::{dns_decode, dns_encode};
-use myodine::dns_proto::{Message, Question, RecordClass, RecordType};
-use myodine::myo_proto::establish::{EstablishQuery, EstablishResponse, password_proof};
-use myodine::myo_proto::name_code::NameCode;
-use myodine::myo_proto::record_code::RecordCode;
+use conn::{capture_received, capture_sent};
+use dns_coding::{dns_decode, dns_encode};
+use dns_proto::{Domain, Message, Question, RecordType};
+use myo_proto::establish::{DestinationQuery, EstablishQuery, EstablishResponse,
+    RESERVED_SESSION_ID, failure_proof_window, failure_server_time, new_establish_nonce,
+    password_proof};
+use myo_proto::name_code::NameCode;
+use myo_proto::record_code::RecordCode;
+use myo_proto::util::payload_answer;
 
-use discovery::Features;
-use flags::Flags;
+use super::config::ClientConfig;
+use super::discovery::Features;
+
+/// The proof window (in seconds) assumed for servers that report their time
+/// but not their window, which is the server's default.
+const DEFAULT_PROOF_WINDOW: u64 = 120;
+
+/// The longest time (in seconds) to wait for one establishment response.
+const MAX_ESTABLISH_TIMEOUT: u64 = 30;
 
 /// Information about an established session.
 pub struct Establishment {
@@ -21,59 +33,297 @@ pub struct Establishment {
     pub query_mtu: u16,
     pub response_mtu: u16,
     pub query_window: u16,
-    pub response_window: u16
+    pub response_window: u16,
+    /// The secret that resume queries present to the server.
+    pub resume_token: u32,
+    /// Data that was delivered with the establishment query.
+    pub early_data: Vec<u8>,
+    /// The establishment query, which is the basis for resuming the session.
+    pub query: EstablishQuery
 }
 
 /// Open a new session.
-pub fn establish(flags: &Flags, features: Features) -> Result<Establishment, String> {
+///
+/// The establishment query is sent over `conn`, which should be connected to
+/// the DNS server.
+///
+/// If `early_source` is provided, data that is already waiting on it is sent
+/// along with the establishment query, as much as fits. That data is consumed
+/// from the stream and recorded in the `Establishment`.
+pub fn establish(
+    config: &ClientConfig,
+    features: Features,
+    conn: &UdpSocket,
+    early_source: Option<&TcpStream>
+) -> Result<Establishment, String> {
     let epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-    let query = EstablishQuery{
-        response_encoding: features.response_encoding,
+    let mut query = establish_query(config, &features, epoch);
+    if let Some(stream) = early_source {
+        query.early_data = read_early_data(stream, &query, config, features.query_mtu)?;
+    }
+    let domain = query.to_domain(&config.host, &config.api_chars)?;
+    let mut response = send_establish(config, conn, features.record_type,
+        &*features.record_code, domain)?;
+    if query.split && response == EstablishResponse::Pending {
+        let follow_up = DestinationQuery{nonce: query.nonce.unwrap(), host: query.host.clone()};
+        let domain = follow_up.to_domain(&config.host, &config.api_chars)?;
+        response = send_establish(config, conn, features.record_type, &*features.record_code,
+            domain)?;
+    }
+    finish_establish(config, features, query, response, epoch)
+}
+
+/// Build the query for a new session, without early data.
+///
+/// The query is split if the destination doesn't fit alongside the other
+/// fields. `epoch` is the current time in seconds, for the proof.
+pub fn establish_query(config: &ClientConfig, features: &Features, epoch: u64) -> EstablishQuery {
+    let mut query = EstablishQuery{
+        response_encoding: features.response_encoding.clone(),
         mtu: features.response_mtu,
-        name_encoding: features.name_encoding,
-        query_window: flags.query_window,
-        response_window: flags.response_window,
-        proof: password_proof(&flags.password, epoch),
-        port: flags.remote_port,
-        host: flags.remote_host.clone()
+        query_mtu: Some(features.query_mtu),
+        name_encoding: features.name_encoding.clone(),
+        query_window: config.query_window,
+        response_window: config.negotiated_response_window(),
+        proof: if config.no_auth {
+            None
+        } else {
+            Some(password_proof(&config.password, epoch))
+        },
+        nonce: Some(new_establish_nonce()),
+        port: if config.remote_unix.is_some() { 0 } else { config.remote_port },
+        host: config.remote_unix.clone().unwrap_or_else(|| config.remote_host.clone()),
+        early_data: Vec::new(),
+        resume: None,
+        padded: config.pad_packets,
+        framed: config.frame_packets,
+        datagram: config.udp,
+        unix: config.remote_unix.is_some(),
+        answers: config.answers,
+        split: false
     };
-    let message = Message::new_query(Question{
-        domain: query.to_domain(&flags.host)?,
-        record_type: features.record_type,
-        record_class: RecordClass::IN
-    });
-    let conn = dial_udp(&flags.addr).map_err(|e| format!("dial {}: {}", flags.addr, e))?;
-    conn.set_read_timeout(Some(Duration::new(5, 0))).map_err(|e| format!("{}", e))?;
-    let response = query_with_retries(&conn, &message, 5)
-        .ok_or("no establishment response".to_owned())?;
-    if response.answers.len() != 1 {
-        return Err("invalid response message".to_owned());
-    }
-    let raw_data = features.record_code.decode_body(&response.answers[0].body)?;
-    match dns_decode(raw_data)? {
-        EstablishResponse::Success{id, seq} => {
+    if query.to_domain(&config.host, &config.api_chars).is_err() {
+        // The destination doesn't fit alongside the other fields, so it
+        // follows in a second query.
+        query.split = true;
+    }
+    query
+}
+
+/// Check the final response to an establishment `query` and get the session
+/// from it.
+///
+/// `epoch` is the time the query's proof was made for.
+pub fn finish_establish(
+    config: &ClientConfig,
+    features: Features,
+    query: EstablishQuery,
+    response: EstablishResponse,
+    epoch: u64
+) -> Result<Establishment, String> {
+    match response {
+        EstablishResponse::Success{id, seq, query_window, response_window, query_mtu,
+            response_mtu, resume_token} =>
+        {
+            check_success(&query, &response)?;
+            check_min_mtu(config.min_mtu, query_mtu, response_mtu)?;
             Ok(Establishment{
                 name_code: features.name_code,
                 record_code: features.record_code,
                 record_type: features.record_type,
                 session_id: id,
                 seq_start: seq,
-                query_mtu: features.query_mtu,
-                response_mtu: features.response_mtu,
-                query_window: flags.query_window,
-                response_window: flags.response_window
+                query_mtu,
+                response_mtu,
+                query_window,
+                response_window,
+                resume_token,
+                early_data: query.early_data.clone(),
+                query
             })
         },
-        EstablishResponse::Failure(msg) => {
-            Err(format!("error from server: {}", msg))
-        },
+        EstablishResponse::Failure(msg) => Err(failure_error(&msg, epoch)),
+        EstablishResponse::Pending => Err("unexpected pending establishment response".to_owned()),
         EstablishResponse::Unknown(x) => {
             Err(format!("unknown establishment response type: {}", x))
         }
     }
 }
 
-fn query_with_retries(conn: &UdpSocket, msg: &Message, tries: usize) -> Option<Message> {
+/// Send a query for an establishment domain, with retries, and decode the
+/// response.
+fn send_establish(
+    config: &ClientConfig,
+    conn: &UdpSocket,
+    record_type: RecordType,
+    record_code: &dyn RecordCode,
+    domain: Domain
+) -> Result<EstablishResponse, String> {
+    let mut message = Message::new_query(Question{
+        domain,
+        record_type,
+        record_class: config.record_class
+    });
+    message.header.recursion_desired = config.recursion_desired;
+    let response = establish_with_retries(conn, &message, config.establish_tries,
+        config.establish_timeout)?.ok_or("no establishment response".to_owned())?;
+    let raw_data = record_code.decode_body(payload_answer(&response)?)?;
+    dns_decode(raw_data)
+}
+
+/// Build a query that moves an established session to a new ID with a new
+/// response MTU, restarting the response stream at `window_start`.
+pub fn resume_query(
+    info: &Establishment,
+    password: &str,
+    window_start: u32,
+    mtu: u16
+) -> EstablishQuery {
+    // Servers only resume sessions for a fresh proof, even if the session
+    // was opened without one.
+    let mut query = info.query.clone();
+    let epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    query.proof = Some(password_proof(password, epoch));
+    query.mtu = mtu;
+    query.early_data = Vec::new();
+    query.nonce = None;
+    query.resume = Some((info.session_id, window_start, info.resume_token));
+    query
+}
+
+/// Check a response to a query from `resume_query`, and get the new session
+/// ID and response MTU from it.
+///
+/// Only the session ID and the response MTU may change when resuming.
+/// The resume token stays the same.
+pub fn check_resumed(
+    info: &Establishment,
+    window_start: u32,
+    mtu: u16,
+    response: &EstablishResponse
+) -> Result<(u16, u16), String> {
+    let mut query = info.query.clone();
+    query.mtu = mtu;
+    check_success(&query, response)?;
+    match *response {
+        EstablishResponse::Success{id, seq, query_window, response_window, query_mtu,
+            response_mtu, resume_token} =>
+        {
+            if seq != window_start {
+                Err(format!("server restarted at the wrong sequence number: {}", seq))
+            } else if (query_window, response_window, query_mtu, resume_token) !=
+                (info.query_window, info.response_window, info.query_mtu, info.resume_token)
+            {
+                Err("server changed the session parameters".to_owned())
+            } else {
+                Ok((id, response_mtu))
+            }
+        },
+        _ => Err("not a successful response".to_owned())
+    }
+}
+
+/// Read the data that is already waiting on `stream`, up to the query MTU and
+/// as much as fits in the establishment query's domain name.
+fn read_early_data(
+    stream: &TcpStream,
+    query: &EstablishQuery,
+    config: &ClientConfig,
+    query_mtu: u16
+) -> Result<Vec<u8>, String> {
+    let mut buffer = vec![0u8; query_mtu as usize];
+    stream.set_nonblocking(true).map_err(|e| format!("{}", e))?;
+    let peek_res = stream.peek(&mut buffer);
+    stream.set_nonblocking(false).map_err(|e| format!("{}", e))?;
+    let available = match peek_res {
+        Ok(size) => size,
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => 0,
+        Err(e) => return Err(format!("error reading early data: {}", e))
+    };
+    let mut trial = query.clone();
+    let mut size = available;
+    while size > 0 {
+        trial.early_data = buffer[..size].to_vec();
+        if trial.to_domain(&config.host, &config.api_chars).is_ok() {
+            break;
+        }
+        size -= 1;
+    }
+    buffer.truncate(size);
+    let mut stream = stream;
+    stream.read_exact(&mut buffer).map_err(|e| format!("error reading early data: {}", e))?;
+    Ok(buffer)
+}
+
+/// Check the fields of a successful establishment response to `query`.
+///
+/// A hostile resolver could forge a success, so fields that no server would
+/// send are rejected. The server may lower the requested windows and MTUs,
+/// but never raise them or set them to 0 (except for the response window of
+/// a push-only session). Every sequence number is valid, so `seq` is not
+/// checked.
+pub fn check_success(
+    query: &EstablishQuery,
+    response: &EstablishResponse
+) -> Result<(), String> {
+    let (id, query_window, response_window, query_mtu, response_mtu) = match *response {
+        EstablishResponse::Success{id, query_window, response_window, query_mtu, response_mtu,
+            ..} => (id, query_window, response_window, query_mtu, response_mtu),
+        _ => return Err("not a successful response".to_owned())
+    };
+    let requested_query_mtu = query.query_mtu.unwrap_or(0);
+    if id == RESERVED_SESSION_ID {
+        Err(format!("server assigned reserved session ID: {}", id))
+    } else if response_mtu == 0 || response_mtu > query.mtu {
+        Err(format!("server accepted invalid response MTU: {}", response_mtu))
+    } else if query_mtu > requested_query_mtu || (query_mtu == 0) != (requested_query_mtu == 0) {
+        Err(format!("server accepted invalid query MTU: {}", query_mtu))
+    } else if query_window == 0 || query_window > query.query_window {
+        Err(format!("server accepted invalid query window: {}", query_window))
+    } else if response_window > query.response_window ||
+        (response_window == 0) != (query.response_window == 0)
+    {
+        Err(format!("server accepted invalid response window: {}", response_window))
+    } else {
+        Ok(())
+    }
+}
+
+/// Make sure the negotiated MTUs are not below the configured floor.
+///
+/// A tiny MTU usually means a hostile path, and failing early is more useful
+/// than a tunnel with almost no throughput.
+pub fn check_min_mtu(min_mtu: Option<u16>, query_mtu: u16, response_mtu: u16) -> Result<(), String> {
+    if let Some(min) = min_mtu {
+        if query_mtu < min {
+            return Err(format!("query MTU {} is below the minimum of {}", query_mtu, min));
+        } else if response_mtu < min {
+            return Err(format!("response MTU {} is below the minimum of {}", response_mtu, min));
+        }
+    }
+    Ok(())
+}
+
+/// Describe a failure response for the user, blaming clock skew if the
+/// server reported its time and our clock is too far off for any proof to
+/// pass.
+fn failure_error(msg: &str, epoch: u64) -> String {
+    if let Some(server_time) = failure_server_time(msg) {
+        // Skew within the window can't have caused the failure, so the
+        // password is wrong.
+        let skew = server_time as i64 - epoch as i64;
+        let window = failure_proof_window(msg).unwrap_or(DEFAULT_PROOF_WINDOW);
+        if skew.unsigned_abs() > window {
+            return format!("error from server: server clock differs by {} seconds; fix NTP",
+                skew);
+        }
+    }
+    format!("error from server: {}", msg)
+}
+
+/// Send a query and wait for a response with a matching identifier, retrying
+/// on timeouts and errors.
+pub fn query_with_retries(conn: &UdpSocket, msg: &Message, tries: usize) -> Option<Message> {
     for _ in 0..tries {
         if let Ok(msg) = attempt_query(conn, msg) {
             return Some(msg);
@@ -82,14 +332,242 @@ fn query_with_retries(conn: &UdpSocket, msg: &Message, tries: usize) -> Option<M
     None
 }
 
+/// Send an establishment query until it gets a response, waiting twice as
+/// long after every try, up to `MAX_ESTABLISH_TIMEOUT`.
+///
+/// Every try resends the same message, so the proof stays the same and the
+/// server can tell a retry from a new session.
+fn establish_with_retries(
+    conn: &UdpSocket,
+    msg: &Message,
+    tries: usize,
+    timeout: Duration
+) -> Result<Option<Message>, String> {
+    let mut timeout = timeout;
+    for _ in 0..tries {
+        conn.set_read_timeout(Some(timeout)).map_err(|e| format!("{}", e))?;
+        if let Ok(msg) = attempt_query(conn, msg) {
+            return Ok(Some(msg));
+        }
+        timeout = next_establish_timeout(timeout);
+    }
+    Ok(None)
+}
+
+/// Get how long to wait for the next try of an establishment query, after a
+/// try that waited for `timeout`.
+pub fn next_establish_timeout(timeout: Duration) -> Duration {
+    cmp::min(timeout * 2, Duration::from_secs(MAX_ESTABLISH_TIMEOUT))
+}
+
 fn attempt_query(conn: &UdpSocket, msg: &Message) -> Result<Message, String> {
-    conn.send(&dns_encode(msg)?).map_err(|e| format!("{}", e))?;
+    let data = dns_encode(msg)?;
+    capture_sent(conn, None, &data);
+    conn.send(&data).map_err(|e| format!("{}", e))?;
+    let timeout = conn.read_timeout().map_err(|e| format!("{}", e))?;
+    let result = wait_for_response(conn, msg, timeout.map(|t| Instant::now() + t));
+    conn.set_read_timeout(timeout).map_err(|e| format!("{}", e))?;
+    result
+}
+
+/// Read responses until one answers the query, dropping late responses to
+/// earlier queries so they can't be taken for this one's.
+fn wait_for_response(conn: &UdpSocket, msg: &Message, deadline: Option<Instant>)
+    -> Result<Message, String>
+{
     let mut res_data = [0u8; 2048];
-    let size = conn.recv(&mut res_data).map_err(|e| format!("{}", e))?;
-    let res = dns_decode::<Message>(res_data[..size].to_vec())?;
-    if res.header.identifier == msg.header.identifier {
-        Ok(res)
-    } else {
-        Err("bad response identifier".to_owned())
+    loop {
+        if let Some(deadline) = deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err("timed out waiting for response".to_owned());
+            }
+            conn.set_read_timeout(Some(deadline - now)).map_err(|e| format!("{}", e))?;
+        }
+        let size = conn.recv(&mut res_data).map_err(|e| format!("{}", e))?;
+        capture_received(conn, None, &res_data[..size]);
+        let res = dns_decode::<Message>(res_data[..size].to_vec())?;
+        if res.header.identifier == msg.header.identifier {
+            return Ok(res);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use myo_proto::name_code::get_name_code;
+    use myo_proto::record_code::get_record_code;
+
+    #[test]
+    fn implausible_success() {
+        let query = EstablishQuery{
+            response_encoding: "raw".to_owned(),
+            mtu: 100,
+            query_mtu: Some(50),
+            name_encoding: "b16".to_owned(),
+            query_window: 4,
+            response_window: 8,
+            proof: None,
+            nonce: None,
+            port: 22,
+            host: "127.0.0.1".parse().unwrap(),
+            early_data: Vec::new(),
+            resume: None,
+            padded: false,
+            framed: false,
+            datagram: false,
+            unix: false,
+            answers: 1,
+            split: false
+        };
+        let success = |id, query_window, response_window, query_mtu, response_mtu| {
+            EstablishResponse::Success{id, seq: 0, query_window,
+                response_window, query_mtu,
+                response_mtu, resume_token: 0}
+        };
+        assert!(check_success(&query, &success(0, 4, 8, 50, 100)).is_ok());
+        assert!(check_success(&query, &success(65534, 1, 1, 1, 1)).is_ok());
+        assert!(check_success(&query, &success(RESERVED_SESSION_ID, 4, 8, 50, 100)).is_err());
+        assert!(check_success(&query, &success(0, 4, 8, 50, 0)).is_err());
+        assert!(check_success(&query, &success(0, 4, 8, 50, 101)).is_err());
+        assert!(check_success(&query, &success(0, 4, 8, 0, 100)).is_err());
+        assert!(check_success(&query, &success(0, 4, 8, 51, 100)).is_err());
+        assert!(check_success(&query, &success(0, 0, 8, 50, 100)).is_err());
+        assert!(check_success(&query, &success(0, 5, 8, 50, 100)).is_err());
+        assert!(check_success(&query, &success(0, 4, 0, 50, 100)).is_err());
+        assert!(check_success(&query, &success(0, 4, 9, 50, 100)).is_err());
+        assert!(check_success(&query, &EstablishResponse::Failure("x".to_owned())).is_err());
+
+        let mut push_only = query.clone();
+        push_only.response_window = 0;
+        assert!(check_success(&push_only, &success(0, 4, 0, 50, 100)).is_ok());
+        assert!(check_success(&push_only, &success(0, 4, 1, 50, 100)).is_err());
+    }
+
+    #[test]
+    fn min_mtu_floor() {
+        assert!(check_min_mtu(None, 1, 1).is_ok());
+        assert!(check_min_mtu(Some(32), 32, 100).is_ok());
+        assert!(check_min_mtu(Some(32), 31, 100).is_err());
+        assert!(check_min_mtu(Some(32), 100, 16).is_err());
+    }
+
+    #[test]
+    fn skew_blamed_outside_window() {
+        let skewed = "error from server: server clock differs by 200 seconds; fix NTP";
+        assert_eq!(failure_error("invalid proof; server time: 1200; proof window: 120", 1000),
+            skewed);
+        assert_eq!(failure_error("invalid proof; server time: 800; proof window: 300", 1000),
+            "error from server: invalid proof; server time: 800; proof window: 300");
+        // A password mismatch isn't blamed on skew the server tolerates.
+        assert_eq!(failure_error("invalid proof; server time: 1010; proof window: 120", 1000),
+            "error from server: invalid proof; server time: 1010; proof window: 120");
+        assert_eq!(failure_error("invalid proof; server time: 1200", 1000), skewed);
+        assert_eq!(failure_error("invalid proof; server time: 1100", 1000),
+            "error from server: invalid proof; server time: 1100");
+        assert_eq!(failure_error("invalid proof", 1000), "error from server: invalid proof");
+    }
+
+    #[test]
+    fn resume_token() {
+        let query = EstablishQuery{
+            response_encoding: "raw".to_owned(),
+            mtu: 100,
+            query_mtu: Some(50),
+            name_encoding: "b16".to_owned(),
+            query_window: 4,
+            response_window: 8,
+            proof: None,
+            nonce: None,
+            port: 22,
+            host: "127.0.0.1".parse().unwrap(),
+            early_data: Vec::new(),
+            resume: None,
+            padded: false,
+            framed: false,
+            datagram: false,
+            unix: false,
+            answers: 1,
+            split: false
+        };
+        let info = Establishment{
+            name_code: get_name_code("b16").unwrap(),
+            record_code: get_record_code(RecordType::TXT, "raw").unwrap(),
+            record_type: RecordType::TXT,
+            session_id: 3,
+            seq_start: 0,
+            query_mtu: 50,
+            response_mtu: 100,
+            query_window: 4,
+            response_window: 8,
+            resume_token: 0xabcd,
+            early_data: Vec::new(),
+            query
+        };
+
+        // Resumes carry a proof even for sessions opened without one.
+        let resume = resume_query(&info, "pw", 10, 60);
+        assert!(resume.proof.is_some());
+        assert_eq!(resume.resume, Some((3, 10, 0xabcd)));
+
+        let success = |resume_token| EstablishResponse::Success{id: 7, seq: 10, query_window: 4,
+            response_window: 8, query_mtu: 50, response_mtu: 60, resume_token};
+        assert_eq!(check_resumed(&info, 10, 60, &success(0xabcd)), Ok((7, 60)));
+        assert!(check_resumed(&info, 10, 60, &success(0xabce)).is_err());
+    }
+
+    #[test]
+    fn stale_response_skipped() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let conn = UdpSocket::bind("127.0.0.1:0").unwrap();
+        conn.connect(server.local_addr().unwrap()).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut query = Message::new_query(Question{
+            domain: "a.com".parse().unwrap(),
+            record_type: RecordType::A,
+            record_class: ::dns_proto::RecordClass::IN
+        });
+        query.header.identifier = 7;
+
+        let mut stale = query.clone();
+        stale.header.identifier = 6;
+        stale.header.is_response = true;
+        let mut response = stale.clone();
+        response.header.identifier = 7;
+        let addr = conn.local_addr().unwrap();
+        server.send_to(&dns_encode(&stale).unwrap(), addr).unwrap();
+        server.send_to(&dns_encode(&response).unwrap(), addr).unwrap();
+
+        let result = attempt_query(&conn, &query).unwrap();
+        assert_eq!(result.header.identifier, 7);
+        assert_eq!(conn.read_timeout().unwrap(), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn establish_retransmits() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let conn = UdpSocket::bind("127.0.0.1:0").unwrap();
+        conn.connect(server.local_addr().unwrap()).unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut query = Message::new_query(Question{
+            domain: "a.com".parse().unwrap(),
+            record_type: RecordType::A,
+            record_class: ::dns_proto::RecordClass::IN
+        });
+        query.header.identifier = 7;
+
+        let start = Instant::now();
+        let result = establish_with_retries(&conn, &query, 3, Duration::from_millis(50));
+        assert_eq!(result, Ok(None));
+        // The waits double: 50ms, 100ms, and 200ms.
+        assert!(start.elapsed() >= Duration::from_millis(350));
+
+        let mut buffer = [0u8; 512];
+        for _ in 0..3 {
+            let size = server.recv(&mut buffer).unwrap();
+            let retry: Message = dns_decode(buffer[..size].to_vec()).unwrap();
+            assert_eq!(retry, query);
+        }
     }
 }
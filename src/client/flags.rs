@@ -3,125 +3,346 @@ use std::time::Duration;
 
 use clap::{App, Arg};
 
+use myodine::client::{ClientConfig, parse_codec_list, parse_remote_host};
 use myodine::dns_proto::Domain;
 
-#[derive(Clone)]
-pub struct Flags {
-    pub addr: String,
-    pub host: Domain,
-    pub concurrency: usize,
-    pub query_window: u16,
-    pub response_window: u16,
-    pub password: String,
-    pub remote_host: Domain,
-    pub remote_port: u16,
-    pub listen_port: u16,
-    pub query_min_time: Duration,
-    pub query_max_time: Duration,
-    pub query_mtu: Option<u16>,
-    pub response_mtu: Option<u16>
+/// The parsed command-line arguments.
+pub struct Args {
+    pub config: ClientConfig,
+
+    /// If set, probe the resolvers and exit instead of running the client.
+    pub test: bool,
+
+    /// If set, round-trip data through every codec locally and exit.
+    pub selftest: bool,
+
+    /// If set, decode a DNS message from stdin, print it, and exit.
+    pub decode: bool,
+
+    /// A pcap file to write every DNS message to.
+    pub capture: Option<String>
 }
 
-impl Flags {
-    pub fn parse() -> Result<Flags, String> {
-        let matches = App::new("myodine-client")
-            .arg(Arg::with_name("concurrency")
-                .short("c")
-                .long("concurrency")
-                .value_name("NUM")
-                .help("Set the maximum number of concurrent requests")
-                .takes_value(true))
-            .arg(Arg::with_name("query-window")
-                .short("q")
-                .long("query-window")
-                .value_name("NUM")
-                .help("Set the window size for outgoing data")
-                .takes_value(true))
-            .arg(Arg::with_name("response-window")
-                .short("w")
-                .long("response-window")
-                .value_name("NUM")
-                .help("Set the window size for incoming data")
-                .takes_value(true))
-            .arg(Arg::with_name("remote-host")
-                .short("r")
-                .long("remote-host")
-                .value_name("ADDR")
-                .help("Set the remote address to proxy to")
-                .takes_value(true))
-            .arg(Arg::with_name("remote-port")
-                .short("n")
-                .long("remote-port")
-                .value_name("PORT")
-                .help("Set the remote port to proxy to")
-                .takes_value(true))
-            .arg(Arg::with_name("listen-port")
-                .short("l")
-                .long("listen-port")
-                .value_name("PORT")
-                .help("Set the local port to listen on")
-                .takes_value(true))
-            .arg(Arg::with_name("password")
-                .short("p")
-                .long("password")
-                .value_name("VALUE")
-                .help("Set the server password")
-                .takes_value(true))
-            .arg(Arg::with_name("query-max-time")
-                .long("query-max-time")
-                .value_name("INT")
-                .help("Set the query timeout in milliseconds")
-                .takes_value(true))
-            .arg(Arg::with_name("query-min-time")
-                .long("query-min-time")
-                .value_name("INT")
-                .help("Set the minimum query delay in milliseconds")
-                .takes_value(true))
-            .arg(Arg::with_name("query-mtu")
-                .long("query-mtu")
-                .value_name("INT")
-                .help("Set the query MTU to an explicit value")
-                .takes_value(true))
-            .arg(Arg::with_name("response-mtu")
-                .long("response-mtu")
-                .value_name("INT")
-                .help("Set the response MTU to an explicit value")
-                .takes_value(true))
-            .arg(Arg::with_name("addr")
-                .help("Set the address of the proxy")
-                .required(true)
-                .index(1))
-            .arg(Arg::with_name("host")
-                .help("Set the root domain name of the proxy")
-                .required(true)
-                .index(2))
-            .get_matches();
+/// Parse the command-line arguments.
+pub fn parse() -> Result<Args, String> {
+    let matches = App::new("myodine-client")
+        .arg(Arg::with_name("concurrency")
+            .short("c")
+            .long("concurrency")
+            .value_name("NUM")
+            .help("Set the maximum number of concurrent requests (lowered while queries fail)")
+            .takes_value(true))
+        .arg(Arg::with_name("read-ahead")
+            .long("read-ahead")
+            .value_name("NUM")
+            .help("Limit the number of concurrent queries that only poll for incoming data \
+                   (default: all of them)")
+            .takes_value(true))
+        .arg(Arg::with_name("query-window")
+            .short("q")
+            .long("query-window")
+            .value_name("NUM")
+            .help("Set the window size for outgoing data")
+            .takes_value(true))
+        .arg(Arg::with_name("response-window")
+            .short("w")
+            .long("response-window")
+            .value_name("NUM")
+            .help("Set the window size for incoming data")
+            .takes_value(true))
+        .arg(Arg::with_name("remote-host")
+            .short("r")
+            .long("remote-host")
+            .value_name("ADDR")
+            .help("Set the remote IPv4 address to proxy to")
+            .takes_value(true))
+        .arg(Arg::with_name("remote-port")
+            .short("n")
+            .long("remote-port")
+            .value_name("PORT")
+            .help("Set the remote port to proxy to")
+            .takes_value(true))
+        .arg(Arg::with_name("remote-unix")
+            .long("remote-unix")
+            .value_name("NAME")
+            .help("Proxy to the server's Unix socket with this name instead of a remote port")
+            .takes_value(true))
+        .arg(Arg::with_name("listen-addr")
+            .long("listen-addr")
+            .value_name("ADDR")
+            .help("Set the local IP address to listen on (default 127.0.0.1)")
+            .takes_value(true))
+        .arg(Arg::with_name("listen-port")
+            .short("l")
+            .long("listen-port")
+            .value_name("PORT")
+            .help("Set the local port to listen on")
+            .takes_value(true))
+        .arg(Arg::with_name("password")
+            .short("p")
+            .long("password")
+            .value_name("VALUE")
+            .help("Set the server password")
+            .takes_value(true))
+        .arg(Arg::with_name("query-max-time")
+            .long("query-max-time")
+            .value_name("INT")
+            .help("Set the query timeout in milliseconds")
+            .takes_value(true))
+        .arg(Arg::with_name("query-response-time")
+            .long("query-response-time")
+            .value_name("INT")
+            .help("Treat a query as dropped if no response arrives within this many milliseconds")
+            .takes_value(true))
+        .arg(Arg::with_name("max-poll-delay")
+            .long("max-poll-delay")
+            .value_name("INT")
+            .help("Back off polls for incoming data to at most one per this many milliseconds \
+                   while idle (0 to disable)")
+            .takes_value(true))
+        .arg(Arg::with_name("query-min-time")
+            .long("query-min-time")
+            .value_name("INT")
+            .help("Set the minimum query delay in milliseconds")
+            .takes_value(true))
+        .arg(Arg::with_name("query-mtu")
+            .long("query-mtu")
+            .value_name("INT")
+            .help("Set the query MTU to an explicit value")
+            .takes_value(true))
+        .arg(Arg::with_name("response-mtu")
+            .long("response-mtu")
+            .value_name("INT")
+            .help("Set the response MTU to an explicit value")
+            .takes_value(true))
+        .arg(Arg::with_name("min-mtu")
+            .long("min-mtu")
+            .value_name("INT")
+            .help("Abort if the negotiated query or response MTU is below this value")
+            .takes_value(true))
+        .arg(Arg::with_name("probe-mtu")
+            .long("probe-mtu")
+            .help("Probe the largest query and response MTUs that get through, separately \
+                   (unless set by flag)"))
+        .arg(Arg::with_name("max-server-failures")
+            .long("max-server-failures")
+            .value_name("NUM")
+            .help("Set the number of consecutive SERVFAIL/REFUSED responses to tolerate \
+                   before reconnecting")
+            .takes_value(true))
+        .arg(Arg::with_name("establish-tries")
+            .long("establish-tries")
+            .value_name("NUM")
+            .help("Set how many times to send the establishment query before giving up")
+            .takes_value(true))
+        .arg(Arg::with_name("establish-timeout")
+            .long("establish-timeout")
+            .value_name("SECS")
+            .help("Set how long to wait for the first establishment response (doubled on \
+                   every retry)")
+            .takes_value(true))
+        .arg(Arg::with_name("loss-warning")
+            .long("loss-warning")
+            .value_name("PERCENT")
+            .help("Warn when this share of transfer queries is lost (0 to disable)")
+            .takes_value(true))
+        .arg(Arg::with_name("max-inflight-bytes")
+            .long("max-inflight-bytes")
+            .value_name("NUM")
+            .help("Set the maximum number of unacknowledged outgoing bytes")
+            .takes_value(true))
+        .arg(Arg::with_name("response-codecs")
+            .long("response-codecs")
+            .value_name("LIST")
+            .help("Set the downstream codecs to try, in order of preference (e.g. null,txt,a)")
+            .takes_value(true))
+        .arg(Arg::with_name("push-only")
+            .long("push-only")
+            .help("Disable downstream data; responses only carry acknowledgements")
+            .conflicts_with("response-window"))
+        .arg(Arg::with_name("early-data")
+            .long("early-data")
+            .help("Send data that is already waiting along with the establishment query"))
+        .arg(Arg::with_name("pad-packets")
+            .long("pad-packets")
+            .help("Pad every query and response to a constant size (costs bandwidth)"))
+        .arg(Arg::with_name("frame-packets")
+            .long("frame-packets")
+            .help("Wrap every query and response in a container that analysis tools can parse"))
+        .arg(Arg::with_name("answers")
+            .long("answers")
+            .value_name("NUM")
+            .help("Split each transfer response across NUM answer records (1 to 16)")
+            .takes_value(true))
+        .arg(Arg::with_name("tcp-nodelay")
+            .long("tcp-nodelay")
+            .help("Disable Nagle's algorithm on local connections (lower latency)"))
+        .arg(Arg::with_name("udp")
+            .long("udp")
+            .help("Forward UDP datagrams instead of TCP connections")
+            .conflicts_with("early-data"))
+        .arg(Arg::with_name("source-port")
+            .long("source-port")
+            .value_name("PORT")
+            .help("Send queries from PORT, PORT+1, ... (one per lane) instead of random ports")
+            .takes_value(true))
+        .arg(Arg::with_name("udp-idle-timeout")
+            .long("udp-idle-timeout")
+            .value_name("SECS")
+            .help("End a UDP session after this many seconds without datagrams")
+            .takes_value(true))
+        .arg(Arg::with_name("no-auth")
+            .long("no-auth")
+            .help("Skip the password proof (the server must allow unauthenticated sessions)")
+            .conflicts_with("password"))
+        .arg(Arg::with_name("api-chars")
+            .long("api-chars")
+            .value_name("CHARS")
+            .help("Set the establish, transfer, and poll API characters (must match the server)")
+            .takes_value(true))
+        .arg(Arg::with_name("resolver")
+            .long("resolver")
+            .value_name("ADDR[,CAP]")
+            .help("Also spread queries across this resolver, with an optional concurrency cap \
+                   (may be repeated)")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("no-0x20")
+            .long("no-0x20")
+            .help("Never randomize the case of query names"))
+        .arg(Arg::with_name("decoy-labels")
+            .long("decoy-labels")
+            .value_name("INT")
+            .help("Add random labels to each transfer query name (uses query space)")
+            .takes_value(true))
+        .arg(Arg::with_name("name-template")
+            .long("name-template")
+            .value_name("TEMPLATE")
+            .help("Set the layout of transfer query names (must match the server)")
+            .takes_value(true))
+        .arg(Arg::with_name("max-data-labels")
+            .long("max-data-labels")
+            .value_name("INT")
+            .help("Limit the number of data labels in each transfer query name")
+            .takes_value(true))
+        .arg(Arg::with_name("verbose")
+            .short("v")
+            .long("verbose")
+            .help("Log the session's throughput every second"))
+        .arg(Arg::with_name("no-recursion")
+            .long("no-recursion")
+            .help("Clear the recursion-desired bit (for querying the server directly)"))
+        .arg(Arg::with_name("record-class")
+            .long("record-class")
+            .value_name("CLASS")
+            .help("Set the DNS class of queries (advanced; must match the server)")
+            .takes_value(true))
+        .arg(Arg::with_name("health-socket")
+            .long("health-socket")
+            .value_name("PATH")
+            .help("Report ok, degraded, or down to anyone who connects to this Unix socket")
+            .takes_value(true))
+        .arg(Arg::with_name("capture")
+            .long("capture")
+            .value_name("FILE")
+            .help("Write every DNS message sent or received to a pcap file")
+            .takes_value(true))
+        .arg(Arg::with_name("shutdown-grace")
+            .long("shutdown-grace")
+            .value_name("SECS")
+            .help("Set how long to wait for buffered data to be acknowledged when exiting")
+            .takes_value(true))
+        .arg(Arg::with_name("test")
+            .long("test")
+            .help("Probe the resolvers, print the results, and exit"))
+        .arg(Arg::with_name("selftest")
+            .long("selftest")
+            .help("Check every record and name codec locally, print the results, and exit"))
+        .arg(Arg::with_name("decode")
+            .long("decode")
+            .help("Decode a hex or base64 DNS message from stdin, print it, and exit"))
+        .arg(Arg::with_name("addr")
+            .help("Set the address of the proxy")
+            .required_unless_one(&["selftest", "decode"])
+            .index(1))
+        .arg(Arg::with_name("host")
+            .help("Set the root domain name of the proxy")
+            .required_unless_one(&["selftest", "decode"])
+            .index(2))
+        .get_matches();
 
-        macro_rules! parse_arg {
-            ( $name:expr, $default:expr ) => {
-                matches.value_of($name).unwrap_or($default).parse()
-                    .map_err(|e| format!("bad {} argument: {}", $name, e))
-            }
+    macro_rules! parse_arg {
+        ( $name:expr, $default:expr ) => {
+            matches.value_of($name).unwrap_or($default).parse()
+                .map_err(|e| format!("bad {} argument: {}", $name, e))
         }
-
-        let min_time: u64 = parse_arg!("query-min-time", "50")?;
-        let max_time: u64 = parse_arg!("query-max-time", "5000")?;
-        Ok(Flags{
-            addr: matches.value_of("addr").unwrap_or("localhost:53").to_owned(),
-            host: parse_arg!("host", "")?,
-            concurrency: parse_arg!("concurrency", "2")?,
-            query_window: parse_arg!("query-window", "4")?,
-            response_window: parse_arg!("response-window", "4")?,
-            password: matches.value_of("password").unwrap_or("").to_owned(),
-            remote_host: parse_arg!("remote-host", "127.0.0.1")?,
-            remote_port: parse_arg!("remote-port", "22")?,
-            listen_port: parse_arg!("listen-port", "2222")?,
-            query_min_time: Duration::from_millis(min_time),
-            query_max_time: Duration::from_millis(max_time),
-            query_mtu: parse_optional(matches.value_of("query-mtu"))?,
-            response_mtu: parse_optional(matches.value_of("response-mtu"))?
-        })
     }
+
+    let min_time: u64 = parse_arg!("query-min-time", "50")?;
+    let max_time: u64 = parse_arg!("query-max-time", "5000")?;
+    let config = ClientConfig{
+        addr: matches.value_of("addr").unwrap_or("localhost:53").to_owned(),
+        host: Domain::from_user_input(matches.value_of("host").unwrap_or("localhost"))
+            .map_err(|e| format!("bad host argument: {}", e))?,
+        concurrency: parse_arg!("concurrency", "2")?,
+        read_ahead: parse_optional(matches.value_of("read-ahead"))?,
+        query_window: parse_arg!("query-window", "4")?,
+        response_window: parse_arg!("response-window", "4")?,
+        push_only: matches.is_present("push-only"),
+        early_data: matches.is_present("early-data"),
+        pad_packets: matches.is_present("pad-packets"),
+        frame_packets: matches.is_present("frame-packets"),
+        answers: parse_arg!("answers", "1")?,
+        tcp_nodelay: matches.is_present("tcp-nodelay"),
+        udp: matches.is_present("udp"),
+        udp_idle_timeout: Duration::from_secs(parse_arg!("udp-idle-timeout", "60")?),
+        source_port: parse_optional(matches.value_of("source-port"))?,
+        password: matches.value_of("password").unwrap_or("").to_owned(),
+        remote_host: parse_remote_host(matches.value_of("remote-host").unwrap_or("127.0.0.1"))
+            .map_err(|e| format!("bad remote-host argument: {}", e))?,
+        remote_port: parse_arg!("remote-port", "22")?,
+        remote_unix: parse_optional(matches.value_of("remote-unix"))
+            .map_err(|e| format!("bad remote-unix argument: {}", e))?,
+        listen_addr: parse_arg!("listen-addr", "127.0.0.1")?,
+        listen_port: parse_arg!("listen-port", "2222")?,
+        query_min_time: Duration::from_millis(min_time),
+        query_max_time: Duration::from_millis(max_time),
+        query_response_time: parse_optional(matches.value_of("query-response-time"))?
+            .map(Duration::from_millis),
+        max_poll_delay: Duration::from_millis(parse_arg!("max-poll-delay", "1000")?),
+        query_mtu: parse_optional(matches.value_of("query-mtu"))?,
+        response_mtu: parse_optional(matches.value_of("response-mtu"))?,
+        min_mtu: parse_optional(matches.value_of("min-mtu"))?,
+        probe_mtu: matches.is_present("probe-mtu"),
+        recursion_desired: !matches.is_present("no-recursion"),
+        max_server_failures: parse_arg!("max-server-failures", "32")?,
+        establish_tries: parse_arg!("establish-tries", "5")?,
+        establish_timeout: Duration::from_secs(parse_arg!("establish-timeout", "5")?),
+        loss_warning: parse_arg!("loss-warning", "20")?,
+        max_inflight_bytes: parse_optional(matches.value_of("max-inflight-bytes"))?,
+        response_codecs: parse_codec_list(matches.value_of("response-codecs").unwrap_or("txt"))
+            .map_err(|e| format!("bad response-codecs argument: {}", e))?,
+        no_auth: matches.is_present("no-auth"),
+        api_chars: parse_arg!("api-chars", "etp")?,
+        extra_resolvers: parse_all(matches.values_of("resolver"))?,
+        case_randomization: !matches.is_present("no-0x20"),
+        decoy_labels: parse_arg!("decoy-labels", "0")?,
+        name_template: parse_arg!("name-template", "{api}{session}.{data}")?,
+        verbose: matches.is_present("verbose"),
+        health_socket: matches.value_of("health-socket").map(|x| x.to_owned()),
+        shutdown_grace: Duration::from_secs(parse_arg!("shutdown-grace", "5")?),
+        max_data_labels: parse_optional(matches.value_of("max-data-labels"))?,
+        record_class: parse_arg!("record-class", "IN")?
+    };
+    config.validate()?;
+    Ok(Args{
+        config,
+        test: matches.is_present("test"),
+        selftest: matches.is_present("selftest"),
+        decode: matches.is_present("decode"),
+        capture: matches.value_of("capture").map(|x| x.to_owned())
+    })
 }
 
 fn parse_optional<T: FromStr>(x: Option<&str>) -> Result<Option<T>, String> {
@@ -130,3 +351,13 @@ fn parse_optional<T: FromStr>(x: Option<&str>) -> Result<Option<T>, String> {
         None => Ok(None),
     }
 }
+
+fn parse_all<'a, T, I>(x: Option<I>) -> Result<Vec<T>, String>
+    where T: FromStr, T::Err: ::std::fmt::Display, I: Iterator<Item = &'a str>
+{
+    match x {
+        Some(values) => values.map(|s| s.parse().map_err(|e| format!("bad argument {}: {}", s, e)))
+            .collect(),
+        None => Ok(Vec::new())
+    }
+}
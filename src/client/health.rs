@@ -0,0 +1,180 @@
+//! A local health check for supervisors.
+//!
+//! Watchdogs connect to a Unix socket and read a single line describing the
+//! tunnel, then the client closes the connection. The line is one of:
+//!
+//! * `ok` - every session has received an acknowledgement recently, or there
+//!   are no sessions.
+//! * `degraded` - some sessions have stopped receiving acknowledgements.
+//! * `down` - no session has received an acknowledgement in a long time.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Error, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The number of seconds without an acknowledgement after which a session
+/// counts as unhealthy.
+pub const DEGRADED_AFTER_SECS: u64 = 10;
+
+/// The number of seconds without an acknowledgement on any session after which
+/// the tunnel counts as down.
+pub const DOWN_AFTER_SECS: u64 = 30;
+
+/// The overall health of the client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HealthStatus {
+    Ok,
+    Degraded,
+    Down
+}
+
+impl Display for HealthStatus {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match *self {
+            HealthStatus::Ok => write!(f, "ok"),
+            HealthStatus::Degraded => write!(f, "degraded"),
+            HealthStatus::Down => write!(f, "down")
+        }
+    }
+}
+
+/// The time of the last acknowledgement on every running session.
+#[derive(Clone)]
+pub struct Health(Arc<Mutex<HealthState>>);
+
+struct HealthState {
+    next_id: usize,
+    last_acks: HashMap<usize, Instant>
+}
+
+impl Health {
+    pub fn new() -> Health {
+        Health(Arc::new(Mutex::new(HealthState{next_id: 0, last_acks: HashMap::new()})))
+    }
+
+    /// Start tracking a session until the returned handle is dropped.
+    ///
+    /// A new session counts as healthy until `DEGRADED_AFTER_SECS` pass
+    /// without an acknowledgement.
+    pub fn register(&self) -> HealthHandle {
+        let mut state = self.0.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.last_acks.insert(id, Instant::now());
+        HealthHandle{health: self.clone(), id}
+    }
+
+    /// Get the current health of the client.
+    pub fn status(&self) -> HealthStatus {
+        self.status_at(Instant::now())
+    }
+
+    fn status_at(&self, now: Instant) -> HealthStatus {
+        let state = self.0.lock().unwrap();
+        let ages: Vec<Duration> = state.last_acks.values().map(|x| now - *x).collect();
+        let degraded = Duration::from_secs(DEGRADED_AFTER_SECS);
+        let down = Duration::from_secs(DOWN_AFTER_SECS);
+        if ages.iter().all(|x| *x < degraded) {
+            HealthStatus::Ok
+        } else if ages.iter().all(|x| *x >= down) {
+            HealthStatus::Down
+        } else {
+            HealthStatus::Degraded
+        }
+    }
+}
+
+impl Default for Health {
+    fn default() -> Health {
+        Health::new()
+    }
+}
+
+/// A session's entry in a `Health`.
+pub struct HealthHandle {
+    health: Health,
+    id: usize
+}
+
+impl HealthHandle {
+    /// Record that the session received a valid acknowledgement.
+    pub fn record_ack(&self) {
+        self.health.0.lock().unwrap().last_acks.insert(self.id, Instant::now());
+    }
+}
+
+impl Drop for HealthHandle {
+    fn drop(&mut self) {
+        self.health.0.lock().unwrap().last_acks.remove(&self.id);
+    }
+}
+
+/// Serve the health status on a Unix socket.
+///
+/// The socket is only accessible to its owner.
+#[cfg(unix)]
+pub fn listen(path: &str, health: Health) -> Result<(), String> {
+    use std::fs::{Permissions, metadata, remove_file, set_permissions};
+    use std::io::Write;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::os::unix::net::UnixListener;
+    use std::thread::spawn;
+
+    if let Ok(info) = metadata(path) {
+        // Remove a socket left behind by a previous run.
+        if info.file_type().is_socket() {
+            remove_file(path).map_err(|e| format!("remove {}: {}", path, e))?;
+        }
+    }
+    let listener = UnixListener::bind(path).map_err(|e| format!("health listen failed: {}", e))?;
+    set_permissions(path, Permissions::from_mode(0o600))
+        .map_err(|e| format!("chmod {}: {}", path, e))?;
+    spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            stream.write_all(format!("{}\n", health.status()).as_bytes()).ok();
+        }
+    });
+    Ok(())
+}
+
+/// Serve the health status on a Unix socket.
+///
+/// This is only supported on Unix systems.
+#[cfg(not(unix))]
+pub fn listen(_path: &str, _health: Health) -> Result<(), String> {
+    Err("the health socket is only supported on Unix".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_from_ack_ages() {
+        let health = Health::new();
+        let start = Instant::now();
+        assert_eq!(health.status_at(start), HealthStatus::Ok);
+
+        let first = health.register();
+        let second = health.register();
+        let set_ack = |handle: &HealthHandle, time: Instant| {
+            health.0.lock().unwrap().last_acks.insert(handle.id, time);
+        };
+        set_ack(&first, start);
+        set_ack(&second, start);
+        assert_eq!(health.status_at(start + Duration::from_secs(9)), HealthStatus::Ok);
+        assert_eq!(health.status_at(start + Duration::from_secs(10)), HealthStatus::Degraded);
+        assert_eq!(health.status_at(start + Duration::from_secs(30)), HealthStatus::Down);
+
+        // One session still getting acks keeps the tunnel from being down.
+        set_ack(&second, start + Duration::from_secs(25));
+        assert_eq!(health.status_at(start + Duration::from_secs(30)), HealthStatus::Degraded);
+
+        // Finished sessions no longer count.
+        drop(first);
+        assert_eq!(health.status_at(start + Duration::from_secs(30)), HealthStatus::Ok);
+        drop(second);
+        assert_eq!(health.status_at(start + Duration::from_secs(100)), HealthStatus::Ok);
+    }
+}
@@ -1,5 +1,7 @@
-use chrono::Local;
+extern crate chrono;
+use self::chrono::Local;
 
+use std::collections::VecDeque;
 use std::fmt::{Display, Error, Formatter};
 use std::sync::mpsc::{Receiver, Sender, TryRecvError, channel};
 use std::thread::{sleep, spawn};
@@ -26,6 +28,18 @@ impl RawLogger {
     }
 }
 
+impl Default for RawLogger {
+    fn default() -> RawLogger {
+        RawLogger::new()
+    }
+}
+
+/// How often the periodic stats line is logged.
+const FLUSH_INTERVAL_SECS: u64 = 10;
+
+/// The span of time that the live throughput estimate averages over.
+const RATE_WINDOW_SECS: u64 = 5;
+
 pub struct SessionLogger {
     sender: Sender<SessionMessage>,
 
@@ -35,15 +49,18 @@ pub struct SessionLogger {
 }
 
 impl SessionLogger {
-    pub fn new(raw: RawLogger) -> SessionLogger {
+    /// Create a logger for a session.
+    ///
+    /// If `verbose` is set, a throughput estimate is also logged every second.
+    pub fn new(raw: RawLogger, verbose: bool) -> SessionLogger {
         let (sender, receiver) = channel();
         spawn(|| {
             SessionLogger::log_loop(raw, receiver);
         });
         let local_sender = sender.clone();
         let (timeout_closer, close_receiver) = channel();
-        spawn(|| {
-            SessionLogger::interval_loop(local_sender, close_receiver);
+        spawn(move || {
+            SessionLogger::interval_loop(local_sender, close_receiver, verbose);
         });
         SessionLogger{
             sender: sender,
@@ -55,6 +72,10 @@ impl SessionLogger {
         self.sender.send(SessionMessage::Timeout).unwrap();
     }
 
+    pub fn log_server_failure(&self) {
+        self.sender.send(SessionMessage::ServerFailure).unwrap();
+    }
+
     pub fn log_response(&self) {
         self.sender.send(SessionMessage::Response).unwrap();
     }
@@ -67,12 +88,19 @@ impl SessionLogger {
         self.sender.send(SessionMessage::Outbound(size)).unwrap();
     }
 
+    pub fn log_retransmissions(&self, count: u64) {
+        self.sender.send(SessionMessage::Retransmissions(count)).unwrap();
+    }
+
     pub fn log_raw(&self, msg: String) {
         self.sender.send(SessionMessage::Raw(msg)).unwrap();
     }
 
     fn log_loop(raw: RawLogger, receiver: Receiver<SessionMessage>) {
         let mut stats = SessionStats::new();
+        let window = Duration::from_secs(RATE_WINDOW_SECS);
+        let (mut inbound_rate, mut outbound_rate) = (RollingRate::new(window),
+            RollingRate::new(window));
         for msg in receiver {
             match msg {
                 SessionMessage::Raw(x) => {
@@ -82,6 +110,20 @@ impl SessionLogger {
                     raw.log(format!("{}", stats));
                     stats = SessionStats::new();
                 },
+                SessionMessage::Status => {
+                    let now = Instant::now();
+                    raw.log(format!("throughput ({}s avg): dl={} bytes/sec\tul={} bytes/sec",
+                        RATE_WINDOW_SECS, inbound_rate.rate(now) as u64,
+                        outbound_rate.rate(now) as u64));
+                },
+                SessionMessage::Inbound(x) => {
+                    inbound_rate.add(Instant::now(), x);
+                    stats.update(msg);
+                },
+                SessionMessage::Outbound(x) => {
+                    outbound_rate.add(Instant::now(), x);
+                    stats.update(msg);
+                },
                 _ => {
                     stats.update(msg);
                 }
@@ -89,14 +131,19 @@ impl SessionLogger {
         }
     }
 
-    fn interval_loop(sender: Sender<SessionMessage>, closer: Receiver<()>) {
+    fn interval_loop(sender: Sender<SessionMessage>, closer: Receiver<()>, verbose: bool) {
+        let mut seconds = 0;
         loop {
-            sleep(Duration::from_secs(10));
+            sleep(Duration::from_secs(1));
+            seconds += 1;
             match closer.try_recv() {
                 Err(TryRecvError::Disconnected) => return,
                 _ => ()
             }
-            if sender.send(SessionMessage::Flush).is_err() {
+            if verbose && sender.send(SessionMessage::Status).is_err() {
+                return;
+            }
+            if seconds % FLUSH_INTERVAL_SECS == 0 && sender.send(SessionMessage::Flush).is_err() {
                 return;
             }
         }
@@ -105,19 +152,24 @@ impl SessionLogger {
 
 enum SessionMessage {
     Flush,
+    Status,
     Timeout,
+    ServerFailure,
     Response,
     Inbound(usize),
     Outbound(usize),
+    Retransmissions(u64),
     Raw(String)
 }
 
 struct SessionStats {
     start_time: Instant,
     total_timeouts: usize,
+    total_server_failures: usize,
     total_responses: usize,
     total_inbound: usize,
-    total_outbound: usize
+    total_outbound: usize,
+    total_retransmissions: u64
 }
 
 impl SessionStats {
@@ -125,18 +177,22 @@ impl SessionStats {
         SessionStats{
             start_time: Instant::now(),
             total_timeouts: 0,
+            total_server_failures: 0,
             total_responses: 0,
             total_inbound: 0,
-            total_outbound: 0
+            total_outbound: 0,
+            total_retransmissions: 0
         }
     }
 
     fn update(&mut self, msg: SessionMessage) {
         match msg {
             SessionMessage::Timeout => self.total_timeouts += 1,
+            SessionMessage::ServerFailure => self.total_server_failures += 1,
             SessionMessage::Response => self.total_responses += 1,
             SessionMessage::Inbound(x) => self.total_inbound += x,
             SessionMessage::Outbound(x) => self.total_outbound += x,
+            SessionMessage::Retransmissions(x) => self.total_retransmissions += x,
             _ => ()
         }
     }
@@ -146,9 +202,78 @@ impl Display for SessionStats {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
         let duration = self.start_time.elapsed();
         let seconds = ((duration * 1000).as_secs() as f64) / 1000.0;
-        write!(f, "timeouts={}\tresponses={}\tdl={}\tbytes/sec\tul={} bytes/sec",
-            self.total_timeouts, self.total_responses,
+        write!(f, "timeouts={}\tservfails={}\tresponses={}\tretransmits={}\tdl={}\tbytes/sec\t\
+            ul={} bytes/sec", self.total_timeouts, self.total_server_failures,
+            self.total_responses, self.total_retransmissions,
             ((self.total_inbound as f64) / seconds) as u64,
             ((self.total_outbound as f64) / seconds) as u64)
     }
 }
+
+/// A throughput estimate over a sliding window of time.
+struct RollingRate {
+    window: Duration,
+    start_time: Instant,
+    samples: VecDeque<(Instant, usize)>
+}
+
+impl RollingRate {
+    fn new(window: Duration) -> RollingRate {
+        RollingRate{
+            window,
+            start_time: Instant::now(),
+            samples: VecDeque::new()
+        }
+    }
+
+    fn add(&mut self, now: Instant, bytes: usize) {
+        self.samples.push_back((now, bytes));
+        self.expire(now);
+    }
+
+    /// Get the bytes per second over the window ending at `now`.
+    ///
+    /// Before a full window has passed, the average is over the time so far.
+    fn rate(&mut self, now: Instant) -> f64 {
+        self.expire(now);
+        let span = if now - self.start_time < self.window {
+            now - self.start_time
+        } else {
+            self.window
+        };
+        let seconds = span.as_secs() as f64 + span.subsec_nanos() as f64 / 1e9;
+        if seconds == 0.0 {
+            return 0.0;
+        }
+        self.samples.iter().map(|x| x.1).sum::<usize>() as f64 / seconds
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(time, _)) = self.samples.front() {
+            if now - time > self.window {
+                self.samples.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rolling_rate() {
+        let mut rate = RollingRate::new(Duration::from_secs(5));
+        let start = rate.start_time;
+        assert_eq!(rate.rate(start), 0.0);
+        rate.add(start + Duration::from_secs(1), 1000);
+        assert_eq!(rate.rate(start + Duration::from_secs(2)), 500.0);
+        rate.add(start + Duration::from_secs(4), 4000);
+        assert_eq!(rate.rate(start + Duration::from_secs(5)), 1000.0);
+        // The first sample has left the window.
+        assert_eq!(rate.rate(start + Duration::from_millis(6500)), 800.0);
+        assert_eq!(rate.rate(start + Duration::from_secs(20)), 0.0);
+    }
+}
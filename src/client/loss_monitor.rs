@@ -0,0 +1,142 @@
+use std::time::{Duration, Instant};
+
+/// How often the loss rate is checked.
+pub const CHECK_INTERVAL_SECS: u64 = 10;
+
+/// The fewest transfer queries that must finish in an interval for its loss
+/// rate to mean anything.
+const MIN_SAMPLE: u64 = 20;
+
+/// Watches the share of transfer queries that time out or get an unusable
+/// response.
+///
+/// Retransmitted chunks are not a good measure of loss, since chunks are also
+/// sent again on purpose whenever there are more lanes than unacknowledged
+/// chunks.
+///
+/// The rate is measured over intervals of `CHECK_INTERVAL_SECS`. An interval
+/// above the threshold is reported once; after that, the rate has to drop
+/// below the threshold before it is reported again.
+pub struct LossMonitor {
+    threshold: u32,
+    interval_start: Instant,
+    queries: u64,
+    failures: u64,
+    reported: bool
+}
+
+impl LossMonitor {
+    /// Create a monitor that reports loss rates of at least `threshold`
+    /// percent.
+    ///
+    /// A threshold of 0 disables reporting.
+    pub fn new(threshold: u32) -> LossMonitor {
+        LossMonitor{
+            threshold,
+            interval_start: Instant::now(),
+            queries: 0,
+            failures: 0,
+            reported: false
+        }
+    }
+
+    /// Record a transfer query that got a valid response.
+    pub fn record_success(&mut self) {
+        self.queries += 1;
+    }
+
+    /// Record a transfer query that timed out or got an unusable response.
+    pub fn record_failure(&mut self) {
+        self.queries += 1;
+        self.failures += 1;
+    }
+
+    /// Get the loss rate (as a percentage) of the interval that just ended,
+    /// if it had enough queries to mean anything.
+    ///
+    /// Each interval's rate is only returned once, and never if the monitor
+    /// is disabled.
+    pub fn check_interval(&mut self, now: Instant) -> Option<u32> {
+        let interval = Duration::from_secs(CHECK_INTERVAL_SECS);
+        if self.threshold == 0 || now - self.interval_start < interval {
+            return None;
+        }
+        let (queries, failures) = (self.queries, self.failures);
+        self.interval_start = now;
+        self.queries = 0;
+        self.failures = 0;
+        if queries < MIN_SAMPLE {
+            return None;
+        }
+        Some((failures * 100 / queries) as u32)
+    }
+
+    /// Check if a loss rate is at or above the threshold.
+    pub fn is_high(&self, rate: u32) -> bool {
+        rate >= self.threshold
+    }
+
+    /// Check if an interval's loss rate should be reported, which is only
+    /// the first time in a row that it is high.
+    pub fn should_report(&mut self, rate: u32) -> bool {
+        if !self.is_high(rate) {
+            self.reported = false;
+            false
+        } else if self.reported {
+            false
+        } else {
+            self.reported = true;
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(monitor: &mut LossMonitor, successes: usize, failures: usize) {
+        for _ in 0..successes {
+            monitor.record_success();
+        }
+        for _ in 0..failures {
+            monitor.record_failure();
+        }
+    }
+
+    fn check(monitor: &mut LossMonitor, now: Instant) -> Option<u32> {
+        monitor.check_interval(now).filter(|&rate| monitor.should_report(rate))
+    }
+
+    #[test]
+    fn reports_high_rates_once() {
+        let mut monitor = LossMonitor::new(20);
+        let start = monitor.interval_start;
+        let interval = Duration::from_secs(CHECK_INTERVAL_SECS);
+        record(&mut monitor, 70, 30);
+        assert_eq!(check(&mut monitor, start + interval / 2), None);
+        assert_eq!(check(&mut monitor, start + interval), Some(30));
+        record(&mut monitor, 70, 30);
+        assert_eq!(check(&mut monitor, start + interval * 2), None);
+
+        // Dropping below the threshold re-arms the report.
+        record(&mut monitor, 90, 10);
+        assert_eq!(check(&mut monitor, start + interval * 3), None);
+        record(&mut monitor, 80, 20);
+        assert_eq!(check(&mut monitor, start + interval * 4), Some(20));
+
+        // Too few queries to tell.
+        record(&mut monitor, 0, 10);
+        assert_eq!(check(&mut monitor, start + interval * 5), None);
+
+        // Every interval with enough queries has a rate, reported or not.
+        record(&mut monitor, 90, 10);
+        assert_eq!(monitor.check_interval(start + interval * 6), Some(10));
+        assert!(!monitor.is_high(10));
+        assert!(monitor.is_high(20));
+
+        let mut disabled = LossMonitor::new(0);
+        record(&mut disabled, 0, 100);
+        assert_eq!(check(&mut disabled, start + interval), None);
+    }
+}
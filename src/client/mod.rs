@@ -0,0 +1,42 @@
+//! A client that proxies local TCP connections over a myodine server.
+//!
+//! Each connection runs on its own threads: a `Highway` thread per query
+//! lane, two threads for the local `TcpChunker`, and a session loop that
+//! reacts to `Event`s. New transports (like DoH or DoT) can be added as
+//! `Highway` implementations.
+//!
+//! With the `async` feature, `run_async` and `handle_connection_async` run
+//! sessions as futures on a single tokio thread instead, with the query lanes
+//! polled together.
+
+#[cfg(feature = "async")]
+mod async_engine;
+mod concurrency;
+mod config;
+mod decode;
+mod discovery;
+mod engine;
+mod establish;
+mod health;
+mod logger;
+mod loss_monitor;
+mod mtu_monitor;
+mod poll_backoff;
+mod probe;
+mod resolvers;
+mod selftest;
+mod session;
+mod shutdown;
+
+#[cfg(feature = "async")]
+pub use self::async_engine::{AsyncConnection, handle_connection_async, run_async};
+pub use self::config::{ClientConfig, parse_remote_host};
+pub use self::decode::run_decode;
+pub use self::discovery::{Features, discover_features, discover_features_with, max_query_mtu,
+    max_response_mtu, parse_codec_list, select_codec};
+pub use self::engine::{handle_connection, handle_connection_with_sockets, run, run_test};
+pub use self::establish::check_success;
+pub use self::health::{Health, HealthStatus};
+pub use self::logger::RawLogger;
+pub use self::resolvers::{Resolver, assign_lanes};
+pub use self::selftest::run_selftest;
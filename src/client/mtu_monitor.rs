@@ -0,0 +1,153 @@
+/// The number of consecutive failed transfer queries after which the path is
+/// probed for a smaller response MTU.
+pub const PROBE_AFTER_FAILURES: usize = 8;
+
+/// The smallest response MTU to renegotiate down to, unless the client has a
+/// larger `min_mtu`.
+pub const DEFAULT_MIN_MTU: u16 = 16;
+
+/// Watches for failures that correlate with the response size, and picks a
+/// smaller response MTU when they happen.
+///
+/// After enough consecutive failures, a probe response the size of the
+/// current MTU is requested. If that fails too, the probe size shrinks by a
+/// quarter each time until a probe gets through, and that size becomes the
+/// new MTU. If the current MTU gets through, or even the minimum fails, the
+/// failures weren't about size and the monitor starts over.
+pub struct MtuMonitor {
+    mtu: u16,
+    min_mtu: u16,
+    failures: usize,
+    probe: Option<Probe>
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Probe {
+    Waiting(u16),
+    Sent(u16)
+}
+
+impl MtuMonitor {
+    pub fn new(mtu: u16, min_mtu: u16) -> MtuMonitor {
+        MtuMonitor{mtu, min_mtu, failures: 0, probe: None}
+    }
+
+    /// Use a new response MTU after a renegotiation.
+    pub fn set_mtu(&mut self, mtu: u16) {
+        self.mtu = mtu;
+        self.reset();
+    }
+
+    /// Record a transfer query that got a valid response.
+    pub fn record_success(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Record a transfer query that timed out or got an unusable response.
+    ///
+    /// There is nothing to probe for once the MTU is at the minimum.
+    pub fn record_failure(&mut self) {
+        self.failures += 1;
+        if self.failures >= PROBE_AFTER_FAILURES && self.probe.is_none() &&
+            self.mtu > self.min_mtu
+        {
+            self.probe = Some(Probe::Waiting(self.mtu));
+        }
+    }
+
+    /// Get the response size to probe next, if a probe should be sent.
+    pub fn next_probe(&mut self) -> Option<u16> {
+        if let Some(Probe::Waiting(size)) = self.probe {
+            self.probe = Some(Probe::Sent(size));
+            Some(size)
+        } else {
+            None
+        }
+    }
+
+    /// Record the outcome of the probe from `next_probe`.
+    ///
+    /// Returns the MTU to renegotiate to, if the probes found one.
+    pub fn probe_result(&mut self, success: bool) -> Option<u16> {
+        let size = match self.probe {
+            Some(Probe::Sent(size)) => size,
+            _ => return None
+        };
+        if success {
+            self.reset();
+            if size < self.mtu { Some(size) } else { None }
+        } else if size <= self.min_mtu {
+            self.reset();
+            None
+        } else {
+            self.probe = Some(Probe::Waiting((size - size / 4).max(self.min_mtu)));
+            None
+        }
+    }
+
+    fn reset(&mut self) {
+        self.failures = 0;
+        self.probe = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shrinking_probes() {
+        let mut monitor = MtuMonitor::new(200, 16);
+        for _ in 0..(PROBE_AFTER_FAILURES - 1) {
+            monitor.record_failure();
+        }
+        assert_eq!(monitor.next_probe(), None);
+        monitor.record_failure();
+        assert_eq!(monitor.next_probe(), Some(200));
+        assert_eq!(monitor.next_probe(), None);
+        assert_eq!(monitor.probe_result(false), None);
+        assert_eq!(monitor.next_probe(), Some(150));
+        assert_eq!(monitor.probe_result(false), None);
+        assert_eq!(monitor.next_probe(), Some(113));
+        assert_eq!(monitor.probe_result(true), Some(113));
+        assert_eq!(monitor.next_probe(), None);
+    }
+
+    #[test]
+    fn failures_unrelated_to_size() {
+        // The current MTU still gets through.
+        let mut monitor = MtuMonitor::new(200, 16);
+        for _ in 0..PROBE_AFTER_FAILURES {
+            monitor.record_failure();
+        }
+        assert_eq!(monitor.next_probe(), Some(200));
+        assert_eq!(monitor.probe_result(true), None);
+        monitor.record_failure();
+        assert_eq!(monitor.next_probe(), None);
+
+        // Nothing gets through, down to the minimum.
+        let mut monitor = MtuMonitor::new(20, 16);
+        for _ in 0..PROBE_AFTER_FAILURES {
+            monitor.record_failure();
+        }
+        assert_eq!(monitor.next_probe(), Some(20));
+        assert_eq!(monitor.probe_result(false), None);
+        assert_eq!(monitor.next_probe(), Some(16));
+        assert_eq!(monitor.probe_result(false), None);
+        assert_eq!(monitor.next_probe(), None);
+
+        // Successes in between reset the count.
+        for _ in 0..(PROBE_AFTER_FAILURES * 2) {
+            monitor.record_failure();
+            monitor.record_success();
+        }
+        assert_eq!(monitor.next_probe(), None);
+
+        // There is no smaller MTU to find.
+        let mut monitor = MtuMonitor::new(16, 16);
+        for _ in 0..PROBE_AFTER_FAILURES {
+            monitor.record_failure();
+        }
+        assert_eq!(monitor.next_probe(), None);
+    }
+}
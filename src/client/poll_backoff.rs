@@ -0,0 +1,115 @@
+use std::cmp::min;
+use std::time::{Duration, Instant};
+
+/// The gap between polls after the first one that comes back empty.
+const MIN_DELAY_MS: u64 = 50;
+
+/// Spaces out queries that only poll for incoming data while the server has
+/// nothing to send.
+///
+/// Each poll that comes back without a chunk doubles the gap between polls,
+/// up to a cap. Any chunk in either direction means the connection is busy
+/// again, so the gap goes back to zero.
+pub struct PollBackoff {
+    max_delay: Duration,
+    delay: Duration,
+    next_poll: Instant
+}
+
+impl PollBackoff {
+    /// Create a backoff whose gap between polls never exceeds `max_delay`.
+    ///
+    /// A `max_delay` of zero disables the backoff.
+    pub fn new(max_delay: Duration) -> PollBackoff {
+        PollBackoff{
+            max_delay,
+            delay: Duration::from_secs(0),
+            next_poll: Instant::now()
+        }
+    }
+
+    /// Get how much longer to wait before polling, if at all.
+    pub fn wait_time(&self, now: Instant) -> Option<Duration> {
+        if self.next_poll > now { Some(self.next_poll - now) } else { None }
+    }
+
+    /// Record a poll being sent.
+    pub fn record_poll(&mut self, now: Instant) {
+        self.next_poll = now + self.delay;
+    }
+
+    /// Record a chunk being sent to the server.
+    pub fn record_chunk_sent(&mut self, now: Instant) {
+        self.reset(now);
+    }
+
+    /// Record the response to a transfer query.
+    ///
+    /// Responses to queries that carried a chunk don't count as empty polls,
+    /// since the server may not have had time to reply with data yet.
+    pub fn record_response(&mut self, now: Instant, polled: bool, got_chunk: bool) {
+        if got_chunk {
+            self.reset(now);
+        } else if polled && self.max_delay > Duration::from_secs(0) {
+            let doubled = if self.delay == Duration::from_secs(0) {
+                Duration::from_millis(MIN_DELAY_MS)
+            } else {
+                self.delay * 2
+            };
+            self.delay = min(doubled, self.max_delay);
+        }
+    }
+
+    fn reset(&mut self, now: Instant) {
+        self.delay = Duration::from_secs(0);
+        self.next_poll = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_and_reset() {
+        let ms = Duration::from_millis;
+        let mut backoff = PollBackoff::new(ms(300));
+        let start = Instant::now();
+        assert_eq!(backoff.wait_time(start), None);
+
+        // A response to an upload doesn't count as an empty poll.
+        backoff.record_response(start, false, false);
+        backoff.record_poll(start);
+        assert_eq!(backoff.wait_time(start), None);
+
+        let mut delays = Vec::new();
+        for _ in 0..5 {
+            backoff.record_response(start, true, false);
+            backoff.record_poll(start);
+            delays.push(backoff.wait_time(start).unwrap());
+        }
+        assert_eq!(delays, vec![ms(50), ms(100), ms(200), ms(300), ms(300)]);
+        assert_eq!(backoff.wait_time(start + ms(100)), Some(ms(200)));
+        assert_eq!(backoff.wait_time(start + ms(300)), None);
+
+        backoff.record_response(start + ms(300), true, true);
+        backoff.record_poll(start + ms(300));
+        assert_eq!(backoff.wait_time(start + ms(300)), None);
+
+        backoff.record_response(start, true, false);
+        backoff.record_poll(start);
+        backoff.record_chunk_sent(start);
+        assert_eq!(backoff.wait_time(start), None);
+    }
+
+    #[test]
+    fn disabled() {
+        let mut backoff = PollBackoff::new(Duration::from_secs(0));
+        let start = Instant::now();
+        for _ in 0..3 {
+            backoff.record_response(start, true, false);
+            backoff.record_poll(start);
+        }
+        assert_eq!(backoff.wait_time(start), None);
+    }
+}
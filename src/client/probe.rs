@@ -0,0 +1,391 @@
+extern crate rand;
+use self::rand::{Rng, thread_rng};
+
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+use dns_proto::{Domain, Message, Question, RecordBody, RecordType};
+use myo_proto::discovery::DownloadGenQuery;
+use myo_proto::record_code::get_record_code;
+use myo_proto::util::{payload_answer, randomize_case};
+
+use super::config::ClientConfig;
+use super::discovery::{Features, max_query_mtu, max_response_mtu, query_overhead,
+    response_overhead};
+use super::establish::query_with_retries;
+
+/// How long to wait for the response to an MTU probe.
+const MTU_PROBE_TIMEOUT_MS: u64 = 2000;
+
+/// How many times to send an MTU probe before counting it as failed.
+const MTU_PROBE_TRIES: usize = 2;
+
+/// How many probes to spend narrowing down an MTU after halving it.
+const MTU_REFINE_STEPS: usize = 4;
+
+/// How many bytes of generated data to ask for when probing a response codec.
+const CODEC_PROBE_LEN: u16 = 32;
+
+/// The results of probing the path to the server through a resolver.
+pub struct ProbeResult {
+    pub round_trip: Duration,
+
+    /// Whether the resolver echoed back the exact case of the query name, in
+    /// which case 0x20 case randomization can be used.
+    pub preserves_case: bool
+}
+
+impl ProbeResult {
+    /// Check if 0x20 case randomization should be used on this path.
+    pub fn use_case_randomization(&self, config: &ClientConfig) -> bool {
+        config.case_randomization && self.preserves_case
+    }
+
+    /// Describe the decision about case randomization.
+    pub fn describe_case_randomization(&self, config: &ClientConfig) -> String {
+        if !config.case_randomization {
+            "0x20 disabled by configuration".to_owned()
+        } else if self.preserves_case {
+            "0x20 enabled".to_owned()
+        } else {
+            "0x20 disabled (resolver does not preserve case)".to_owned()
+        }
+    }
+}
+
+/// Send a discovery query with randomized case through the connected socket
+/// and check that the response echoes the name back exactly.
+pub fn probe(config: &ClientConfig, conn: &UdpSocket) -> Result<ProbeResult, String> {
+    let mut parts = vec![format!("f{:08x}", thread_rng().gen::<u32>())];
+    parts.extend(config.host.parts().to_vec());
+    let domain = Domain::from_parts(parts)?;
+    let mut randomized = randomize_case(&domain);
+    while randomized == domain {
+        randomized = randomize_case(&domain);
+    }
+    let mut message = Message::new_query(Question{
+        domain: randomized.clone(),
+        record_type: RecordType::A,
+        record_class: config.record_class
+    });
+    message.header.recursion_desired = config.recursion_desired;
+    conn.set_read_timeout(Some(Duration::new(5, 0))).map_err(|e| format!("{}", e))?;
+    let start = Instant::now();
+    let response = query_with_retries(conn, &message, 3)
+        .ok_or("no response to probe".to_owned())?;
+    Ok(ProbeResult{
+        round_trip: start.elapsed(),
+        preserves_case: response.questions.len() == 1 && response.questions[0].domain == randomized
+    })
+}
+
+/// Get the domain for a discovery query whose TXT response carries `len`
+/// bytes of generated data, to test if responses of that size get through.
+///
+/// Responses repeat the query name, so the name is padded to at least
+/// `name_len` bytes on the wire to match the transfer queries being tested.
+pub fn size_probe_domain(host: &Domain, len: u16, name_len: usize) -> Result<Domain, String> {
+    let mut rng = thread_rng();
+    let query = DownloadGenQuery{
+        encoding: "raw".to_owned(),
+        len,
+        // Random parameters keep resolvers from answering out of their cache.
+        bias: rng.gen(),
+        coefficient: rng.gen(),
+        modulus: rng.gen_range(128, 256) as u8
+    };
+    (name_len..256).filter_map(|x| query.to_domain(host, x).ok()).next()
+        .ok_or("no room for a size probe".to_owned())
+}
+
+/// Check if a response to a `size_probe_domain` query carries all of the
+/// generated data.
+pub fn size_probe_succeeded(response: &Message, len: u16) -> bool {
+    if response.header.truncated {
+        return false;
+    }
+    let question = match response.questions.first() {
+        Some(question) => question,
+        None => return false
+    };
+    let expected = match DownloadGenQuery::from_domain(&question.domain) {
+        Ok(query) => query,
+        Err(_) => return false
+    };
+    let code = get_record_code(RecordType::TXT, "raw").unwrap();
+    match payload_answer(response).and_then(|x| code.decode_body(x)) {
+        Ok(data) => expected.len == len && data == expected.generated_data(),
+        Err(_) => false
+    }
+}
+
+/// Check if responses with the given record type and encoding get through
+/// the path behind `conn`, by asking the server for a little generated data
+/// in them.
+///
+/// This fails if the server doesn't support the codec, or if something on
+/// the path drops or mangles its records.
+pub fn probe_codec(
+    config: &ClientConfig,
+    conn: &UdpSocket,
+    record_type: RecordType,
+    encoding: &str
+) -> bool {
+    let code = match get_record_code(record_type, encoding) {
+        Some(code) => code,
+        None => return false
+    };
+    let mut rng = thread_rng();
+    let query = DownloadGenQuery{
+        encoding: encoding.to_owned(),
+        len: CODEC_PROBE_LEN,
+        bias: rng.gen(),
+        coefficient: rng.gen(),
+        modulus: rng.gen_range(128, 256) as u8
+    };
+    let domain = match (0..256).filter_map(|x| query.to_domain(&config.host, x).ok()).next() {
+        Some(domain) => domain,
+        None => return false
+    };
+    if conn.set_read_timeout(Some(Duration::from_millis(MTU_PROBE_TIMEOUT_MS))).is_err() {
+        return false;
+    }
+    let message = probe_message(config, domain, record_type);
+    match query_with_retries(conn, &message, MTU_PROBE_TRIES) {
+        Some(response) => match payload_answer(&response).and_then(|x| code.decode_body(x)) {
+            Ok(data) => data == query.generated_data(),
+            Err(_) => false
+        },
+        None => false
+    }
+}
+
+/// Find the largest upstream and downstream MTUs that get through the path
+/// behind `conn`, skipping the ones that `config` sets.
+///
+/// The two directions go through different middleboxes, so each one gets
+/// its own search. Queries only test how long a name may be, and responses
+/// only test how much data an answer may carry, although they are sent for
+/// names as long as a transfer query at the upstream MTU, since responses
+/// repeat the question.
+///
+/// Returns the new query and response MTUs, or None for a direction that
+/// was skipped or where even one byte didn't get through.
+pub fn probe_mtus(
+    config: &ClientConfig,
+    features: &Features,
+    conn: &UdpSocket
+) -> Result<(Option<u16>, Option<u16>), String> {
+    conn.set_read_timeout(Some(Duration::from_millis(MTU_PROBE_TIMEOUT_MS)))
+        .map_err(|e| format!("{}", e))?;
+    let query_mtu = if config.query_mtu.is_none() {
+        let max = max_query_mtu(config, &features.name_encoding)?;
+        largest_passing(clamp_mtu(max), |size| {
+            query_probe_domain(config, features, size)
+                .map(|domain| query_probe_succeeded(config, conn, domain))
+                .unwrap_or(false)
+        })
+    } else {
+        None
+    };
+    let name_len = query_probe_domain(config, features, query_mtu.unwrap_or(features.query_mtu))
+        .map(|x| x.wire_len()).unwrap_or(0);
+    let response_mtu = if config.response_mtu.is_none() {
+        let max = max_response_mtu(config, features.record_type, &features.response_encoding);
+        largest_passing(clamp_mtu(max), |size| {
+            let len = size as usize + response_overhead(config);
+            match size_probe_domain(&config.host, clamp_mtu(len), name_len) {
+                Ok(domain) => {
+                    let message = probe_message(config, domain, RecordType::TXT);
+                    query_with_retries(conn, &message, MTU_PROBE_TRIES)
+                        .map(|x| size_probe_succeeded(&x, clamp_mtu(len))).unwrap_or(false)
+                },
+                Err(_) => false
+            }
+        })
+    } else {
+        None
+    };
+    Ok((query_mtu, response_mtu))
+}
+
+/// Find the largest size from 1 to `max` that passes a probe, assuming that
+/// smaller sizes pass whenever larger ones do.
+///
+/// Probes start at `max` and halve until one passes, and the remaining gap
+/// is narrowed down by a few more probes, so a slow or lossy path doesn't
+/// take long to probe. The result may be a little below the real limit.
+pub fn largest_passing<F: FnMut(u16) -> bool>(max: u16, mut passes: F) -> Option<u16> {
+    let mut size = max;
+    let mut failed = None;
+    while size > 0 && !passes(size) {
+        failed = Some(size);
+        size /= 2;
+    }
+    if size == 0 {
+        return None;
+    }
+    if let Some(mut high) = failed {
+        for _ in 0..MTU_REFINE_STEPS {
+            if high - size <= 1 {
+                break;
+            }
+            let mid = size + (high - size) / 2;
+            if passes(mid) {
+                size = mid;
+            } else {
+                high = mid;
+            }
+        }
+    }
+    Some(size)
+}
+
+fn clamp_mtu(size: usize) -> u16 {
+    if size > 0xffff { 0xffff } else { size as u16 }
+}
+
+/// Get the domain for a discovery query whose name is as long as a transfer
+/// query carrying a chunk of `size` bytes.
+///
+/// It is a discovery name (an `f` label, like the one `probe` sends) padded
+/// with random data in the session's name encoding, so the server answers it
+/// with a domain hash. A transfer name can't be reused, since a custom name
+/// template may not start it with the API character.
+fn query_probe_domain(
+    config: &ClientConfig,
+    features: &Features,
+    size: u16
+) -> Result<Domain, String> {
+    let data_len = size as usize + query_overhead(config);
+    let transfer = config.name_template.encode(&*features.name_code, config.api_chars.transfer,
+        0, &vec![0xff; data_len], &config.host, config.decoy_labels)?;
+    // The discovery name has less overhead, so it needs at least as much
+    // data to be as long.
+    let mut len = data_len;
+    loop {
+        let data: Vec<u8> = (0..len).map(|_| thread_rng().gen()).collect();
+        let mut parts = vec!["f".to_owned()];
+        parts.extend(features.name_code.encode_parts(&data)?);
+        parts.extend(config.host.parts().to_vec());
+        let domain = Domain::from_parts(parts)?;
+        if domain.wire_len() >= transfer.wire_len() {
+            return Ok(domain);
+        }
+        len += 1;
+    }
+}
+
+/// Check if a domain hash query gets an answer.
+///
+/// The hash itself isn't checked, since resolvers may change the case of
+/// the name on its way to the server.
+fn query_probe_succeeded(config: &ClientConfig, conn: &UdpSocket, domain: Domain) -> bool {
+    let message = probe_message(config, domain, RecordType::A);
+    match query_with_retries(conn, &message, MTU_PROBE_TRIES) {
+        Some(response) => matches!(payload_answer(&response), Ok(&RecordBody::A(_))),
+        None => false
+    }
+}
+
+fn probe_message(config: &ClientConfig, domain: Domain, record_type: RecordType) -> Message {
+    let mut message = Message::new_query(Question{
+        domain,
+        record_type,
+        record_class: config.record_class
+    });
+    message.header.identifier = thread_rng().gen();
+    message.header.recursion_desired = config.recursion_desired;
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::spawn;
+
+    use conn::dial_udp_from;
+    use dns_coding::{dns_decode, dns_encode};
+    use myo_proto::discovery::is_domain_hash_query;
+    use myo_proto::name_code::get_name_code;
+    use server::{Flags, Server};
+
+    #[test]
+    fn query_probe_custom_template() {
+        let mut config = ClientConfig::new("127.0.0.1:53", "proxy.com".parse().unwrap());
+        config.name_template = "{data}.x{session}{api}".parse().unwrap();
+        let features = Features{
+            record_type: RecordType::TXT,
+            response_encoding: "raw".to_owned(),
+            response_mtu: 64,
+            name_encoding: "b16".to_owned(),
+            query_mtu: 64,
+            name_code: get_name_code("b16").unwrap(),
+            record_code: get_record_code(RecordType::TXT, "raw").unwrap()
+        };
+        for &size in &[1, 30, 90] {
+            let domain = query_probe_domain(&config, &features, size).unwrap();
+            let transfer = config.name_template.encode(&*features.name_code, 't', 0,
+                &vec![0; size as usize + query_overhead(&config)], &config.host, 0).unwrap();
+            assert!(domain.wire_len() >= transfer.wire_len());
+            assert!(domain.wire_len() <= transfer.wire_len() + 2);
+            let message = probe_message(&config, domain, RecordType::A);
+            assert!(is_domain_hash_query(&message));
+        }
+    }
+
+    #[test]
+    fn largest_passing_size() {
+        let mut probes = Vec::new();
+        let result = largest_passing(200, |x| {
+            probes.push(x);
+            x <= 37
+        });
+        assert_eq!(result, Some(37));
+        assert_eq!(probes, vec![200, 100, 50, 25, 37, 43, 40, 38]);
+
+        assert_eq!(largest_passing(200, |_| true), Some(200));
+        assert_eq!(largest_passing(200, |_| false), None);
+        assert_eq!(largest_passing(0, |_| true), None);
+        // The refinement stops early, below the real limit.
+        assert_eq!(largest_passing(1000, |x| x <= 900), Some(875));
+    }
+
+    #[test]
+    fn codec_probe() {
+        let host: Domain = "proxy.com".parse().unwrap();
+        let config = ClientConfig::new(&serve_discovery(&host, false), host.clone());
+        let conn = dial_udp_from(&config.addr, None).unwrap();
+        assert!(probe_codec(&config, &conn, RecordType::TXT, "raw"));
+        assert!(!probe_codec(&config, &conn, RecordType::TXT, "b32"));
+
+        // A path that strips answers fails the probe.
+        let config = ClientConfig::new(&serve_discovery(&host, true), host);
+        let conn = dial_udp_from(&config.addr, None).unwrap();
+        assert!(!probe_codec(&config, &conn, RecordType::TXT, "raw"));
+    }
+
+    /// Answer queries with a server on a local socket, optionally dropping
+    /// the answers from its responses, and return the socket's address.
+    fn serve_discovery(host: &Domain, strip_answers: bool) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        let flags = Flags::new(vec![host.clone()]);
+        spawn(move || {
+            let mut server = Server::new(flags);
+            loop {
+                let mut buf = [0; 2048];
+                let (size, addr) = socket.recv_from(&mut buf).unwrap();
+                let message = dns_decode::<Message>(buf[..size].to_vec()).unwrap();
+                if let Ok(mut response) = server.handle_message(message, addr) {
+                    if strip_answers {
+                        response.answers.clear();
+                        response.header.answer_count = 0;
+                    }
+                    socket.send_to(&dns_encode(&response).unwrap(), addr).unwrap();
+                }
+            }
+        });
+        addr
+    }
+}
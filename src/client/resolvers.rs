@@ -0,0 +1,86 @@
+use std::str::FromStr;
+
+/// An additional resolver to spread queries across.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Resolver {
+    /// The "IP:port" address of the resolver.
+    pub addr: String,
+
+    /// The maximum number of concurrent queries to send to this resolver.
+    pub max_concurrency: Option<usize>
+}
+
+impl FromStr for Resolver {
+    type Err = String;
+
+    /// Parse a resolver of the form "IP:port[,max_concurrency]".
+    fn from_str(s: &str) -> Result<Resolver, String> {
+        let mut pieces = s.splitn(2, ',');
+        let addr = pieces.next().unwrap().to_owned();
+        if addr.is_empty() {
+            return Err("missing resolver address".to_owned());
+        }
+        let max_concurrency = match pieces.next() {
+            Some(x) => match x.parse() {
+                Ok(0) | Err(_) => return Err(format!("invalid concurrency cap: {}", x)),
+                Ok(n) => Some(n)
+            },
+            None => None
+        };
+        Ok(Resolver{addr, max_concurrency})
+    }
+}
+
+/// Distribute a concurrency budget across resolvers.
+///
+/// Lanes are assigned to resolvers round-robin, skipping resolvers which have
+/// reached their cap. The first lane always goes to the first resolver.
+///
+/// Returns the index of the resolver for each lane. There may be fewer than
+/// `concurrency` lanes if every resolver is capped.
+pub fn assign_lanes(concurrency: usize, caps: &[Option<usize>]) -> Vec<usize> {
+    let mut counts = vec![0; caps.len()];
+    let mut result = Vec::new();
+    while result.len() < concurrency {
+        let mut assigned = false;
+        for (i, cap) in caps.iter().enumerate() {
+            if result.len() < concurrency && cap.map(|c| counts[i] < c).unwrap_or(true) {
+                counts[i] += 1;
+                result.push(i);
+                assigned = true;
+            }
+        }
+        if !assigned {
+            break;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_resolver() {
+        assert_eq!("8.8.8.8:53".parse(), Ok(Resolver{
+            addr: "8.8.8.8:53".to_owned(),
+            max_concurrency: None
+        }));
+        assert_eq!("1.1.1.1:53,3".parse(), Ok(Resolver{
+            addr: "1.1.1.1:53".to_owned(),
+            max_concurrency: Some(3)
+        }));
+        assert!("1.1.1.1:53,0".parse::<Resolver>().is_err());
+        assert!(",2".parse::<Resolver>().is_err());
+    }
+
+    #[test]
+    fn lane_assignment() {
+        assert_eq!(assign_lanes(5, &[None]), vec![0, 0, 0, 0, 0]);
+        assert_eq!(assign_lanes(5, &[None, None]), vec![0, 1, 0, 1, 0]);
+        assert_eq!(assign_lanes(6, &[None, Some(1), Some(2)]), vec![0, 1, 2, 0, 2, 0]);
+        assert_eq!(assign_lanes(6, &[Some(1), Some(2)]), vec![0, 1, 1]);
+        assert_eq!(assign_lanes(0, &[None]), Vec::<usize>::new());
+    }
+}
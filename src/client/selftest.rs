@@ -0,0 +1,166 @@
+extern crate rand;
+use self::rand::{Rng, thread_rng};
+
+use dns_coding::{dns_decode, dns_encode};
+use dns_proto::{Domain, Message, Question, Record, RecordClass, RecordHeader, RecordType};
+use myo_proto::api_chars::ApiChars;
+use myo_proto::name_code::{NameCode, get_name_code, supported_name_encodings};
+use myo_proto::record_code::{RecordCode, get_record_code, max_payload, supported_record_codes,
+    supported_record_types};
+
+/// The root domain name that test queries are encoded under.
+const HOST: &str = "selftest.myodine.invalid";
+
+/// The number of random sizes to try for each codec, on top of the edge cases.
+const RANDOM_SIZES: usize = 16;
+
+/// Round-trip random data through every record and name codec, including the
+/// DNS wire format, and print one line per codec.
+///
+/// Each line has the form "pass KIND NAME" or "fail KIND NAME: REASON", and
+/// the last line counts the results. No network access is needed.
+///
+/// Fails if any codec failed.
+pub fn run_selftest() -> Result<(), String> {
+    let host: Domain = HOST.parse()?;
+    let mut results = Vec::new();
+    let record_codes = supported_record_types().into_iter()
+        .flat_map(|x| supported_record_codes(x).into_iter().map(move |name| (x, name)));
+    for (record_type, name) in record_codes {
+        let result = get_record_code(record_type, name)
+            .ok_or_else(|| "not registered".to_owned())
+            .and_then(|code| {
+                let max = max_payload(record_type, name, host.wire_len(), 1);
+                for size in test_sizes(0, max) {
+                    check_record_code(&*code, &host, record_type, size)?;
+                }
+                Ok(())
+            });
+        results.push((format!("record {:?}/{}", record_type, name), result));
+    }
+    for name in supported_name_encodings() {
+        let result = get_name_code(name)
+            .ok_or_else(|| "not registered".to_owned())
+            .and_then(|code| {
+                for decoys in 0..3 {
+                    let api = ApiChars::default().transfer;
+                    // Data labels can't be empty, but transfer packets never are.
+                    let max = code.max_domain_data(api, &host, decoys);
+                    for size in test_sizes(1, max) {
+                        check_name_code(&*code, &host, decoys, size)?;
+                    }
+                }
+                Ok(())
+            });
+        results.push((format!("name {}", name), result));
+    }
+
+    let failed = results.iter().filter(|x| x.1.is_err()).count();
+    for (codec, result) in results.iter() {
+        match result {
+            Ok(()) => println!("pass {}", codec),
+            Err(msg) => println!("fail {}: {}", codec, msg)
+        }
+    }
+    println!("selftest: {} passed, {} failed", results.len() - failed, failed);
+    if failed > 0 {
+        Err(format!("{} codec(s) failed the self-test", failed))
+    } else {
+        Ok(())
+    }
+}
+
+/// Get the edge-case sizes for data of `min` to `max` bytes, followed by some
+/// random ones.
+fn test_sizes(min: usize, max: usize) -> Vec<usize> {
+    let mut rng = thread_rng();
+    let mut sizes: Vec<usize> = [0, 1, 2, 254, 255, 256, 257].iter().cloned()
+        .filter(|&x| x >= min && x < max).collect();
+    sizes.push(max);
+    sizes.extend((0..RANDOM_SIZES).map(|_| rng.gen_range(min, max + 1)));
+    sizes
+}
+
+fn random_data(size: usize) -> Vec<u8> {
+    let mut rng = thread_rng();
+    (0..size).map(|_| rng.gen()).collect()
+}
+
+fn check_record_code(
+    code: &dyn RecordCode,
+    host: &Domain,
+    record_type: RecordType,
+    size: usize
+) -> Result<(), String> {
+    let data = random_data(size);
+    let mut message = Message::new_query(Question{
+        domain: host.clone(),
+        record_type,
+        record_class: RecordClass::IN
+    });
+    message.answers.push(Record{
+        header: RecordHeader{
+            domain: host.clone(),
+            record_type,
+            record_class: RecordClass::IN,
+            ttl: 0
+        },
+        body: code.encode_body(&data).map_err(|e| format!("{} bytes: {}", size, e))?
+    });
+    message.header.answer_count = 1;
+    let decoded = dns_encode(&message).and_then(dns_decode::<Message>)
+        .and_then(|x| x.answers.first().ok_or_else(|| "answer went missing".to_owned())
+            .and_then(|answer| code.decode_body(&answer.body)))
+        .map_err(|e| format!("{} bytes: {}", size, e))?;
+    if decoded != data {
+        return Err(format!("{} bytes: data changed", size));
+    }
+    Ok(())
+}
+
+fn check_name_code(
+    code: &dyn NameCode,
+    host: &Domain,
+    decoys: usize,
+    size: usize
+) -> Result<(), String> {
+    let data = random_data(size);
+    let api = ApiChars::default().transfer;
+    let session_id = thread_rng().gen();
+    let domain = code.encode_domain(api, session_id, &data, host, decoys)
+        .map_err(|e| format!("{} bytes: {}", size, e))?;
+    let message = Message::new_query(Question{
+        domain,
+        record_type: RecordType::TXT,
+        record_class: RecordClass::IN
+    });
+    let decoded = dns_encode(&message).and_then(dns_decode::<Message>)
+        .and_then(|x| x.questions.first().ok_or_else(|| "question went missing".to_owned())
+            .and_then(|question| code.decode_domain(&question.domain, host)))
+        .map_err(|e| format!("{} bytes: {}", size, e))?;
+    if decoded != (api, session_id, data) {
+        return Err(format!("{} bytes with {} decoys: data changed", size, decoys));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_codecs_pass() {
+        assert!(run_selftest().is_ok());
+    }
+
+    #[test]
+    fn sizes_in_range() {
+        let sizes = test_sizes(0, 100);
+        assert_eq!(&sizes[..4], &[0, 1, 2, 100]);
+        assert!(sizes.iter().all(|&x| x <= 100));
+        let sizes = test_sizes(1, 100);
+        assert_eq!(&sizes[..3], &[1, 2, 100]);
+        assert!(sizes.iter().all(|x| (1..=100).contains(x)));
+        assert_eq!(test_sizes(0, 0)[0], 0);
+    }
+}
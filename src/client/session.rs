@@ -1,109 +1,853 @@
-use std::net::TcpStream;
-use std::sync::mpsc::Receiver;
+use std::cmp::{max, min};
+use std::net::UdpSocket;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
 
-use myodine::conn::{Highway, Event, TcpChunker, UDPHighway};
-use myodine::dns_proto::{Domain, Message, Question, RecordClass};
-use myodine::myo_proto::xfer::{Packet, WwrState, handle_packet_in, next_packet_out};
+use conn::{Chunker, ConnState, Highway, Event, UDPHighway};
+use dns_coding::dns_decode;
+use dns_proto::{Domain, Message, Question, RecordClass, RecordType, ResponseCode};
+use myo_proto::api_chars::ApiChars;
+use myo_proto::establish::{EstablishQuery, EstablishResponse};
+use myo_proto::name_template::NameTemplate;
+use myo_proto::record_code::decode_answers;
+use myo_proto::util::{payload_answer, payload_answers, randomize_case};
+use myo_proto::xfer::{Ack, Packet, WwrState, handle_packet_in, next_packet_out, unwrap_packet,
+    wrap_packet};
 
-use flags::Flags;
-use establish::Establishment;
-use logger::{RawLogger, SessionLogger};
+use super::concurrency::ConcurrencyLimit;
+use super::config::ClientConfig;
+use super::establish::{Establishment, check_resumed, resume_query};
+use super::health::HealthHandle;
+use super::logger::{RawLogger, SessionLogger};
+use super::loss_monitor::{CHECK_INTERVAL_SECS, LossMonitor};
+use super::mtu_monitor::{DEFAULT_MIN_MTU, MtuMonitor};
+use super::poll_backoff::PollBackoff;
+use super::probe::{size_probe_domain, size_probe_succeeded};
+use super::shutdown;
+
+/// The number of times to send a resume query before giving up on it.
+const RESUME_TRIES: usize = 5;
+
+/// How often a lane that is held back by the read-ahead limit checks for
+/// outgoing data.
+const PARKED_LANE_MS: u64 = 20;
 
 pub fn run_session(
-    flags: Flags,
-    conn: TcpStream,
+    config: ClientConfig,
+    conn: Box<dyn Chunker>,
     info: Establishment,
-    logger: &RawLogger
+    sockets: Vec<UdpSocket>,
+    case_lanes: Vec<bool>,
+    logger: &RawLogger,
+    health: HealthHandle
 ) -> Result<(), String> {
-    let (highway, events) = UDPHighway::open(&flags.addr, flags.concurrency,
-        flags.query_min_time, flags.query_max_time);
-    let conn = TcpChunker::new(
-        conn,
-        info.query_mtu as usize,
-        info.query_window as usize,
-        info.response_window as usize
-    ).map_err(|e| format!("error creating chunker: {}", e))?;
-    let mut session = Session{
-        highway: Box::new(highway),
-        state: WwrState::new(info.response_window, info.query_window, info.seq_start),
-        conn: conn,
-        info: info,
-        host: flags.host,
-        logger: SessionLogger::new(logger.clone())
-    };
+    let (mut highway, events) = UDPHighway::from_sockets(sockets, config.query_min_time,
+        config.query_max_time);
+    highway.set_first_response_time(config.query_response_time);
+    let mut session = Session::new(config, conn, Box::new(highway), info, case_lanes, logger,
+        health);
     session.run(events)
 }
 
 struct Session {
     highway: Box<Highway>,
     state: WwrState,
-    conn: TcpChunker,
+    conn: Box<dyn Chunker>,
     info: Establishment,
     host: Domain,
-    logger: SessionLogger
+    recursion_desired: bool,
+    api_chars: ApiChars,
+    record_class: RecordClass,
+    max_server_failures: usize,
+    server_failures: usize,
+    /// Whether the session reconnected and hasn't had a transfer response
+    /// since.
+    reconnected: bool,
+    read_ahead: Option<usize>,
+    pull_lanes: Vec<bool>,
+    deferred_lanes: Vec<(Instant, usize)>,
+    case_lanes: Vec<bool>,
+    decoy_labels: usize,
+    name_template: NameTemplate,
+    sent_domains: Vec<Option<Domain>>,
+    lane_queries: Vec<LaneQuery>,
+    longest_name: usize,
+    mtu_monitor: MtuMonitor,
+    loss_monitor: LossMonitor,
+    poll_backoff: PollBackoff,
+    concurrency: ConcurrencyLimit,
+    busy_lanes: Vec<bool>,
+    resume: Option<Resume>,
+    /// The password for resume proofs, or None if the session can't be
+    /// resumed.
+    password: Option<String>,
+    logger: SessionLogger,
+    health: HealthHandle,
+    shutdown_grace: Duration,
+    drain_deadline: Option<Instant>
+}
+
+/// What the outstanding query on a lane is for.
+#[derive(Clone, Copy, PartialEq)]
+enum LaneQuery {
+    /// A transfer query for the given session ID.
+    Transfer(u16),
+    /// A discovery query for a response with the given data size.
+    SizeProbe(u16),
+    /// A query to resume the session with a new response MTU, or to
+    /// reconnect it.
+    Resume,
+    /// Nothing is outstanding while a resume is in progress.
+    Idle,
+    /// Nothing is outstanding because enough lanes are already polling for
+    /// incoming data; the lane is deferred until it checks again.
+    Parked
+}
+
+/// A renegotiation of the response MTU, or a reconnection at the same MTU.
+///
+/// Incoming chunks are ignored until it finishes, so the incoming window
+/// stays at `window_start`.
+struct Resume {
+    mtu: u16,
+    window_start: u32,
+    sent: bool,
+    tries: usize,
+    /// Whether the session is failing without this resume, rather than
+    /// moving to a better MTU.
+    reconnect: bool
 }
 
 impl Session {
+    fn new(
+        config: ClientConfig,
+        conn: Box<dyn Chunker>,
+        highway: Box<dyn Highway>,
+        info: Establishment,
+        case_lanes: Vec<bool>,
+        logger: &RawLogger,
+        health: HealthHandle
+    ) -> Session {
+        let num_lanes = highway.num_lanes();
+        let mut state = WwrState::new(info.response_window, info.query_window, info.seq_start);
+        if !info.early_data.is_empty() {
+            // The server already delivered the early data as the first chunk.
+            state.push_send_buffer(info.early_data.clone());
+            state.handle_ack(&Ack{
+                window_start: info.seq_start.wrapping_add(1),
+                window_mask: vec![false; (info.query_window - 1) as usize]
+            });
+        }
+        state.set_send_byte_limit(config.max_inflight_bytes);
+        state.set_recv_chunk_limit(Some(info.response_mtu as usize));
+        let min_mtu = renegotiation_floor(&config, &info.query, info.response_mtu);
+        let resume_blocker = resume_blocker(&config, &info.query);
+        if let Some(reason) = resume_blocker {
+            logger.log(format!("response MTU renegotiation and reconnecting are disabled, since \
+                {}", reason));
+        }
+        // More polls than the response window can only fetch chunks again.
+        let read_ahead = config.read_ahead.map(|x| min(x, info.response_window as usize));
+        Session{
+            mtu_monitor: MtuMonitor::new(info.response_mtu, min_mtu),
+            loss_monitor: LossMonitor::new(config.loss_warning),
+            poll_backoff: PollBackoff::new(config.max_poll_delay),
+            concurrency: ConcurrencyLimit::new(num_lanes),
+            busy_lanes: vec![false; num_lanes],
+            highway,
+            state,
+            conn,
+            info,
+            host: config.host,
+            recursion_desired: config.recursion_desired,
+            api_chars: config.api_chars,
+            record_class: config.record_class,
+            max_server_failures: config.max_server_failures,
+            server_failures: 0,
+            reconnected: false,
+            read_ahead,
+            pull_lanes: vec![false; num_lanes],
+            deferred_lanes: Vec::new(),
+            case_lanes,
+            decoy_labels: config.decoy_labels,
+            name_template: config.name_template,
+            sent_domains: vec![None; num_lanes],
+            lane_queries: vec![LaneQuery::Idle; num_lanes],
+            longest_name: 0,
+            resume: None,
+            password: if resume_blocker.is_some() { None } else { Some(config.password) },
+            logger: SessionLogger::new(logger.clone(), config.verbose),
+            health,
+            shutdown_grace: config.shutdown_grace,
+            drain_deadline: None
+        }
+    }
+
     pub fn run(&mut self, events: Receiver<Event>) -> Result<(), String> {
         for lane in 0..self.highway.num_lanes() {
             self.populate_lane(lane)?;
         }
-        for event in events {
-            match event {
-                Event::Response(lane, msg) => {
-                    self.logger.log_response();
-                    self.handle_message(msg);
-                    self.populate_lane(lane)?;
-                },
-                Event::Timeout(lane) => {
-                    self.logger.log_timeout();
-                    self.populate_lane(lane)?;
-                },
-                Event::SendError(lane, msg) => {
-                    self.logger.log_raw(format!("lane {}: error sending message: {}", lane, msg));
-                },
-                Event::ConnectError(lane, err) => {
-                    return Err(format!("lane {}: error connecting: {}", lane, err));
-                },
-                Event::SocketError(lane, err) => {
-                    return Err(format!("lane {}: error on socket: {}", lane, err));
+        loop {
+            self.populate_deferred_lanes()?;
+            let event = if let Some(deadline) = self.next_wake_time() {
+                let now = Instant::now();
+                let timeout = if deadline > now { deadline - now } else { Duration::new(0, 0) };
+                match events.recv_timeout(timeout) {
+                    Ok(event) => Some(event),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break
                 }
+            } else {
+                match events.recv() {
+                    Ok(event) => Some(event),
+                    Err(_) => break
+                }
+            };
+            if let Some(event) = event {
+                self.handle_event(event)?;
             }
             if self.state.is_done() {
                 break;
+            } else if self.conn.state() == ConnState::Errored {
+                return Err("local connection failed".to_owned());
+            } else if self.check_shutdown()? {
+                break;
             }
         }
         Ok(())
     }
 
-    fn handle_message(&mut self, msg: Message) {
-        if msg.answers.len() != 1 || msg.header.truncated {
-            self.logger.log_raw(format!("invalid response (truncated={}, answers={})",
-                msg.header.truncated, msg.answers.len()));
-            return;
+    fn handle_event(&mut self, event: Event) -> Result<(), String> {
+        match event {
+            Event::Response(lane, msg) => {
+                let polled = self.pull_lanes[lane];
+                self.pull_lanes[lane] = false;
+                self.busy_lanes[lane] = false;
+                if !self.check_case(lane, &msg) {
+                    self.logger.log_raw(format!("lane {}: response failed 0x20 check", lane));
+                    self.handle_lane_failure(lane)
+                } else if is_server_failure(&msg) {
+                    self.logger.log_server_failure();
+                    if let LaneQuery::Transfer(_) = self.lane_queries[lane] {
+                        self.mtu_monitor.record_failure();
+                        self.loss_monitor.record_failure();
+                        self.handle_server_failure(lane)
+                    } else {
+                        self.handle_lane_failure(lane)
+                    }
+                } else {
+                    self.handle_response(lane, msg, polled)
+                }
+            },
+            Event::Timeout(lane) => {
+                self.pull_lanes[lane] = false;
+                self.busy_lanes[lane] = false;
+                self.logger.log_timeout();
+                self.handle_lane_failure(lane)
+            },
+            Event::SendError(lane, msg) => {
+                self.busy_lanes[lane] = false;
+                self.logger.log_raw(format!("lane {}: error sending message: {}", lane, msg));
+                Ok(())
+            },
+            Event::ConnectError(lane, err) => {
+                Err(format!("lane {}: error connecting: {}", lane, err))
+            },
+            Event::SocketError(lane, err) => {
+                Err(format!("lane {}: error on socket: {}", lane, err))
+            }
         }
-        if let Ok(raw_body) = self.info.record_code.decode_body(&msg.answers[0].body) {
-            if let Ok(packet) = Packet::decode_response(&raw_body, self.info.query_window) {
+    }
+
+    /// Start draining the session once the client is asked to exit, and
+    /// check if the drain has finished.
+    ///
+    /// While draining, no new local data is read, but everything already read
+    /// is sent along with an EOF. The session ends when all of it has been
+    /// acknowledged, or fails when the grace period runs out first.
+    fn check_shutdown(&mut self) -> Result<bool, String> {
+        if let Some(deadline) = self.drain_deadline {
+            if self.state.is_send_done() {
+                self.logger.log_raw("flushed outgoing data before exiting".to_owned());
+                Ok(true)
+            } else if Instant::now() >= deadline {
+                Err(format!("gave up flushing outgoing data after {}s",
+                    self.shutdown_grace.as_secs()))
+            } else {
+                Ok(false)
+            }
+        } else if shutdown::requested() {
+            self.logger.log_raw(format!("shutting down: flushing outgoing data for up to {}s",
+                self.shutdown_grace.as_secs()));
+            self.drain_deadline = Some(Instant::now() + self.shutdown_grace);
+            self.conn.stop_reading();
+            Ok(self.state.is_send_done())
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn check_case(&self, lane: usize, msg: &Message) -> bool {
+        if !self.case_lanes[lane] {
+            return true;
+        }
+        match (msg.questions.first(), self.sent_domains[lane].as_ref()) {
+            (Some(question), Some(domain)) => question.domain == *domain,
+            _ => false
+        }
+    }
+
+    /// Back off after a server failure on a transfer query.
+    ///
+    /// After too many in a row, the session is resumed at the same MTU
+    /// rather than dropping the proxied connection. That restarts the
+    /// response stream at the incoming window under a new session ID. The
+    /// session only gives up if the failures continue before a transfer
+    /// query succeeds again.
+    fn handle_server_failure(&mut self, lane: usize) -> Result<(), String> {
+        self.record_concurrency_failure();
+        self.server_failures += 1;
+        if self.server_failures > self.max_server_failures {
+            if self.reconnected || self.resume.is_some() {
+                return Err(format!("giving up after {} consecutive server failures",
+                    self.server_failures));
+            } else if self.password.is_none() {
+                return Err(format!("giving up after {} consecutive server failures (the \
+                    session can't be resumed)", self.server_failures));
+            }
+            self.logger.log_raw(format!("reconnecting after {} consecutive server failures",
+                self.server_failures));
+            self.server_failures = 0;
+            self.resume = Some(Resume{
+                mtu: self.info.response_mtu,
+                window_start: self.state.recv_window_start(),
+                sent: false,
+                tries: 0,
+                reconnect: true
+            });
+            return self.populate_lane(lane);
+        }
+        let exponent = min(self.server_failures - 1, 6) as u32;
+        let delay = min(Duration::from_millis(100 * 2u64.pow(exponent)), Duration::from_secs(5));
+        self.deferred_lanes.push((Instant::now() + delay, lane));
+        Ok(())
+    }
+
+    /// Get the next time the session has to act without an event: when a
+    /// deferred lane is ready, or when the shutdown grace period runs out.
+    fn next_wake_time(&self) -> Option<Instant> {
+        self.deferred_lanes.iter().map(|x| x.0).chain(self.drain_deadline).min()
+    }
+
+    fn populate_deferred_lanes(&mut self) -> Result<(), String> {
+        let now = Instant::now();
+        let (ready, waiting) = self.deferred_lanes.drain(..).partition(|x| x.0 <= now);
+        self.deferred_lanes = waiting;
+        for (_, lane) in ready {
+            self.populate_lane(lane)?;
+        }
+        Ok(())
+    }
+
+    fn handle_response(&mut self, lane: usize, msg: Message, polled: bool) -> Result<(), String> {
+        match self.lane_queries[lane] {
+            LaneQuery::Transfer(id) => {
+                self.server_failures = 0;
+                self.reconnected = false;
+                self.concurrency.record_success();
+                self.logger.log_response();
+                // Chunks from before a resume are numbered differently.
+                if self.resume.is_none() && id == self.info.session_id {
+                    let got_chunk = self.handle_message(msg);
+                    self.poll_backoff.record_response(Instant::now(), polled, got_chunk);
+                }
+            },
+            LaneQuery::SizeProbe(size) => {
+                let success = size_probe_succeeded(&msg, size);
+                self.handle_probe_result(success);
+            },
+            LaneQuery::Resume => return self.handle_resume_response(lane, msg),
+            LaneQuery::Idle | LaneQuery::Parked => ()
+        }
+        self.populate_lane(lane)
+    }
+
+    /// Handle a lane whose query timed out or got an unusable response.
+    fn handle_lane_failure(&mut self, lane: usize) -> Result<(), String> {
+        match self.lane_queries[lane] {
+            LaneQuery::Transfer(_) => {
+                self.mtu_monitor.record_failure();
+                self.loss_monitor.record_failure();
+                self.record_concurrency_failure();
+            },
+            LaneQuery::SizeProbe(_) => self.handle_probe_result(false),
+            LaneQuery::Resume => {
+                if let Some(ref mut resume) = self.resume {
+                    resume.sent = false;
+                    resume.tries += 1;
+                }
+                match self.resume.as_ref().map(|x| (x.tries, x.reconnect)) {
+                    Some((tries, _)) if tries >= RESUME_TRIES => {
+                        return self.finish_resume(lane, Err("no response".to_owned()));
+                    },
+                    Some((tries, true)) => {
+                        // Give the path time to recover. The lane stays a
+                        // resume lane, so nothing else populates it meanwhile.
+                        let delay = Duration::from_secs(1 << tries);
+                        self.deferred_lanes.push((Instant::now() + delay, lane));
+                        return Ok(());
+                    },
+                    _ => ()
+                }
+            },
+            LaneQuery::Idle | LaneQuery::Parked => ()
+        }
+        self.populate_lane(lane)
+    }
+
+    fn handle_probe_result(&mut self, success: bool) {
+        if let Some(mtu) = self.mtu_monitor.probe_result(success) {
+            self.resume = Some(Resume{
+                mtu,
+                window_start: self.state.recv_window_start(),
+                sent: false,
+                tries: 0,
+                reconnect: false
+            });
+        }
+    }
+
+    fn handle_resume_response(&mut self, lane: usize, msg: Message) -> Result<(), String> {
+        let result = if msg.header.truncated {
+            Err("invalid response message".to_owned())
+        } else {
+            payload_answer(&msg).and_then(|x| self.info.record_code.decode_body(x))
+                .and_then(dns_decode::<EstablishResponse>)
+        };
+        let result = match result {
+            Ok(response @ EstablishResponse::Success{..}) => {
+                let requested = self.resume.as_ref().map(|x| (x.mtu, x.window_start)).unwrap();
+                check_resumed(&self.info, requested.1, requested.0, &response)
+            },
+            Ok(EstablishResponse::Failure(msg)) => Err(format!("error from server: {}", msg)),
+            Ok(EstablishResponse::Pending) => Err("unexpected pending response".to_owned()),
+            Ok(EstablishResponse::Unknown(x)) => Err(format!("unknown response type: {}", x)),
+            Err(msg) => Err(msg)
+        };
+        self.finish_resume(lane, result)
+    }
+
+    /// End a resume, moving to the new session ID and MTU if it succeeded,
+    /// and put the idle lanes back to work.
+    ///
+    /// A failed reconnection ends the session.
+    fn finish_resume(
+        &mut self,
+        lane: usize,
+        result: Result<(u16, u16), String>
+    ) -> Result<(), String> {
+        let reconnect = self.resume.take().map(|x| x.reconnect).unwrap_or(false);
+        match result {
+            Err(msg) if reconnect => return Err(format!("failed to reconnect: {}", msg)),
+            Ok((id, mtu)) => {
+                if reconnect {
+                    self.logger.log_raw(format!("reconnected as session {}", id));
+                    self.reconnected = true;
+                } else {
+                    self.logger.log_raw(format!("renegotiated response MTU from {} to {}",
+                        self.info.response_mtu, mtu));
+                }
+                self.state.restart_recv();
+                self.state.set_recv_chunk_limit(Some(mtu as usize));
+                self.info.session_id = id;
+                self.info.response_mtu = mtu;
+                self.mtu_monitor.set_mtu(mtu);
+            },
+            Err(msg) => {
+                self.logger.log_raw(format!("failed to renegotiate response MTU: {}", msg));
+            }
+        }
+        self.lane_queries[lane] = LaneQuery::Idle;
+        for lane in 0..self.lane_queries.len() {
+            if self.lane_queries[lane] == LaneQuery::Idle {
+                self.populate_lane(lane)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle the response to a transfer query, and check if it carried a
+    /// chunk.
+    fn handle_message(&mut self, msg: Message) -> bool {
+        let answers = if msg.header.truncated {
+            Err("truncated".to_owned())
+        } else if self.info.query.answers == 1 {
+            payload_answer(&msg).map(|x| vec![x])
+        } else {
+            payload_answers(&msg)
+        };
+        let answers = match answers {
+            Ok(answers) => answers,
+            Err(reason) => {
+                self.logger.log_raw(format!("invalid response ({} answers): {}",
+                    msg.answers.len(), reason));
+                self.mtu_monitor.record_failure();
+                self.loss_monitor.record_failure();
+                return false;
+            }
+        };
+        self.mtu_monitor.record_success();
+        self.loss_monitor.record_success();
+        let answer_count = self.info.query.answers;
+        if let Ok(raw_body) = decode_answers(&*self.info.record_code, &answers, answer_count) {
+            let body = unwrap_packet(raw_body, self.info.query.padded, self.info.query.framed);
+            let window = self.info.query_window;
+            if let Ok(packet) = body.and_then(|x| Packet::decode_response(&x, window)) {
+                let got_chunk = packet.chunk.is_some();
                 self.handle_packet(packet);
+                return got_chunk;
             }
         }
+        false
     }
 
     fn handle_packet(&mut self, packet: Packet) {
-        self.logger.log_inbound(handle_packet_in(packet, &mut self.state, &mut self.conn));
+        self.health.record_ack();
+        self.logger.log_inbound(handle_packet_in(packet, &mut self.state, &mut *self.conn));
     }
 
     fn populate_lane(&mut self, lane: usize) -> Result<(), String> {
-        let (packet, sent_size) = next_packet_out(&mut self.state, &mut self.conn);
+        if let Some(size) = self.mtu_monitor.next_probe() {
+            // The probe's data stands in for a chunk, so add the rest of a response packet.
+            let len = if self.info.query.padded {
+                Packet::padded_size(self.info.query_window, size)
+            } else {
+                size as usize + Packet::query_overhead(self.info.query_window)
+            };
+            let domain = size_probe_domain(&self.host, len as u16, self.longest_name)?;
+            return self.send_query(lane, domain, RecordType::TXT, LaneQuery::SizeProbe(len as u16));
+        }
+        if let Some((mtu, window_start, sent)) = self.resume.as_ref()
+            .map(|x| (x.mtu, x.window_start, x.sent))
+        {
+            if sent {
+                self.lane_queries[lane] = LaneQuery::Idle;
+                return Ok(());
+            }
+            self.resume.as_mut().unwrap().sent = true;
+            let password = self.password.as_ref().ok_or("can't resume without a password")?;
+            let query = resume_query(&self.info, password, window_start, mtu);
+            let domain = query.to_domain(&self.host, &self.api_chars)?;
+            let record_type = self.info.record_type;
+            return self.send_query(lane, domain, record_type, LaneQuery::Resume);
+        }
+        if self.busy_lanes.iter().filter(|&&x| x).count() >= self.concurrency.limit() {
+            self.lane_queries[lane] = LaneQuery::Parked;
+            let delay = Duration::from_millis(PARKED_LANE_MS);
+            self.deferred_lanes.push((Instant::now() + delay, lane));
+            return Ok(());
+        }
+        let retransmissions = self.state.retransmissions();
+        let (packet, sent_size) = next_packet_out(&mut self.state, &mut *self.conn);
         self.logger.log_outbound(sent_size);
-        let (api_code, data) = packet.encode_query()?;
-        let message = Message::new_query(Question{
-            domain: self.info.name_code.encode_domain(api_code, self.info.session_id, &data,
-                &self.host)?,
-            record_type: self.info.record_type,
-            record_class: RecordClass::IN
+        if self.state.retransmissions() > retransmissions {
+            self.logger.log_retransmissions(self.state.retransmissions() - retransmissions);
+        }
+        self.check_loss();
+        let pull = packet.chunk.is_none();
+        let now = Instant::now();
+        let backoff = if pull { self.poll_backoff.wait_time(now) } else { None };
+        if pull && (!self.can_pull() || backoff.is_some()) {
+            // Parked lanes check for outgoing data often, even while polls
+            // are backed off for longer.
+            self.lane_queries[lane] = LaneQuery::Parked;
+            let parked = Duration::from_millis(PARKED_LANE_MS);
+            let delay = backoff.map(|x| min(x, parked)).unwrap_or(parked);
+            self.deferred_lanes.push((now + delay, lane));
+            return Ok(());
+        }
+        if pull {
+            self.poll_backoff.record_poll(now);
+        } else {
+            self.poll_backoff.record_chunk_sent(now);
+        }
+        self.pull_lanes[lane] = pull;
+        let (api_code, data) = packet.encode_query(&self.api_chars)?;
+        let padded_size = if self.info.query.padded {
+            Some(Packet::padded_size(self.info.response_window, self.info.query_mtu))
+        } else {
+            None
+        };
+        let data = wrap_packet(data, padded_size, self.info.query.framed)?;
+        let domain = self.name_template.encode(&*self.info.name_code, api_code,
+            self.info.session_id, &data, &self.host, self.decoy_labels)?;
+        let (record_type, id) = (self.info.record_type, self.info.session_id);
+        self.longest_name = max(self.longest_name, domain.wire_len());
+        self.send_query(lane, domain, record_type, LaneQuery::Transfer(id))
+    }
+
+    /// Cut the concurrency limit after a failed transfer query, logging the
+    /// new limit if it changed.
+    fn record_concurrency_failure(&mut self) {
+        let old_limit = self.concurrency.limit();
+        self.concurrency.record_failure(Instant::now());
+        if self.concurrency.limit() < old_limit {
+            self.logger.log_raw(format!("lowered concurrency to {} after a failed query",
+                self.concurrency.limit()));
+        }
+    }
+
+    /// Check if another query without an outgoing chunk may be sent.
+    ///
+    /// Such a query only polls for incoming data, so the read-ahead limit
+    /// caps how many of them are in flight at once.
+    fn can_pull(&self) -> bool {
+        self.read_ahead.map(|x| self.pull_lanes.iter().filter(|&&p| p).count() < x)
+            .unwrap_or(true)
+    }
+
+    fn check_loss(&mut self) {
+        let rate = match self.loss_monitor.check_interval(Instant::now()) {
+            Some(rate) => rate,
+            None => return
+        };
+        if self.loss_monitor.should_report(rate) {
+            self.logger.log_raw(format!("warning: {}% of transfer queries were lost in the last \
+                {}s. Try a smaller --response-mtu or --query-mtu, a lower --concurrency, or a \
+                different resolver", rate, CHECK_INTERVAL_SECS));
+        }
+        let current = self.state.recv_window();
+        let window = next_response_window(current, self.info.response_window,
+            self.loss_monitor.is_high(rate));
+        if window != current && self.state.propose_recv_window(window) {
+            self.logger.log_raw(format!("asking the server for a response window of {} after \
+                {}% loss", window, rate));
+        }
+    }
+
+    fn send_query(
+        &mut self,
+        lane: usize,
+        domain: Domain,
+        record_type: RecordType,
+        kind: LaneQuery
+    ) -> Result<(), String> {
+        let domain = if self.case_lanes[lane] { randomize_case(&domain) } else { domain };
+        self.sent_domains[lane] = Some(domain.clone());
+        self.lane_queries[lane] = kind;
+        self.busy_lanes[lane] = true;
+        let mut message = Message::new_query(Question{
+            domain,
+            record_type,
+            record_class: self.record_class
         });
+        message.header.recursion_desired = self.recursion_desired;
         self.highway.send(lane, message);
         Ok(())
     }
 }
+
+/// Get the lowest response MTU that the session may renegotiate down to.
+///
+/// Sessions that can't be resumed are kept at their MTU, and so are datagram
+/// sessions, since resuming would split pending chunks and with them
+/// datagrams.
+fn renegotiation_floor(config: &ClientConfig, query: &EstablishQuery, response_mtu: u16) -> u16 {
+    if query.datagram || resume_blocker(config, query).is_some() {
+        response_mtu
+    } else {
+        config.min_mtu.unwrap_or(DEFAULT_MIN_MTU)
+    }
+}
+
+/// Get the reason that a session can't be resumed, if there is one.
+fn resume_blocker(config: &ClientConfig, query: &EstablishQuery) -> Option<&'static str> {
+    if config.no_auth {
+        Some("resuming a session requires a password")
+    } else if query.split {
+        // Resume queries aren't split, so the destination must fit in one.
+        Some("the destination is too long for a resume query")
+    } else {
+        None
+    }
+}
+
+/// Pick the response window to ask for after a loss check: half the current
+/// one after a lossy interval, and twice the current one after a clean one.
+///
+/// The window can't grow past the one negotiated at establishment, since that
+/// fixes the size of every acknowledgement.
+fn next_response_window(current: u16, negotiated: u16, lossy: bool) -> u16 {
+    if lossy {
+        max(current / 2, 1)
+    } else {
+        min(current.saturating_mul(2), negotiated)
+    }
+}
+
+fn is_server_failure(msg: &Message) -> bool {
+    msg.header.response_code == ResponseCode::ServerFailure ||
+        msg.header.response_code == ResponseCode::Refused
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    use conn::{ChunkSink, ChunkSource};
+    use myo_proto::name_code::get_name_code;
+    use myo_proto::record_code::get_record_code;
+    use super::super::health::Health;
+
+    /// A highway that records the lanes it sends on.
+    struct FakeHighway {
+        num_lanes: usize,
+        sent: Rc<RefCell<Vec<usize>>>
+    }
+
+    impl Highway for FakeHighway {
+        fn num_lanes(&self) -> usize {
+            self.num_lanes
+        }
+
+        fn send(&self, lane: usize, _message: Message) {
+            self.sent.borrow_mut().push(lane);
+        }
+    }
+
+    /// A local connection whose outgoing chunks are fed in by the test.
+    struct FakeConn(Rc<RefCell<VecDeque<Vec<u8>>>>);
+
+    impl ChunkSource for FakeConn {
+        fn recv(&mut self) -> Option<Vec<u8>> {
+            self.0.borrow_mut().pop_front()
+        }
+    }
+
+    impl ChunkSink for FakeConn {
+        fn can_send(&mut self) -> bool {
+            true
+        }
+
+        fn send(&mut self, _chunk: Vec<u8>) {}
+
+        fn send_finished(&mut self) {}
+    }
+
+    impl Chunker for FakeConn {
+        fn state(&self) -> ConnState {
+            ConnState::Open
+        }
+
+        fn stop_reading(&mut self) {}
+
+        fn close(&mut self) {}
+    }
+
+    fn test_establishment() -> Establishment {
+        Establishment{
+            name_code: get_name_code("b16").unwrap(),
+            record_code: get_record_code(RecordType::TXT, "raw").unwrap(),
+            record_type: RecordType::TXT,
+            session_id: 7,
+            seq_start: 0,
+            query_mtu: 100,
+            response_mtu: 100,
+            query_window: 4,
+            response_window: 4,
+            resume_token: 0,
+            early_data: Vec::new(),
+            query: EstablishQuery{
+                mtu: 100,
+                query_mtu: Some(100),
+                response_encoding: "raw".to_owned(),
+                name_encoding: "b16".to_owned(),
+                query_window: 4,
+                response_window: 4,
+                proof: None,
+                nonce: None,
+                port: 22,
+                host: "localhost".parse().unwrap(),
+                early_data: Vec::new(),
+                resume: None,
+                padded: false,
+                framed: false,
+                datagram: false,
+                unix: false,
+                answers: 1,
+                split: false
+            }
+        }
+    }
+
+    #[test]
+    fn no_auth_keeps_mtu() {
+        let mut config = ClientConfig::new("127.0.0.1:53", "proxy.com".parse().unwrap());
+        let mut query = test_establishment().query;
+        assert_eq!(renegotiation_floor(&config, &query, 200), DEFAULT_MIN_MTU);
+        query.datagram = true;
+        assert_eq!(renegotiation_floor(&config, &query, 200), 200);
+        query.datagram = false;
+        config.min_mtu = Some(50);
+        assert_eq!(renegotiation_floor(&config, &query, 200), 50);
+        query.split = true;
+        assert_eq!(renegotiation_floor(&config, &query, 200), 200);
+        query.split = false;
+        config.no_auth = true;
+        assert_eq!(renegotiation_floor(&config, &query, 200), 200);
+    }
+
+    #[test]
+    fn read_ahead_parks_lanes() {
+        let mut config = ClientConfig::new("127.0.0.1:53", "proxy.com".parse().unwrap());
+        config.read_ahead = Some(1);
+        config.max_poll_delay = Duration::from_secs(0);
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let outgoing = Rc::new(RefCell::new(VecDeque::new()));
+        let highway = FakeHighway{num_lanes: 3, sent: sent.clone()};
+        let mut session = Session::new(config, Box::new(FakeConn(outgoing.clone())),
+            Box::new(highway), test_establishment(), vec![false; 3], &RawLogger::new(),
+            Health::new().register());
+
+        // With nothing to send, only one lane polls and the rest park.
+        for lane in 0..3 {
+            session.populate_lane(lane).unwrap();
+        }
+        assert!(session.lane_queries[0] == LaneQuery::Transfer(7));
+        assert!(session.lane_queries[1] == LaneQuery::Parked);
+        assert!(session.lane_queries[2] == LaneQuery::Parked);
+        assert!(!session.can_pull());
+        assert_eq!(*sent.borrow(), vec![0]);
+
+        // A parked lane that checks again stays parked while the poll is out.
+        session.populate_lane(1).unwrap();
+        assert!(session.lane_queries[1] == LaneQuery::Parked);
+
+        // Once the poll is answered and the lane moves on to sending data,
+        // the parked lanes send queries again.
+        outgoing.borrow_mut().push_back(vec![1, 2, 3]);
+        let mut response = Message::new_query(Question{
+            domain: "a.proxy.com".parse().unwrap(),
+            record_type: RecordType::TXT,
+            record_class: RecordClass::IN
+        });
+        response.header.is_response = true;
+        session.handle_event(Event::Response(0, response)).unwrap();
+        assert!(session.can_pull());
+        session.populate_lane(1).unwrap();
+        assert!(session.lane_queries[1] == LaneQuery::Transfer(7));
+        assert_eq!(*sent.borrow(), vec![0, 0, 1]);
+    }
+
+    #[test]
+    fn response_window_adapts() {
+        assert_eq!(next_response_window(8, 8, true), 4);
+        assert_eq!(next_response_window(1, 8, true), 1);
+        assert_eq!(next_response_window(4, 8, false), 8);
+        assert_eq!(next_response_window(5, 8, false), 8);
+        assert_eq!(next_response_window(8, 8, false), 8);
+    }
+}
@@ -0,0 +1,43 @@
+//! Graceful shutdown on SIGINT and SIGTERM.
+//!
+//! The first signal sets a flag that running sessions poll, so they can stop
+//! reading local data and flush what they already have. A second signal exits
+//! right away.
+
+#[cfg(unix)]
+extern crate libc;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Check if the client has been asked to exit.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Catch SIGINT and SIGTERM, setting the flag that `requested()` reports.
+#[cfg(unix)]
+pub fn install_handler() -> Result<(), String> {
+    extern "C" fn handle_signal(_: libc::c_int) {
+        if REQUESTED.swap(true, Ordering::SeqCst) {
+            unsafe { libc::_exit(130) };
+        }
+    }
+    for &signal in &[libc::SIGINT, libc::SIGTERM] {
+        let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            return Err(format!("failed to install handler for signal {}", signal));
+        }
+    }
+    Ok(())
+}
+
+/// Catch SIGINT and SIGTERM, setting the flag that `requested()` reports.
+///
+/// This is only supported on Unix systems, so elsewhere signals still end the
+/// process immediately.
+#[cfg(not(unix))]
+pub fn install_handler() -> Result<(), String> {
+    Ok(())
+}
@@ -0,0 +1,222 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The pcap link type for packets that start with an IPv4 or IPv6 header.
+const LINKTYPE_RAW: u32 = 101;
+
+/// The largest packet recorded in full. DNS messages are far smaller.
+const SNAP_LEN: u32 = 65535;
+
+const IP_PROTO_UDP: u8 = 17;
+
+static CAPTURE: Mutex<Option<PcapWriter<File>>> = Mutex::new(None);
+
+/// Whether `CAPTURE` holds a writer, so that datagrams skip the lock when no
+/// capture is running.
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+
+/// Start writing every DNS datagram the process sends or receives to a new
+/// pcap file at `path`.
+///
+/// Datagrams are recorded as raw bytes before they are decoded, so
+/// malformed ones are captured too.
+pub fn start_capture(path: &str) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("create {}: {}", path, e))?;
+    let writer = PcapWriter::new(file).map_err(|e| format!("write {}: {}", path, e))?;
+    *CAPTURE.lock().unwrap() = Some(writer);
+    CAPTURING.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Record a datagram sent on `socket`, if a capture is running.
+///
+/// The `peer` may be None for a connected socket.
+pub fn capture_sent(socket: &UdpSocket, peer: Option<SocketAddr>, data: &[u8]) {
+    capture_datagram(socket, peer, data, true);
+}
+
+/// Record a datagram received on `socket`, if a capture is running.
+///
+/// The `peer` may be None for a connected socket.
+pub fn capture_received(socket: &UdpSocket, peer: Option<SocketAddr>, data: &[u8]) {
+    capture_datagram(socket, peer, data, false);
+}
+
+fn capture_datagram(socket: &UdpSocket, peer: Option<SocketAddr>, data: &[u8], sent: bool) {
+    if !CAPTURING.load(Ordering::Acquire) {
+        return;
+    }
+    let mut capture = CAPTURE.lock().unwrap();
+    if let Some(ref mut writer) = *capture {
+        let local = socket.local_addr();
+        let peer = peer.map(Ok).unwrap_or_else(|| socket.peer_addr());
+        if let (Ok(local), Ok(peer)) = (local, peer) {
+            let (src, dst) = if sent { (local, peer) } else { (peer, local) };
+            if let Err(e) = writer.write_datagram(SystemTime::now(), src, dst, data) {
+                eprintln!("stopped packet capture: {}", e);
+                *capture = None;
+                CAPTURING.store(false, Ordering::Release);
+            }
+        }
+    }
+}
+
+/// Writes UDP datagrams to a pcap stream, inventing the IP and UDP headers
+/// that the socket API doesn't expose.
+pub struct PcapWriter<W: Write> {
+    output: W
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Write the pcap file header and create a writer for the packets.
+    pub fn new(mut output: W) -> io::Result<PcapWriter<W>> {
+        // Magic number, version 2.4, UTC timestamps, snap length, and link type.
+        let mut header = Vec::new();
+        header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        for &x in &[0u32, 0, SNAP_LEN, LINKTYPE_RAW] {
+            header.extend_from_slice(&x.to_le_bytes());
+        }
+        output.write_all(&header)?;
+        output.flush()?;
+        Ok(PcapWriter{output})
+    }
+
+    /// Write one datagram as an IPv4 or IPv6 packet.
+    ///
+    /// An IPv4 address is written as IPv4-mapped IPv6 if the other address
+    /// is IPv6. Each record is written and flushed at once, so the file is
+    /// usable even if the process is killed.
+    pub fn write_datagram(
+        &mut self,
+        time: SystemTime,
+        src: SocketAddr,
+        dst: SocketAddr,
+        data: &[u8]
+    ) -> io::Result<()> {
+        let packet = ip_packet(src, dst, data);
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut record = Vec::new();
+        for &x in &[since_epoch.as_secs() as u32, since_epoch.subsec_micros(),
+            packet.len() as u32, packet.len() as u32]
+        {
+            record.extend_from_slice(&x.to_le_bytes());
+        }
+        record.extend(packet);
+        self.output.write_all(&record)?;
+        self.output.flush()
+    }
+}
+
+fn ip_packet(src: SocketAddr, dst: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let udp_len = 8 + data.len();
+    let mut udp = Vec::new();
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(data);
+
+    let mut packet = Vec::new();
+    let mut pseudo_header = Vec::new();
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&((20 + udp_len) as u16).to_be_bytes());
+            // No ID, don't fragment, TTL 64, and room for the checksum.
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, IP_PROTO_UDP, 0, 0]);
+            packet.extend_from_slice(&src_ip.octets());
+            packet.extend_from_slice(&dst_ip.octets());
+            let checksum = internet_checksum(&packet);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+            pseudo_header.extend_from_slice(&packet[12..20]);
+            pseudo_header.extend_from_slice(&[0, IP_PROTO_UDP]);
+            pseudo_header.extend_from_slice(&(udp_len as u16).to_be_bytes());
+        },
+        (src_ip, dst_ip) => {
+            let (src_ip, dst_ip) = (ipv6_octets(src_ip), ipv6_octets(dst_ip));
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            packet.extend_from_slice(&[IP_PROTO_UDP, 64]);
+            packet.extend_from_slice(&src_ip);
+            packet.extend_from_slice(&dst_ip);
+            pseudo_header.extend_from_slice(&packet[8..40]);
+            pseudo_header.extend_from_slice(&(udp_len as u32).to_be_bytes());
+            pseudo_header.extend_from_slice(&[0, 0, 0, IP_PROTO_UDP]);
+        }
+    }
+    pseudo_header.extend_from_slice(&udp);
+    let checksum = match internet_checksum(&pseudo_header) {
+        // A zero checksum means "none", so it is sent as all ones instead.
+        0 => 0xffff,
+        x => x
+    };
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend(udp);
+    packet
+}
+
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets()
+    }
+}
+
+/// Compute the ones' complement checksum used by IP and UDP headers.
+fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for pair in data.chunks(2) {
+        let word = if pair.len() == 2 {
+            u16::from_be_bytes([pair[0], pair[1]])
+        } else {
+            u16::from_be_bytes([pair[0], 0])
+        };
+        sum += word as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn pcap_layout() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        let time = UNIX_EPOCH + Duration::from_micros(1_500_000);
+        let src: SocketAddr = "10.0.0.1:5353".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.2:53".parse().unwrap();
+        // Malformed data is written as is.
+        writer.write_datagram(time, src, dst, &[1, 2, 3]).unwrap();
+        writer.write_datagram(time, "[::1]:53".parse().unwrap(), src, &[4]).unwrap();
+        let data = writer.output;
+
+        assert_eq!(&data[..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(&data[20..24], &LINKTYPE_RAW.to_le_bytes());
+        let record = &data[24..];
+        assert_eq!(&record[..8], &[1, 0, 0, 0, 0x20, 0xa1, 0x07, 0]);
+        assert_eq!(&record[8..16], &[31, 0, 0, 0, 31, 0, 0, 0]);
+        let packet = &record[16..47];
+        assert_eq!(internet_checksum(&packet[..20]), 0);
+        assert_eq!(&packet[12..20], &[10, 0, 0, 1, 10, 0, 0, 2]);
+        assert_eq!(&packet[20..28], &[0x14, 0xe9, 0, 53, 0, 11, 0xd2, 0xb5]);
+        assert_eq!(&packet[28..], &[1, 2, 3]);
+
+        let packet = &record[47 + 16..];
+        assert_eq!(packet.len(), 40 + 9);
+        assert_eq!(packet[0], 0x60);
+        assert_eq!(&packet[24..40], &"::ffff:10.0.0.1".parse::<IpAddr>().map(ipv6_octets)
+            .unwrap());
+        assert_eq!(packet[48], 4);
+    }
+}
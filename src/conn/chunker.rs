@@ -1,50 +1,180 @@
+use std::fmt::{Display, Error, Formatter};
 use std::io;
 use std::io::{Read, Write};
 use std::mem::replace;
 use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{SyncSender, Receiver, TrySendError, sync_channel};
 use std::thread::spawn;
 
-/// A TCP connection that reads and writes data in chunks.
-pub struct TcpChunker {
-    stream: TcpStream,
+/// A local source of outgoing data, such as the application side of a tunnel.
+pub trait ChunkSource {
+    /// Receive the next chunk if one is available.
+    ///
+    /// If no new chunks are available, None is returned.
+    /// An empty chunk represents EOF.
+    fn recv(&mut self) -> Option<Vec<u8>>;
+}
+
+/// A local destination for incoming data.
+pub trait ChunkSink {
+    /// Check if there is room to send another chunk.
+    ///
+    /// If this returns false, the sink is applying backpressure.
+    fn can_send(&mut self) -> bool;
+
+    /// Send a chunk of data.
+    ///
+    /// Before calling this, you should check can_send().
+    fn send(&mut self, chunk: Vec<u8>);
+
+    /// Signal that no more data will be sent.
+    fn send_finished(&mut self);
+
+    /// Check if every chunk is a separate message, like a UDP datagram.
+    ///
+    /// Chunks sent to a stream sink can be merged before they are sent.
+    fn is_datagram(&self) -> bool {
+        false
+    }
+}
+
+/// A local connection carried by a session, such as a `TcpChunker`.
+pub trait Chunker: ChunkSource + ChunkSink {
+    /// Get the state of the connection.
+    fn state(&self) -> ConnState;
+
+    /// Stop reading from the connection.
+    ///
+    /// Chunks that were already read are still returned by recv(), followed
+    /// by an EOF. Sending is unaffected.
+    fn stop_reading(&mut self);
+
+    /// Close the connection in both directions, discarding buffered data.
+    fn close(&mut self);
+}
+
+/// The state of the connection underneath a `Chunker`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnState {
+    /// Data can flow in both directions.
+    Open,
+
+    /// One direction has finished cleanly: either the remote end sent EOF,
+    /// or our EOF has been written.
+    HalfClosed,
+
+    /// Both directions have finished cleanly, or the chunker was closed.
+    Closed,
+
+    /// Reading or writing failed, e.g. because the connection was reset.
+    Errored
+}
+
+impl Display for ConnState {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "{}", match *self {
+            ConnState::Open => "open",
+            ConnState::HalfClosed => "half-closed",
+            ConnState::Closed => "closed",
+            ConnState::Errored => "errored"
+        })
+    }
+}
+
+/// Flags updated by the read and write threads.
+#[derive(Default)]
+struct StreamStatus {
+    read_finished: AtomicBool,
+    write_finished: AtomicBool,
+    errored: AtomicBool,
+    closed: AtomicBool
+}
+
+/// A stream socket that a `StreamChunker` can wrap.
+pub trait Stream: Read + Write + Send + Sized + 'static {
+    /// Create another handle to the same socket.
+    fn try_clone(&self) -> io::Result<Self>;
+
+    /// Shut down one or both directions of the socket.
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<TcpStream> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn try_clone(&self) -> io::Result<UnixStream> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+}
+
+/// A stream connection that reads and writes data in chunks.
+pub struct StreamChunker<S: Stream> {
+    stream: S,
     incoming: Receiver<Vec<u8>>,
     outgoing: Option<SyncSender<Vec<u8>>>,
-    buffer_chunk: Option<Vec<u8>>
+    buffer_chunk: Option<Vec<u8>>,
+    status: Arc<StreamStatus>
 }
 
-impl TcpChunker {
-    /// Create a new TCP chunker.
+/// A TCP connection that reads and writes data in chunks.
+pub type TcpChunker = StreamChunker<TcpStream>;
+
+/// A Unix socket connection that reads and writes data in chunks.
+#[cfg(unix)]
+pub type UnixChunker = StreamChunker<UnixStream>;
+
+impl<S: Stream> StreamChunker<S> {
+    /// Create a new chunker.
     ///
     /// # Arguments
     ///
-    /// * `stream` - A TCP stream to wrap.
+    /// * `stream` - A stream to wrap.
     /// * `recv_mtu` - The maximum incoming chunk size.
     /// * `in_buf` - The number of incoming chunks to buffer.
     /// * `out_buf` - The number of outgoing chunks to buffer.
     pub fn new(
-        stream: TcpStream,
+        stream: S,
         recv_mtu: usize,
         in_buf: usize,
         out_buf: usize
-    ) -> io::Result<TcpChunker> {
+    ) -> io::Result<StreamChunker<S>> {
         let (in_sender, in_receiver) = sync_channel(in_buf);
         let (out_sender, out_receiver) = sync_channel(out_buf);
         let clone1 = stream.try_clone()?;
         let clone2 = stream.try_clone()?;
+        let status = Arc::new(StreamStatus::default());
+        let (read_status, write_status) = (status.clone(), status.clone());
         // TODO: why is `move` necessary here, but not below?
         spawn(move || {
-            TcpChunker::read_loop(&in_sender, clone1, recv_mtu);
+            StreamChunker::read_loop(&in_sender, clone1, recv_mtu, &read_status);
             in_sender.send(Vec::new()).ok();
         });
-        spawn(|| {
-            TcpChunker::write_loop(out_receiver, clone2);
+        spawn(move || {
+            StreamChunker::write_loop(out_receiver, clone2, &write_status);
         });
-        Ok(TcpChunker{
+        Ok(StreamChunker{
             stream: stream,
             incoming: in_receiver,
             outgoing: Some(out_sender),
-            buffer_chunk: None
+            buffer_chunk: None,
+            status
         })
     }
 
@@ -101,16 +231,60 @@ impl TcpChunker {
         self.incoming.try_recv().ok()
     }
 
-    fn write_loop(channel: Receiver<Vec<u8>>, mut stream: TcpStream) {
+    /// Stop reading from the connection.
+    ///
+    /// Chunks that were already read are still returned by recv(), followed
+    /// by an EOF. Writing is unaffected.
+    pub fn stop_reading(&mut self) {
+        self.stream.shutdown(Shutdown::Read).ok();
+    }
+
+    /// Close the connection in both directions, discarding buffered data.
+    pub fn close(&mut self) {
+        self.status.closed.store(true, Ordering::SeqCst);
+        self.outgoing = None;
+        self.buffer_chunk = None;
+        self.stream.shutdown(Shutdown::Both).ok();
+    }
+
+    /// Get the state of the underlying connection.
+    ///
+    /// Unlike an empty chunk from recv(), this also reports errors and
+    /// whether our own EOF has been written.
+    pub fn state(&self) -> ConnState {
+        let status = &self.status;
+        let read_finished = status.read_finished.load(Ordering::SeqCst);
+        let write_finished = status.write_finished.load(Ordering::SeqCst);
+        if status.closed.load(Ordering::SeqCst) {
+            ConnState::Closed
+        } else if status.errored.load(Ordering::SeqCst) {
+            ConnState::Errored
+        } else if read_finished && write_finished {
+            ConnState::Closed
+        } else if read_finished || write_finished {
+            ConnState::HalfClosed
+        } else {
+            ConnState::Open
+        }
+    }
+
+    fn write_loop(channel: Receiver<Vec<u8>>, mut stream: S, status: &StreamStatus) {
         for chunk in channel {
             if let Err(_) = stream.write_all(&chunk) {
+                status.errored.store(true, Ordering::SeqCst);
                 return;
             }
         }
         stream.shutdown(Shutdown::Write).ok();
+        status.write_finished.store(true, Ordering::SeqCst);
     }
 
-    fn read_loop(channel: &SyncSender<Vec<u8>>, mut stream: TcpStream, chunk_size: usize) {
+    fn read_loop(
+        channel: &SyncSender<Vec<u8>>,
+        mut stream: S,
+        chunk_size: usize,
+        status: &StreamStatus
+    ) {
         let mut data = Vec::new();
         for _ in 0..chunk_size {
             data.push(0u8);
@@ -119,21 +293,155 @@ impl TcpChunker {
             if let Ok(size) = stream.read(&mut data) {
                 if size == 0 {
                     // For some reason, this seems to happen on EOF.
+                    status.read_finished.store(true, Ordering::SeqCst);
                     return;
                 }
                 if channel.send(data[0..size].to_vec()).is_err() {
                     return;
                 }
             } else {
+                status.errored.store(true, Ordering::SeqCst);
                 return;
             }
         }
     }
 }
 
-impl Drop for TcpChunker {
+impl<S: Stream> ChunkSource for StreamChunker<S> {
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        StreamChunker::recv(self)
+    }
+}
+
+impl<S: Stream> ChunkSink for StreamChunker<S> {
+    fn can_send(&mut self) -> bool {
+        StreamChunker::can_send(self)
+    }
+
+    fn send(&mut self, chunk: Vec<u8>) {
+        StreamChunker::send(self, chunk)
+    }
+
+    fn send_finished(&mut self) {
+        StreamChunker::send_finished(self)
+    }
+}
+
+impl<S: Stream> Chunker for StreamChunker<S> {
+    fn state(&self) -> ConnState {
+        StreamChunker::state(self)
+    }
+
+    fn stop_reading(&mut self) {
+        StreamChunker::stop_reading(self)
+    }
+
+    fn close(&mut self) {
+        StreamChunker::close(self)
+    }
+}
+
+impl<S: Stream> Drop for StreamChunker<S> {
     fn drop(&mut self) {
         // Force the read loop to die.
         self.stream.shutdown(Shutdown::Read).ok();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn clean_close_states() {
+        let (mut chunker, mut remote) = chunker_pair();
+        assert_eq!(chunker.state(), ConnState::Open);
+
+        remote.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(wait_for_recv(&mut chunker), Vec::<u8>::new());
+        wait_for_state(&chunker, ConnState::HalfClosed);
+
+        chunker.send(b"bye".to_vec());
+        chunker.send_finished();
+        wait_for_state(&chunker, ConnState::Closed);
+        let mut received = Vec::new();
+        remote.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"bye");
+    }
+
+    #[test]
+    fn reset_state() {
+        let (mut chunker, remote) = chunker_pair();
+        chunker.send(b"unread".to_vec());
+        sleep(Duration::from_millis(50));
+        // Closing a socket with unread data resets the connection.
+        drop(remote);
+        wait_for_state(&chunker, ConnState::Errored);
+
+        chunker.close();
+        assert_eq!(chunker.state(), ConnState::Closed);
+    }
+
+    #[test]
+    fn stop_reading() {
+        let (mut chunker, mut remote) = chunker_pair();
+        remote.write_all(b"hi").unwrap();
+        assert_eq!(wait_for_recv(&mut chunker), b"hi");
+        chunker.stop_reading();
+        assert_eq!(wait_for_recv(&mut chunker), Vec::<u8>::new());
+
+        // The other direction still works.
+        chunker.send(b"bye".to_vec());
+        chunker.send_finished();
+        let mut received = Vec::new();
+        remote.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"bye");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_stream() {
+        let (local, mut remote) = UnixStream::pair().unwrap();
+        let mut chunker = UnixChunker::new(local, 16, 1, 1).unwrap();
+        remote.write_all(b"hi").unwrap();
+        assert_eq!(wait_for_recv(&mut chunker), b"hi");
+
+        chunker.send(b"bye".to_vec());
+        chunker.send_finished();
+        let mut received = Vec::new();
+        remote.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"bye");
+        remote.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(wait_for_recv(&mut chunker), Vec::<u8>::new());
+        wait_for_state(&chunker, ConnState::Closed);
+    }
+
+    fn chunker_pair() -> (TcpChunker, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (remote, _) = listener.accept().unwrap();
+        (TcpChunker::new(stream, 16, 1, 1).unwrap(), remote)
+    }
+
+    fn wait_for_recv<S: Stream>(chunker: &mut StreamChunker<S>) -> Vec<u8> {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if let Some(chunk) = chunker.recv() {
+                return chunk;
+            }
+            sleep(Duration::from_millis(1));
+        }
+        panic!("no chunk received");
+    }
+
+    fn wait_for_state<S: Stream>(chunker: &StreamChunker<S>, state: ConnState) {
+        let start = Instant::now();
+        while chunker.state() != state {
+            assert!(start.elapsed() < Duration::from_secs(5), "state is {}", chunker.state());
+            sleep(Duration::from_millis(1));
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use std::io;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, SyncSender, TryRecvError, TrySendError, sync_channel};
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+use super::chunker::{ChunkSink, ChunkSource, Chunker, ConnState};
+
+/// The largest possible UDP datagram.
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// A reasonable number of incoming datagrams to buffer.
+///
+/// Since datagrams that don't fit are dropped, this should cover bursts and
+/// the time it takes to establish a session.
+pub const DATAGRAM_BUFFER: usize = 64;
+
+/// How often the read thread of a connected chunker checks if it was closed.
+const READ_POLL_INTERVAL_MS: u64 = 500;
+
+/// A UDP peer that sends and receives datagrams as chunks.
+///
+/// Unlike a `TcpChunker`, this never applies backpressure. Datagrams that
+/// arrive while the incoming buffer is full are dropped, as are datagrams
+/// larger than the maximum chunk size and empty datagrams (since an empty
+/// chunk means EOF).
+///
+/// UDP has no EOF, so recv() only reports one after stop_reading(),
+/// send_finished(), or a configured idle timeout.
+pub struct UdpChunker {
+    socket: UdpSocket,
+    peer: Option<SocketAddr>,
+    incoming: Receiver<Vec<u8>>,
+    max_size: usize,
+    idle_timeout: Option<Duration>,
+    last_active: Instant,
+    stopped: bool,
+    reading: bool,
+    writing: bool,
+    errored: Arc<AtomicBool>,
+    closed: Arc<AtomicBool>
+}
+
+impl UdpChunker {
+    /// Create a chunker for a UDP socket that is connected to its peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `socket` - A connected UDP socket to wrap.
+    /// * `max_size` - The maximum incoming datagram size.
+    /// * `in_buf` - The number of incoming datagrams to buffer.
+    pub fn connect(socket: UdpSocket, max_size: usize, in_buf: usize) -> io::Result<UdpChunker> {
+        let read_socket = socket.try_clone()?;
+        read_socket.set_read_timeout(Some(Duration::from_millis(READ_POLL_INTERVAL_MS)))?;
+        let (sender, receiver) = sync_channel(in_buf);
+        let result = UdpChunker::new(socket, None, receiver, max_size);
+        let (errored, closed) = (result.errored.clone(), result.closed.clone());
+        spawn(move || {
+            UdpChunker::read_loop(&sender, read_socket, &errored, &closed);
+        });
+        Ok(result)
+    }
+
+    /// Create a chunker for one peer of an unconnected UDP socket.
+    ///
+    /// The caller reads datagrams from the socket and passes the peer's
+    /// datagrams along through `incoming`, e.g. with `forward_datagram`.
+    pub fn with_peer(
+        socket: UdpSocket,
+        peer: SocketAddr,
+        incoming: Receiver<Vec<u8>>,
+        max_size: usize
+    ) -> UdpChunker {
+        UdpChunker::new(socket, Some(peer), incoming, max_size)
+    }
+
+    fn new(
+        socket: UdpSocket,
+        peer: Option<SocketAddr>,
+        incoming: Receiver<Vec<u8>>,
+        max_size: usize
+    ) -> UdpChunker {
+        UdpChunker{
+            socket,
+            peer,
+            incoming,
+            max_size,
+            idle_timeout: None,
+            last_active: Instant::now(),
+            stopped: false,
+            reading: true,
+            writing: true,
+            errored: Arc::new(AtomicBool::new(false)),
+            closed: Arc::new(AtomicBool::new(false))
+        }
+    }
+
+    /// Report an EOF once no datagrams have been sent or received for the
+    /// given amount of time.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Receive the next datagram if one is available.
+    ///
+    /// If no new datagrams are available, None is returned.
+    /// An empty chunk represents EOF.
+    pub fn recv(&mut self) -> Option<Vec<u8>> {
+        if !self.reading {
+            return None;
+        }
+        loop {
+            match self.incoming.try_recv() {
+                Ok(data) => {
+                    if data.is_empty() || data.len() > self.max_size {
+                        continue;
+                    }
+                    self.last_active = Instant::now();
+                    return Some(data);
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return self.recv_eof()
+            }
+        }
+        let idle = self.idle_timeout.map(|x| self.last_active.elapsed() >= x).unwrap_or(false);
+        if self.stopped || idle {
+            self.recv_eof()
+        } else {
+            None
+        }
+    }
+
+    /// Stop reading datagrams.
+    ///
+    /// Datagrams that were already received are still returned by recv(),
+    /// followed by an EOF.
+    pub fn stop_reading(&mut self) {
+        self.stopped = true;
+    }
+
+    /// Stop sending and receiving, discarding buffered datagrams.
+    pub fn close(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.reading = false;
+        self.writing = false;
+    }
+
+    /// Get the state of the chunker.
+    pub fn state(&self) -> ConnState {
+        if self.closed.load(Ordering::SeqCst) {
+            ConnState::Closed
+        } else if self.errored.load(Ordering::SeqCst) {
+            ConnState::Errored
+        } else if !self.reading && !self.writing {
+            ConnState::Closed
+        } else if !self.reading || !self.writing {
+            ConnState::HalfClosed
+        } else {
+            ConnState::Open
+        }
+    }
+
+    fn recv_eof(&mut self) -> Option<Vec<u8>> {
+        self.reading = false;
+        Some(Vec::new())
+    }
+
+    fn read_loop(
+        channel: &SyncSender<Vec<u8>>,
+        socket: UdpSocket,
+        errored: &AtomicBool,
+        closed: &AtomicBool
+    ) {
+        let mut data = vec![0u8; MAX_DATAGRAM_SIZE];
+        while !closed.load(Ordering::SeqCst) {
+            match socket.recv(&mut data) {
+                Ok(size) => {
+                    if let Err(TrySendError::Disconnected(_)) = forward_datagram(channel,
+                        data[0..size].to_vec())
+                    {
+                        return;
+                    }
+                },
+                // An earlier datagram was refused by the peer; later ones may
+                // still get through.
+                Err(ref e) if e.kind() == ErrorKind::ConnectionRefused => (),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                    e.kind() == ErrorKind::TimedOut => (),
+                Err(_) => {
+                    errored.store(true, Ordering::SeqCst);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Pass a datagram along to a `UdpChunker`, dropping it if the chunker's
+/// buffer is full.
+pub fn forward_datagram(
+    channel: &SyncSender<Vec<u8>>,
+    data: Vec<u8>
+) -> Result<(), TrySendError<Vec<u8>>> {
+    match channel.try_send(data) {
+        Err(TrySendError::Full(_)) => Ok(()),
+        x => x
+    }
+}
+
+impl ChunkSource for UdpChunker {
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        UdpChunker::recv(self)
+    }
+}
+
+impl ChunkSink for UdpChunker {
+    fn can_send(&mut self) -> bool {
+        self.writing
+    }
+
+    fn send(&mut self, chunk: Vec<u8>) {
+        assert!(self.writing);
+        self.last_active = Instant::now();
+        // Like any other UDP traffic, a datagram that can't be sent is lost.
+        if let Some(peer) = self.peer {
+            self.socket.send_to(&chunk, peer).ok();
+        } else {
+            self.socket.send(&chunk).ok();
+        }
+    }
+
+    /// Stop sending datagrams.
+    ///
+    /// Since UDP has no way to pass the EOF along, reading stops as well, so
+    /// the session can finish once the remote end has stopped sending.
+    fn send_finished(&mut self) {
+        self.writing = false;
+        self.stop_reading();
+    }
+
+    fn is_datagram(&self) -> bool {
+        true
+    }
+}
+
+impl Chunker for UdpChunker {
+    fn state(&self) -> ConnState {
+        UdpChunker::state(self)
+    }
+
+    fn stop_reading(&mut self) {
+        UdpChunker::stop_reading(self)
+    }
+
+    fn close(&mut self) {
+        UdpChunker::close(self)
+    }
+}
+
+impl Drop for UdpChunker {
+    fn drop(&mut self) {
+        // Let the read loop (if any) die.
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    #[test]
+    fn connected_datagrams() {
+        let remote = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(remote.local_addr().unwrap()).unwrap();
+        remote.connect(socket.local_addr().unwrap()).unwrap();
+        let mut chunker = UdpChunker::connect(socket, 4, 8).unwrap();
+        assert!(chunker.is_datagram());
+
+        // Boundaries are kept, and oversized datagrams are dropped.
+        for data in &[&b"ab"[..], &b"toolong"[..], &b"cd"[..]] {
+            remote.send(data).unwrap();
+        }
+        assert_eq!(wait_for_recv(&mut chunker), b"ab");
+        assert_eq!(wait_for_recv(&mut chunker), b"cd");
+
+        chunker.send(b"hello".to_vec());
+        let mut buf = [0u8; 16];
+        assert_eq!(remote.recv(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+
+        chunker.send_finished();
+        assert_eq!(chunker.state(), ConnState::HalfClosed);
+        assert_eq!(wait_for_recv(&mut chunker), Vec::<u8>::new());
+        assert_eq!(chunker.state(), ConnState::Closed);
+        assert_eq!(chunker.recv(), None);
+    }
+
+    #[test]
+    fn peer_idle_timeout() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (sender, receiver) = sync_channel(1);
+        let mut chunker = UdpChunker::with_peer(socket, peer.local_addr().unwrap(), receiver,
+            16);
+        chunker.set_idle_timeout(Some(Duration::from_millis(50)));
+
+        forward_datagram(&sender, b"a".to_vec()).unwrap();
+        forward_datagram(&sender, b"dropped".to_vec()).unwrap();
+        assert_eq!(chunker.recv(), Some(b"a".to_vec()));
+        assert_eq!(chunker.recv(), None);
+
+        chunker.send(b"b".to_vec());
+        let mut buf = [0u8; 16];
+        assert_eq!(peer.recv(&mut buf).unwrap(), 1);
+        sleep(Duration::from_millis(60));
+        assert_eq!(chunker.recv(), Some(Vec::new()));
+        assert_eq!(chunker.state(), ConnState::HalfClosed);
+    }
+
+    fn wait_for_recv(chunker: &mut UdpChunker) -> Vec<u8> {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if let Some(chunk) = chunker.recv() {
+                return chunk;
+            }
+            sleep(Duration::from_millis(1));
+        }
+        panic!("no chunk received");
+    }
+}
@@ -1,6 +1,9 @@
+extern crate rand;
+use self::rand::{Rng, thread_rng};
+
 use std::io;
 use std::io::ErrorKind;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket, TcpStream};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket, TcpStream};
 
 /// Create a TCP socket and connect to an address.
 ///
@@ -11,26 +14,83 @@ pub fn dial_tcp(addr: &str) -> io::Result<TcpStream> {
     TcpStream::connect(remote)
 }
 
+/// The first port of the dynamic range, where random source ports are picked.
+const DYNAMIC_PORT_START: u16 = 49152;
+
+/// How many random source ports to try before leaving the choice to the OS.
+const RANDOM_PORT_TRIES: usize = 16;
+
 /// Create a UDP socket and connect to an address.
 ///
-/// The address should parse into an "IP:port" pair.
+/// The address should parse into an "IP:port" pair. The source port is
+/// picked at random, like a resolver would, to make spoofed responses harder
+/// to forge.
 pub fn dial_udp(addr: &str) -> io::Result<UdpSocket> {
-    let sock = UdpSocket::bind(OutgoingAddrRange{})?;
+    dial_udp_from(addr, None)
+}
+
+/// Create a UDP socket with the given source port and connect to an address.
+///
+/// If `source_port` is None, a random port from the dynamic range is used.
+/// If every random port that was tried is taken, the OS picks one instead.
+///
+/// A fixed source port that is already in use fails with `AddrInUse`.
+pub fn dial_udp_from(addr: &str, source_port: Option<u16>) -> io::Result<UdpSocket> {
     let remote = addr.parse::<SocketAddr>()
         .map_err(|x| io::Error::new(ErrorKind::ConnectionRefused, x))?;
+    let sock = match source_port {
+        Some(port) => bind_udp(&remote, port).map_err(|e| if e.kind() == ErrorKind::AddrInUse {
+            io::Error::new(ErrorKind::AddrInUse, format!("source port {} is in use", port))
+        } else {
+            e
+        })?,
+        None => bind_random_udp(&remote)?
+    };
     sock.connect(remote)?;
     Ok(sock)
 }
 
-struct OutgoingAddrRange;
+fn bind_random_udp(remote: &SocketAddr) -> io::Result<UdpSocket> {
+    let mut rng = thread_rng();
+    for _ in 0..RANDOM_PORT_TRIES {
+        let port = rng.gen_range(DYNAMIC_PORT_START as u32, 0x10000) as u16;
+        match bind_udp(remote, port) {
+            Err(ref e) if e.kind() == ErrorKind::AddrInUse ||
+                e.kind() == ErrorKind::PermissionDenied => (),
+            x => return x
+        }
+    }
+    bind_udp(remote, 0)
+}
+
+/// Bind a UDP socket to a local port, on every address of the remote
+/// address's family.
+fn bind_udp(remote: &SocketAddr, port: u16) -> io::Result<UdpSocket> {
+    if remote.is_ipv4() {
+        UdpSocket::bind((Ipv4Addr::new(0, 0, 0, 0), port))
+    } else {
+        UdpSocket::bind((Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl ToSocketAddrs for OutgoingAddrRange {
-    type Iter = <Vec<SocketAddr> as IntoIterator>::IntoIter;
+    #[test]
+    fn udp_source_ports() {
+        let remote = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = remote.local_addr().unwrap().to_string();
+        let random = dial_udp(&addr).unwrap();
+        assert!(random.local_addr().unwrap().port() >= DYNAMIC_PORT_START);
 
-    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
-        let mut result = Vec::new();
-        result.extend((Ipv4Addr::new(0, 0, 0, 0), 0u16).to_socket_addrs()?);
-        result.extend((Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), 0u16).to_socket_addrs()?);
-        Ok(result.into_iter())
+        let taken = random.local_addr().unwrap().port();
+        match dial_udp_from(&addr, Some(taken)) {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::AddrInUse),
+            Ok(_) => panic!("expected a bind conflict")
+        }
+        drop(random);
+        let fixed = dial_udp_from(&addr, Some(taken)).unwrap();
+        assert_eq!(fixed.local_addr().unwrap().port(), taken);
     }
 }
@@ -13,6 +13,12 @@ pub enum Event {
 }
 
 /// A batched DNS requester.
+///
+/// Every message sent gets exactly one event. Resolvers sometimes answer a
+/// query more than once, so responses that don't match the lane's
+/// outstanding query, including copies of one that was already delivered,
+/// are dropped. A query stops being outstanding when it gets its event, so
+/// a response arriving after its timeout is never matched to a later query.
 pub trait Highway {
     /// Get the number of virtual connections.
     fn num_lanes(&self) -> usize;
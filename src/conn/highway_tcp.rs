@@ -7,7 +7,7 @@ use std::sync::mpsc::{Sender, Receiver, TryRecvError, channel};
 use std::thread::spawn;
 use std::time::{Duration, Instant};
 
-use dns_coding::{dns_decode, dns_encode};
+use dns_coding::{MessageFramer, dns_encode_framed};
 use dns_proto::Message;
 
 use super::highway::{Event, Highway};
@@ -107,13 +107,10 @@ fn write_loop(
     for (lane, mut msg) in receiver {
         msg.header.identifier = cur_seq.0;
         cur_seq = cur_seq + Wrapping(1);
-        // TODO: look into using encode_with_length here.
-        match dns_encode(&msg) {
+        match dns_encode_framed(&msg) {
             Ok(data) => {
-                let mut data_1 = dns_encode(&(data.len() as u16)).unwrap();
-                data_1.extend(data);
                 // TODO: look for built-in function for this.
-                if let Err(err) = write_data(&mut socket, &data_1) {
+                if let Err(err) = write_data(&mut socket, &data) {
                     let msg = format!("failed to write to socket: {}", err);
                     sender.send(Event::SendError(lane, msg)).ok();
                 } else {
@@ -215,12 +212,12 @@ impl PendingQueue {
 }
 
 struct MessageReader {
-    buffer: Vec<u8>
+    framer: MessageFramer<Message>
 }
 
 impl MessageReader {
     fn new() -> MessageReader {
-        MessageReader{buffer: Vec::new()}
+        MessageReader{framer: MessageFramer::new()}
     }
 
     fn read_chunk(&mut self, socket: &mut TcpStream) -> Result<Vec<Message>, io::Error> {
@@ -231,8 +228,14 @@ impl MessageReader {
                     // TODO: return EOF error here.
                     return Ok(Vec::new());
                 }
-                self.buffer.extend(buffer[..size].iter());
-                self.pop_all()
+                self.framer.push(&buffer[..size]);
+                let mut res = Vec::new();
+                while let Some(result) = self.framer.next_message() {
+                    if let Ok(msg) = result {
+                        res.push(msg);
+                    }
+                }
+                Ok(res)
             }
             Err(_) => {
                 // TODO: handle timeout properly
@@ -240,34 +243,4 @@ impl MessageReader {
             }
         }
     }
-
-    fn pop_all(&mut self) -> Result<Vec<Message>, io::Error> {
-        let mut res = Vec::new();
-        loop {
-            match self.pop_message() {
-                None => return Ok(res),
-                Some(x) => {
-                    if let Ok(msg) = dns_decode(x) {
-                        res.push(msg);
-                    }
-                }
-            }
-        }
-    }
-
-    fn pop_message(&mut self) -> Option<Vec<u8>> {
-        if self.buffer.len() < 2 {
-            return None;
-        }
-        let msg_size = (((self.buffer[0] as u16) << 8) | (self.buffer[1] as u16)) as usize;
-        // TODO: does size include 2 byte size field?
-        if self.buffer.len() >= msg_size + 2 {
-            let data = self.buffer[2..(2 + msg_size)].to_vec();
-            // TODO: more efficient way to slice up data?
-            self.buffer = self.buffer[(2 + msg_size)..self.buffer.len()].to_vec();
-            Some(data)
-        } else {
-            None
-        }
-    }
 }
@@ -1,3 +1,4 @@
+use std::cmp;
 use std::io;
 use std::net::UdpSocket;
 use std::num::Wrapping;
@@ -6,16 +7,16 @@ use std::thread::{sleep, spawn};
 use std::time::{Duration, Instant};
 
 use dns_coding::{dns_decode, dns_encode};
-use dns_proto::Message;
+use dns_proto::{Message, Question};
 
+use super::capture::{capture_received, capture_sent};
 use super::highway::{Event, Highway};
 use super::dial_udp;
 
 /// A highway that opens one UDP socket per lane.
 pub struct UDPHighway {
-    min_time: Duration,
-    max_time: Duration,
-    senders: Vec<Sender<(Message, Duration, Duration)>>
+    timing: Timing,
+    senders: Vec<Sender<(Message, Timing)>>
 }
 
 impl Highway for UDPHighway {
@@ -24,7 +25,7 @@ impl Highway for UDPHighway {
     }
 
     fn send(&self, lane: usize, message: Message) {
-        self.senders[lane].send((message, self.min_time.clone(), self.max_time.clone())).ok();
+        self.senders[lane].send((message, self.timing)).ok();
     }
 }
 
@@ -58,48 +59,129 @@ impl UDPHighway {
             });
         }
         (UDPHighway{
-            min_time: min_time,
-            max_time: max_time,
+            timing: Timing::new(min_time, max_time),
             senders: senders
         }, event_receiver)
     }
 
+    /// Create a new Highway on top of existing, connected UDP sockets.
+    ///
+    /// This is useful when sockets are created ahead of time, e.g. before
+    /// dropping privileges or when they are inherited from a parent process.
+    ///
+    /// # Arguments
+    ///
+    /// * `sockets` - The sockets to use, one per lane.
+    /// * `min_time` - the minimum time for a query to last.
+    /// * `max_time` - a soft upper bound on the time for a query to last.
+    ///
+    /// Returns the new UDPHighway and its corresponding event queue.
+    pub fn from_sockets(
+        sockets: Vec<UdpSocket>,
+        min_time: Duration,
+        max_time: Duration
+    ) -> (UDPHighway, Receiver<Event>) {
+        let (event_sender, event_receiver) = channel();
+        let mut senders = Vec::new();
+        for (lane, socket) in sockets.into_iter().enumerate() {
+            let (sender, receiver) = channel();
+            senders.push(sender);
+            let local_sender = event_sender.clone();
+            spawn(move || {
+                UDPHighway::run_socket_lane(lane, receiver, socket, local_sender);
+            });
+        }
+        (UDPHighway{
+            timing: Timing::new(min_time, max_time),
+            senders
+        }, event_receiver)
+    }
+
+    /// Set how long to wait for any response datagram before treating a
+    /// query as dropped.
+    ///
+    /// Once a datagram has arrived, the query may take up to the full maximum
+    /// time. This separates a dropped query from a slow path. If None, only
+    /// the maximum time applies.
+    pub fn set_first_response_time(&mut self, time: Option<Duration>) {
+        self.timing.first_response_time = time;
+    }
+
     fn run_lane(
         lane: usize,
-        receiver: Receiver<(Message, Duration, Duration)>,
+        receiver: Receiver<(Message, Timing)>,
         addr: String,
         event_sender: Sender<Event>
     ) {
         match dial_udp(&addr) {
-            Ok(socket) => {
-                Lane{
-                    lane: lane,
-                    seq_number: (Wrapping(lane as u16) * Wrapping(1337)).0,
-                    sender: event_sender,
-                    socket: socket
-                }.run_loop(receiver);
-            },
+            Ok(socket) => UDPHighway::run_socket_lane(lane, receiver, socket, event_sender),
             Err(err) => {
                 event_sender.send(Event::ConnectError(lane, err)).ok();
             }
         }
     }
+
+    fn run_socket_lane(
+        lane: usize,
+        receiver: Receiver<(Message, Timing)>,
+        socket: UdpSocket,
+        event_sender: Sender<Event>
+    ) {
+        Lane{
+            lane,
+            seq_number: (Wrapping(lane as u16) * Wrapping(1337)).0,
+            outstanding: None,
+            sender: event_sender,
+            socket
+        }.run_loop(receiver);
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Timing {
+    min_time: Duration,
+    max_time: Duration,
+    first_response_time: Option<Duration>
+}
+
+impl Timing {
+    fn new(min_time: Duration, max_time: Duration) -> Timing {
+        Timing{min_time, max_time, first_response_time: None}
+    }
 }
 
 struct Lane {
     lane: usize,
     seq_number: u16,
+    /// The query the lane is waiting on, if it hasn't been answered or given
+    /// up on.
+    outstanding: Option<Outstanding>,
     sender: Sender<Event>,
     socket: UdpSocket
 }
 
+/// What a response has to match to belong to the lane's current query.
+struct Outstanding {
+    identifier: u16,
+    questions: Vec<Question>,
+    /// When the query started, for its deadlines.
+    start: Instant
+}
+
+impl Outstanding {
+    fn matches(&self, response: &Message) -> bool {
+        response.header.identifier == self.identifier &&
+            same_questions(&response.questions, &self.questions)
+    }
+}
+
 impl Lane {
-    fn run_loop(&mut self, receiver: Receiver<(Message, Duration, Duration)>) {
-        for (mut message, min_time, max_time) in receiver {
+    fn run_loop(&mut self, receiver: Receiver<(Message, Timing)>) {
+        for (message, timing) in receiver {
             let send_res = if let Err(err) = self.send_message(message) {
                 self.send_event(Event::SendError(self.lane, err))
             } else {
-                match self.recv_response(min_time, max_time) {
+                match self.recv_response(timing) {
                     Ok(None) => self.send_event(Event::Timeout(self.lane)),
                     Ok(Some(m)) => self.send_event(Event::Response(self.lane, m)),
                     Err(err) => self.send_event(Event::SocketError(self.lane, err))
@@ -113,36 +195,68 @@ impl Lane {
 
     fn send_message(&mut self, mut message: Message) -> Result<(), String> {
         message.header.identifier = self.next_seq();
-        if let Err(err) = self.socket.send(&dns_encode(&message)?) {
+        self.outstanding = Some(Outstanding{
+            identifier: message.header.identifier,
+            questions: message.questions.clone(),
+            start: Instant::now()
+        });
+        let data = dns_encode(&message)?;
+        capture_sent(&self.socket, None, &data);
+        if let Err(err) = self.socket.send(&data) {
             Err(format!("error sending datagram: {}", err))
         } else {
             Ok(())
         }
     }
 
-    fn recv_response(
-        &self,
-        min_time: Duration,
-        max_time: Duration
-    ) -> io::Result<Option<Message>> {
-        let start = Instant::now();
+    /// Wait for the response to the outstanding query.
+    ///
+    /// Datagrams that don't match it, such as late responses to queries that
+    /// already timed out, are dropped. The query is forgotten once it is
+    /// answered or its deadline passes, so nothing can match it afterwards.
+    fn recv_response(&mut self, timing: Timing) -> io::Result<Option<Message>> {
+        let result = self.wait_for_response(timing);
+        self.outstanding = None;
+        result
+    }
+
+    fn wait_for_response(&self, timing: Timing) -> io::Result<Option<Message>> {
+        let outstanding = match self.outstanding {
+            Some(ref x) => x,
+            None => return Ok(None)
+        };
+        let start = outstanding.start;
+        let mut got_datagram = false;
         loop {
             let elapsed = Instant::now().duration_since(start);
-            if elapsed >= max_time {
+            let deadline = match timing.first_response_time {
+                Some(time) if !got_datagram => cmp::min(time, timing.max_time),
+                _ => timing.max_time
+            };
+            if elapsed >= deadline {
                 return Ok(None);
             }
-            self.socket.set_read_timeout(Some(max_time - elapsed))?;
+            self.socket.set_read_timeout(Some(deadline - elapsed))?;
             let mut buffer = [0u8; 2048];
             if let Ok(size) = self.socket.recv(&mut buffer) {
-                if let Ok(response) = dns_decode::<Message>(buffer[..size].to_vec()) {
-                    if response.header.identifier == self.seq_number {
-                        let passed = Instant::now().duration_since(start);
-                        if passed < min_time {
-                            sleep(min_time - passed);
-                        }
-                        return Ok(Some(response));
+                capture_received(&self.socket, None, &buffer[..size]);
+                let response = match dns_decode::<Message>(buffer[..size].to_vec()) {
+                    Ok(response) => response,
+                    Err(_) => {
+                        got_datagram = true;
+                        continue;
                     }
+                };
+                if outstanding.matches(&response) {
+                    let passed = Instant::now().duration_since(start);
+                    if passed < timing.min_time {
+                        sleep(timing.min_time - passed);
+                    }
+                    return Ok(Some(response));
                 }
+                // A late response to an earlier query says nothing about
+                // whether this one got through.
+                got_datagram |= response.header.identifier == outstanding.identifier;
             }
         }
     }
@@ -156,3 +270,117 @@ impl Lane {
         self.sender.send(event).is_ok()
     }
 }
+
+/// Check if a response's questions match the query's, ignoring the case of
+/// domain names since some resolvers change it.
+fn same_questions(x: &[Question], y: &[Question]) -> bool {
+    x.len() == y.len() && x.iter().zip(y).all(|(a, b)| {
+        a.record_type == b.record_type && a.record_class == b.record_class &&
+            a.domain.raw_parts().len() == b.domain.raw_parts().len() &&
+            a.domain.raw_parts().iter().zip(b.domain.raw_parts())
+                .all(|(p1, p2)| p1.eq_ignore_ascii_case(p2))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dns_proto::{RecordClass, RecordType, ResponseCode};
+
+    #[test]
+    fn first_response_timeout() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(server.local_addr().unwrap()).unwrap();
+        let (mut highway, events) = UDPHighway::from_sockets(vec![socket],
+            Duration::from_millis(0), Duration::from_secs(30));
+        highway.set_first_response_time(Some(Duration::from_millis(50)));
+
+        let start = Instant::now();
+        highway.send(0, Message::new_query(Question{
+            domain: "a.com".parse().unwrap(),
+            record_type: RecordType::A,
+            record_class: RecordClass::IN
+        }));
+        match events.recv().unwrap() {
+            Event::Timeout(0) => (),
+            _ => panic!("expected a timeout")
+        }
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn duplicate_responses() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(server.local_addr().unwrap()).unwrap();
+        let (highway, events) = UDPHighway::from_sockets(vec![socket],
+            Duration::from_millis(0), Duration::from_secs(30));
+
+        for name in &["a.com", "b.com"] {
+            highway.send(0, Message::new_query(Question{
+                domain: name.parse().unwrap(),
+                record_type: RecordType::A,
+                record_class: RecordClass::IN
+            }));
+            let mut buffer = [0u8; 512];
+            let (size, addr) = server.recv_from(&mut buffer).unwrap();
+            let mut response: Message = dns_decode(buffer[..size].to_vec()).unwrap();
+            response.header.is_response = true;
+            // Each response arrives twice, so the copy of the first one is
+            // waiting when the second query is sent.
+            for _ in 0..2 {
+                server.send_to(&dns_encode(&response).unwrap(), addr).unwrap();
+            }
+            match events.recv_timeout(Duration::from_secs(10)).unwrap() {
+                Event::Response(0, msg) => assert_eq!(msg.questions[0].domain.to_string(),
+                    name.to_string()),
+                _ => panic!("expected a response")
+            }
+        }
+        assert!(events.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn late_response_ignored() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(server.local_addr().unwrap()).unwrap();
+        let (highway, events) = UDPHighway::from_sockets(vec![socket],
+            Duration::from_millis(0), Duration::from_millis(100));
+        let query = Message::new_query(Question{
+            domain: "a.com".parse().unwrap(),
+            record_type: RecordType::A,
+            record_class: RecordClass::IN
+        });
+        let mut buffer = [0u8; 512];
+        let mut receive_query = || {
+            let (size, addr) = server.recv_from(&mut buffer).unwrap();
+            let mut response: Message = dns_decode(buffer[..size].to_vec()).unwrap();
+            response.header.is_response = true;
+            (response, addr)
+        };
+
+        highway.send(0, query.clone());
+        let (mut late, addr) = receive_query();
+        match events.recv_timeout(Duration::from_secs(10)).unwrap() {
+            Event::Timeout(0) => (),
+            _ => panic!("expected a timeout")
+        }
+
+        // The same question again, with the first answer arriving late.
+        highway.send(0, query);
+        let (mut response, _) = receive_query();
+        late.header.response_code = ResponseCode::ServerFailure;
+        server.send_to(&dns_encode(&late).unwrap(), addr).unwrap();
+        response.header.authoritative = true;
+        server.send_to(&dns_encode(&response).unwrap(), addr).unwrap();
+        match events.recv_timeout(Duration::from_secs(10)).unwrap() {
+            Event::Response(0, msg) => {
+                assert_eq!(msg.header.identifier, response.header.identifier);
+                assert!(msg.header.authoritative);
+            },
+            _ => panic!("expected a response")
+        }
+    }
+}
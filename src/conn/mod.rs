@@ -1,13 +1,20 @@
 //! APIs for managing UDP and DNS connections.
 
+mod capture;
 mod chunker;
+mod chunker_udp;
 mod dial;
 mod highway;
 mod highway_tcp;
 mod highway_udp;
 
-pub use self::chunker::TcpChunker;
-pub use self::dial::{dial_tcp, dial_udp};
+pub use self::capture::{PcapWriter, capture_received, capture_sent, start_capture};
+pub use self::chunker::{ChunkSink, ChunkSource, Chunker, ConnState, Stream, StreamChunker,
+    TcpChunker};
+#[cfg(unix)]
+pub use self::chunker::UnixChunker;
+pub use self::chunker_udp::{DATAGRAM_BUFFER, UdpChunker, forward_datagram};
+pub use self::dial::{dial_tcp, dial_udp, dial_udp_from};
 pub use self::highway::{Event, Highway};
 pub use self::highway_tcp::TCPHighway;
 pub use self::highway_udp::UDPHighway;
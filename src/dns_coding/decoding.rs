@@ -1,24 +1,109 @@
+use std::cmp::min;
+use std::fmt::Write;
+#[cfg(not(feature = "std"))]
+use std::prelude::*;
+
+/// The number of bytes on each side of the offset to show in error messages.
+const ERROR_CONTEXT_BYTES: usize = 8;
+
 /// Decode a packet using a Decoder implementation.
 pub fn dns_decode<T: Decoder>(data: Vec<u8>) -> Result<T, String> {
     let mut dec = DecPacket::new(data);
     T::dns_decode(&mut dec)
 }
 
+/// Decode a packet using a Decoder implementation, with the given options.
+///
+/// # Example
+///
+/// ```
+/// use myodine::dns_coding::{DecodeOptions, dns_decode_with};
+/// use myodine::dns_proto::Message;
+///
+/// // A query for example.com with type 0.
+/// let mut data = vec![0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+/// data.extend_from_slice(b"\x07example\x03com\x00\x00\x00\x00\x01");
+/// assert!(dns_decode_with::<Message>(data.clone(), DecodeOptions::default()).is_ok());
+/// assert!(dns_decode_with::<Message>(data, DecodeOptions::strict()).is_err());
+/// ```
+pub fn dns_decode_with<T: Decoder>(data: Vec<u8>, options: DecodeOptions) -> Result<T, String> {
+    let mut dec = DecPacket::with_options(data, options);
+    T::dns_decode(&mut dec)
+}
+
+/// Settings that decoders read from the `DecPacket`.
+///
+/// By default, decoding is lenient: it checks only what it needs to make
+/// sense of a message, since the tunnel has to read whatever resolvers and
+/// middleboxes hand it. Both modes reject reads past the end of the buffer,
+/// label length fields with the reserved `01` and `10` type bits, names over
+/// 255 bytes, compression pointers that don't point backwards, record data
+/// that doesn't match its length field, header counts that can't fit in the
+/// message, and trailing data.
+///
+/// Strict decoding also rejects messages that break RFC constraints a
+/// general-purpose parser should enforce:
+///
+/// * The reserved Z bit of the header is set (RFC 1035). The AD and CD bits
+///   next to it are allowed, since RFC 4035 gave them a meaning.
+/// * A question or record has type 0 or class 0, which are reserved
+///   (RFC 6895).
+/// * A standard query (opcode 0) has more than one question (RFC 9619).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DecodeOptions {
+    pub strict: bool
+}
+
+impl DecodeOptions {
+    /// Options that enforce the RFC constraints listed above.
+    pub fn strict() -> DecodeOptions {
+        DecodeOptions{strict: true}
+    }
+}
+
 /// A buffer that represents a packet and the current offset within it.
 pub struct DecPacket {
     buffer: Vec<u8>,
-    offset: usize
+    offset: usize,
+    options: DecodeOptions
 }
 
 impl DecPacket {
     /// Create a new `DecPacket` with the given data, seeked to the start.
     pub fn new(data: Vec<u8>) -> DecPacket {
+        DecPacket::with_options(data, DecodeOptions::default())
+    }
+
+    /// Create a new `DecPacket` with the given data and decoding options,
+    /// seeked to the start.
+    pub fn with_options(data: Vec<u8>, options: DecodeOptions) -> DecPacket {
         DecPacket{
             buffer: data,
-            offset: 0
+            offset: 0,
+            options
         }
     }
 
+    /// Get the options that decoders should follow.
+    pub fn options(&self) -> DecodeOptions {
+        self.options
+    }
+
+    /// Create a new `DecPacket` with a copy of the given bytes.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use myodine::dns_coding::{DecPacket, Decoder};
+    ///
+    /// let mut packet = DecPacket::from_bytes(&[0x12, 0x34, 0x56]);
+    /// assert_eq!(u16::dns_decode(&mut packet).unwrap(), 0x1234);
+    /// assert_eq!(packet.remaining(), 1);
+    /// ```
+    pub fn from_bytes(data: &[u8]) -> DecPacket {
+        DecPacket::new(data.to_vec())
+    }
+
     /// Get the current seek offset.
     pub fn current_offset(&self) -> usize {
         self.offset
@@ -30,7 +115,7 @@ impl DecPacket {
     /// Fails if a parameter is out of bounds.
     pub fn seek(&self, new_offset: usize, new_size: usize) -> Result<DecPacket, String> {
         if new_offset >= new_size || new_size > self.buffer.len() {
-            return Err("seek out of bounds".to_owned());
+            return Err(self.error("seek out of bounds"));
         }
         let mut res = Vec::new();
         for x in &self.buffer[0..new_size] {
@@ -39,9 +124,39 @@ impl DecPacket {
         Ok(DecPacket{
             buffer: res,
             offset: new_offset,
+            options: self.options
         })
     }
 
+    /// Add the current offset and the nearby bytes to an error message.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use myodine::dns_coding::{DecPacket, Decoder};
+    ///
+    /// let mut packet = DecPacket::from_bytes(&[0x12, 0x34, 0x56]);
+    /// u16::dns_decode(&mut packet).unwrap();
+    /// assert_eq!(packet.error("bad value"), "bad value at offset 2 (bytes 0-2: 12 34 [56])");
+    /// ```
+    pub fn error(&self, message: &str) -> String {
+        let start = self.offset.saturating_sub(ERROR_CONTEXT_BYTES);
+        let end = min(self.buffer.len(), self.offset + ERROR_CONTEXT_BYTES);
+        let mut result = format!("{} at offset {}", message, self.offset);
+        if start < end {
+            write!(result, " (bytes {}-{}:", start, end - 1).unwrap();
+            for i in start..end {
+                if i == self.offset {
+                    write!(result, " [{:02x}]", self.buffer[i]).unwrap();
+                } else {
+                    write!(result, " {:02x}", self.buffer[i]).unwrap();
+                }
+            }
+            result.push(')');
+        }
+        result
+    }
+
     /// Get the bytes remaining in the buffer.
     pub fn remaining(&self) -> usize {
         self.buffer.len() - self.offset
@@ -76,7 +191,7 @@ impl DecPacket {
         let offset = self.offset;
         let result = f(self, len)?;
         if self.offset < len || self.offset - len != offset {
-            Err("incorrect length field".to_owned())
+            Err(self.error("incorrect length field"))
         } else {
             Ok(result)
         }
@@ -91,7 +206,7 @@ pub trait Decoder where Self: Sized {
 impl Decoder for u8 {
     fn dns_decode(packet: &mut DecPacket) -> Result<u8, String> {
         if packet.offset >= packet.buffer.len() {
-            Err("buffer underflow".to_owned())
+            Err(packet.error("buffer underflow"))
         } else {
             packet.offset += 1;
             Ok(packet.buffer[packet.offset - 1])
@@ -140,18 +255,23 @@ impl BitReader {
     }
 
     /// Read the next bit, starting from the MSB.
-    pub fn read_bit(&mut self) -> Option<bool> {
+    pub fn read_bit(&mut self) -> Result<bool, String> {
         if self.bits_remaining == 0 {
-            None
+            Err("no bits remaining".to_owned())
         } else {
             self.bits_remaining -= 1;
-            let result = (self.value & (1 << self.bits_remaining)) != 0;
-            Some(result)
+            Ok((self.value & (1 << self.bits_remaining)) != 0)
         }
     }
 
     /// Read a sequence of bits and pack it back into an integer.
-    pub fn read_bits(&mut self, num_bits: usize) -> Option<usize> {
+    ///
+    /// Fails without consuming any bits if there are not enough bits left.
+    pub fn read_bits(&mut self, num_bits: usize) -> Result<usize, String> {
+        if num_bits > self.bits_remaining {
+            return Err(format!("cannot read {} bits ({} remaining)", num_bits,
+                self.bits_remaining));
+        }
         let mut result = 0usize;
         for _ in 0..num_bits {
             result <<= 1;
@@ -159,6 +279,35 @@ impl BitReader {
                 result |= 1;
             }
         }
-        Some(result)
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bit_reader_bounds() {
+        let mut reader = BitReader::new(0b1011, 4);
+        assert_eq!(reader.read_bit(), Ok(true));
+        assert!(reader.read_bits(4).is_err());
+        assert_eq!(reader.read_bits(3), Ok(0b011));
+        assert!(reader.read_bit().is_err());
+        assert_eq!(reader.read_bits(0), Ok(0));
+    }
+
+    #[test]
+    fn error_context() {
+        let mut packet = DecPacket::from_bytes(&[1, 2, 3]);
+        assert_eq!(u32::dns_decode(&mut packet),
+            Err("buffer underflow at offset 3 (bytes 0-2: 01 02 03)".to_owned()));
+
+        let data: Vec<u8> = (0..32).collect();
+        let mut packet = DecPacket::new(data);
+        packet.read_bytes(20).unwrap();
+        assert_eq!(packet.error("oops"),
+            "oops at offset 20 (bytes 12-27: 0c 0d 0e 0f 10 11 12 13 [14] 15 16 17 18 19 1a 1b)");
+        assert_eq!(DecPacket::new(Vec::new()).error("empty"), "empty at offset 0");
     }
 }
@@ -1,5 +1,7 @@
 use std::iter::IntoIterator;
 use std::mem::size_of;
+#[cfg(not(feature = "std"))]
+use std::prelude::*;
 
 /// Encode an object as binary data using an Encoder implementation.
 pub fn dns_encode<T: Encoder>(x: &T) -> Result<Vec<u8>, String> {
@@ -40,6 +42,36 @@ impl EncPacket {
     pub fn data(&self) -> &Vec<u8> {
         &self.0
     }
+
+    /// View the current buffer as a slice.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use myodine::dns_coding::{EncPacket, Encoder};
+    ///
+    /// let mut packet = EncPacket::new();
+    /// 0x1234u16.dns_encode(&mut packet).unwrap();
+    /// assert_eq!(packet.as_slice(), &[0x12, 0x34]);
+    /// ```
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Consume the packet and get its encoded bytes.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use myodine::dns_coding::{EncPacket, Encoder};
+    ///
+    /// let mut packet = EncPacket::new();
+    /// 7u8.dns_encode(&mut packet).unwrap();
+    /// assert_eq!(packet.into_bytes(), vec![7]);
+    /// ```
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
 }
 
 /// An object which can be serialized into an `EncPacket`.
@@ -106,19 +138,34 @@ impl BitWriter {
     }
 
     /// Write a bit as the new LSB.
-    pub fn write_bit(&mut self, bit: bool) {
+    ///
+    /// Fails if the bit-fields would no longer fit in a `usize`.
+    pub fn write_bit(&mut self, bit: bool) -> Result<(), String> {
+        if self.bits_used == usize::BITS as usize {
+            return Err("bit-fields overflow".to_owned());
+        }
         self.bits_used += 1;
         self.value <<= 1;
         if bit {
             self.value |= 1;
         }
+        Ok(())
     }
 
     /// Write a sequence of bits which are packed into an integer.
-    pub fn write_bits(&mut self, value: usize, num_bits: usize) {
+    ///
+    /// Fails if `value` does not fit in `num_bits` bits, or if the bit-fields
+    /// would no longer fit in a `usize`.
+    pub fn write_bits(&mut self, value: usize, num_bits: usize) -> Result<(), String> {
+        if num_bits < usize::BITS as usize && value >> num_bits != 0 {
+            return Err(format!("value {} does not fit in {} bits", value, num_bits));
+        } else if self.bits_used + num_bits > usize::BITS as usize {
+            return Err("bit-fields overflow".to_owned());
+        }
         for i in 0..num_bits {
-            self.write_bit(value & (1 << (num_bits - (i + 1))) != 0);
+            self.write_bit(value & (1 << (num_bits - (i + 1))) != 0)?;
         }
+        Ok(())
     }
 
     /// Check if the bit-fields take up exactly the size of an integer type.
@@ -137,10 +184,21 @@ impl BitWriter {
 /// # Example
 ///
 /// ```
-/// if let Err(msg) = encode_all!(&mut packet, length, data, suffix) {
-///     // Handle error here.
+/// #[macro_use]
+/// extern crate myodine;
+///
+/// use myodine::dns_coding::{EncPacket, Encoder};
+///
+/// fn main() {
+///     let mut packet = EncPacket::new();
+///     let (length, data, suffix) = (2u8, vec![0xabu8, 0xcd], 0x1234u16);
+///     if let Err(msg) = encode_all!(&mut packet, length, data, suffix) {
+///         panic!("encoding failed: {}", msg);
+///     }
+///     assert_eq!(packet.as_slice(), &[2, 0xab, 0xcd, 0x12, 0x34]);
 /// }
 /// ```
+#[macro_export]
 macro_rules! encode_all {
     ( $dest:expr ) => { Ok(()) };
     ( $dest:expr, $first:expr $(, $rest:expr )* ) => {
@@ -0,0 +1,139 @@
+use std::marker::PhantomData;
+#[cfg(not(feature = "std"))]
+use std::prelude::*;
+
+use super::decoding::{Decoder, dns_decode};
+use super::encoding::{Encoder, dns_encode};
+
+/// Encode an object with the 2-byte length prefix used by DNS over TCP.
+pub fn dns_encode_framed<T: Encoder>(x: &T) -> Result<Vec<u8>, String> {
+    let data = dns_encode(x)?;
+    if data.len() > 0xffff {
+        return Err(format!("message too long for a frame: {} bytes", data.len()));
+    }
+    let mut result = vec![(data.len() >> 8) as u8, data.len() as u8];
+    result.extend(data);
+    Ok(result)
+}
+
+/// A buffer that splits a stream of length-prefixed frames (as used by DNS
+/// over TCP) into decoded objects.
+///
+/// Bytes may be pushed in arbitrary pieces; frames which are split across
+/// pushes are held until they are complete.
+pub struct MessageFramer<T: Decoder> {
+    buffer: Vec<u8>,
+    offset: usize,
+    phantom: PhantomData<T>
+}
+
+impl<T: Decoder> MessageFramer<T> {
+    /// Create an empty `MessageFramer`.
+    pub fn new() -> MessageFramer<T> {
+        MessageFramer{buffer: Vec::new(), offset: 0, phantom: PhantomData}
+    }
+
+    /// Add bytes from the stream.
+    pub fn push(&mut self, data: &[u8]) {
+        if self.offset > 0 && self.offset * 2 >= self.buffer.len() {
+            self.buffer.drain(..self.offset);
+            self.offset = 0;
+        }
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Get the next complete frame, if there is one.
+    ///
+    /// A frame which fails to decode is consumed and reported as an error, so
+    /// the stream can continue with the next frame.
+    pub fn next_message(&mut self) -> Option<Result<T, String>> {
+        let remaining = &self.buffer[self.offset..];
+        if remaining.len() < 2 {
+            return None;
+        }
+        let size = ((remaining[0] as usize) << 8) | (remaining[1] as usize);
+        if remaining.len() < size + 2 {
+            return None;
+        }
+        let data = remaining[2..(size + 2)].to_vec();
+        self.offset += size + 2;
+        Some(dns_decode(data))
+    }
+
+    /// Get the number of buffered bytes which are not yet part of a complete
+    /// frame.
+    pub fn pending_bytes(&self) -> usize {
+        self.buffer.len() - self.offset
+    }
+}
+
+impl<T: Decoder> Default for MessageFramer<T> {
+    fn default() -> MessageFramer<T> {
+        MessageFramer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dns_proto::{Message, Question, RecordClass, RecordType};
+
+    #[test]
+    fn byte_at_a_time() {
+        let messages = test_messages();
+        let stream = framed_stream(&messages);
+        let mut framer = MessageFramer::<Message>::new();
+        let mut decoded = Vec::new();
+        for b in stream {
+            framer.push(&[b]);
+            while let Some(msg) = framer.next_message() {
+                decoded.push(msg.unwrap());
+            }
+        }
+        assert_eq!(decoded, messages);
+        assert_eq!(framer.pending_bytes(), 0);
+    }
+
+    #[test]
+    fn coalesced_frames() {
+        let messages = test_messages();
+        let mut stream = framed_stream(&messages);
+        stream.extend(vec![0, 100, 1, 2, 3]);
+        let mut framer = MessageFramer::<Message>::new();
+        framer.push(&stream);
+        let mut decoded = Vec::new();
+        while let Some(msg) = framer.next_message() {
+            decoded.push(msg.unwrap());
+        }
+        assert_eq!(decoded, messages);
+        assert_eq!(framer.pending_bytes(), 5);
+    }
+
+    #[test]
+    fn invalid_frame() {
+        let messages = test_messages();
+        let mut stream = vec![0, 3, 1, 2, 3];
+        stream.extend(framed_stream(&messages[..1]));
+        let mut framer = MessageFramer::<Message>::new();
+        framer.push(&stream);
+        assert!(framer.next_message().unwrap().is_err());
+        assert_eq!(framer.next_message().unwrap().unwrap(), messages[0]);
+        assert!(framer.next_message().is_none());
+    }
+
+    fn test_messages() -> Vec<Message> {
+        ["a.com", "b.example.org", "c.d.e.net"].iter().map(|x| Message::new_query(Question{
+            domain: x.parse().unwrap(),
+            record_type: RecordType::TXT,
+            record_class: RecordClass::IN
+        })).collect()
+    }
+
+    fn framed_stream(messages: &[Message]) -> Vec<u8> {
+        let mut result = Vec::new();
+        for msg in messages {
+            result.extend(dns_encode_framed(msg).unwrap());
+        }
+        result
+    }
+}
@@ -0,0 +1,42 @@
+use std::fmt::Write;
+#[cfg(not(feature = "std"))]
+use std::prelude::*;
+
+/// Format bytes as a hex dump, with 16 bytes per line and each line prefixed
+/// by its offset.
+///
+/// This is meant for tests and debugging tools.
+///
+/// # Example
+///
+/// ```
+/// use myodine::dns_coding::hex_dump;
+///
+/// assert_eq!(hex_dump(&[0xde, 0xad, 0xbe, 0xef]), "0000: de ad be ef");
+/// ```
+pub fn hex_dump(data: &[u8]) -> String {
+    let mut result = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        if i > 0 {
+            result.push('\n');
+        }
+        write!(result, "{:04x}:", i * 16).unwrap();
+        for b in line {
+            write!(result, " {:02x}", b).unwrap();
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_line_dump() {
+        let data: Vec<u8> = (0..20).collect();
+        assert_eq!(hex_dump(&data), "0000: 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f\n\
+            0010: 10 11 12 13");
+        assert_eq!(hex_dump(&[]), "");
+    }
+}
@@ -3,6 +3,11 @@
 #[macro_use]
 mod encoding;
 mod decoding;
+mod framing;
+mod hex_dump;
 
-pub use self::decoding::{DecPacket, Decoder, BitReader, dns_decode};
+pub use self::decoding::{DecPacket, DecodeOptions, Decoder, BitReader, dns_decode,
+    dns_decode_with};
 pub use self::encoding::{EncPacket, Encoder, BitWriter, dns_encode};
+pub use self::framing::{MessageFramer, dns_encode_framed};
+pub use self::hex_dump::hex_dump;
@@ -1,27 +1,39 @@
+extern crate idna;
+
 use std::fmt::{Display, Error, Formatter};
 use std::str::FromStr;
+#[cfg(not(feature = "std"))]
+use std::prelude::*;
 
 use dns_coding::{Decoder, DecPacket, Encoder, EncPacket};
 
+/// The longest a domain name can be on the wire.
+pub const MAX_DOMAIN_LEN: usize = 255;
+
 /// A DNS domain name.
+///
+/// Labels are stored as raw bytes, since DNS allows arbitrary bytes in labels
+/// even though most names only use letters, digits, and hyphens.
 #[derive(Clone, PartialEq, Eq, Debug)]
-pub struct Domain(Vec<String>);
+pub struct Domain {
+    labels: Vec<Vec<u8>>,
+
+    /// The labels as text for `parts`, with invalid UTF-8 replaced.
+    text: Vec<String>
+}
 
 impl Domain {
     /// Create a domain name from the labels.
     ///
     /// This may fail if the requested domain name is invalid.
+    /// Labels may only contain the characters a-zA-Z0-9-.
     pub fn from_parts(labels: Vec<String>) -> Result<Domain, String> {
-        let mut total_len = 1usize;
         for label in &labels {
-            if label.len() == 0 {
-                return Err(format!("empty domain name label"));
-            } else if !label.is_ascii() {
+            if !label.is_ascii() {
                 return Err(format!("domain label is not ASCII: {}", label));
-            } else if label.len() > 63 {
-                return Err(format!("domain label is too long: {}", label));
+            } else if label.is_empty() {
+                continue;
             }
-            total_len += label.len() + 1usize;
             let chars: Vec<char> = label.chars().collect();
             if chars[chars.len() - 1] == '-'  || chars[0] == '-' {
                 return Err(format!("domain label may not end or start with -"));
@@ -33,27 +45,76 @@ impl Domain {
                 }
             }
         }
-        if total_len > 255 {
-            return Err(format!("domain name is too long: {:?}", &labels));
+        Domain::from_raw_parts(labels.into_iter().map(String::into_bytes).collect())
+    }
+
+    /// Parse a dotted domain name that a user typed in, such as a host
+    /// argument.
+    ///
+    /// Internationalized names are converted to their ASCII-compatible
+    /// ("xn--") form with IDNA (UTS #46), which is what resolvers see on the
+    /// wire. ASCII names are used as typed. Names from the wire or from other
+    /// code should be parsed with `FromStr` instead.
+    pub fn from_user_input(s: &str) -> Result<Domain, String> {
+        if s.is_ascii() {
+            return s.parse();
+        }
+        let ascii = idna::domain_to_ascii(s)
+            .map_err(|e| format!("invalid domain name {}: {}", s, e))?;
+        ascii.parse()
+    }
+
+    /// Create a domain name from labels containing arbitrary bytes.
+    ///
+    /// This only checks the length limits imposed by the DNS wire format.
+    pub fn from_raw_parts(labels: Vec<Vec<u8>>) -> Result<Domain, String> {
+        let mut total_len = 1usize;
+        for label in &labels {
+            if label.is_empty() {
+                return Err(format!("empty domain name label"));
+            } else if label.len() > 63 {
+                return Err(format!("domain label is too long: {}", escape_label(label)));
+            }
+            total_len += label.len() + 1usize;
+        }
+        if total_len > MAX_DOMAIN_LEN {
+            return Err(format!("domain name is too long: {}", Domain::new_unchecked(labels)));
         }
-        Ok(Domain(labels))
+        Ok(Domain::new_unchecked(labels))
     }
 
-    /// Get the name's labels.
+    /// Get the name's labels as strings.
+    ///
+    /// Bytes which are not valid UTF-8 are replaced, so this is lossy for
+    /// binary labels. Use `raw_parts` to get the exact label contents.
     pub fn parts(&self) -> &[String] {
-        &self.0
+        &self.text
+    }
+
+    /// Get the name's labels as raw bytes.
+    pub fn raw_parts(&self) -> &[Vec<u8>] {
+        &self.labels
+    }
+
+    /// Get the length of the name on the wire, without compression.
+    pub fn wire_len(&self) -> usize {
+        self.labels.iter().map(|x| x.len() + 1).sum::<usize>() + 1
+    }
+
+    fn new_unchecked(labels: Vec<Vec<u8>>) -> Domain {
+        let text = labels.iter().map(|x| String::from_utf8_lossy(x).into_owned()).collect();
+        Domain{labels, text}
     }
 
     fn split_first(&self) -> (Vec<u8>, Domain) {
-        let first = &self.0[0];
-        let rest = self.0[1..self.0.len()].to_vec();
-        (first.as_bytes().to_vec(), Domain(rest))
+        let first = &self.labels[0];
+        let rest = self.labels[1..self.labels.len()].to_vec();
+        (first.clone(), Domain::new_unchecked(rest))
     }
 
     fn encode_raw(&self) -> Vec<u8> {
         let mut raw_data = Vec::new();
-        for part in self.parts() {
-            let bytes = part.as_bytes();
+        for bytes in self.raw_parts() {
             assert!(bytes.len() < 64);
             raw_data.push(bytes.len() as u8);
             for b in bytes {
@@ -75,11 +136,11 @@ impl FromStr for Domain {
 
 impl Display for Domain {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        for (i, x) in (&self.0).into_iter().enumerate() {
+        for (i, x) in self.labels.iter().enumerate() {
             if i != 0 {
                 write!(f, ".")?;
             }
-            write!(f, "{}", x)?;
+            write!(f, "{}", escape_label(x))?;
         }
         Ok(())
     }
@@ -87,7 +148,7 @@ impl Display for Domain {
 
 impl Encoder for Domain {
     fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), String> {
-        if self.0.len() == 0 {
+        if self.labels.is_empty() {
             return 0u8.dns_encode(packet);
         }
 
@@ -115,7 +176,7 @@ impl Encoder for Domain {
 
 impl Decoder for Domain {
     fn dns_decode(packet: &mut DecPacket) -> Result<Domain, String> {
-        let mut parts = Vec::<String>::new();
+        let mut parts = Vec::<Vec<u8>>::new();
         loop {
             let size = u8::dns_decode(packet)?;
             if size & 0xc0 == 0xc0 {
@@ -123,19 +184,16 @@ impl Decoder for Domain {
                 let addr = (((size & 0x3f) as usize) << 8) | (addr_lower as usize);
                 let mut seeked = packet.seek(addr, packet.current_offset() - 2)?;
                 let pointer_domain = Domain::dns_decode(&mut seeked)?;
-                for part in pointer_domain.0 {
+                for part in pointer_domain.labels {
                     parts.push(part);
                 }
-                return Domain::from_parts(parts);
+                return Domain::from_raw_parts(parts);
             } else if size & 0xc0 != 0 {
-                return Err("invalid label length field".to_owned())
+                return Err(packet.error("invalid label length field"))
             } else if size == 0 {
-                return Domain::from_parts(parts);
+                return Domain::from_raw_parts(parts);
             } else {
-                match String::from_utf8(packet.read_bytes(size as usize)?) {
-                    Ok(s) => parts.push(s),
-                    Err(_) => return Err("invalid UTF-8 label".to_owned())
-                }
+                parts.push(packet.read_bytes(size as usize)?);
             }
         }
     }
@@ -145,16 +203,43 @@ fn in_char_range(start: char, end: char, ch: char) -> bool {
     ch >= start && ch <= end
 }
 
+/// Format a label in master file syntax, escaping special and
+/// non-printable bytes as \DDD.
+fn escape_label(label: &[u8]) -> String {
+    let mut res = String::new();
+    for b in label {
+        if *b == b'.' || *b == b'\\' || *b <= b' ' || *b > b'~' {
+            res.push_str(&format!("\\{:03}", b));
+        } else {
+            res.push(*b as char);
+        }
+    }
+    res
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::iter::FromIterator;
 
+    #[test]
+    fn parse_idn() {
+        let domain = Domain::from_user_input("tunnel.münchen.de").unwrap();
+        assert_eq!(domain, "tunnel.xn--mnchen-3ya.de".parse().unwrap());
+        assert_eq!(format!("{}", domain), "tunnel.xn--mnchen-3ya.de");
+        assert_eq!(Domain::from_user_input("proxy.com").unwrap(), "proxy.com".parse().unwrap());
+        assert_eq!(Domain::from_user_input("Bücher.例え.テスト").unwrap(),
+            "xn--bcher-kva.xn--r8jz45g.xn--zckzah".parse().unwrap());
+        assert!(Domain::from_user_input("a\u{2028}ü.com").is_err());
+        // Other names are never converted.
+        assert!("tunnel.münchen.de".parse::<Domain>().is_err());
+    }
+
     #[test]
     fn successful_parse() {
         let domain: Domain = "zoo-1bar.Aol9.123.AOE".parse().unwrap();
-        assert_eq!(domain.0,
-            Vec::from_iter(["zoo-1bar", "Aol9", "123", "AOE"].iter().map(|x| x.to_owned())));
+        assert_eq!(domain.parts(),
+            Vec::from_iter(["zoo-1bar", "Aol9", "123", "AOE"].iter().map(|x| x.to_string())));
     }
 
     #[test]
@@ -219,6 +304,21 @@ mod tests {
         assert!(u8::dns_decode(&mut dec_packet).is_err());
     }
 
+    #[test]
+    fn binary_labels() {
+        let domain = Domain::from_raw_parts(vec![vec![0x00u8, b'a', 0xffu8], vec![0xffu8],
+            b"com".to_vec()]).unwrap();
+        let mut enc_packet = EncPacket::new();
+        domain.dns_encode(&mut enc_packet).unwrap();
+        let mut dec_packet = DecPacket::new(enc_packet.data().clone());
+        let decoded = Domain::dns_decode(&mut dec_packet).unwrap();
+        assert_eq!(decoded, domain);
+        assert_eq!(decoded.raw_parts()[0], vec![0x00u8, b'a', 0xffu8]);
+        assert_eq!(format!("{}", decoded), "\\000a\\255.\\255.com");
+        assert_eq!(decoded.parts()[0], "\0a\u{fffd}");
+        assert_eq!(decoded.parts()[2], "com");
+    }
+
     #[test]
     fn encode_pointers_start() {
         let mut enc_packet = EncPacket::new();
@@ -1,3 +1,6 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::*;
+
 use dns_coding::{Decoder, DecPacket, BitReader, Encoder, EncPacket, BitWriter};
 
 #[derive(PartialEq, Clone, Copy, Debug)]
@@ -50,15 +53,17 @@ impl Encoder for Header {
         self.identifier.dns_encode(packet)?;
 
         let mut flags = BitWriter::new();
-        flags.write_bit(self.is_response);
-        flags.write_bits(self.opcode.encode(), 4);
-        flags.write_bit(self.authoritative);
-        flags.write_bit(self.truncated);
-        flags.write_bit(self.recursion_desired);
-        flags.write_bit(self.recursion_available);
-        flags.write_bits(0, 3);
-        flags.write_bits(self.response_code.encode(), 4);
-        assert!(flags.fits::<u16>());
+        flags.write_bit(self.is_response)?;
+        flags.write_bits(u8::from(self.opcode) as usize, 4)?;
+        flags.write_bit(self.authoritative)?;
+        flags.write_bit(self.truncated)?;
+        flags.write_bit(self.recursion_desired)?;
+        flags.write_bit(self.recursion_available)?;
+        flags.write_bits(0, 3)?;
+        flags.write_bits(u8::from(self.response_code) as usize, 4)?;
+        if !flags.fits::<u16>() {
+            return Err("header flags do not fit in 16 bits".to_owned());
+        }
         (flags.value() as u16).dns_encode(packet)?;
 
         encode_all!(packet, self.question_count, self.answer_count, self.authority_count,
@@ -71,14 +76,18 @@ impl Decoder for Header {
         let identifier = Decoder::dns_decode(packet)?;
 
         let mut flags = BitReader::new(u16::dns_decode(packet)? as usize, 16);
-        let is_response = flags.read_bit().unwrap();
-        let opcode = Opcode::decode(flags.read_bits(4).unwrap());
-        let authoritative = flags.read_bit().unwrap();
-        let truncated = flags.read_bit().unwrap();
-        let recursion_desired = flags.read_bit().unwrap();
-        let recursion_available = flags.read_bit().unwrap();
-        flags.read_bits(3).unwrap();
-        let response_code = ResponseCode::decode(flags.read_bits(4).unwrap());
+        let is_response = flags.read_bit()?;
+        let opcode = Opcode::from(flags.read_bits(4)? as u8);
+        let authoritative = flags.read_bit()?;
+        let truncated = flags.read_bit()?;
+        let recursion_desired = flags.read_bit()?;
+        let recursion_available = flags.read_bit()?;
+        // The Z bit, then the AD and CD bits from RFC 4035.
+        if flags.read_bit()? && packet.options().strict {
+            return Err(packet.error("reserved header bit is set"));
+        }
+        flags.read_bits(2)?;
+        let response_code = ResponseCode::from(flags.read_bits(4)? as u8);
 
         let question_count = Decoder::dns_decode(packet)?;
         let answer_count = Decoder::dns_decode(packet)?;
@@ -102,33 +111,58 @@ impl Decoder for Header {
     }
 }
 
-impl Opcode {
-    fn encode(&self) -> usize {
-        match *self {
+/// Opcodes convert to and from their numeric codes, which are 4 bits on the
+/// wire. Codes without a variant become `Unknown`, and an `Unknown` code
+/// above 15 fails to encode.
+impl From<u8> for Opcode {
+    fn from(value: u8) -> Opcode {
+        match value {
+            0 => Opcode::Query,
+            1 => Opcode::IQuery,
+            2 => Opcode::Status,
+            4 => Opcode::Notify,
+            5 => Opcode::Update,
+            _ => Opcode::Unknown(value)
+        }
+    }
+}
+
+impl From<Opcode> for u8 {
+    fn from(opcode: Opcode) -> u8 {
+        match opcode {
             Opcode::Query => 0,
             Opcode::IQuery => 1,
             Opcode::Status => 2,
             Opcode::Notify => 4,
             Opcode::Update => 5,
-            Opcode::Unknown(x) => x as usize
+            Opcode::Unknown(x) => x
         }
     }
+}
 
-    fn decode(value: usize) -> Opcode {
+/// Response codes convert to and from their numeric codes like `Opcode`s.
+impl From<u8> for ResponseCode {
+    fn from(value: u8) -> ResponseCode {
         match value {
-            0 => Opcode::Query,
-            1 => Opcode::IQuery,
-            2 => Opcode::Status,
-            4 => Opcode::Notify,
-            5 => Opcode::Update,
-            _ => Opcode::Unknown(value as u8)
+            0 => ResponseCode::NoError,
+            1 => ResponseCode::FormatError,
+            2 => ResponseCode::ServerFailure,
+            3 => ResponseCode::NXDomain,
+            4 => ResponseCode::NotImplemented,
+            5 => ResponseCode::Refused,
+            6 => ResponseCode::YXDomain,
+            7 => ResponseCode::YXRRSet,
+            8 => ResponseCode::NXRRSet,
+            9 => ResponseCode::NotAuth,
+            10 => ResponseCode::NotZone,
+            _ => ResponseCode::Unknown(value)
         }
     }
 }
 
-impl ResponseCode {
-    fn encode(&self) -> usize {
-        match *self {
+impl From<ResponseCode> for u8 {
+    fn from(code: ResponseCode) -> u8 {
+        match code {
             ResponseCode::NoError => 0,
             ResponseCode::FormatError => 1,
             ResponseCode::ServerFailure => 2,
@@ -140,24 +174,47 @@ impl ResponseCode {
             ResponseCode::NXRRSet => 8,
             ResponseCode::NotAuth => 9,
             ResponseCode::NotZone => 10,
-            ResponseCode::Unknown(x) => x as usize
+            ResponseCode::Unknown(x) => x
         }
     }
+}
 
-    fn decode(value: usize) -> ResponseCode {
-        match value {
-            0 => ResponseCode::NoError,
-            1 => ResponseCode::FormatError,
-            2 => ResponseCode::ServerFailure,
-            3 => ResponseCode::NXDomain,
-            4 => ResponseCode::NotImplemented,
-            5 => ResponseCode::Refused,
-            6 => ResponseCode::YXDomain,
-            7 => ResponseCode::YXRRSet,
-            8 => ResponseCode::NXRRSet,
-            9 => ResponseCode::NotAuth,
-            10 => ResponseCode::NotZone,
-            _ => ResponseCode::Unknown(value as u8)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dns_coding::{dns_decode, dns_encode};
+
+    #[test]
+    fn code_round_trips() {
+        for value in 0..16u8 {
+            assert_eq!(u8::from(Opcode::from(value)), value);
+            assert_eq!(u8::from(ResponseCode::from(value)), value);
         }
+        assert_eq!(Opcode::from(4), Opcode::Notify);
+        assert_eq!(Opcode::from(3), Opcode::Unknown(3));
+        assert_eq!(u8::from(ResponseCode::Refused), 5);
+        assert_eq!(ResponseCode::from(11), ResponseCode::Unknown(11));
+    }
+
+    #[test]
+    fn header_codes() {
+        let mut header = Header{
+            identifier: 1,
+            is_response: true,
+            opcode: Opcode::from(15),
+            authoritative: false,
+            truncated: false,
+            recursion_desired: false,
+            recursion_available: false,
+            response_code: ResponseCode::from(15),
+            question_count: 0,
+            answer_count: 0,
+            authority_count: 0,
+            additional_count: 0
+        };
+        let decoded: Header = dns_decode(dns_encode(&header).unwrap()).unwrap();
+        assert_eq!(decoded, header);
+        header.opcode = Opcode::from(16);
+        assert!(dns_encode(&header).is_err());
     }
 }
@@ -1,3 +1,6 @@
+#[cfg(not(feature = "std"))]
+use std::prelude::*;
+
 use dns_coding::{Encoder, EncPacket, Decoder, DecPacket};
 use super::domain::Domain;
 use super::header::{Header, Opcode, ResponseCode};
@@ -59,15 +62,35 @@ impl Encoder for Message {
     }
 }
 
+/// The smallest possible question: a root name, a type, and a class.
+const MIN_QUESTION_SIZE: usize = 1 + 2 + 2;
+
+/// The smallest possible record: a root name, a type, a class, a TTL, and an
+/// empty body.
+const MIN_RECORD_SIZE: usize = 1 + 2 + 2 + 4 + 2;
+
 impl Decoder for Message {
     fn dns_decode(packet: &mut DecPacket) -> Result<Message, String> {
         let header = Header::dns_decode(packet)?;
+        // The counts come from the sender, so check them against the data
+        // before decoding anything.
+        let num_records = header.answer_count as usize + header.authority_count as usize +
+            header.additional_count as usize;
+        let min_size = header.question_count as usize * MIN_QUESTION_SIZE +
+            num_records * MIN_RECORD_SIZE;
+        if min_size > packet.remaining() {
+            return Err(packet.error("header counts exceed packet size"));
+        } else if packet.options().strict && header.opcode == Opcode::Query &&
+            header.question_count > 1
+        {
+            return Err(packet.error("more than one question in a query"));
+        }
         let questions = packet.decode_all(header.question_count as usize)?;
         let answers = packet.decode_all(header.answer_count as usize)?;
         let authorities = packet.decode_all(header.authority_count as usize)?;
         let additional = packet.decode_all(header.additional_count as usize)?;
         if packet.remaining() > 0 {
-            Err("trailing data in packet".to_owned())
+            Err(packet.error("trailing data in packet"))
         } else {
             Ok(Message{
                 header: header,
@@ -102,7 +125,7 @@ impl Decoder for Question {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use dns_coding::{dns_decode, dns_encode};
+    use dns_coding::{DecodeOptions, dns_decode, dns_decode_with, dns_encode};
     use dns_proto::header::Opcode;
     use dns_proto::record::RecordBody;
 
@@ -128,6 +151,35 @@ mod tests {
         assert_eq!(request.to_vec(), dns_encode(&message).unwrap());
     }
 
+    #[test]
+    fn inflated_counts() {
+        let request = [0x4Du8, 0xB1u8, 0x01u8, 0x00u8, 0x00u8, 0x01u8, 0x00u8, 0x00u8,
+                       0x00u8, 0x00u8, 0x00u8, 0x00u8, 0x03u8, 0x66u8, 0x6Fu8, 0x6Fu8,
+                       0x03u8, 0x63u8, 0x6Fu8, 0x6Du8, 0x00u8, 0x00u8, 0x1Cu8, 0x00u8,
+                       0x01u8];
+        let with_counts = |counts: [u16; 4]| {
+            let mut data = request.to_vec();
+            for (i, count) in counts.iter().enumerate() {
+                data[4 + i * 2] = (count >> 8) as u8;
+                data[5 + i * 2] = *count as u8;
+            }
+            dns_decode::<Message>(data)
+        };
+        assert!(with_counts([1, 0, 0, 0]).is_ok());
+
+        // More records than the data could hold.
+        for counts in &[[0xffff, 0, 0, 0], [1, 0xffff, 0xffff, 0xffff], [1, 0, 0, 2]] {
+            let err = with_counts(*counts).unwrap_err();
+            assert!(err.contains("header counts exceed packet size"), "{}", err);
+        }
+
+        // Claims a record that isn't there, but is small enough to fit.
+        assert!(with_counts([2, 0, 0, 0]).is_err());
+
+        // Fewer records than are present.
+        assert!(with_counts([0, 0, 0, 0]).is_err());
+    }
+
     #[test]
     fn soa_response() {
         let response = [0x4Du8, 0xB1u8, 0x81u8, 0x80u8, 0x00u8, 0x01u8, 0x00u8, 0x00u8,
@@ -170,4 +222,45 @@ mod tests {
         }
         assert_eq!(dns_decode::<Message>(dns_encode(&message).unwrap()).unwrap(), message);
     }
+
+    #[test]
+    fn strict_decoding() {
+        let query = |flags: u16, questions: &[(u16, u16)]| {
+            let mut data = vec![0x12, 0x34];
+            data.extend_from_slice(&flags.to_be_bytes());
+            data.extend_from_slice(&[0, questions.len() as u8, 0, 0, 0, 0, 0, 0]);
+            for &(record_type, record_class) in questions {
+                data.extend_from_slice(&[3, b'f', b'o', b'o', 0]);
+                data.extend_from_slice(&record_type.to_be_bytes());
+                data.extend_from_slice(&record_class.to_be_bytes());
+            }
+            data
+        };
+        let decodes = |data: &Vec<u8>, strict: bool| {
+            let options = DecodeOptions{strict};
+            dns_decode_with::<Message>(data.clone(), options).is_ok()
+        };
+        let good = [
+            query(0x0100, &[(1, 1)]),
+            // AD and CD.
+            query(0x0030, &[(1, 1)]),
+            // Several questions are fine outside of standard queries.
+            query(0x2800, &[(1, 1), (28, 1)])
+        ];
+        for data in &good {
+            assert!(decodes(data, false) && decodes(data, true));
+        }
+        let bad = [
+            query(0x0140, &[(1, 1)]),
+            query(0x0100, &[(0, 1)]),
+            query(0x0100, &[(1, 0)]),
+            query(0x0100, &[(1, 1), (28, 1)])
+        ];
+        for data in &bad {
+            assert!(decodes(data, false) && !decodes(data, true));
+        }
+        assert_eq!(dns_decode_with::<Message>(bad[0].clone(), DecodeOptions::strict()),
+            Err("reserved header bit is set at offset 4 (bytes 0-11: 12 34 01 40 [00] 01 \
+                00 00 00 00 00 00)".to_owned()));
+    }
 }
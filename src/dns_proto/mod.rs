@@ -5,7 +5,7 @@ mod header;
 mod record;
 mod message;
 
-pub use self::domain::Domain;
+pub use self::domain::{Domain, MAX_DOMAIN_LEN};
 pub use self::header::{Header, Opcode, ResponseCode};
 pub use self::message::{Message, Question};
 pub use self::record::{Record, RecordBody, RecordClass, RecordHeader, RecordType, SOADetails};
@@ -1,4 +1,7 @@
 use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+#[cfg(not(feature = "std"))]
+use std::prelude::*;
 
 use dns_coding::{Decoder, DecPacket, Encoder, EncPacket};
 use super::domain::Domain;
@@ -13,6 +16,8 @@ pub enum RecordType {
     MX,
     TXT,
     AAAA,
+    IXFR,
+    AXFR,
     Unknown(u16)
 }
 
@@ -134,6 +139,8 @@ impl Encoder for RecordType {
             RecordType::MX => 15,
             RecordType::TXT => 16,
             RecordType::AAAA => 28,
+            RecordType::IXFR => 251,
+            RecordType::AXFR => 252,
             RecordType::Unknown(x) => x
         } as u16).dns_encode(packet)
     }
@@ -151,6 +158,7 @@ impl Encoder for RecordClass {
 impl Decoder for RecordType {
     fn dns_decode(packet: &mut DecPacket) -> Result<RecordType, String> {
         Ok(match u16::dns_decode(packet)? {
+            0 if packet.options().strict => return Err(packet.error("reserved record type 0")),
             1 => RecordType::A,
             2 => RecordType::NS,
             5 => RecordType::CNAME,
@@ -159,16 +167,63 @@ impl Decoder for RecordType {
             15 => RecordType::MX,
             16 => RecordType::TXT,
             28 => RecordType::AAAA,
+            251 => RecordType::IXFR,
+            252 => RecordType::AXFR,
             x => RecordType::Unknown(x)
         })
     }
 }
 
+impl RecordType {
+    /// Check if this is a zone transfer query type (AXFR or IXFR).
+    pub fn is_zone_transfer(&self) -> bool {
+        *self == RecordType::AXFR || *self == RecordType::IXFR
+    }
+}
+
 impl Decoder for RecordClass {
     fn dns_decode(packet: &mut DecPacket) -> Result<RecordClass, String> {
-        Ok(match u16::dns_decode(packet)? {
+        match u16::dns_decode(packet)? {
+            0 if packet.options().strict => Err(packet.error("reserved record class 0")),
+            code => Ok(RecordClass::from_code(code))
+        }
+    }
+}
+
+impl RecordClass {
+    fn from_code(code: u16) -> RecordClass {
+        match code {
             1 => RecordClass::IN,
             x => RecordClass::Unknown(x)
-        })
+        }
+    }
+}
+
+impl FromStr for RecordClass {
+    type Err = String;
+
+    /// Parse a class mnemonic (IN, CH, or HS) or a numeric class code.
+    fn from_str(s: &str) -> Result<RecordClass, String> {
+        match s.to_uppercase().as_str() {
+            "IN" => Ok(RecordClass::IN),
+            "CH" => Ok(RecordClass::Unknown(3)),
+            "HS" => Ok(RecordClass::Unknown(4)),
+            x => x.parse().map(RecordClass::from_code)
+                .map_err(|_| format!("unknown record class: {}", s))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_record_class() {
+        assert_eq!("in".parse(), Ok(RecordClass::IN));
+        assert_eq!("1".parse(), Ok(RecordClass::IN));
+        assert_eq!("CH".parse(), Ok(RecordClass::Unknown(3)));
+        assert_eq!("254".parse(), Ok(RecordClass::Unknown(254)));
+        assert!("XY".parse::<RecordClass>().is_err());
     }
 }
@@ -1,8 +1,38 @@
 //! A system for proxying a virtual circuit over DNS.
+//!
+//! Without the default `std` feature, only `dns_coding` and `dns_proto` are
+//! built, and they depend on nothing but `core` and `alloc`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(not(feature = "std"))]
+#[macro_use]
+extern crate alloc;
+
+/// Stand-in for the parts of `std` that the core modules use, so that their
+/// `use std::...` paths work without `std`.
+#[cfg(not(feature = "std"))]
+mod std {
+    pub use core::*;
+
+    pub mod prelude {
+        pub use alloc::borrow::ToOwned;
+        pub use alloc::string::String;
+        pub use alloc::vec::Vec;
+    }
+}
 
 #[macro_use]
 pub mod dns_coding;
 
 pub mod dns_proto;
+#[cfg(feature = "std")]
 pub mod myo_proto;
+#[cfg(feature = "std")]
 pub mod conn;
+#[cfg(feature = "std")]
+pub mod client;
+#[cfg(feature = "std")]
+pub mod server;
+#[cfg(feature = "wasm")]
+pub mod wasm;
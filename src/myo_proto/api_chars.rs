@@ -0,0 +1,57 @@
+use std::str::FromStr;
+
+/// The leading characters that identify establishment and transfer queries.
+///
+/// These may be changed per deployment, but the client and server must agree
+/// on them. Feature discovery always uses `f`, since it happens before any
+/// other negotiation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ApiChars {
+    pub establish: char,
+    pub transfer: char,
+    pub poll: char
+}
+
+impl Default for ApiChars {
+    fn default() -> ApiChars {
+        ApiChars{establish: 'e', transfer: 't', poll: 'p'}
+    }
+}
+
+impl FromStr for ApiChars {
+    type Err = String;
+
+    /// Parse a string of three characters: establish, transfer, and poll.
+    fn from_str(s: &str) -> Result<ApiChars, String> {
+        let chars: Vec<char> = s.chars().map(|x| x.to_ascii_lowercase()).collect();
+        if chars.len() != 3 {
+            return Err(format!("expected three API characters but got: {}", s));
+        }
+        for (i, ch) in chars.iter().enumerate() {
+            if !ch.is_ascii_lowercase() {
+                return Err(format!("API character must be a letter: {}", ch));
+            } else if *ch == 'f' {
+                return Err("API character 'f' is reserved for discovery".to_owned());
+            } else if chars[..i].contains(ch) {
+                return Err(format!("duplicate API character: {}", ch));
+            }
+        }
+        Ok(ApiChars{establish: chars[0], transfer: chars[1], poll: chars[2]})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_api_chars() {
+        assert_eq!("etp".parse::<ApiChars>().unwrap(), ApiChars::default());
+        assert_eq!("XyZ".parse::<ApiChars>().unwrap(),
+            ApiChars{establish: 'x', transfer: 'y', poll: 'z'});
+        assert!("et".parse::<ApiChars>().is_err());
+        assert!("etf".parse::<ApiChars>().is_err());
+        assert!("ete".parse::<ApiChars>().is_err());
+        assert!("e1p".parse::<ApiChars>().is_err());
+    }
+}
@@ -86,14 +86,15 @@ pub struct DownloadGenQuery {
 impl DownloadGenQuery {
     /// Decode a `DownloadGenQuery` from a requested domain.
     pub fn from_domain(domain: &Domain) -> Result<DownloadGenQuery, String> {
-        if domain.parts().len() < 5 {
+        let parts = domain.parts();
+        if parts.len() < 5 {
             return Err("not enough domain parts".to_owned());
         }
-        let encoding = domain_part_lowercase(&domain.parts()[0]).chars().skip(1).collect();
-        let len = domain.parts()[1].parse();
-        let bias = domain.parts()[2].parse();
-        let coefficient = domain.parts()[3].parse();
-        let modulus = domain.parts()[4].parse();
+        let encoding = domain_part_lowercase(&parts[0]).chars().skip(1).collect();
+        let len = parts[1].parse();
+        let bias = parts[2].parse();
+        let coefficient = parts[3].parse();
+        let modulus = parts[4].parse();
         if len.is_err() || bias.is_err() || coefficient.is_err() || modulus.is_err() ||
             *modulus.as_ref().unwrap() < 2 {
             Err("invalid number in domain".to_owned())
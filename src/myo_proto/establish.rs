@@ -1,15 +1,30 @@
+extern crate rand;
 extern crate sha1;
+use self::rand::{Rng, thread_rng};
 use self::sha1::Sha1;
 
+use std::str::FromStr;
+
 use dns_coding::{DecPacket, Decoder, EncPacket, Encoder, dns_encode};
 use dns_proto::{Domain, Message, Record, RecordHeader};
 
+use super::api_chars::ApiChars;
+use super::name_code::{get_name_code, supported_name_encodings};
 use super::record_code::{get_record_code};
-use super::util::{is_api_query, domain_ends_with, domain_part_lowercase};
+use super::util::{crc32, is_api_query, domain_ends_with, domain_part_lowercase};
 
 /// Check if a DNS message is an establishment API call.
-pub fn is_establish_query(query: &Message) -> bool {
-    is_api_query(query, 'e')
+///
+/// This includes the follow-ups to split establishment queries, which
+/// `is_destination_query` tells apart.
+pub fn is_establish_query(query: &Message, api: &ApiChars) -> bool {
+    is_api_query(query, api.establish)
+}
+
+/// Check if a DNS message is the follow-up to a split establishment query.
+pub fn is_destination_query(query: &Message, api: &ApiChars) -> bool {
+    is_establish_query(query, api) &&
+        query.questions[0].domain.raw_parts()[0].get(1) == Some(&(DESTINATION_SEPARATOR as u8))
 }
 
 /// Produce a response message for an establishment request.
@@ -18,15 +33,38 @@ pub fn is_establish_query(query: &Message) -> bool {
 ///
 /// * `query` - The query to respond to.
 /// * `host` - The root domain name of the server.
+/// * `api` - The API characters in use.
 /// * `resp` - The response to encode.
 pub fn establish_response(
     query: &Message,
     host: &Domain,
+    api: &ApiChars,
+    resp: EstablishResponse
+) -> Result<Message, String> {
+    let equery = EstablishQuery::from_query(query, host, api)?;
+    encode_response(query, &equery.response_encoding, resp)
+}
+
+/// Produce a response message for the follow-up to a split establishment
+/// query.
+///
+/// The response is encoded as the split query asked for, since the follow-up
+/// doesn't say.
+pub fn destination_response(
+    query: &Message,
+    split_query: &EstablishQuery,
+    resp: EstablishResponse
+) -> Result<Message, String> {
+    encode_response(query, &split_query.response_encoding, resp)
+}
+
+fn encode_response(
+    query: &Message,
+    response_encoding: &str,
     resp: EstablishResponse
 ) -> Result<Message, String> {
-    let equery = EstablishQuery::from_query(query, host)?;
     let question = &query.questions[0];
-    let code = get_record_code(question.record_type, &equery.response_encoding)
+    let code = get_record_code(question.record_type, response_encoding)
         .ok_or("no response encoding".to_owned())?;
     let body = code.encode_body(&dns_encode(&resp)?)?;
     let mut result = query.clone();
@@ -44,6 +82,34 @@ pub fn establish_response(
     Ok(result)
 }
 
+/// Check that a name encoding requested by a client is supported.
+///
+/// The error lists the supported encodings, so that the client can tell
+/// what to ask for instead.
+pub fn check_name_encoding(name_encoding: &str) -> Result<(), String> {
+    let supported = supported_name_encodings();
+    if supported.contains(&name_encoding) {
+        Ok(())
+    } else {
+        Err(format!("unsupported name encoding: {} (supported: {})", name_encoding,
+            supported.join(", ")))
+    }
+}
+
+/// Generate the secret that a client must present to resume a session.
+///
+/// The token is only sent in responses and in resume queries, so it ties a
+/// resume to a client that saw the establishment.
+pub fn new_resume_token() -> u32 {
+    thread_rng().gen()
+}
+
+/// Generate a value that tells one establishment query apart from another
+/// with the same fields.
+pub fn new_establish_nonce() -> u32 {
+    thread_rng().gen()
+}
+
 /// Produce proof that we know the given password, using the current epoch
 /// time in seconds.
 pub fn password_proof(password: &str, cur_time: u64) -> u64 {
@@ -55,17 +121,210 @@ pub fn password_proof(password: &str, cur_time: u64) -> u64 {
         ((hash[6] as u64) << 8) | (hash[7] as u64)
 }
 
+/// Check a password proof against the correct password.
+///
+/// # Arguments
+///
+/// * `proof` - The proof provided by the client.
+/// * `password` - The correct password.
+/// * `cur_time` - The current epoch time, in seconds.
+/// * `window` - The number of seconds by which the client's clock is allowed
+///   to be off from `cur_time`. The higher this value, the slower the check.
+///
+/// With the `parallel-proof` feature, large windows are checked on multiple
+/// threads.
+pub fn verify_proof(proof: u64, password: &str, cur_time: u64, window: u64) -> bool {
+    let (start, end) = (cur_time.saturating_sub(window), cur_time.saturating_add(window));
+    #[cfg(feature = "parallel-proof")]
+    {
+        use std::thread::available_parallelism;
+        let num_threads = available_parallelism().map(|x| x.get() as u64).unwrap_or(1);
+        if num_threads > 1 && end - start >= MIN_PARALLEL_PROOF_WINDOW {
+            return verify_proof_parallel(proof, password, start, end, num_threads);
+        }
+    }
+    verify_proof_serial(proof, password, start, end)
+}
+
+/// The smallest number of timestamps worth splitting across threads, since
+/// spawning threads costs about as much as a few hundred hashes.
+#[cfg(feature = "parallel-proof")]
+const MIN_PARALLEL_PROOF_WINDOW: u64 = 256;
+
+fn verify_proof_serial(proof: u64, password: &str, start: u64, end: u64) -> bool {
+    (start..end).any(|i| proof == password_proof(password, i))
+}
+
+#[cfg(feature = "parallel-proof")]
+fn verify_proof_parallel(
+    proof: u64,
+    password: &str,
+    start: u64,
+    end: u64,
+    num_threads: u64
+) -> bool {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread::scope;
+
+    let found = AtomicBool::new(false);
+    scope(|s| {
+        for t in 0..num_threads {
+            let found = &found;
+            s.spawn(move || {
+                for i in ((start + t)..end).step_by(num_threads as usize) {
+                    if found.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    if proof == password_proof(password, i) {
+                        found.store(true, Ordering::Relaxed);
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    found.load(Ordering::Relaxed)
+}
+
+/// Produce the failure message for an invalid proof.
+///
+/// If `server_time` is specified, it is included in the message along with
+/// `proof_window`, so that the client can tell if clock skew is to blame.
+pub fn proof_failure_message(server_time: Option<u64>, proof_window: u64) -> String {
+    match server_time {
+        Some(time) => format!("invalid proof; server time: {}; proof window: {}", time,
+            proof_window),
+        None => "invalid proof".to_owned()
+    }
+}
+
+/// Extract the server's epoch time from a failure message, if it was included.
+pub fn failure_server_time(message: &str) -> Option<u64> {
+    failure_field(message, "server time")
+}
+
+/// Extract the server's proof window (in seconds) from a failure message, if
+/// it was included.
+///
+/// Older servers include their time but not their window.
+pub fn failure_proof_window(message: &str) -> Option<u64> {
+    failure_field(message, "proof window")
+}
+
+fn failure_field(message: &str, name: &str) -> Option<u64> {
+    let fields = message.strip_prefix("invalid proof; ")?;
+    fields.split("; ").filter_map(|x| x.strip_prefix(name)?.strip_prefix(": "))
+        .next()?.parse().ok()
+}
+
+// Positions of the fields in an establish domain.
+//
+// The wire format is positional, so `to_domain` and `from_domain` must both
+// use these indices. Never build the labels by iterating over a map.
+const RESPONSE_ENCODING_LABEL: usize = 0;
+const MTU_LABEL: usize = 1;
+const NAME_ENCODING_LABEL: usize = 2;
+const QUERY_WINDOW_LABEL: usize = 3;
+const RESPONSE_WINDOW_LABEL: usize = 4;
+const PROOF_LABEL: usize = 5;
+const PORT_LABEL: usize = 6;
+
+/// The number of labels before the early data and destination host in an
+/// establish domain.
+const NUM_FIELD_LABELS: usize = 7;
+
+/// Separates the port from the number of early data labels in the port label.
+const EARLY_DATA_SEPARATOR: char = 'x';
+
+/// Sent in place of the port number to request a Unix socket destination.
+const UNIX_PORT: &str = "u";
+
+/// Separates the port from the nonce in the port label.
+const NONCE_SEPARATOR: char = 'n';
+
+/// Separates the port from the resumed session in the port label.
+const RESUME_SEPARATOR: char = 'r';
+
+/// Follows the MTU in the MTU label to request padded transfer packets.
+const PADDED_SUFFIX: char = 'p';
+
+/// Follows the MTU in the MTU label, before any `PADDED_SUFFIX`, to request
+/// framed transfer packets.
+const FRAMED_SUFFIX: char = 'f';
+
+/// Follows the MTU in the MTU label, before any `FRAMED_SUFFIX`, to request a
+/// UDP destination.
+const DATAGRAM_SUFFIX: char = 'd';
+
+/// Follows the MTU in the MTU label, after any `PADDED_SUFFIX`, when the
+/// destination host is left out and sent in a `DestinationQuery` instead.
+const SPLIT_SUFFIX: char = 'h';
+
+/// Follows the API character in the first label of a `DestinationQuery`.
+/// Response encodings are alphanumeric, so establish domains never have it.
+const DESTINATION_SEPARATOR: char = '-';
+
+/// Separates the response MTU from the query MTU in the MTU label.
+const QUERY_MTU_SEPARATOR: char = 'q';
+
+/// Separates the response MTU from the number of answers per transfer
+/// response in the MTU label.
+const ANSWERS_SEPARATOR: char = 'a';
+
+/// The maximum number of labels in the destination host of an establish
+/// domain.
+const MAX_HOST_LABELS: usize = 32;
+
+/// A session ID that servers never assign.
+pub const RESERVED_SESSION_ID: u16 = 65535;
+
+/// The label sent in place of a proof to request an unauthenticated session.
+const NO_AUTH_PROOF: &str = "noauth";
+
 /// The contents of an establishment query.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct EstablishQuery {
     pub response_encoding: String,
     pub mtu: u16,
+    /// The largest chunk the client will send, if it says.
+    pub query_mtu: Option<u16>,
     pub name_encoding: String,
     pub query_window: u16,
     pub response_window: u16,
-    pub proof: u64,
+    /// The password proof, or None to request an unauthenticated session.
+    pub proof: Option<u64>,
+    /// A random value that makes the query unique, so that the server can
+    /// answer a retry with the session it already created. Resume queries
+    /// don't need one.
+    pub nonce: Option<u32>,
     pub port: u16,
-    pub host: Domain
+    pub host: Domain,
+    /// Data to deliver to the destination as soon as the session is created,
+    /// encoded with the name encoding. Empty if there is none, or if the name
+    /// encoding is unknown (see `check_name_encoding`).
+    pub early_data: Vec<u8>,
+    /// The ID of an existing session to move to a new session ID, the
+    /// client's incoming window start, and the session's resume token. See
+    /// `WwrState::restart_send`.
+    pub resume: Option<(u16, u32, u32)>,
+    /// Whether every transfer packet is padded to a constant size. See
+    /// `pad_packet`.
+    pub padded: bool,
+    /// Whether every transfer packet is wrapped in a container. See
+    /// `frame_packet`.
+    pub framed: bool,
+    /// Whether the destination is a UDP port, with one datagram per chunk.
+    pub datagram: bool,
+    /// Whether the destination is one of the server's Unix sockets, named by
+    /// `host`. The port is 0 for these.
+    pub unix: bool,
+    /// The number of answers that each transfer response is split across.
+    /// See `encode_answers`.
+    pub answers: usize,
+    /// Whether the destination host is left out of the domain, to be sent in
+    /// a `DestinationQuery` that follows. Split queries need a nonce. When
+    /// decoded, their host is empty.
+    pub split: bool
 }
 
 impl EstablishQuery {
@@ -75,8 +334,13 @@ impl EstablishQuery {
     ///
     /// * `query` - The query to decode.
     /// * `host` - The root domain name of the server.
-    pub fn from_query(query: &Message, host: &Domain) -> Result<EstablishQuery, String> {
-        if !is_establish_query(query) {
+    /// * `api` - The API characters in use.
+    pub fn from_query(
+        query: &Message,
+        host: &Domain,
+        api: &ApiChars
+    ) -> Result<EstablishQuery, String> {
+        if !is_establish_query(query, api) {
             return Err("not an establish query".to_owned());
         }
         EstablishQuery::from_domain(&query.questions[0].domain, host)
@@ -86,90 +350,385 @@ impl EstablishQuery {
         if !domain_ends_with(domain, host) {
             return Err("incorrect host domain".to_owned());
         }
-        if domain.parts().len() - host.parts().len() < 8 {
+        let num_labels = domain.raw_parts().len() - host.raw_parts().len();
+        if num_labels < NUM_FIELD_LABELS {
             return Err("not enough labels".to_owned());
+        } else if num_labels > NUM_FIELD_LABELS + MAX_HOST_LABELS {
+            return Err("too many labels".to_owned());
         }
-        let response_encoding = domain_part_lowercase(&domain.parts()[0])
-            .chars().skip(1).collect();
-        let mtu = domain.parts()[1].parse();
-        let name_encoding = domain_part_lowercase(&domain.parts()[2]);
-        let query_window = domain.parts()[3].parse();
-        let response_window = domain.parts()[4].parse();
-        let proof = u64::from_str_radix(&domain.parts()[5], 16);
-        let port = domain.parts()[6].parse();
-        let host = &domain.parts()[7..(domain.parts().len() - host.parts().len())];
-        if mtu.is_err() || query_window.is_err() || response_window.is_err() || proof.is_err() ||
-            port.is_err() {
-            Err("invalid number in domain".to_owned())
+        let parts = domain.parts();
+        // The API character was already checked by is_establish_query().
+        let response_encoding = parse_name_label(
+            &parts[RESPONSE_ENCODING_LABEL].chars().skip(1).collect::<String>())?;
+        let mtu_label = domain_part_lowercase(&parts[MTU_LABEL]);
+        let (mtu, answers, query_mtu, datagram, framed, padded, split) =
+            parse_mtu_label(&mtu_label)?;
+        let name_encoding = parse_name_label(&parts[NAME_ENCODING_LABEL])?;
+        let query_window = parse_decimal_label(&parts[QUERY_WINDOW_LABEL])?;
+        let response_window = parse_decimal_label(&parts[RESPONSE_WINDOW_LABEL])?;
+        let proof = if domain_part_lowercase(&parts[PROOF_LABEL]) == NO_AUTH_PROOF {
+            None
+        } else if parts[PROOF_LABEL].bytes().all(|x| x.is_ascii_hexdigit()) {
+            Some(u64::from_str_radix(&parts[PROOF_LABEL], 16)
+                .map_err(|_| "invalid number in domain".to_owned())?)
         } else {
-            Ok(EstablishQuery{
-                response_encoding: response_encoding,
-                mtu: mtu.unwrap(),
-                name_encoding: name_encoding,
-                query_window: query_window.unwrap(),
-                response_window: response_window.unwrap(),
-                proof: proof.unwrap(),
-                port: port.unwrap(),
-                host: Domain::from_parts(host.to_vec())?
-            })
+            return Err("invalid number in domain".to_owned());
+        };
+        let mut port_fields = parts[PORT_LABEL].splitn(2, RESUME_SEPARATOR);
+        let (port, unix, nonce, num_early) = parse_port_label(port_fields.next().unwrap())?;
+        if unix && datagram {
+            return Err("Unix destinations must be streams".to_owned());
+        }
+        let resume = match port_fields.next() {
+            Some(x) if num_early == 0 => Some(parse_resume_field(x)?),
+            Some(_) => return Err("cannot send early data when resuming a session".to_owned()),
+            None => None
+        };
+        let host_labels = num_labels.checked_sub(NUM_FIELD_LABELS + num_early)
+            .ok_or("not enough labels".to_owned())?;
+        if split && (nonce.is_none() || resume.is_some()) {
+            return Err("split establishment queries need a nonce".to_owned());
+        } else if split && host_labels > 0 {
+            return Err("split establishment query has a host".to_owned());
+        } else if !split && host_labels == 0 {
+            return Err("not enough labels".to_owned());
         }
+        let early_labels = &parts[NUM_FIELD_LABELS..(NUM_FIELD_LABELS + num_early)];
+        // Queries with unknown name encodings are still parsed, so that the
+        // server can say which encodings it supports.
+        let early_data = match get_name_code(&name_encoding) {
+            Some(code) if num_early > 0 => code.decode_parts(early_labels)?,
+            _ => Vec::new()
+        };
+        let host = &parts[(NUM_FIELD_LABELS + num_early)..(parts.len() - host.raw_parts().len())];
+        Ok(EstablishQuery{
+            response_encoding,
+            mtu,
+            query_mtu,
+            name_encoding,
+            query_window,
+            response_window,
+            proof,
+            nonce,
+            port,
+            host: Domain::from_parts(host.to_vec())?,
+            early_data,
+            resume,
+            padded,
+            framed,
+            datagram,
+            unix,
+            answers,
+            split
+        })
     }
 
     /// Encode the request into a domain name, given the root domain name of the
-    /// server, `host`.
-    pub fn to_domain(&self, host: &Domain) -> Result<Domain, String> {
-        let mut parts = Vec::new();
-        parts.push(format!("e{}", self.response_encoding));
-        macro_rules! push_fmt {
-            ( $($x:expr),* ) => { { $(parts.push(format!("{}", $x));)* } }
-        }
-        push_fmt!(self.mtu, self.name_encoding, self.query_window, self.response_window);
-        parts.push(format!("{:x}", self.proof));
-        push_fmt!(self.port);
-        parts.extend(self.host.parts().to_vec());
+    /// server, `host`, and the API characters in use.
+    ///
+    /// The field labels always come first, in the fixed order that
+    /// `from_query` expects, followed by the early data (if any) and the
+    /// destination host, unless the query is split.
+    pub fn to_domain(&self, host: &Domain, api: &ApiChars) -> Result<Domain, String> {
+        let mut parts = vec![String::new(); NUM_FIELD_LABELS];
+        parts[RESPONSE_ENCODING_LABEL] = format!("{}{}", api.establish, self.response_encoding);
+        parts[MTU_LABEL] = format!("{}", self.mtu);
+        if self.answers != 1 {
+            parts[MTU_LABEL].push_str(&format!("{}{}", ANSWERS_SEPARATOR, self.answers));
+        }
+        if let Some(query_mtu) = self.query_mtu {
+            parts[MTU_LABEL].push_str(&format!("{}{}", QUERY_MTU_SEPARATOR, query_mtu));
+        }
+        if self.datagram {
+            parts[MTU_LABEL].push(DATAGRAM_SUFFIX);
+        }
+        if self.framed {
+            parts[MTU_LABEL].push(FRAMED_SUFFIX);
+        }
+        if self.padded {
+            parts[MTU_LABEL].push(PADDED_SUFFIX);
+        }
+        if self.split {
+            if self.nonce.is_none() || self.resume.is_some() {
+                return Err("split establishment queries need a nonce".to_owned());
+            }
+            parts[MTU_LABEL].push(SPLIT_SUFFIX);
+        }
+        parts[NAME_ENCODING_LABEL] = self.name_encoding.clone();
+        parts[QUERY_WINDOW_LABEL] = format!("{}", self.query_window);
+        parts[RESPONSE_WINDOW_LABEL] = format!("{}", self.response_window);
+        parts[PROOF_LABEL] = match self.proof {
+            Some(proof) => format!("{:x}", proof),
+            None => NO_AUTH_PROOF.to_owned()
+        };
+        parts[PORT_LABEL] = if self.unix {
+            UNIX_PORT.to_owned()
+        } else {
+            format!("{}", self.port)
+        };
+        if let Some(nonce) = self.nonce {
+            parts[PORT_LABEL].push_str(&format!("{}{:x}", NONCE_SEPARATOR, nonce));
+        }
+        if let Some((id, window_start, token)) = self.resume {
+            if !self.early_data.is_empty() {
+                return Err("cannot send early data when resuming a session".to_owned());
+            }
+            parts[PORT_LABEL].push_str(&format!("{}{}-{}-{:x}", RESUME_SEPARATOR, id,
+                window_start, token));
+        } else if !self.early_data.is_empty() {
+            let code = get_name_code(&self.name_encoding)
+                .ok_or(format!("bad name code: {}", self.name_encoding))?;
+            let early_labels = code.encode_parts(&self.early_data)?;
+            parts[PORT_LABEL].push_str(&format!("{}{}", EARLY_DATA_SEPARATOR,
+                early_labels.len()));
+            parts.extend(early_labels);
+        }
+        if !self.split {
+            parts.extend(self.host.parts().to_vec());
+        }
         parts.extend(host.parts().to_vec());
         Domain::from_parts(parts)
     }
 
+    /// Describe the destination, as `host:port` or as `unix:<name>`.
+    pub fn destination(&self) -> String {
+        if self.unix {
+            format!("unix:{}", self.host)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
     /// Check the password proof in the query.
     ///
-    /// # Arguments
+    /// Unauthenticated queries always fail this check.
     ///
-    /// * `password` - The correct password.
-    /// * `cur_time` - The current epoch time, in seconds.
-    /// * `window` - The number of seconds by which the client's clock is allowed
-    ///   to be off from `cur_time`. The higher this value, the slower the check.
+    /// See `verify_proof` for details on the arguments.
     pub fn check_proof(&self, password: &str, cur_time: u64, window: u64) -> bool {
-        for i in (cur_time - window)..(cur_time + window) {
-            if self.proof == password_proof(password, i) {
-                return true;
-            }
+        match self.proof {
+            Some(proof) => verify_proof(proof, password, cur_time, window),
+            None => false
         }
-        false
+    }
+}
+
+/// The port (0 for a Unix socket), whether the destination is a Unix socket,
+/// the nonce, and the number of early data labels.
+type PortFields = (u16, bool, Option<u32>, usize);
+
+/// Parse a port label of the form `<port>[n<nonce>][x<early-labels>]`, where
+/// `<port>` may be `UNIX_PORT`.
+fn parse_port_label(label: &str) -> Result<PortFields, String> {
+    let mut pieces = label.splitn(2, EARLY_DATA_SEPARATOR);
+    let mut port_fields = pieces.next().unwrap().splitn(2, NONCE_SEPARATOR);
+    let (port, unix) = match port_fields.next().unwrap() {
+        x if x.eq_ignore_ascii_case(UNIX_PORT) => (0, true),
+        x => (parse_decimal_label(x)?, false)
+    };
+    let nonce = match port_fields.next() {
+        Some(x) => Some(u32::from_str_radix(x, 16).map_err(|_| "invalid nonce".to_owned())?),
+        None => None
+    };
+    let num_early = match pieces.next() {
+        Some(x) => parse_decimal_label(x)?,
+        None => 0
+    };
+    Ok((port, unix, nonce, num_early))
+}
+
+/// Parse the `<session-id>-<window-start>-<token>` field that follows the
+/// port when resuming a session.
+fn parse_resume_field(field: &str) -> Result<(u16, u32, u32), String> {
+    let mut pieces = field.splitn(3, '-');
+    let id = parse_decimal_label(pieces.next().unwrap())?;
+    let window_start = parse_decimal_label(pieces.next().unwrap_or(""))?;
+    let token = pieces.next().and_then(|x| u32::from_str_radix(x, 16).ok())
+        .ok_or_else(|| "invalid resume token".to_owned())?;
+    Ok((id, window_start, token))
+}
+
+/// The response MTU, the number of answers, the query MTU, whether a UDP
+/// destination, framing, and padding were requested, and whether the query
+/// is split.
+type MtuFields = (u16, usize, Option<u16>, bool, bool, bool, bool);
+
+/// Parse a lowercase MTU label into its fields.
+fn parse_mtu_label(label: &str) -> Result<MtuFields, String> {
+    let (label, split) = match label.strip_suffix(SPLIT_SUFFIX) {
+        Some(x) => (x, true),
+        None => (label, false)
+    };
+    let (label, padded) = match label.strip_suffix(PADDED_SUFFIX) {
+        Some(x) => (x, true),
+        None => (label, false)
+    };
+    let (label, framed) = match label.strip_suffix(FRAMED_SUFFIX) {
+        Some(x) => (x, true),
+        None => (label, false)
+    };
+    let (label, datagram) = match label.strip_suffix(DATAGRAM_SUFFIX) {
+        Some(x) => (x, true),
+        None => (label, false)
+    };
+    let mut fields = label.splitn(2, QUERY_MTU_SEPARATOR);
+    let mut mtu_fields = fields.next().unwrap().splitn(2, ANSWERS_SEPARATOR);
+    let mtu = parse_decimal_label(mtu_fields.next().unwrap())?;
+    let answers = match mtu_fields.next() {
+        Some(x) => parse_decimal_label(x)?,
+        None => 1
+    };
+    let query_mtu = match fields.next() {
+        Some(x) => Some(parse_decimal_label(x)?),
+        None => None
+    };
+    Ok((mtu, answers, query_mtu, datagram, framed, padded, split))
+}
+
+/// Parse a label that must be a plain decimal number.
+///
+/// Unlike `str::parse`, this rejects signs and anything else but ASCII digits.
+fn parse_decimal_label<T: FromStr>(label: &str) -> Result<T, String> {
+    if label.is_empty() || !label.bytes().all(|x| x.is_ascii_digit()) {
+        return Err("invalid number in domain".to_owned());
+    }
+    label.parse().map_err(|_| "invalid number in domain".to_owned())
+}
+
+/// Parse a label that names an encoding, which must be ASCII letters and
+/// digits. The result is lowercase.
+fn parse_name_label(label: &str) -> Result<String, String> {
+    if label.is_empty() || !label.bytes().all(|x| x.is_ascii_alphanumeric()) {
+        return Err("invalid encoding name in domain".to_owned());
+    }
+    Ok(domain_part_lowercase(label))
+}
+
+/// The follow-up to a split establishment query, which carries the
+/// destination host that didn't fit alongside the other fields.
+///
+/// The domain has the form `<api><separator><nonce>.<host>.HOSTNAME`, and the
+/// nonce ties it to the split query.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DestinationQuery {
+    pub nonce: u32,
+    pub host: Domain
+}
+
+impl DestinationQuery {
+    /// Decode a destination query.
+    ///
+    /// See `EstablishQuery::from_query` for the arguments.
+    pub fn from_query(
+        query: &Message,
+        host: &Domain,
+        api: &ApiChars
+    ) -> Result<DestinationQuery, String> {
+        if !is_destination_query(query, api) {
+            return Err("not a destination query".to_owned());
+        }
+        DestinationQuery::from_domain(&query.questions[0].domain, host)
+    }
+
+    fn from_domain(domain: &Domain, host: &Domain) -> Result<DestinationQuery, String> {
+        if !domain_ends_with(domain, host) {
+            return Err("incorrect host domain".to_owned());
+        }
+        let num_labels = domain.raw_parts().len() - host.raw_parts().len();
+        if num_labels < 2 {
+            return Err("not enough labels".to_owned());
+        } else if num_labels > 1 + MAX_HOST_LABELS {
+            return Err("too many labels".to_owned());
+        }
+        let parts = domain.parts();
+        let nonce = u32::from_str_radix(&parts[0][2..], 16)
+            .map_err(|_| "invalid nonce".to_owned())?;
+        Ok(DestinationQuery{
+            nonce,
+            host: Domain::from_parts(parts[1..num_labels].to_vec())?
+        })
+    }
+
+    /// Encode the query into a domain name, given the root domain name of the
+    /// server, `host`, and the API characters in use.
+    pub fn to_domain(&self, host: &Domain, api: &ApiChars) -> Result<Domain, String> {
+        let mut parts = vec![format!("{}{}{:x}", api.establish, DESTINATION_SEPARATOR,
+            self.nonce)];
+        parts.extend(self.host.parts().to_vec());
+        parts.extend(host.parts().to_vec());
+        Domain::from_parts(parts)
     }
 }
 
 /// A response to an establishment query.
-#[derive(Debug, PartialEq)]
+///
+/// A success carries every parameter of the session as the server accepted
+/// it. Clients use these values rather than the ones they asked for.
+#[derive(Clone, Debug, PartialEq)]
 pub enum EstablishResponse {
-    Success{id: u16, seq: u32},
+    Success{
+        id: u16,
+        seq: u32,
+        query_window: u16,
+        response_window: u16,
+        /// The query MTU from the request, or 0 if it didn't have one.
+        query_mtu: u16,
+        response_mtu: u16,
+        /// The secret a client needs to resume the session.
+        resume_token: u32
+    },
     Failure(String),
+    /// The answer to a split query: the server is waiting for the
+    /// `DestinationQuery`, whose response is the real one.
+    Pending,
     Unknown(u8)
 }
 
+/// The number of bytes in the fields of a successful `EstablishResponse`,
+/// between the status and the checksum.
+const SUCCESS_FIELDS_LEN: usize = 18;
+
+/// Compute the checksum of a successful `EstablishResponse`, which covers
+/// the status and the fields.
+fn success_checksum(fields: &[u8]) -> u32 {
+    crc32([0u8].iter().chain(fields.iter()).cloned())
+}
+
 impl Decoder for EstablishResponse {
     fn dns_decode(packet: &mut DecPacket) -> Result<EstablishResponse, String> {
         Ok(match u8::dns_decode(packet)? {
             0 => {
+                let fields = packet.read_bytes(SUCCESS_FIELDS_LEN)?;
+                let checksum: u32 = Decoder::dns_decode(packet)?;
+                if packet.remaining() != 0 {
+                    return Err("establish response is too long".to_owned());
+                } else if checksum != success_checksum(&fields) {
+                    return Err("establish response checksum mismatch".to_owned());
+                }
+                let mut packet = DecPacket::new(fields);
+                let packet = &mut packet;
                 let session_id = Decoder::dns_decode(packet)?;
                 let seq_num = Decoder::dns_decode(packet)?;
-                EstablishResponse::Success{id: session_id, seq: seq_num}
+                let response_mtu = Decoder::dns_decode(packet)?;
+                let query_window = Decoder::dns_decode(packet)?;
+                let response_window = Decoder::dns_decode(packet)?;
+                let query_mtu = Decoder::dns_decode(packet)?;
+                let resume_token = Decoder::dns_decode(packet)?;
+                EstablishResponse::Success{
+                    id: session_id,
+                    seq: seq_num,
+                    query_window,
+                    response_window,
+                    query_mtu,
+                    response_mtu,
+                    resume_token
+                }
             },
             1 => {
                 let size = packet.remaining();
                 let raw = packet.read_bytes(size)?;
                 EstablishResponse::Failure(String::from(String::from_utf8_lossy(&raw)))
             },
+            2 if packet.remaining() == 0 => EstablishResponse::Pending,
             x => {
                 let size = packet.remaining();
                 packet.read_bytes(size)?;
@@ -182,15 +741,20 @@ impl Decoder for EstablishResponse {
 impl Encoder for EstablishResponse {
     fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), String> {
         match self {
-            &EstablishResponse::Success{id: ref session_id, seq: ref seq_num} => {
-                0u8.dns_encode(packet)?;
-                session_id.dns_encode(packet)?;
-                seq_num.dns_encode(packet)
+            &EstablishResponse::Success{id: ref session_id, seq: ref seq_num, ref query_window,
+                ref response_window, ref query_mtu, ref response_mtu, ref resume_token} => {
+                // The response MTU comes first, where it was before the other fields.
+                let mut fields = EncPacket::new();
+                encode_all!(&mut fields, session_id, seq_num, response_mtu, query_window,
+                    response_window, query_mtu, resume_token)?;
+                let checksum = success_checksum(fields.as_slice());
+                encode_all!(packet, 0u8, fields.into_bytes(), checksum)
             },
             &EstablishResponse::Failure(ref message) => {
                 1u8.dns_encode(packet)?;
                 message.as_bytes().to_vec().dns_encode(packet)
             },
+            &EstablishResponse::Pending => 2u8.dns_encode(packet),
             &EstablishResponse::Unknown(_) => {
                 Err("cannot encode unknown establish response".to_owned())
             }
@@ -201,20 +765,33 @@ impl Encoder for EstablishResponse {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use dns_coding::dns_decode;
+    use dns_proto::{MAX_DOMAIN_LEN, Question, RecordClass, RecordType};
 
     #[test]
     fn query_encoding() {
         let query = EstablishQuery{
             response_encoding: "raw".to_owned(),
             mtu: 123,
+            query_mtu: None,
             name_encoding: "b64".to_owned(),
             query_window: 64,
             response_window: 32,
-            proof: 0x913379,
+            proof: Some(0x913379),
+            nonce: None,
             port: 1337,
-            host: "foo.bob.com".parse().unwrap()
+            host: "foo.bob.com".parse().unwrap(),
+            early_data: Vec::new(),
+            resume: None,
+            padded: false,
+            framed: false,
+            datagram: false,
+            unix: false,
+            answers: 1,
+            split: false
         };
-        let encoded = query.to_domain(&"baz.proxy.com".parse().unwrap()).unwrap();
+        let encoded = query.to_domain(&"baz.proxy.com".parse().unwrap(), &ApiChars::default())
+            .unwrap();
         let expected = "eraw.123.b64.64.32.913379.1337.foo.bob.com.baz.proxy.com";
         assert_eq!(expected.parse::<Domain>().unwrap(), encoded);
     }
@@ -228,12 +805,476 @@ mod tests {
         assert_eq!(query, EstablishQuery{
             response_encoding: "raw".to_owned(),
             mtu: 123,
+            query_mtu: None,
             name_encoding: "b64".to_owned(),
             query_window: 64,
             response_window: 32,
-            proof: 0x913379,
+            proof: Some(0x913379),
+            nonce: None,
             port: 1337,
-            host: "foo.bob.com".parse().unwrap()
+            host: "foo.bob.com".parse().unwrap(),
+            early_data: Vec::new(),
+            resume: None,
+            padded: false,
+            framed: false,
+            datagram: false,
+            unix: false,
+            answers: 1,
+            split: false
+        });
+    }
+
+    #[test]
+    fn malformed_labels() {
+        let host: Domain = "proxy.com".parse().unwrap();
+        let valid = vec!["eraw", "123", "b16", "4", "4", "noauth", "22", "foo", "proxy", "com"];
+        let decode = |labels: &[&str]| {
+            let parts = labels.iter().map(|x| x.as_bytes().to_vec()).collect();
+            EstablishQuery::from_domain(&Domain::from_raw_parts(parts).unwrap(), &host)
+        };
+        assert!(decode(&valid).is_ok());
+        for &(index, label) in [
+            (0, "er\x01aw"), (0, "e"), (1, "12\t3"), (1, "+123"), (1, " 123"),
+            (2, "b1\x7f"), (2, "b\u{e9}16"), (3, "4\n"), (4, "-4"), (5, "+abc"),
+            (5, "ab\x00c"), (6, "22\r"), (6, "22x"), (6, "22x+1"), (6, "22n"), (6, "22nxyz"),
+            (6, "22n100000000"), (6, "n1f")
+        ].iter() {
+            let mut labels = valid.clone();
+            labels[index] = label;
+            assert!(decode(&labels).is_err(), "accepted label {:?}", label);
+        }
+    }
+
+    #[test]
+    fn label_order() {
+        let query = EstablishQuery{
+            response_encoding: "raw".to_owned(),
+            mtu: 1,
+            query_mtu: None,
+            name_encoding: "b16".to_owned(),
+            query_window: 2,
+            response_window: 3,
+            proof: Some(0xabc),
+            nonce: None,
+            port: 4,
+            host: "dest.com".parse().unwrap(),
+            early_data: Vec::new(),
+            resume: None,
+            padded: false,
+            framed: false,
+            datagram: false,
+            unix: false,
+            answers: 1,
+            split: false
+        };
+        let host = "proxy.com".parse().unwrap();
+        let encoded = query.to_domain(&host, &ApiChars::default()).unwrap();
+        let parts = encoded.parts();
+        assert_eq!(parts.len(), NUM_FIELD_LABELS + 4);
+        assert_eq!(parts[RESPONSE_ENCODING_LABEL], "eraw");
+        assert_eq!(parts[MTU_LABEL], "1");
+        assert_eq!(parts[NAME_ENCODING_LABEL], "b16");
+        assert_eq!(parts[QUERY_WINDOW_LABEL], "2");
+        assert_eq!(parts[RESPONSE_WINDOW_LABEL], "3");
+        assert_eq!(parts[PROOF_LABEL], "abc");
+        assert_eq!(parts[PORT_LABEL], "4");
+        assert_eq!(&parts[NUM_FIELD_LABELS..], &["dest", "com", "proxy", "com"]);
+
+        // The indices must cover every field label exactly once.
+        let mut indices = vec![RESPONSE_ENCODING_LABEL, MTU_LABEL, NAME_ENCODING_LABEL,
+            QUERY_WINDOW_LABEL, RESPONSE_WINDOW_LABEL, PROOF_LABEL, PORT_LABEL];
+        indices.sort();
+        assert_eq!(indices, (0..NUM_FIELD_LABELS).collect::<Vec<_>>());
+
+        assert_eq!(EstablishQuery::from_domain(&encoded, &host).unwrap(), query);
+    }
+
+    #[test]
+    fn early_data_query() {
+        let query = EstablishQuery{
+            response_encoding: "raw".to_owned(),
+            mtu: 123,
+            query_mtu: None,
+            name_encoding: "b16".to_owned(),
+            query_window: 64,
+            response_window: 32,
+            proof: Some(0x913379),
+            nonce: None,
+            port: 1337,
+            host: "foo.bob.com".parse().unwrap(),
+            early_data: vec![0x12, 0xab, 0xff],
+            resume: None,
+            padded: false,
+            framed: false,
+            datagram: false,
+            unix: false,
+            answers: 1,
+            split: false
+        };
+        let host = "baz.proxy.com".parse().unwrap();
+        let encoded = query.to_domain(&host, &ApiChars::default()).unwrap();
+        let expected = "eraw.123.b16.64.32.913379.1337x1.12abff.foo.bob.com.baz.proxy.com";
+        assert_eq!(expected.parse::<Domain>().unwrap(), encoded);
+        assert_eq!(EstablishQuery::from_domain(&encoded, &host).unwrap(), query);
+
+        let missing = "eraw.123.b16.64.32.913379.1337x3.12abff.foo.baz.proxy.com";
+        assert!(EstablishQuery::from_domain(&missing.parse().unwrap(), &host).is_err());
+
+        let mut query = query;
+        query.nonce = Some(0xc0ffee);
+        let encoded = query.to_domain(&host, &ApiChars::default()).unwrap();
+        let expected = "eraw.123.b16.64.32.913379.1337nc0ffeex1.12abff.foo.bob.com.baz.proxy.com";
+        assert_eq!(expected.parse::<Domain>().unwrap(), encoded);
+        assert_eq!(EstablishQuery::from_domain(&encoded, &host).unwrap(), query);
+    }
+
+    #[test]
+    fn unix_query() {
+        let query = EstablishQuery{
+            response_encoding: "raw".to_owned(),
+            mtu: 80,
+            query_mtu: None,
+            name_encoding: "b16".to_owned(),
+            query_window: 4,
+            response_window: 4,
+            proof: None,
+            nonce: Some(0xab),
+            port: 0,
+            host: "db".parse().unwrap(),
+            early_data: Vec::new(),
+            resume: None,
+            padded: false,
+            framed: false,
+            datagram: false,
+            unix: true,
+            answers: 1,
+            split: false
+        };
+        let host = "proxy.com".parse().unwrap();
+        let encoded = query.to_domain(&host, &ApiChars::default()).unwrap();
+        let expected = "eraw.80.b16.4.4.noauth.unab.db.proxy.com";
+        assert_eq!(expected.parse::<Domain>().unwrap(), encoded);
+        assert_eq!(EstablishQuery::from_domain(&encoded, &host).unwrap(), query);
+        assert_eq!(query.destination(), "unix:db");
+
+        let datagram = "eraw.80d.b16.4.4.noauth.u.db.proxy.com";
+        assert!(EstablishQuery::from_domain(&datagram.parse().unwrap(), &host).is_err());
+    }
+
+    #[test]
+    fn mtu_label_suffixes() {
+        let query = EstablishQuery{
+            response_encoding: "raw".to_owned(),
+            mtu: 80,
+            query_mtu: None,
+            name_encoding: "b16".to_owned(),
+            query_window: 4,
+            response_window: 4,
+            proof: None,
+            nonce: None,
+            port: 22,
+            host: "foo.com".parse().unwrap(),
+            early_data: Vec::new(),
+            resume: None,
+            padded: true,
+            framed: false,
+            datagram: false,
+            unix: false,
+            answers: 1,
+            split: false
+        };
+        let host = "proxy.com".parse().unwrap();
+        let encoded = query.to_domain(&host, &ApiChars::default()).unwrap();
+        let expected = "eraw.80p.b16.4.4.noauth.22.foo.com.proxy.com";
+        assert_eq!(expected.parse::<Domain>().unwrap(), encoded);
+        assert_eq!(EstablishQuery::from_domain(&encoded, &host).unwrap(), query);
+
+        let mut with_query_mtu = query.clone();
+        with_query_mtu.query_mtu = Some(40);
+        let encoded = with_query_mtu.to_domain(&host, &ApiChars::default()).unwrap();
+        assert_eq!(encoded.parts()[MTU_LABEL], "80q40p");
+        assert_eq!(EstablishQuery::from_domain(&encoded, &host).unwrap(), with_query_mtu);
+
+        with_query_mtu.framed = true;
+        let encoded = with_query_mtu.to_domain(&host, &ApiChars::default()).unwrap();
+        assert_eq!(encoded.parts()[MTU_LABEL], "80q40fp");
+        assert_eq!(EstablishQuery::from_domain(&encoded, &host).unwrap(), with_query_mtu);
+
+        with_query_mtu.datagram = true;
+        let encoded = with_query_mtu.to_domain(&host, &ApiChars::default()).unwrap();
+        assert_eq!(encoded.parts()[MTU_LABEL], "80q40dfp");
+        assert_eq!(EstablishQuery::from_domain(&encoded, &host).unwrap(), with_query_mtu);
+
+        with_query_mtu.answers = 4;
+        let encoded = with_query_mtu.to_domain(&host, &ApiChars::default()).unwrap();
+        assert_eq!(encoded.parts()[MTU_LABEL], "80a4q40dfp");
+        assert_eq!(EstablishQuery::from_domain(&encoded, &host).unwrap(), with_query_mtu);
+
+        let bad_labels = ["p", "80pp", "p80", "80q", "q40", "80q40q1", "80pq40", "80pf", "80ff",
+            "80fd", "80a", "a4", "80q40a4", "80a4a4", "80pa4"];
+        for bad in &bad_labels {
+            let domain = format!("eraw.{}.b16.4.4.noauth.22.foo.com.proxy.com", bad);
+            assert!(EstablishQuery::from_domain(&domain.parse().unwrap(), &host).is_err(),
+                "accepted {}", bad);
+        }
+    }
+
+    #[test]
+    fn resume_query() {
+        let query = EstablishQuery{
+            response_encoding: "raw".to_owned(),
+            mtu: 80,
+            query_mtu: None,
+            name_encoding: "b16".to_owned(),
+            query_window: 4,
+            response_window: 4,
+            proof: Some(0x913379),
+            nonce: None,
+            port: 22,
+            host: "foo.com".parse().unwrap(),
+            early_data: Vec::new(),
+            resume: Some((12, 4000000000, 0xbeef)),
+            padded: false,
+            framed: false,
+            datagram: false,
+            unix: false,
+            answers: 1,
+            split: false
+        };
+        let host = "proxy.com".parse().unwrap();
+        let encoded = query.to_domain(&host, &ApiChars::default()).unwrap();
+        let expected = "eraw.80.b16.4.4.913379.22r12-4000000000-beef.foo.com.proxy.com";
+        assert_eq!(expected.parse::<Domain>().unwrap(), encoded);
+        assert_eq!(EstablishQuery::from_domain(&encoded, &host).unwrap(), query);
+
+        for bad in &["22r12", "22r-5", "22r12-5", "22r12-5-xyz", "22r12-5-1ffffffff",
+            "22x1r12-5-1", "22r12-5x1-1"]
+        {
+            let domain = format!("eraw.80.b16.4.4.913379.{}.00.foo.com.proxy.com", bad);
+            assert!(EstablishQuery::from_domain(&domain.parse().unwrap(), &host).is_err(),
+                "accepted {}", bad);
+        }
+        let mut early = query.clone();
+        early.early_data = vec![1];
+        assert!(early.to_domain(&host, &ApiChars::default()).is_err());
+    }
+
+    #[test]
+    fn longest_fields_fit() {
+        // With an IPv4 destination, only the server's host can make an
+        // establish domain overflow. Longer destinations are split.
+        let query = EstablishQuery{
+            response_encoding: "raw".to_owned(),
+            mtu: 65535,
+            query_mtu: Some(65535),
+            name_encoding: "b16".to_owned(),
+            query_window: 65535,
+            response_window: 65535,
+            proof: Some(u64::MAX),
+            nonce: None,
+            port: 65535,
+            host: "255.255.255.255".parse().unwrap(),
+            early_data: Vec::new(),
+            resume: Some((65535, u32::MAX, u32::MAX)),
+            padded: true,
+            framed: true,
+            datagram: true,
+            unix: false,
+            answers: 16,
+            split: false
+        };
+        let host = |len: usize| -> Domain {
+            format!("{}.{}.{}", "a".repeat(63), "b".repeat(63), "c".repeat(len)).parse().unwrap()
+        };
+        assert_eq!(host(21).wire_len(), 151);
+        let encoded = query.to_domain(&host(21), &ApiChars::default()).unwrap();
+        assert_eq!(encoded.wire_len(), MAX_DOMAIN_LEN);
+        assert_eq!(EstablishQuery::from_domain(&encoded, &host(21)).unwrap(), query);
+        assert!(query.to_domain(&host(22), &ApiChars::default()).is_err());
+    }
+
+    #[test]
+    fn split_query() {
+        let dest = format!("{}.{}.{}.{}", "a".repeat(60), "b".repeat(60), "c".repeat(60),
+            "d".repeat(40));
+        let mut query = EstablishQuery{
+            response_encoding: "raw".to_owned(),
+            mtu: 123,
+            query_mtu: None,
+            name_encoding: "b16".to_owned(),
+            query_window: 64,
+            response_window: 32,
+            proof: Some(0x1234),
+            nonce: Some(0xabcd),
+            port: 0,
+            host: dest.parse().unwrap(),
+            early_data: b"hi".to_vec(),
+            resume: None,
+            padded: false,
+            framed: false,
+            datagram: false,
+            unix: true,
+            answers: 1,
+            split: false
+        };
+        let host: Domain = "proxy.com".parse().unwrap();
+        let api = ApiChars::default();
+        assert!(query.to_domain(&host, &api).is_err());
+
+        query.split = true;
+        let encoded = query.to_domain(&host, &api).unwrap();
+        let expected = "eraw.123h.b16.64.32.1234.unabcdx1.6869.proxy.com";
+        assert_eq!(expected.parse::<Domain>().unwrap(), encoded);
+        let decoded = EstablishQuery::from_domain(&encoded, &host).unwrap();
+        assert_eq!(decoded, EstablishQuery{host: Domain::from_parts(Vec::new()).unwrap(),
+            ..query.clone()});
+
+        let follow_up = DestinationQuery{nonce: 0xabcd, host: query.host.clone()};
+        let encoded = follow_up.to_domain(&host, &api).unwrap();
+        assert_eq!(encoded.to_string(), format!("e-abcd.{}.proxy.com", dest));
+        assert_eq!(DestinationQuery::from_domain(&encoded, &host).unwrap(), follow_up);
+        let message = Message::new_query(Question{
+            domain: encoded,
+            record_type: RecordType::TXT,
+            record_class: RecordClass::IN
         });
+        assert!(is_establish_query(&message, &api));
+        assert!(is_destination_query(&message, &api));
+        assert!(EstablishQuery::from_query(&message, &host, &api).is_err());
+
+        // The nonce ties the two queries together.
+        query.nonce = None;
+        assert!(query.to_domain(&host, &api).is_err());
+        let no_nonce = "eraw.123h.b16.64.32.1234.ux1.6869.proxy.com".parse().unwrap();
+        assert!(EstablishQuery::from_domain(&no_nonce, &host).is_err());
+        let with_host = "eraw.123h.b16.64.32.1234.unabcd.db.proxy.com".parse().unwrap();
+        assert!(EstablishQuery::from_domain(&with_host, &host).is_err());
+
+        let encoded = dns_encode(&EstablishResponse::Pending).unwrap();
+        assert_eq!(encoded, vec![2]);
+        assert_eq!(dns_decode::<EstablishResponse>(encoded).unwrap(), EstablishResponse::Pending);
+    }
+
+    #[test]
+    fn too_many_labels() {
+        let mut domain = "eraw.123.b64.64.32.913379.1337".to_owned();
+        for _ in 0..100 {
+            domain.push_str(".a");
+        }
+        domain.push_str(".proxy.com");
+        let result = EstablishQuery::from_domain(&domain.parse().unwrap(),
+            &"proxy.com".parse().unwrap());
+        assert_eq!(result, Err("too many labels".to_owned()));
+    }
+
+    #[test]
+    fn no_auth_query() {
+        let query = EstablishQuery{
+            response_encoding: "raw".to_owned(),
+            mtu: 123,
+            query_mtu: None,
+            name_encoding: "b64".to_owned(),
+            query_window: 64,
+            response_window: 32,
+            proof: None,
+            nonce: None,
+            port: 1337,
+            host: "foo.bob.com".parse().unwrap(),
+            early_data: Vec::new(),
+            resume: None,
+            padded: false,
+            framed: false,
+            datagram: false,
+            unix: false,
+            answers: 1,
+            split: false
+        };
+        let host = "baz.proxy.com".parse().unwrap();
+        let encoded = query.to_domain(&host, &ApiChars::default()).unwrap();
+        let expected = "eraw.123.b64.64.32.noauth.1337.foo.bob.com.baz.proxy.com";
+        assert_eq!(expected.parse::<Domain>().unwrap(), encoded);
+        let decoded = EstablishQuery::from_domain(&encoded, &host).unwrap();
+        assert_eq!(decoded, query);
+        assert!(!decoded.check_proof("", 1000, 120));
+    }
+
+    #[test]
+    fn proof_verification() {
+        let proof = password_proof("hunter2", 1000);
+        assert!(verify_proof(proof, "hunter2", 1000, 1));
+        assert!(verify_proof(proof, "hunter2", 1010, 11));
+        assert!(!verify_proof(proof, "hunter2", 1010, 5));
+        assert!(!verify_proof(proof, "hunter3", 1000, 1));
+        assert!(!verify_proof(proof, "hunter2", 3, 10));
+    }
+
+    #[test]
+    fn proof_failure_time() {
+        let message = proof_failure_message(Some(1337), 120);
+        assert_eq!(failure_server_time(&message), Some(1337));
+        assert_eq!(failure_proof_window(&message), Some(120));
+        assert_eq!(failure_server_time(&proof_failure_message(None, 120)), None);
+        assert_eq!(failure_proof_window(&proof_failure_message(None, 120)), None);
+        assert_eq!(failure_server_time("no free session IDs"), None);
+
+        // Older servers only report their time.
+        assert_eq!(failure_server_time("invalid proof; server time: 5"), Some(5));
+        assert_eq!(failure_proof_window("invalid proof; server time: 5"), None);
+    }
+
+    #[test]
+    #[cfg(feature = "parallel-proof")]
+    fn parallel_proof_verification() {
+        let proof = password_proof("hunter2", 1000);
+        for &num_threads in &[2, 3, 8] {
+            for &time in &[701, 999, 1000, 1299] {
+                assert!(verify_proof_parallel(proof, "hunter2", time - 300, time + 300,
+                    num_threads));
+            }
+            assert!(!verify_proof_parallel(proof, "hunter2", 1001, 1300, num_threads));
+            assert!(!verify_proof_parallel(proof, "hunter3", 700, 1300, num_threads));
+        }
+    }
+
+    #[test]
+    fn response_encoding() {
+        let responses = vec![
+            EstablishResponse::Success{id: 1337, seq: 0xdeadbeef, query_window: 8,
+                response_window: 0, query_mtu: 0xffff, response_mtu: 200, resume_token: 7},
+            EstablishResponse::Failure("invalid proof".to_owned())
+        ];
+        for response in responses {
+            let encoded = dns_encode(&response).unwrap();
+            assert_eq!(dns_decode::<EstablishResponse>(encoded).unwrap(), response);
+        }
+        let encoded = dns_encode(&EstablishResponse::Success{id: 1, seq: 2, query_window: 4,
+            response_window: 5, query_mtu: 6, response_mtu: 3, resume_token: 0x01020304}).unwrap();
+        assert_eq!(encoded, vec![0, 0, 1, 0, 0, 0, 2, 0, 3, 0, 4, 0, 5, 0, 6, 1, 2, 3, 4,
+            0xb3, 0xfc, 0x7e, 0xbc]);
+
+        // Responses from before the windows, query MTU, resume token, and
+        // checksum were added.
+        assert!(dns_decode::<EstablishResponse>(vec![0, 0, 1, 0, 0, 0, 2, 0, 3]).is_err());
+        assert!(dns_decode::<EstablishResponse>(encoded[..15].to_vec()).is_err());
+        assert!(dns_decode::<EstablishResponse>(encoded[..19].to_vec()).is_err());
+    }
+
+    #[test]
+    fn mangled_success() {
+        let encoded = dns_encode(&EstablishResponse::Success{id: 1, seq: 2, query_window: 4,
+            response_window: 5, query_mtu: 6, response_mtu: 3, resume_token: 7}).unwrap();
+        for size in 1..encoded.len() {
+            assert!(dns_decode::<EstablishResponse>(encoded[..size].to_vec()).is_err());
+        }
+        let mut long = encoded.clone();
+        long.push(0);
+        assert_eq!(dns_decode::<EstablishResponse>(long),
+            Err("establish response is too long".to_owned()));
+        for i in 1..encoded.len() {
+            let mut corrupt = encoded.clone();
+            corrupt[i] ^= 0x10;
+            assert_eq!(dns_decode::<EstablishResponse>(corrupt),
+                Err("establish response checksum mismatch".to_owned()));
+        }
     }
 }
@@ -1,7 +1,9 @@
 //! A set of APIs for both serving and using the myodine protocol.
 
 pub mod util;
+pub mod api_chars;
 pub mod name_code;
+pub mod name_template;
 pub mod record_code;
 pub mod discovery;
 pub mod establish;
@@ -1,8 +1,29 @@
+use std::cmp::min;
 use std::fmt::Write;
 
-use dns_proto::Domain;
+use dns_proto::{Domain, MAX_DOMAIN_LEN};
 
-use super::util::{domain_ends_with, domain_part_lowercase};
+use super::name_template::NameTemplate;
+
+extern crate rand;
+use self::rand::{Rng, thread_rng};
+
+/// The length of each decoy label in a transfer query name.
+pub const DECOY_LABEL_LEN: usize = 6;
+
+/// The most labels a domain name can have, if each holds one byte.
+pub const MAX_LABELS: usize = (MAX_DOMAIN_LEN - 1) / 2;
+
+/// Separates the session ID from the decoy label count in the first label.
+pub const DECOY_SEPARATOR: char = '-';
+
+/// Every identifier that `get_name_code` knows.
+pub const NAME_CODES: [&str; 1] = ["b16"];
+
+/// Get the identifier of every `NameCode`.
+pub fn supported_name_encodings() -> Vec<&'static str> {
+    NAME_CODES.to_vec()
+}
 
 /// Lookup the NameCode for the given identifier.
 pub fn get_name_code(name: &str) -> Option<Box<NameCode>> {
@@ -20,7 +41,8 @@ pub trait NameCode {
     /// Decode the raw data from domain name labels.
     fn decode_parts(&self, parts: &[String]) -> Result<Vec<u8>, String>;
 
-    /// Encode the data into the full `Domain` for a transfer query.
+    /// Encode the data into the full `Domain` for a transfer query, using the
+    /// default `NameTemplate`.
     ///
     /// # Arguments
     ///
@@ -28,18 +50,48 @@ pub trait NameCode {
     /// * `sess_id` - The session ID corresponding to the transfer packet.
     /// * `data` - The raw data to encode.
     /// * `host` - The root domain name of the server.
+    /// * `decoys` - The number of random labels to add after the session
+    ///   label. These make query names more diverse and carry no data.
     fn encode_domain(
         &self,
         api_flag: char,
         sess_id: u16,
         data: &[u8],
-        host: &Domain
+        host: &Domain,
+        decoys: usize
     ) -> Result<Domain, String> {
-        let mut parts = Vec::new();
-        parts.push(format!("{}{}", api_flag, sess_id));
-        parts.extend(self.encode_parts(data)?);
-        parts.extend(host.parts().to_vec());
-        Domain::from_parts(parts)
+        NameTemplate::default().encode(self, api_flag, sess_id, data, host, decoys)
+    }
+
+    /// Get the largest number of bytes that `encode_domain` can fit into a
+    /// single domain name under `host`, for any session ID.
+    fn max_domain_data(&self, api_flag: char, host: &Domain, decoys: usize) -> usize {
+        NameTemplate::default().max_data(self, api_flag, host, decoys)
+    }
+
+    /// Get the largest number of bytes that `encode_parts` can fit into at
+    /// most `labels` labels.
+    ///
+    /// Counts above `MAX_LABELS` are treated as `MAX_LABELS`, since no name
+    /// could hold more.
+    fn max_label_data(&self, labels: usize) -> usize {
+        let labels = min(labels, MAX_LABELS);
+        let fits = |len: usize| match self.encode_parts(&vec![0xffu8; len]) {
+            Ok(parts) => parts.len() <= labels,
+            Err(_) => false
+        };
+        // Labels are at most 63 bytes, and each holds less than 63 bytes.
+        let bound = labels.checked_mul(63).and_then(|x| x.checked_add(1));
+        let (mut low, mut high) = (0, bound.expect("label count overflow"));
+        while high - low > 1 {
+            let mid = (low + high) / 2;
+            if fits(mid) {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        low
     }
 
     /// Decode the data from a transfer query's domain name.
@@ -52,22 +104,10 @@ pub trait NameCode {
     /// # Returns
     ///
     /// A tuple of the form (api_code, session_id, data).
+    ///
+    /// Decoy labels are skipped.
     fn decode_domain(&self, name: &Domain, host: &Domain) -> Result<(char, u16, Vec<u8>), String> {
-        if !domain_ends_with(name, host) {
-            Err("incorrect host domain".to_owned())
-        } else if name.parts().len() < host.parts().len() + 2 {
-            Err("not enough data".to_owned())
-        } else {
-            let mut all_parts = name.parts().to_vec();
-            let sess_part = all_parts.remove(0);
-            let api_code = domain_part_lowercase(&sess_part).chars().next().unwrap();
-            let sess_id = sess_part.chars().skip(1).collect::<String>().parse();
-            if sess_id.is_err() {
-                return Err("invalid session ID".to_owned());
-            }
-            Ok((api_code, sess_id.unwrap(),
-                self.decode_parts(&all_parts[0..(all_parts.len() - host.parts().len())])?))
-        }
+        NameTemplate::default().decode(self, name, host)
     }
 }
 
@@ -103,6 +143,22 @@ impl NameCode for HexNameCode {
     }
 }
 
+/// Generate random labels that carry no data.
+pub fn decoy_labels(count: usize) -> Vec<String> {
+    if count == 0 {
+        // Without decoys, the thread RNG isn't needed, and it may not work on
+        // targets without an entropy source.
+        return Vec::new();
+    }
+    let mut rng = thread_rng();
+    (0..count).map(|_| {
+        (0..DECOY_LABEL_LEN).map(|_| {
+            let x = rng.gen_range(0, 36);
+            if x < 10 { (b'0' + x) as char } else { (b'a' + x - 10) as char }
+        }).collect()
+    }).collect()
+}
+
 fn split_labels(all_data: String) -> Vec<String> {
     let mut res = Vec::new();
     let mut cur_data = all_data;
@@ -118,6 +174,39 @@ fn split_labels(all_data: String) -> Vec<String> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn registry_complete() {
+        let names = supported_name_encodings();
+        for name in &["b16", "b32", "b36", "b62", "b64", "raw", "hex"] {
+            assert_eq!(get_name_code(name).is_some(), names.contains(name));
+        }
+        for name in &names {
+            assert!(get_name_code(name).is_some());
+        }
+    }
+
+    #[test]
+    fn hex_max_domain_data() {
+        let code = HexNameCode{};
+        let short: Domain = "a.com".parse().unwrap();
+        let max = code.max_domain_data('t', &short, 0);
+        assert!(code.encode_domain('t', 65535, &vec![0; max], &short, 0).is_ok());
+        assert!(code.encode_domain('t', 65535, &vec![0; max + 1], &short, 0).is_err());
+
+        let long: Domain = format!("{}.{}.com", "x".repeat(60), "y".repeat(60)).parse().unwrap();
+        assert!(code.max_domain_data('t', &long, 0) < max - 50);
+    }
+
+    #[test]
+    fn hex_max_label_data() {
+        let code = HexNameCode{};
+        assert_eq!(code.max_label_data(1), 31);
+        assert_eq!(code.max_label_data(2), 63);
+        assert_eq!(code.max_label_data(usize::MAX), code.max_label_data(MAX_LABELS));
+        assert_eq!(code.encode_parts(&[0; 63]).unwrap().len(), 2);
+        assert_eq!(code.encode_parts(&[0; 64]).unwrap().len(), 3);
+    }
+
     #[test]
     fn hex_encode_decode_short() {
         let root: Domain = "hello.com".parse().unwrap();
@@ -125,7 +214,7 @@ mod tests {
         let sess_id = 13u16;
         let code = HexNameCode{};
         let encoded = code.encode_domain(api_flag, sess_id, &vec![0u8, 1u8, 2u8, 52u8],
-            &root).unwrap();
+            &root, 0).unwrap();
         assert_eq!(encoded, "t13.00010234.hello.com".parse().unwrap());
         let (flag_out, id_out, decoded) = code.decode_domain(&encoded, &root).unwrap();
         assert_eq!(flag_out, api_flag);
@@ -147,7 +236,7 @@ mod tests {
                         0x26u8, 0xB6u8, 0x32u8, 0x37u8, 0xAAu8, 0xE5u8, 0xCCu8, 0x6Du8,
                         0x15u8, 0x08u8, 0xEAu8, 0xDDu8, 0x33u8, 0xADu8, 0xB1u8, 0x00u8,
                         0xEFu8, 0x01u8, 0x0Cu8, 0x71u8, 0xA2u8, 0x85u8, 0xABu8];
-        let encoded = code.encode_domain(api_flag, sess_id, &data, &root).unwrap();
+        let encoded = code.encode_domain(api_flag, sess_id, &data, &root, 0).unwrap();
         assert_eq!(encoded, format!("t13.{}.{}.foo.apple.com",
             "234975ce6b0c717b8c1c4d4dcc259c0f00eeff057f7eb420bb04e3852363299",
             "9c461b4f00ef000a926b63237aae5cc6d1508eadd33adb100ef010c71a285ab").parse().unwrap());
@@ -156,4 +245,25 @@ mod tests {
         assert_eq!(id_out, sess_id);
         assert_eq!(decoded, data);
     }
+
+    #[test]
+    fn decoy_labels_skipped() {
+        let root: Domain = "hello.com".parse().unwrap();
+        let code = HexNameCode{};
+        let data = vec![1u8, 2u8, 3u8];
+        let encoded = code.encode_domain('t', 13, &data, &root, 2).unwrap();
+        assert_eq!(encoded.parts().len(), 6);
+        assert_eq!(encoded.parts()[0], "t13-2");
+        assert_eq!(encoded.parts()[3], "010203");
+        assert_eq!(code.decode_domain(&encoded, &root).unwrap(), ('t', 13, data));
+
+        let max = code.max_domain_data('t', &root, 0);
+        // "-2" plus two 6-character labels take 16 bytes, or 8 bytes of hex.
+        assert_eq!(code.max_domain_data('t', &root, 2), max - 8);
+
+        let bad: Domain = "t13-3.abcdef.abcdef.010203.hello.com".parse().unwrap();
+        assert!(code.decode_domain(&bad, &root).is_err());
+        let bad: Domain = "t13-x.010203.hello.com".parse().unwrap();
+        assert!(code.decode_domain(&bad, &root).is_err());
+    }
 }
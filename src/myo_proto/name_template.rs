@@ -0,0 +1,346 @@
+//! Layouts for the labels of transfer query names.
+//!
+//! A template is a dot-separated list of labels. Each label is either
+//! `{data}`, which expands to the decoy and data labels, or a mix of literal
+//! characters and the `{api}` and `{session}` fields. The default template is
+//! `{api}{session}.{data}`.
+
+use std::fmt::{Display, Error, Formatter};
+use std::str::FromStr;
+
+use dns_proto::Domain;
+
+use super::name_code::{DECOY_SEPARATOR, NameCode, decoy_labels};
+use super::util::{domain_ends_with, domain_part_lowercase};
+
+/// The layout of a transfer query name, excluding the root domain.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NameTemplate {
+    labels: Vec<LabelTemplate>,
+    data_index: usize
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum LabelTemplate {
+    Data,
+    Pieces(Vec<Piece>)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Piece {
+    Literal(String),
+    Api,
+    Session
+}
+
+/// The fields read from the non-data labels of a name.
+#[derive(Default)]
+struct Fields {
+    api: Option<char>,
+    session: Option<u16>,
+    decoys: usize
+}
+
+impl NameTemplate {
+    /// Encode data into the full `Domain` for a transfer query.
+    ///
+    /// See `NameCode::encode_domain` for the arguments.
+    pub fn encode<C: NameCode + ?Sized>(
+        &self,
+        code: &C,
+        api_flag: char,
+        sess_id: u16,
+        data: &[u8],
+        host: &Domain,
+        decoys: usize
+    ) -> Result<Domain, String> {
+        let mut parts = Vec::new();
+        for label in &self.labels {
+            match *label {
+                LabelTemplate::Data => {
+                    parts.extend(decoy_labels(decoys));
+                    parts.extend(code.encode_parts(data)?);
+                },
+                LabelTemplate::Pieces(ref pieces) => {
+                    let mut label = String::new();
+                    for piece in pieces {
+                        match *piece {
+                            Piece::Literal(ref x) => label.push_str(x),
+                            Piece::Api => label.push(api_flag),
+                            Piece::Session => {
+                                label.push_str(&sess_id.to_string());
+                                if decoys > 0 {
+                                    label.push(DECOY_SEPARATOR);
+                                    label.push_str(&decoys.to_string());
+                                }
+                            }
+                        }
+                    }
+                    parts.push(label);
+                }
+            }
+        }
+        parts.extend(host.parts().to_vec());
+        Domain::from_parts(parts)
+    }
+
+    /// Decode the data from a transfer query's domain name.
+    ///
+    /// Returns a tuple of the form (api_code, session_id, data).
+    pub fn decode<C: NameCode + ?Sized>(
+        &self,
+        code: &C,
+        name: &Domain,
+        host: &Domain
+    ) -> Result<(char, u16, Vec<u8>), String> {
+        let (fields, data_parts) = self.decode_fields(name, host)?;
+        Ok((fields.api.unwrap(), fields.session.unwrap(), code.decode_parts(&data_parts)?))
+    }
+
+    /// Get the API character and session ID of a transfer query's domain name,
+    /// without decoding the data.
+    pub fn session_id(&self, name: &Domain, host: &Domain) -> Option<(char, u16)> {
+        let (fields, _) = self.decode_fields(name, host).ok()?;
+        Some((fields.api.unwrap(), fields.session.unwrap()))
+    }
+
+    /// Get the largest number of bytes that `encode` can fit into a single
+    /// domain name under `host`, for any session ID.
+    pub fn max_data<C: NameCode + ?Sized>(
+        &self,
+        code: &C,
+        api_flag: char,
+        host: &Domain,
+        decoys: usize
+    ) -> usize {
+        let fits = |len: usize| {
+            self.encode(code, api_flag, u16::MAX, &vec![0xffu8; len], host, decoys).is_ok()
+        };
+        // A domain name is at most 255 bytes, so this is a safe upper bound.
+        let (mut low, mut high) = (1, 256);
+        if !fits(low) {
+            return 0;
+        }
+        while high - low > 1 {
+            let mid = (low + high) / 2;
+            if fits(mid) {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+
+    fn decode_fields(&self, name: &Domain, host: &Domain) -> Result<(Fields, Vec<String>), String> {
+        if !domain_ends_with(name, host) {
+            return Err("incorrect host domain".to_owned());
+        }
+        let mut parts = name.parts().to_vec();
+        parts.truncate(parts.len() - host.parts().len());
+        let num_fixed = self.labels.len() - 1;
+        if parts.len() < num_fixed + 1 {
+            return Err("not enough data".to_owned());
+        }
+        let num_after = self.labels.len() - self.data_index - 1;
+        let data_end = parts.len() - num_after;
+        let mut fields = Fields::default();
+        for (i, label) in self.labels.iter().enumerate() {
+            if let LabelTemplate::Pieces(ref pieces) = *label {
+                let part = if i < self.data_index {
+                    &parts[i]
+                } else {
+                    &parts[data_end + i - self.data_index - 1]
+                };
+                decode_label(pieces, part, &mut fields)?;
+            }
+        }
+        let data_start = self.data_index + fields.decoys;
+        if data_start >= data_end {
+            return Err("not enough data".to_owned());
+        }
+        Ok((fields, parts[data_start..data_end].to_vec()))
+    }
+}
+
+impl Default for NameTemplate {
+    fn default() -> NameTemplate {
+        "{api}{session}.{data}".parse().unwrap()
+    }
+}
+
+impl FromStr for NameTemplate {
+    type Err = String;
+
+    /// Parse and validate a template.
+    ///
+    /// A template is only accepted if every name it produces can be decoded.
+    fn from_str(s: &str) -> Result<NameTemplate, String> {
+        let mut labels = Vec::new();
+        for label in s.split('.') {
+            if label == "{data}" {
+                labels.push(LabelTemplate::Data);
+            } else {
+                labels.push(LabelTemplate::Pieces(parse_pieces(label)?));
+            }
+        }
+        let data_labels = labels.iter().filter(|x| **x == LabelTemplate::Data).count();
+        if data_labels != 1 {
+            return Err("template needs exactly one {data} label".to_owned());
+        }
+        let count = |piece: Piece| labels.iter().map(|x| match *x {
+            LabelTemplate::Pieces(ref pieces) => pieces.iter().filter(|y| **y == piece).count(),
+            LabelTemplate::Data => 0
+        }).sum::<usize>();
+        if count(Piece::Api) != 1 {
+            return Err("template needs exactly one {api} field".to_owned());
+        } else if count(Piece::Session) != 1 {
+            return Err("template needs exactly one {session} field".to_owned());
+        }
+        let data_index = labels.iter().position(|x| *x == LabelTemplate::Data).unwrap();
+        Ok(NameTemplate{labels, data_index})
+    }
+}
+
+impl Display for NameTemplate {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        for (i, label) in self.labels.iter().enumerate() {
+            if i != 0 {
+                write!(f, ".")?;
+            }
+            match *label {
+                LabelTemplate::Data => write!(f, "{{data}}")?,
+                LabelTemplate::Pieces(ref pieces) => for piece in pieces {
+                    match *piece {
+                        Piece::Literal(ref x) => write!(f, "{}", x)?,
+                        Piece::Api => write!(f, "{{api}}")?,
+                        Piece::Session => write!(f, "{{session}}")?
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_pieces(label: &str) -> Result<Vec<Piece>, String> {
+    if label.is_empty() {
+        return Err("empty label in template".to_owned());
+    }
+    let mut pieces = Vec::new();
+    let mut rest = label;
+    while !rest.is_empty() {
+        if rest.starts_with("{api}") {
+            pieces.push(Piece::Api);
+            rest = &rest[5..];
+        } else if rest.starts_with("{session}") {
+            pieces.push(Piece::Session);
+            rest = &rest[9..];
+        } else if rest.starts_with('{') {
+            return Err(format!("unknown field in template label: {}", label));
+        } else {
+            let end = rest.find('{').unwrap_or(rest.len());
+            let literal = domain_part_lowercase(&rest[..end]);
+            if !literal.bytes().all(|x| x.is_ascii_alphanumeric() || x == b'-') {
+                return Err(format!("template literals may only contain a-z0-9-: {}", literal));
+            }
+            pieces.push(Piece::Literal(literal));
+            rest = &rest[end..];
+        }
+    }
+    for window in pieces.windows(2) {
+        if let (Piece::Session, Piece::Literal(x)) = (&window[0], &window[1]) {
+            // The session ID and decoy count must end where the literal starts.
+            if x.starts_with(|c: char| c.is_ascii_digit() || c == DECOY_SEPARATOR) {
+                return Err(format!("literal after {{session}} may not start with a digit \
+                    or {}: {}", DECOY_SEPARATOR, x));
+            }
+        }
+    }
+    Ok(pieces)
+}
+
+fn decode_label(pieces: &[Piece], label: &str, fields: &mut Fields) -> Result<(), String> {
+    let label = domain_part_lowercase(label);
+    let mut rest = &label[..];
+    for piece in pieces {
+        match *piece {
+            Piece::Literal(ref x) => {
+                if !rest.starts_with(&x[..]) {
+                    return Err("query name does not match template".to_owned());
+                }
+                rest = &rest[x.len()..];
+            },
+            Piece::Api => {
+                let ch = rest.chars().next().ok_or("missing API character".to_owned())?;
+                fields.api = Some(ch);
+                rest = &rest[ch.len_utf8()..];
+            },
+            Piece::Session => {
+                let digits = rest.bytes().take_while(|x| x.is_ascii_digit()).count();
+                fields.session = Some(rest[..digits].parse()
+                    .map_err(|_| "invalid session ID".to_owned())?);
+                rest = &rest[digits..];
+                if rest.starts_with(DECOY_SEPARATOR) {
+                    rest = &rest[1..];
+                    let digits = rest.bytes().take_while(|x| x.is_ascii_digit()).count();
+                    fields.decoys = rest[..digits].parse()
+                        .map_err(|_| "invalid decoy count".to_owned())?;
+                    rest = &rest[digits..];
+                }
+            }
+        }
+    }
+    if rest.is_empty() {
+        Ok(())
+    } else {
+        Err("query name does not match template".to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use myo_proto::name_code::HexNameCode;
+
+    #[test]
+    fn template_validation() {
+        assert_eq!(NameTemplate::default().to_string(), "{api}{session}.{data}");
+        for valid in ["{data}.{api}{session}", "x-{api}.{data}.{session}q", "{session}a{api}.{data}"]
+            .iter()
+        {
+            assert_eq!(valid.parse::<NameTemplate>().unwrap().to_string(), *valid);
+        }
+        for invalid in ["{api}{session}", "{api}{session}.{data}.{data}", "{api}.{data}",
+            "{api}{session}{session}.{data}", "{api}{session}..{data}", "{api}{session}1.{data}",
+            "{api}{session}-x.{data}", "{api}{session}x{data}", "{api}{sess}.{data}",
+            "{api}{session}_.{data}"].iter()
+        {
+            assert!(invalid.parse::<NameTemplate>().is_err(), "accepted {}", invalid);
+        }
+    }
+
+    #[test]
+    fn template_round_trip() {
+        let host: Domain = "proxy.com".parse().unwrap();
+        let code = HexNameCode{};
+        let data: Vec<u8> = (0..100).collect();
+        for template in ["{api}{session}.{data}", "{data}.{api}{session}",
+            "abc.{session}x.{data}.{api}-z"].iter()
+        {
+            let template: NameTemplate = template.parse().unwrap();
+            for &decoys in [0, 2].iter() {
+                let name = template.encode(&code, 't', 1234, &data, &host, decoys).unwrap();
+                assert_eq!(template.decode(&code, &name, &host).unwrap(), ('t', 1234, data.clone()));
+                assert_eq!(template.session_id(&name, &host), Some(('t', 1234)));
+                let max = template.max_data(&code, 't', &host, decoys);
+                assert!(template.encode(&code, 't', 65535, &vec![0; max], &host, decoys).is_ok());
+                assert!(template.encode(&code, 't', 65535, &vec![0; max + 1], &host, decoys)
+                    .is_err());
+            }
+        }
+        let template: NameTemplate = "{data}.{api}{session}".parse().unwrap();
+        let name = "t12.00ff.proxy.com".parse().unwrap();
+        assert!(template.decode(&code, &name, &host).is_err());
+    }
+}
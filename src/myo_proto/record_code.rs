@@ -1,6 +1,27 @@
+use std::cmp;
+
 use dns_coding::{DecPacket, Decoder, EncPacket, Encoder};
 use dns_proto::{RecordBody, RecordType};
 
+/// Every record type and code identifier that `get_record_code` knows.
+pub const RECORD_CODES: [(RecordType, &str); 1] = [(RecordType::TXT, "raw")];
+
+/// Get every record type that has a `RecordCode`, in registry order.
+pub fn supported_record_types() -> Vec<RecordType> {
+    let mut result = Vec::new();
+    for &(record_type, _) in &RECORD_CODES {
+        if !result.contains(&record_type) {
+            result.push(record_type);
+        }
+    }
+    result
+}
+
+/// Get the identifiers of every `RecordCode` for a record type.
+pub fn supported_record_codes(record_type: RecordType) -> Vec<&'static str> {
+    RECORD_CODES.iter().filter(|x| x.0 == record_type).map(|x| x.1).collect()
+}
+
 /// Lookup the RecordCode for the given record type and code identifier.
 pub fn get_record_code(record_type: RecordType, name: &str) -> Option<Box<RecordCode>> {
     match record_type {
@@ -15,23 +36,145 @@ pub fn get_record_code(record_type: RecordType, name: &str) -> Option<Box<Record
     }
 }
 
+/// The largest DNS message, which is also the most a DNS over TCP frame can
+/// hold.
+pub const MAX_MESSAGE_SIZE: usize = 0xffff;
+
+/// The size of a DNS message header.
+const HEADER_SIZE: usize = 12;
+
+/// The size of a question, apart from its name.
+const QUESTION_OVERHEAD: usize = 4;
+
+/// The size of an answer that repeats the question's name, apart from its
+/// body. The name is compressed to a pointer.
+const ANSWER_OVERHEAD: usize = 2 + 10;
+
+/// The most answers that a response's data may be split across.
+pub const MAX_ANSWERS: usize = 16;
+
+/// Get the number of raw bytes that fit in a response, split with
+/// `encode_answers`.
+///
+/// # Arguments
+///
+/// * `record_type` - The type of the answer records.
+/// * `response_encoding` - The name of the `RecordCode` for the record type.
+/// * `host_len` - The length of the question's name on the wire, as given by
+///   `Domain::wire_len`. The name is at most 255 bytes.
+/// * `answers` - The number of answers, from 1 to `MAX_ANSWERS`.
+///
+/// This is 0 for unknown codecs.
+pub fn max_payload(
+    record_type: RecordType,
+    response_encoding: &str,
+    host_len: usize,
+    answers: usize
+) -> usize {
+    let code = match get_record_code(record_type, response_encoding) {
+        Some(code) => code,
+        None => return 0
+    };
+    let overhead = HEADER_SIZE + host_len + QUESTION_OVERHEAD + answers * ANSWER_OVERHEAD;
+    let body_len = MAX_MESSAGE_SIZE.saturating_sub(overhead);
+    if answers <= 1 {
+        code.max_payload(body_len)
+    } else {
+        // Each answer gives up a byte to its index.
+        code.max_payload(body_len / answers).saturating_sub(1) * answers
+    }
+}
+
+/// Split data into `answers` record bodies.
+///
+/// With more than one answer, each body holds a consecutive piece of the
+/// data after a byte with the piece's index. That way, the pieces can be put
+/// back in order after a resolver shuffles them, and no two records are
+/// identical, which a resolver might merge. The pieces are as even as
+/// possible, and some may be empty.
+pub fn encode_answers(
+    code: &dyn RecordCode,
+    data: &[u8],
+    answers: usize
+) -> Result<Vec<RecordBody>, String> {
+    if answers <= 1 {
+        return Ok(vec![code.encode_body(data)?]);
+    } else if answers > MAX_ANSWERS {
+        return Err(format!("too many answers: {}", answers));
+    }
+    let piece_len = data.len().div_ceil(answers);
+    (0..answers).map(|i| {
+        let start = cmp::min(i * piece_len, data.len());
+        let end = cmp::min(start + piece_len, data.len());
+        let mut piece = vec![i as u8];
+        piece.extend_from_slice(&data[start..end]);
+        code.encode_body(&piece)
+    }).collect()
+}
+
+/// Put the data from `encode_answers` back together.
+///
+/// Repeated bodies are ignored, but every index must be present exactly
+/// once.
+pub fn decode_answers(
+    code: &dyn RecordCode,
+    bodies: &[&RecordBody],
+    answers: usize
+) -> Result<Vec<u8>, String> {
+    if answers <= 1 {
+        return match bodies.first() {
+            Some(body) => code.decode_body(body),
+            None => Err("response has no matching answer".to_owned())
+        };
+    }
+    let mut pieces: Vec<Option<Vec<u8>>> = vec![None; answers];
+    for body in bodies {
+        let mut piece = code.decode_body(body)?;
+        if piece.is_empty() || piece[0] as usize >= answers {
+            return Err("invalid answer index".to_owned());
+        }
+        let index = piece.remove(0) as usize;
+        match pieces[index] {
+            Some(ref existing) if *existing != piece => {
+                return Err("response has conflicting answers".to_owned());
+            },
+            _ => pieces[index] = Some(piece)
+        }
+    }
+    let mut result = Vec::new();
+    for piece in pieces {
+        result.extend(piece.ok_or_else(|| "response is missing an answer".to_owned())?);
+    }
+    Ok(result)
+}
+
 /// A method of encoding raw data in DNS records.
 pub trait RecordCode {
     /// Encode the data into a record.
     fn encode_body(&self, data: &[u8]) -> Result<RecordBody, String>;
 
+    /// Get the number of raw bytes that fit in a record body of at most
+    /// `body_len` bytes.
+    fn max_payload(&self, body_len: usize) -> usize;
+
     /// Decode the data from a record.
     fn decode_body(&self, body: &RecordBody) -> Result<Vec<u8>, String>;
 }
 
 /// A RecordCode that puts raw data into TXT records.
+///
+/// The data is prefixed with a 16-bit length field, so that any trailing bytes
+/// added to the record by a resolver are ignored.
 pub struct RawTxtCode;
 
 impl RecordCode for RawTxtCode {
     fn encode_body(&self, data: &[u8]) -> Result<RecordBody, String> {
+        if data.len() > 0xffff {
+            return Err("data too long for record".to_owned());
+        }
         let mut result = EncPacket::new();
         let mut next_buf = Vec::new();
-        for x in data.iter() {
+        for x in (data.len() as u16).to_be_bytes().iter().chain(data.iter()) {
             next_buf.push(*x);
             if next_buf.len() == 255 {
                 255u8.dns_encode(&mut result)?;
@@ -46,17 +189,165 @@ impl RecordCode for RawTxtCode {
         Ok(RecordBody::Unknown(result.data().clone()))
     }
 
+    fn max_payload(&self, body_len: usize) -> usize {
+        // Each character string holds up to 255 bytes after its length byte.
+        let bytes = (body_len / 256) * 255 + (body_len % 256).saturating_sub(1);
+        cmp::min(bytes.saturating_sub(2), 0xffff)
+    }
+
     fn decode_body(&self, body: &RecordBody) -> Result<Vec<u8>, String> {
         if let &RecordBody::Unknown(ref data) = body {
             let mut packet = DecPacket::new(data.clone());
             let mut result = Vec::new();
-            while packet.remaining() > 0 {
+            while result.len() < 2 || result.len() < payload_len(&result) + 2 {
                 let field_len = u8::dns_decode(&mut packet)?;
                 result.extend(packet.decode_all::<u8>(field_len as usize)?);
             }
-            Ok(result)
+            let len = payload_len(&result);
+            Ok(result[2..(len + 2)].to_vec())
         } else {
             Err("unexpected record type".to_owned())
         }
     }
 }
+
+fn payload_len(data: &[u8]) -> usize {
+    ((data[0] as usize) << 8) | (data[1] as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dns_coding::dns_encode;
+    use dns_proto::{Domain, Message, Question, Record, RecordClass, RecordHeader};
+
+    #[test]
+    fn raw_txt_round_trip() {
+        let code = RawTxtCode{};
+        for size in &[0usize, 1, 253, 254, 300, 1000] {
+            let data: Vec<u8> = (0..*size).map(|x| (x * 7) as u8).collect();
+            let body = code.encode_body(&data).unwrap();
+            assert_eq!(code.decode_body(&body).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn registry_complete() {
+        for &(record_type, name) in &RECORD_CODES {
+            assert!(get_record_code(record_type, name).is_some());
+        }
+        let all_types = [RecordType::A, RecordType::NS, RecordType::CNAME, RecordType::SOA,
+            RecordType::PTR, RecordType::MX, RecordType::TXT, RecordType::AAAA,
+            RecordType::Unknown(65399)];
+        let names = ["raw", "b16", "b32", "b64", "hex", "txt"];
+        for &record_type in &all_types {
+            let codes = supported_record_codes(record_type);
+            assert_eq!(supported_record_types().contains(&record_type), !codes.is_empty());
+            for name in &names {
+                assert_eq!(get_record_code(record_type, name).is_some(), codes.contains(name));
+            }
+        }
+        assert_eq!(supported_record_types(), vec![RecordType::TXT]);
+    }
+
+    #[test]
+    fn raw_txt_max_payload() {
+        let code = RawTxtCode{};
+        for body_len in &[0usize, 1, 2, 3, 256, 257, 258, 1000] {
+            let max = code.max_payload(*body_len);
+            if *body_len >= 3 {
+                assert!(encoded_len(&code, max) <= *body_len);
+            }
+            assert!(encoded_len(&code, max + 1) > *body_len);
+        }
+        assert_eq!(code.max_payload(1000), 994);
+        assert_eq!(code.max_payload(0x20000), 0xffff);
+    }
+
+    #[test]
+    fn max_payload_fits_message() {
+        let domain: Domain = format!("{}.{}.com", "a".repeat(60), "b".repeat(60)).parse().unwrap();
+        let message = |size: usize, answers: usize| {
+            let mut message = Message::new_query(Question{
+                domain: domain.clone(),
+                record_type: RecordType::TXT,
+                record_class: RecordClass::IN
+            });
+            for body in encode_answers(&RawTxtCode{}, &vec![0u8; size], answers).unwrap() {
+                message.answers.push(Record{
+                    header: RecordHeader{
+                        domain: domain.clone(),
+                        record_type: RecordType::TXT,
+                        record_class: RecordClass::IN,
+                        ttl: 0
+                    },
+                    body
+                });
+            }
+            message.header.answer_count = answers as u16;
+            dns_encode(&message).unwrap().len()
+        };
+        for &answers in &[1, 2, 3, MAX_ANSWERS] {
+            let max = max_payload(RecordType::TXT, "raw", domain.wire_len(), answers);
+            assert!(max > 60000);
+            assert!(message(max, answers) <= MAX_MESSAGE_SIZE);
+            if answers == 1 {
+                assert!(message(max + 1, answers) > MAX_MESSAGE_SIZE);
+            }
+        }
+
+        // Only TXT records have a codec so far.
+        for record_type in &[RecordType::A, RecordType::AAAA, RecordType::CNAME, RecordType::MX] {
+            assert_eq!(max_payload(*record_type, "raw", domain.wire_len(), 1), 0);
+        }
+        assert_eq!(max_payload(RecordType::TXT, "b32", domain.wire_len(), 1), 0);
+    }
+
+    fn encoded_len(code: &RawTxtCode, size: usize) -> usize {
+        match code.encode_body(&vec![0u8; size]).unwrap() {
+            RecordBody::Unknown(raw) => raw.len(),
+            _ => panic!("expected raw body")
+        }
+    }
+
+    #[test]
+    fn split_answers() {
+        let code = RawTxtCode{};
+        let data: Vec<u8> = (0..10).collect();
+        let bodies = encode_answers(&code, &data, 4).unwrap();
+        assert_eq!(bodies.len(), 4);
+        assert_eq!(code.decode_body(&bodies[3]).unwrap(), vec![3, 9]);
+        let mut shuffled: Vec<&RecordBody> = bodies.iter().rev().collect();
+        assert_eq!(decode_answers(&code, &shuffled, 4).unwrap(), data);
+
+        // Repeats are fine, but not gaps or conflicts.
+        shuffled.push(&bodies[0]);
+        assert_eq!(decode_answers(&code, &shuffled, 4).unwrap(), data);
+        assert!(decode_answers(&code, &shuffled[1..], 4).is_err());
+        let other = code.encode_body(&[0, 1]).unwrap();
+        shuffled.push(&other);
+        assert!(decode_answers(&code, &shuffled, 4).is_err());
+        assert!(decode_answers(&code, &shuffled, 3).is_err());
+
+        // More answers than bytes leaves some empty.
+        let bodies = encode_answers(&code, &[7], 3).unwrap();
+        let refs: Vec<&RecordBody> = bodies.iter().collect();
+        assert_eq!(decode_answers(&code, &refs, 3).unwrap(), vec![7]);
+        let single = encode_answers(&code, &data, 1).unwrap();
+        assert_eq!(single, vec![code.encode_body(&data).unwrap()]);
+        assert!(encode_answers(&code, &data, MAX_ANSWERS + 1).is_err());
+    }
+
+    #[test]
+    fn raw_txt_trailing_padding() {
+        let code = RawTxtCode{};
+        let data = vec![1u8, 2, 3, 4, 5];
+        let mut padded = match code.encode_body(&data).unwrap() {
+            RecordBody::Unknown(raw) => raw,
+            _ => panic!("expected raw body")
+        };
+        // A stray character string, followed by a truncated one.
+        padded.extend(vec![3u8, 0xff, 0xfe, 0xfd, 50u8, 0]);
+        assert_eq!(code.decode_body(&RecordBody::Unknown(padded)).unwrap(), data);
+    }
+}
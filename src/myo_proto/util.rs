@@ -1,19 +1,26 @@
 use std::fmt::Write;
 
-use dns_proto::{Domain, Message};
+use dns_proto::{Domain, Message, RecordBody, RecordType};
 
-/// Check if the DNS message is a valid API call that starts with a prefix.
-pub fn is_api_query(m: &Message, prefix_char: char) -> bool {
-    let is_query = !m.header.is_response &&
+extern crate rand;
+use self::rand::{Rng, thread_rng};
+
+/// Check if the DNS message is a query with one question and no records.
+pub fn is_single_query(m: &Message) -> bool {
+    !m.header.is_response &&
         m.questions.len() == 1 &&
         m.answers.len() == 0 &&
         m.authorities.len() == 0 &&
-        m.additional.len() == 0;
-    if is_query {
+        m.additional.len() == 0
+}
+
+/// Check if the DNS message is a valid API call that starts with a prefix.
+pub fn is_api_query(m: &Message, prefix_char: char) -> bool {
+    if is_single_query(m) {
         let domain = &m.questions[0].domain;
-        if domain.parts().len() > 0 {
-            let first = &domain.parts()[0];
-            return domain_part_lowercase(first).chars().next().unwrap() == prefix_char;
+        if !domain.raw_parts().is_empty() {
+            let first = domain.raw_parts()[0][0];
+            return first.to_ascii_lowercase() as char == prefix_char;
         }
     }
     return false;
@@ -21,12 +28,13 @@ pub fn is_api_query(m: &Message, prefix_char: char) -> bool {
 
 /// Check if the domain ends with another domain, in a case-insensitive way.
 pub fn domain_ends_with(domain: &Domain, suffix: &Domain) -> bool {
-    if domain.parts().len() < suffix.parts().len() {
+    let (parts, suffix_parts) = (domain.raw_parts(), suffix.raw_parts());
+    if parts.len() < suffix_parts.len() {
         return false;
     }
-    let offset = domain.parts().len() - suffix.parts().len();
-    for i in 0..suffix.parts().len() {
-        if !domain_part_equal(&suffix.parts()[i], &domain.parts()[i + offset]) {
+    let offset = parts.len() - suffix_parts.len();
+    for i in 0..suffix_parts.len() {
+        if !suffix_parts[i].eq_ignore_ascii_case(&parts[i + offset]) {
             return false;
         }
     }
@@ -50,3 +58,240 @@ pub fn domain_part_lowercase(x: &str) -> String {
     }
     res
 }
+
+/// Randomly change the case of the letters in a domain name.
+///
+/// This is known as "0x20 encoding". Resolvers that preserve case echo the
+/// exact name back, which makes it harder to spoof responses.
+pub fn randomize_case(domain: &Domain) -> Domain {
+    let mut rng = thread_rng();
+    let parts = domain.raw_parts().iter().map(|label| {
+        label.iter().map(|ch| if ch.is_ascii_alphabetic() && rng.gen() {
+            *ch ^ 0x20
+        } else {
+            *ch
+        }).collect()
+    }).collect();
+    Domain::from_raw_parts(parts).unwrap()
+}
+
+/// Get the body of the answer that carries a response's payload.
+///
+/// Resolvers may reorder the answer section, repeat a record, or add records
+/// of other types (such as a CNAME they followed, or DNSSEC signatures). So
+/// rather than taking the first answer, this looks for answers of the
+/// question's type whose name is the question's or an alias of it, which
+/// must all have the same body. TTLs are ignored, since caches count them
+/// down. The authority and additional sections are never looked at.
+pub fn payload_answer(response: &Message) -> Result<&RecordBody, String> {
+    let bodies = payload_answers(response)?;
+    if bodies.iter().any(|x| *x != bodies[0]) {
+        return Err("response has conflicting answers".to_owned());
+    }
+    Ok(bodies[0])
+}
+
+/// Get the bodies of every answer that `payload_answer` would look at, for
+/// responses split across several answers with `encode_answers`.
+///
+/// Fails if there are none.
+pub fn payload_answers(response: &Message) -> Result<Vec<&RecordBody>, String> {
+    let question = response.questions.first().ok_or("response has no question".to_owned())?;
+    let names = answer_names(response, &question.domain);
+    let answers: Vec<&RecordBody> = response.answers.iter().filter(|x| {
+        x.header.record_type == question.record_type &&
+            x.header.record_class == question.record_class &&
+            names.iter().any(|name| domain_eq_ignore_case(name, &x.header.domain))
+    }).map(|x| &x.body).collect();
+    if answers.is_empty() {
+        return Err("response has no matching answer".to_owned());
+    }
+    Ok(answers)
+}
+
+/// Get a domain and every name that the answer section aliases it to with
+/// CNAME records.
+fn answer_names<'a>(response: &'a Message, domain: &'a Domain) -> Vec<&'a Domain> {
+    let mut names = vec![domain];
+    // Each CNAME adds at most one name, so this also stops alias loops.
+    for _ in 0..response.answers.len() {
+        let target = response.answers.iter().filter_map(|x| match x.body {
+            RecordBody::Domain(ref target) if x.header.record_type == RecordType::CNAME &&
+                names.iter().any(|name| domain_eq_ignore_case(name, &x.header.domain)) &&
+                !names.iter().any(|name| domain_eq_ignore_case(name, target)) => Some(target),
+            _ => None
+        }).next();
+        match target {
+            Some(target) => names.push(target),
+            None => break
+        }
+    }
+    names
+}
+
+fn domain_eq_ignore_case(d1: &Domain, d2: &Domain) -> bool {
+    d1.raw_parts().len() == d2.raw_parts().len() && domain_ends_with(d1, d2)
+}
+
+/// Randomly reorder the answer section of a message.
+pub fn shuffle_answers(m: &mut Message) {
+    thread_rng().shuffle(&mut m.answers);
+}
+
+/// Pick a TTL uniformly from `[base - jitter, base + jitter]`.
+///
+/// The range is clipped at 0 and at `max`.
+pub fn jittered_ttl(base: u32, jitter: u32, max: u32) -> u32 {
+    let low = base.saturating_sub(jitter) as u64;
+    let high = base as u64 + jitter as u64;
+    let ttl = if jitter == 0 {
+        low
+    } else {
+        thread_rng().gen_range(low, high + 1)
+    };
+    if ttl > max as u64 {
+        max
+    } else {
+        ttl as u32
+    }
+}
+
+/// Compute the CRC-32 (IEEE 802.3) of a sequence of bytes.
+pub fn crc32<I: Iterator<Item = u8>>(data: I) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dns_coding::{dns_decode, dns_encode};
+    use dns_proto::{Question, Record, RecordClass, RecordHeader};
+
+    #[test]
+    fn randomized_case() {
+        let domain: Domain = "abcdefghijklmnop.q-9.example.com".parse().unwrap();
+        let mut saw_change = false;
+        for _ in 0..10 {
+            let randomized = randomize_case(&domain);
+            assert!(domain_ends_with(&randomized, &domain));
+            assert!(domain_ends_with(&domain, &randomized));
+            saw_change |= randomized != domain;
+        }
+        assert!(saw_change);
+    }
+
+    #[test]
+    fn reordered_and_repeated_answers() {
+        let domain: Domain = "t1.abcd.proxy.com".parse().unwrap();
+        let mut response = Message::new_query(Question{
+            domain: domain.clone(),
+            record_type: RecordType::TXT,
+            record_class: RecordClass::IN
+        });
+        let record = |record_type, body: &[u8], ttl| Record{
+            header: RecordHeader{
+                domain: domain.clone(),
+                record_type,
+                record_class: RecordClass::IN,
+                ttl
+            },
+            body: RecordBody::Unknown(body.to_vec())
+        };
+        assert!(payload_answer(&response).is_err());
+
+        // An alias first, then the same answer twice with different TTLs.
+        response.answers.push(record(RecordType::CNAME, b"\x05alias\x00", 60));
+        response.answers.push(record(RecordType::TXT, b"\x03abc", 30));
+        response.answers.push(record(RecordType::TXT, b"\x03abc", 29));
+        assert_eq!(payload_answer(&response), Ok(&RecordBody::Unknown(b"\x03abc".to_vec())));
+        response.answers.reverse();
+        assert_eq!(payload_answer(&response), Ok(&RecordBody::Unknown(b"\x03abc".to_vec())));
+
+        response.answers.push(record(RecordType::TXT, b"\x03abd", 30));
+        assert!(payload_answer(&response).is_err());
+    }
+
+    #[test]
+    fn extra_sections_and_aliases() {
+        let domain: Domain = "t1.abcd.proxy.com".parse().unwrap();
+        let alias: Domain = "t1.cache.example".parse().unwrap();
+        let mut response = Message::new_query(Question{
+            domain: domain.clone(),
+            record_type: RecordType::TXT,
+            record_class: RecordClass::IN
+        });
+        response.header.is_response = true;
+        let record = |domain: &Domain, record_type, record_class, body| Record{
+            header: RecordHeader{
+                domain: domain.clone(),
+                record_type,
+                record_class,
+                ttl: 30
+            },
+            body
+        };
+        let payload = RecordBody::Unknown(b"\x03abc".to_vec());
+        let rrsig = RecordType::Unknown(46);
+        let opt = RecordType::Unknown(41);
+        response.answers = vec![
+            record(&"T1.ABCD.proxy.com".parse().unwrap(), RecordType::CNAME, RecordClass::IN,
+                RecordBody::Domain(alias.clone())),
+            record(&alias, rrsig, RecordClass::IN, RecordBody::Unknown(vec![0, 16, 8, 2])),
+            // A record for some other name, which a resolver shouldn't add.
+            record(&"other.proxy.com".parse().unwrap(), RecordType::TXT, RecordClass::IN,
+                RecordBody::Unknown(b"\x03bad".to_vec())),
+            record(&alias, RecordType::TXT, RecordClass::IN, payload.clone())
+        ];
+        response.authorities = vec![
+            record(&"proxy.com".parse().unwrap(), RecordType::NS, RecordClass::IN,
+                RecordBody::Domain("ns1.proxy.com".parse().unwrap())),
+            record(&domain, RecordType::TXT, RecordClass::IN,
+                RecordBody::Unknown(b"\x03bad".to_vec()))
+        ];
+        response.additional = vec![
+            record(&"ns1.proxy.com".parse().unwrap(), RecordType::A, RecordClass::IN,
+                RecordBody::A("1.2.3.4".parse().unwrap())),
+            record(&Domain::from_raw_parts(vec![]).unwrap(), opt, RecordClass::Unknown(1232),
+                RecordBody::Unknown(vec![]))
+        ];
+        response.header.answer_count = 4;
+        response.header.authority_count = 2;
+        response.header.additional_count = 2;
+        let decoded: Message = dns_decode(dns_encode(&response).unwrap()).unwrap();
+        assert_eq!(decoded, response);
+        assert_eq!(payload_answer(&decoded), Ok(&payload));
+
+        // Without the alias, the answer belongs to some other name.
+        response.answers.remove(0);
+        assert!(payload_answer(&response).is_err());
+    }
+
+    #[test]
+    fn crc32_known_value() {
+        assert_eq!(crc32(b"123456789".iter().cloned()), 0xcbf43926);
+    }
+
+    #[test]
+    fn ttl_jitter_bounds() {
+        assert_eq!(jittered_ttl(0, 0, 100), 0);
+        assert_eq!(jittered_ttl(500, 0, 100), 100);
+        for _ in 0..100 {
+            let ttl = jittered_ttl(2, 5, 100);
+            assert!(ttl <= 7);
+            let ttl = jittered_ttl(60, 10, 65);
+            assert!((50..=65).contains(&ttl));
+            assert!(jittered_ttl(u32::MAX, 10, u32::MAX) >= u32::MAX - 10);
+        }
+    }
+}
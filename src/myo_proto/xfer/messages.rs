@@ -1,11 +1,22 @@
-use dns_proto::Message;
-use myo_proto::util::is_api_query;
+use dns_proto::{Domain, Message};
+use myo_proto::api_chars::ApiChars;
+use myo_proto::name_template::NameTemplate;
+use myo_proto::util::is_single_query;
 
 /// Check if a DNS message is a transfer query, and get the session ID if so.
-pub fn xfer_query_session_id(query: &Message) -> Option<u16> {
-    if !is_api_query(query, 't') && !is_api_query(query, 'p') {
+pub fn xfer_query_session_id(
+    query: &Message,
+    host: &Domain,
+    api: &ApiChars,
+    template: &NameTemplate
+) -> Option<u16> {
+    if !is_single_query(query) {
         return None;
     }
-    let part: String = query.questions[0].domain.parts()[0].chars().skip(1).collect();
-    return part.parse().ok()
+    let (api_char, id) = template.session_id(&query.questions[0].domain, host)?;
+    if api_char == api.transfer || api_char == api.poll {
+        Some(id)
+    } else {
+        None
+    }
 }
@@ -5,7 +5,10 @@ mod wwr;
 mod messages;
 mod session;
 
-pub use self::types::{Ack, Chunk, Packet};
+pub use self::types::{Ack, Chunk, Control, Packet, FRAME_MAGIC, FRAME_OVERHEAD, FRAME_VERSION,
+    PADDING_OVERHEAD, frame_packet, pad_packet, unframe_packet, unpad_packet, unwrap_packet,
+    wrap_packet};
 pub use self::wwr::WwrState;
 pub use self::messages::xfer_query_session_id;
-pub use self::session::{handle_packet_in, next_packet_out};
+pub use self::session::{fill_send_buffer, handle_packet_in, next_packet_out,
+    next_packet_out_limited};
@@ -1,27 +1,44 @@
-use conn::TcpChunker;
+use conn::{ChunkSink, ChunkSource};
 use super::{Packet, WwrState};
 
-/// Feed an incoming packet into a WWR state machine and a TCP socket.
+/// Feed an incoming packet into a WWR state machine and a local connection,
+/// such as a `TcpChunker`.
 ///
-/// Automatically deals with backpressure from the TCP socket.
+/// Automatically deals with backpressure from the connection.
+///
+/// The connection is only told that the stream finished once all of the data
+/// before the EOF has been written to it. Chunks are merged before they are
+/// written, unless the connection is a datagram sink.
 ///
 /// Returns the number of bytes written to the connection.
-pub fn handle_packet_in(packet: Packet, state: &mut WwrState, conn: &mut TcpChunker) -> usize {
+pub fn handle_packet_in<C: ChunkSink + ?Sized>(
+    packet: Packet,
+    state: &mut WwrState,
+    conn: &mut C
+) -> usize {
     state.handle_ack(&packet.ack);
+    if let Some(control) = packet.control {
+        state.handle_control(&control);
+    }
     if conn.can_send() && packet.chunk.is_some() {
         let mut buffer = Vec::new();
+        let mut len = 0;
         let mut finished = false;
         for chunk in state.handle_chunk(packet.chunk.unwrap()) {
             if chunk.data.len() == 0 {
                 finished = true;
                 // Data past EOF is meaningless.
                 break;
+            }
+            len += chunk.data.len();
+            if conn.is_datagram() {
+                // Datagram sinks never apply backpressure.
+                conn.send(chunk.data);
             } else {
                 buffer.extend(chunk.data);
             }
         }
-        let len = buffer.len();
-        if len > 0 {
+        if !buffer.is_empty() {
             conn.send(buffer);
         }
         if finished {
@@ -33,12 +50,24 @@ pub fn handle_packet_in(packet: Packet, state: &mut WwrState, conn: &mut TcpChun
     }
 }
 
-/// Feed data from a TCP connection into a WWR state machine.
+/// Feed data from a local connection, such as a `TcpChunker`, into a WWR
+/// state machine.
 ///
 /// Produces the next packet to send on behalf of the WWR state.
 ///
 /// Returns the number of bytes read from the connection.
-pub fn next_packet_out(state: &mut WwrState, conn: &mut TcpChunker) -> (Packet, usize) {
+pub fn next_packet_out<C: ChunkSource + ?Sized>(
+    state: &mut WwrState,
+    conn: &mut C
+) -> (Packet, usize) {
+    next_packet_out_limited(state, conn, || true)
+}
+
+/// Move data from the connection into the outgoing buffer, while there is
+/// room for it.
+///
+/// Returns the number of bytes read from the connection.
+pub fn fill_send_buffer<C: ChunkSource + ?Sized>(state: &mut WwrState, conn: &mut C) -> usize {
     let mut bytes = 0;
     while state.send_buffer_space() > 0 {
         if let Some(data) = conn.recv() {
@@ -52,8 +81,241 @@ pub fn next_packet_out(state: &mut WwrState, conn: &mut TcpChunker) -> (Packet,
             break;
         }
     }
+    bytes
+}
+
+/// Like `next_packet_out`, but the packet only carries a chunk if
+/// `allow_chunk` returns true. It is only called when there is a chunk that
+/// could be sent, and a chunk that isn't allowed stays in line for the next
+/// packet.
+pub fn next_packet_out_limited<C, F>(
+    state: &mut WwrState,
+    conn: &mut C,
+    allow_chunk: F
+) -> (Packet, usize)
+    where C: ChunkSource + ?Sized, F: FnOnce() -> bool
+{
+    let bytes = fill_send_buffer(state, conn);
+    let control = state.next_send_control();
+    let chunk = if control.is_none() && state.has_send_chunk() && allow_chunk() {
+        state.next_send_chunk()
+    } else {
+        None
+    };
     (Packet{
         ack: state.next_send_ack(),
-        chunk: state.next_send_chunk()
+        chunk,
+        control
     }, bytes)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::sync::mpsc::channel;
+    use std::thread::{sleep, spawn};
+    use std::time::{Duration, Instant};
+
+    use conn::TcpChunker;
+
+    /// An in-memory connection.
+    struct MemConn {
+        incoming: VecDeque<Vec<u8>>,
+        outgoing: Vec<u8>,
+        finished: bool
+    }
+
+    impl ChunkSource for MemConn {
+        fn recv(&mut self) -> Option<Vec<u8>> {
+            self.incoming.pop_front()
+        }
+    }
+
+    impl ChunkSink for MemConn {
+        fn can_send(&mut self) -> bool {
+            !self.finished
+        }
+
+        fn send(&mut self, chunk: Vec<u8>) {
+            self.outgoing.extend(chunk);
+        }
+
+        fn send_finished(&mut self) {
+            self.finished = true;
+        }
+    }
+
+    #[test]
+    fn in_memory_transfer() {
+        let data: Vec<u8> = (0..200).map(|x| x as u8).collect();
+        let mut source = MemConn{
+            incoming: data.chunks(7).map(|x| x.to_vec()).chain(Some(Vec::new())).collect(),
+            outgoing: Vec::new(),
+            finished: false
+        };
+        let mut sink = MemConn{
+            incoming: vec![Vec::new()].into_iter().collect(),
+            outgoing: Vec::new(),
+            finished: false
+        };
+        let (mut sender, mut receiver) = (WwrState::new(2, 3, 0), WwrState::new(3, 2, 0));
+        for _ in 0..100 {
+            let (packet, _) = next_packet_out(&mut sender, &mut source);
+            handle_packet_in(packet, &mut receiver, &mut sink);
+            let (packet, _) = next_packet_out(&mut receiver, &mut sink);
+            handle_packet_in(packet, &mut sender, &mut source);
+        }
+        assert_eq!(sink.outgoing, data);
+        assert!(sink.finished);
+        assert!(source.finished);
+    }
+
+    #[test]
+    fn live_window_change() {
+        let data: Vec<u8> = (0..2000).map(|x| x as u8).collect();
+        let mut source = MemConn{
+            incoming: data.chunks(5).map(|x| x.to_vec()).chain(Some(Vec::new())).collect(),
+            outgoing: Vec::new(),
+            finished: false
+        };
+        let mut sink = MemConn{
+            incoming: vec![Vec::new()].into_iter().collect(),
+            outgoing: Vec::new(),
+            finished: false
+        };
+        let (mut sender, mut receiver) = (WwrState::new(2, 6, 0), WwrState::new(6, 2, 0));
+        let mut windows = Vec::new();
+        for round in 0..1000 {
+            match round {
+                40 => assert!(receiver.propose_recv_window(1)),
+                120 => assert!(receiver.propose_recv_window(6)),
+                _ => ()
+            }
+            let (packet, _) = next_packet_out(&mut sender, &mut source);
+            // Every other packet is lost on the way.
+            if round % 2 == 0 {
+                handle_packet_in(packet, &mut receiver, &mut sink);
+            }
+            let (packet, _) = next_packet_out(&mut receiver, &mut sink);
+            handle_packet_in(packet, &mut sender, &mut source);
+            windows.push((sender.send_window(), receiver.recv_window()));
+        }
+        assert!(sink.outgoing == data);
+        assert!(sink.finished);
+        assert!(windows.contains(&(1, 1)));
+        assert_eq!(windows.last(), Some(&(6, 6)));
+    }
+
+    #[test]
+    fn duplicate_packets() {
+        let mut sink = MemConn{incoming: VecDeque::new(), outgoing: Vec::new(), finished: false};
+        let (mut sender, mut receiver) = (WwrState::new(2, 4, 0), WwrState::new(4, 2, 0));
+        for data in &[vec![1, 2], vec![3], vec![4, 5, 6]] {
+            sender.push_send_buffer(data.clone());
+        }
+        let packets: Vec<_> = (0..3).map(|_| Packet{
+            ack: sender.next_send_ack(),
+            chunk: sender.next_send_chunk(),
+            control: None
+        }).collect();
+
+        // The resolver answers every query twice, and the second chunk's
+        // copies arrive on both sides of the first chunk.
+        for i in &[1, 0, 1, 0, 2, 2] {
+            handle_packet_in(packets[*i].clone(), &mut receiver, &mut sink);
+        }
+        assert_eq!(sink.outgoing, vec![1, 2, 3, 4, 5, 6]);
+        sender.handle_ack(&receiver.next_send_ack());
+        handle_packet_in(packets[2].clone(), &mut receiver, &mut sink);
+        assert_eq!(sink.outgoing, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    /// An in-memory datagram sink.
+    struct DatagramSink(Vec<Vec<u8>>);
+
+    impl ChunkSink for DatagramSink {
+        fn can_send(&mut self) -> bool {
+            true
+        }
+
+        fn send(&mut self, chunk: Vec<u8>) {
+            self.0.push(chunk);
+        }
+
+        fn send_finished(&mut self) {
+        }
+
+        fn is_datagram(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn datagram_boundaries() {
+        let (mut sender, mut receiver) = (WwrState::new(0, 3, 0), WwrState::new(3, 0, 0));
+        let datagrams = vec![vec![1, 2, 3], vec![4], vec![5, 6]];
+        for datagram in &datagrams {
+            sender.push_send_buffer(datagram.clone());
+        }
+        let chunks: Vec<_> = (0..3).map(|_| sender.next_send_chunk().unwrap()).collect();
+
+        // The first chunk arrives last, so all three are delivered at once.
+        let mut sink = DatagramSink(Vec::new());
+        for chunk in chunks.into_iter().rev() {
+            let packet = Packet{ack: sender.next_send_ack(), chunk: Some(chunk), control: None};
+            handle_packet_in(packet, &mut receiver, &mut sink);
+        }
+        assert_eq!(sink.0, datagrams);
+    }
+
+    #[test]
+    fn close_after_write() {
+        let data: Vec<u8> = (0..20000).map(|x| (x % 251) as u8).collect();
+        let (mut app, local) = tcp_pair();
+        let (remote, mut dest) = tcp_pair();
+        let mut local = TcpChunker::new(local, 100, 4, 4).unwrap();
+        let mut remote = TcpChunker::new(remote, 100, 4, 4).unwrap();
+
+        // The application writes everything and closes right away.
+        app.write_all(&data).unwrap();
+        drop(app);
+
+        let (result_sender, result_receiver) = channel();
+        spawn(move || {
+            let mut received = Vec::new();
+            dest.read_to_end(&mut received).unwrap();
+            dest.shutdown(Shutdown::Write).unwrap();
+            result_sender.send(received).unwrap();
+        });
+
+        let (mut sender, mut receiver) = (WwrState::new(4, 4, 0), WwrState::new(4, 4, 0));
+        let start = Instant::now();
+        let mut packets = 0;
+        while !sender.is_done() || !receiver.is_done() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            // Drop every third packet in each direction.
+            packets += 1;
+            let (packet, _) = next_packet_out(&mut sender, &mut local);
+            if packets % 3 != 0 {
+                handle_packet_in(packet, &mut receiver, &mut remote);
+            }
+            let (packet, _) = next_packet_out(&mut receiver, &mut remote);
+            if packets % 3 != 1 {
+                handle_packet_in(packet, &mut sender, &mut local);
+            }
+            sleep(Duration::from_millis(1));
+        }
+        let received = result_receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(received.len(), data.len());
+        assert!(received == data);
+    }
+
+    fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (stream, listener.accept().unwrap().0)
+    }
+}
@@ -1,8 +1,12 @@
 extern crate rand;
-use self::rand::thread_rng;
+use self::rand::{Rng, thread_rng};
 use self::rand::distributions::{Range, IndependentSample};
 
 use dns_coding::{DecPacket, Decoder, EncPacket, Encoder};
+use dns_proto::{MAX_DOMAIN_LEN, RecordType};
+use myo_proto::api_chars::ApiChars;
+use myo_proto::record_code::max_payload;
+use myo_proto::util::crc32;
 
 /// An acknowledgement of the chunks that have been seen in a window.
 #[derive(Clone, Debug, PartialEq)]
@@ -12,17 +16,37 @@ pub struct Ack {
 }
 
 /// A sequenced chunk of data.
+///
+/// The checksum covers both the sequence number and the data, allowing
+/// corruption in transit to be detected.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Chunk {
     pub seq: u32,
+    pub checksum: u32,
     pub data: Vec<u8>
 }
 
+/// A message that changes how the session runs, instead of carrying data.
+///
+/// See `WwrState::propose_recv_window` for the semantics.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Control {
+    /// Ask the remote end to use an outgoing window of `window` chunks.
+    ProposeWindow{serial: u8, window: u16},
+
+    /// Tell the remote end which outgoing window is in use since its
+    /// proposal with the same serial number.
+    AckWindow{serial: u8, window: u16}
+}
+
 /// A WWR communication payload.
+///
+/// A packet carries a chunk or a control message, but never both.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Packet {
     pub ack: Ack,
-    pub chunk: Option<Chunk>
+    pub chunk: Option<Chunk>,
+    pub control: Option<Control>
 }
 
 impl Ack {
@@ -32,7 +56,7 @@ impl Ack {
     /// acknowledgement bit-mask is.
     pub fn decode(packet: &mut DecPacket, window_size: u16) -> Result<Ack, String> {
         let window_start = Decoder::dns_decode(packet)?;
-        let num_bits = (window_size as usize) - 1;
+        let num_bits = (window_size as usize).saturating_sub(1);
         let num_bytes = if num_bits % 8 != 0 {
             num_bits / 8 + 1
         } else {
@@ -72,42 +96,160 @@ impl Encoder for Ack {
     }
 }
 
+impl Chunk {
+    /// Create a chunk with the correct checksum for its contents.
+    pub fn new(seq: u32, data: Vec<u8>) -> Chunk {
+        let checksum = chunk_checksum(seq, &data);
+        Chunk{seq, checksum, data}
+    }
+
+    /// Check if the checksum matches the chunk's contents.
+    pub fn is_valid(&self) -> bool {
+        self.checksum == chunk_checksum(self.seq, &self.data)
+    }
+}
+
 impl Decoder for Chunk {
     fn dns_decode(packet: &mut DecPacket) -> Result<Chunk, String> {
         let seq = Decoder::dns_decode(packet)?;
+        let checksum = Decoder::dns_decode(packet)?;
         let remaining = packet.remaining();
         let data = packet.read_bytes(remaining)?;
-        Ok(Chunk{seq: seq, data: data})
+        Ok(Chunk{seq, checksum, data})
     }
 }
 
 impl Encoder for Chunk {
     fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), String> {
         self.seq.dns_encode(packet)?;
+        self.checksum.dns_encode(packet)?;
         self.data.dns_encode(packet)
     }
 }
 
+/// The number of bytes in an encoded `Control`.
+///
+/// This is less than `CHUNK_HEADER_LEN`, so a response can carry either one
+/// without saying which.
+const CONTROL_LEN: usize = 4;
+
+/// The number of bytes in an encoded chunk besides its data.
+const CHUNK_HEADER_LEN: usize = 8;
+
+/// The number of bytes of random data in a poll query.
+const POLL_NONCE_LEN: usize = 8;
+
+const CONTROL_PROPOSE_WINDOW: u8 = 1;
+const CONTROL_ACK_WINDOW: u8 = 2;
+
+impl Decoder for Control {
+    fn dns_decode(packet: &mut DecPacket) -> Result<Control, String> {
+        let kind = u8::dns_decode(packet)?;
+        let serial = Decoder::dns_decode(packet)?;
+        let window = Decoder::dns_decode(packet)?;
+        match kind {
+            CONTROL_PROPOSE_WINDOW => Ok(Control::ProposeWindow{serial, window}),
+            CONTROL_ACK_WINDOW => Ok(Control::AckWindow{serial, window}),
+            _ => Err(format!("unknown control message: {}", kind))
+        }
+    }
+}
+
+impl Encoder for Control {
+    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), String> {
+        match *self {
+            Control::ProposeWindow{serial, window} => {
+                encode_all!(packet, CONTROL_PROPOSE_WINDOW, serial, window)
+            },
+            Control::AckWindow{serial, window} => {
+                encode_all!(packet, CONTROL_ACK_WINDOW, serial, window)
+            }
+        }
+    }
+}
+
 impl Packet {
     /// Encode the `Packet` as a transfer query.
     ///
     /// Returns a tuple (api_code, data), where api_code is used to specify the
     /// kind of transfer packet, and data is to be encoded in the domain name.
-    pub fn encode_query(&self) -> Result<(char, Vec<u8>), String> {
+    pub fn encode_query(&self, api: &ApiChars) -> Result<(char, Vec<u8>), String> {
+        self.encode_query_with_rng(api, &mut thread_rng())
+    }
+
+    /// Like `encode_query`, but take the random bytes of polls from `rng`.
+    ///
+    /// This is for targets where `thread_rng` has no entropy source, such as
+    /// `wasm32-unknown-unknown`.
+    pub fn encode_query_with_rng<R: Rng>(
+        &self,
+        api: &ApiChars,
+        rng: &mut R
+    ) -> Result<(char, Vec<u8>), String> {
+        self.check_contents()?;
         let mut enc_packet = EncPacket::new();
         self.ack.dns_encode(&mut enc_packet)?;
         let api_code = if let &Some(ref chunk) = &self.chunk {
             chunk.dns_encode(&mut enc_packet)?;
-            't'
+            api.transfer
         } else {
-            let mut rng = thread_rng();
             let range = Range::new(0u64, 0xffffffffffffffffu64);
-            range.ind_sample(&mut rng).dns_encode(&mut enc_packet)?;
-            'p'
+            range.ind_sample(rng).dns_encode(&mut enc_packet)?;
+            if let Some(control) = self.control {
+                control.dns_encode(&mut enc_packet)?;
+            }
+            api.poll
         };
         Ok((api_code, enc_packet.data().clone()))
     }
 
+    /// Get the number of bytes a transfer query uses on top of its chunk
+    /// data.
+    ///
+    /// The `window_size` is the window being acknowledged, i.e. the remote
+    /// end's outgoing window size.
+    pub fn query_overhead(window_size: u16) -> usize {
+        let mask_bits = (window_size as usize).saturating_sub(1);
+        // Window start, window mask, chunk sequence, and chunk checksum.
+        4 + mask_bits.div_ceil(8) + 4 + 4
+    }
+
+    /// Get the largest response MTU (chunk size) for which a transfer
+    /// response still fits in a DNS message, whatever the query's name.
+    ///
+    /// # Arguments
+    ///
+    /// * `record_type` - The type of the answer record.
+    /// * `response_encoding` - The name of the `RecordCode` for the record type.
+    /// * `query_window` - The client's outgoing window size.
+    /// * `padded` - Whether transfer packets are padded.
+    /// * `framed` - Whether transfer packets are framed.
+    /// * `answers` - The number of answers that each response is split across.
+    pub fn max_response_mtu(
+        record_type: RecordType,
+        response_encoding: &str,
+        query_window: u16,
+        padded: bool,
+        framed: bool,
+        answers: usize
+    ) -> usize {
+        let mut overhead = Packet::query_overhead(query_window);
+        if padded {
+            overhead += PADDING_OVERHEAD;
+        }
+        if framed {
+            overhead += FRAME_OVERHEAD;
+        }
+        max_payload(record_type, response_encoding, MAX_DOMAIN_LEN, answers)
+            .saturating_sub(overhead)
+    }
+
+    /// Get the size that `pad_packet` fills packets up to, so that a packet
+    /// with a full chunk of `mtu` bytes needs no padding.
+    pub fn padded_size(window_size: u16, mtu: u16) -> usize {
+        Packet::query_overhead(window_size) + mtu as usize + PADDING_OVERHEAD
+    }
+
     /// Decode a transfer query into a `Packet`.
     ///
     /// # Arguments
@@ -115,28 +257,41 @@ impl Packet {
     /// * `data` - The raw data that was encoded in the domain name.
     /// * `window_size` - This end's outgoing window size.
     /// * `api_code` - The API code accompanying this query.
-    pub fn decode_query(data: &[u8], window_size: u16, api_code: char) -> Result<Packet, String> {
+    /// * `api` - The API characters in use.
+    pub fn decode_query(
+        data: &[u8],
+        window_size: u16,
+        api_code: char,
+        api: &ApiChars
+    ) -> Result<Packet, String> {
         let mut packet = DecPacket::new(data.to_vec());
-        if api_code != 't' && api_code != 'p' {
+        if api_code != api.transfer && api_code != api.poll {
             return Err(format!("unknown API code: {}", api_code));
         }
         let ack = Ack::decode(&mut packet, window_size)?;
-        Ok(Packet{
-            ack: ack,
-            chunk: if api_code == 't' {
-                Some(Decoder::dns_decode(&mut packet)?)
-            } else {
-                None
-            }
-        })
+        if api_code == api.transfer {
+            return Ok(Packet{ack, chunk: Some(Decoder::dns_decode(&mut packet)?),
+                control: None});
+        }
+        // Polls from before control messages may end anywhere after the ack.
+        let control = if packet.remaining() > POLL_NONCE_LEN {
+            packet.read_bytes(POLL_NONCE_LEN)?;
+            Some(Decoder::dns_decode(&mut packet)?)
+        } else {
+            None
+        };
+        Ok(Packet{ack, chunk: None, control})
     }
 
     /// Encode the `Packet` for transmission in a DNS response.
     pub fn encode_response(&self) -> Result<Vec<u8>, String> {
+        self.check_contents()?;
         let mut packet = EncPacket::new();
         self.ack.dns_encode(&mut packet)?;
         if let &Some(ref chunk) = &self.chunk {
             chunk.dns_encode(&mut packet)?;
+        } else if let Some(control) = self.control {
+            control.dns_encode(&mut packet)?;
         }
         Ok(packet.data().clone())
     }
@@ -150,13 +305,238 @@ impl Packet {
     pub fn decode_response(data: &[u8], window_size: u16) -> Result<Packet, String> {
         let mut packet = DecPacket::new(data.to_vec());
         let ack = Ack::decode(&mut packet, window_size)?;
-        Ok(Packet{
-            ack: ack,
-            chunk: if packet.remaining() > 0 {
-                Some(Decoder::dns_decode(&mut packet)?)
-            } else {
-                None
+        let (chunk, control) = match packet.remaining() {
+            0 => (None, None),
+            CONTROL_LEN => (None, Some(Decoder::dns_decode(&mut packet)?)),
+            x if x >= CHUNK_HEADER_LEN => (Some(Decoder::dns_decode(&mut packet)?), None),
+            _ => return Err(packet.error("truncated chunk"))
+        };
+        Ok(Packet{ack, chunk, control})
+    }
+
+    fn check_contents(&self) -> Result<(), String> {
+        if self.chunk.is_some() && self.control.is_some() {
+            Err("packet has both a chunk and a control message".to_owned())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The number of bytes that `pad_packet` adds besides the padding itself.
+pub const PADDING_OVERHEAD: usize = 2;
+
+/// Prefix encoded packet data with its length and fill it up to `size` bytes
+/// with random padding, so that every packet looks the same size.
+///
+/// Data that is already too long for `size` only gets the length prefix.
+pub fn pad_packet(data: &[u8], size: usize) -> Result<Vec<u8>, String> {
+    let mut packet = EncPacket::new();
+    if data.len() > 0xffff {
+        return Err("packet too long to pad".to_owned());
+    }
+    (data.len() as u16).dns_encode(&mut packet)?;
+    let mut result = packet.data().clone();
+    result.extend_from_slice(data);
+    let mut rng = thread_rng();
+    while result.len() < size {
+        result.push(rng.gen());
+    }
+    Ok(result)
+}
+
+/// Get the packet data back out of the result of `pad_packet`.
+pub fn unpad_packet(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut packet = DecPacket::new(data.to_vec());
+    let len: u16 = Decoder::dns_decode(&mut packet)?;
+    if (len as usize) > packet.remaining() {
+        return Err(packet.error("padded length exceeds packet"));
+    }
+    packet.read_bytes(len as usize)
+}
+
+/// The bytes that start every framed packet. See `frame_packet`.
+pub const FRAME_MAGIC: [u8; 2] = [0x6d, 0x79];
+
+/// The version of the frame layout written by `frame_packet`.
+pub const FRAME_VERSION: u8 = 1;
+
+/// The flag in a frame header that marks the contents as padded.
+const FRAME_FLAG_PADDED: u8 = 1;
+
+/// The number of bytes that `frame_packet` adds.
+pub const FRAME_OVERHEAD: usize = 6;
+
+/// Wrap packet data in a self-describing container, so that captured traffic
+/// can be parsed without knowing what was negotiated.
+///
+/// The container is `FRAME_MAGIC`, `FRAME_VERSION`, a flags byte, and the
+/// length of the contents as a u16, followed by the contents. If `padded`
+/// is set, the contents are the result of `pad_packet`.
+pub fn frame_packet(data: &[u8], padded: bool) -> Result<Vec<u8>, String> {
+    if data.len() > 0xffff {
+        return Err("packet too long to frame".to_owned());
+    }
+    let flags = if padded { FRAME_FLAG_PADDED } else { 0 };
+    let mut packet = EncPacket::new();
+    encode_all!(&mut packet, FRAME_MAGIC[0], FRAME_MAGIC[1], FRAME_VERSION, flags,
+        data.len() as u16)?;
+    let mut result = packet.data().clone();
+    result.extend_from_slice(data);
+    Ok(result)
+}
+
+/// Get the contents of a container from `frame_packet`, and whether they
+/// are padded.
+pub fn unframe_packet(data: &[u8]) -> Result<(Vec<u8>, bool), String> {
+    let mut packet = DecPacket::new(data.to_vec());
+    let magic = [u8::dns_decode(&mut packet)?, u8::dns_decode(&mut packet)?];
+    if magic != FRAME_MAGIC {
+        return Err(packet.error("bad frame magic"));
+    }
+    let version = u8::dns_decode(&mut packet)?;
+    if version != FRAME_VERSION {
+        return Err(format!("unsupported frame version: {}", version));
+    }
+    let flags = u8::dns_decode(&mut packet)?;
+    if flags & !FRAME_FLAG_PADDED != 0 {
+        return Err(format!("unknown frame flags: {:#x}", flags));
+    }
+    let len = u16::dns_decode(&mut packet)? as usize;
+    if len != packet.remaining() {
+        return Err(packet.error("frame length does not match packet"));
+    }
+    Ok((packet.read_bytes(len)?, flags & FRAME_FLAG_PADDED != 0))
+}
+
+/// Apply the negotiated padding and framing to encoded packet data.
+///
+/// Packets are padded to `padded_size` if it is set, and then framed if
+/// `framed` is set.
+pub fn wrap_packet(
+    data: Vec<u8>,
+    padded_size: Option<usize>,
+    framed: bool
+) -> Result<Vec<u8>, String> {
+    let data = match padded_size {
+        Some(size) => pad_packet(&data, size)?,
+        None => data
+    };
+    if framed { frame_packet(&data, padded_size.is_some()) } else { Ok(data) }
+}
+
+/// Undo `wrap_packet`.
+pub fn unwrap_packet(data: Vec<u8>, padded: bool, framed: bool) -> Result<Vec<u8>, String> {
+    let data = if framed {
+        let (contents, frame_padded) = unframe_packet(&data)?;
+        if frame_padded != padded {
+            return Err("frame padding flag does not match the session".to_owned());
+        }
+        contents
+    } else {
+        data
+    };
+    if padded { unpad_packet(&data) } else { Ok(data) }
+}
+
+fn chunk_checksum(seq: u32, data: &[u8]) -> u32 {
+    let seq_bytes = [(seq >> 24) as u8, (seq >> 16) as u8, (seq >> 8) as u8, seq as u8];
+    crc32(seq_bytes.iter().chain(data.iter()).cloned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_round_trip() {
+        let packet = Packet{
+            ack: Ack{window_start: 3, window_mask: vec![true, false, true]},
+            chunk: Some(Chunk::new(5, vec![1, 2, 3])),
+            control: None
+        };
+        let data = packet.encode_response().unwrap();
+        let decoded = Packet::decode_response(&data, 4).unwrap();
+        assert_eq!(decoded, packet);
+        assert!(decoded.chunk.unwrap().is_valid());
+    }
+
+    #[test]
+    fn control_round_trip() {
+        let api = ApiChars::default();
+        let ack = Ack{window_start: 3, window_mask: vec![true, false, true]};
+        for control in &[Control::ProposeWindow{serial: 1, window: 4},
+            Control::AckWindow{serial: 255, window: 0x1234}]
+        {
+            let packet = Packet{ack: ack.clone(), chunk: None, control: Some(*control)};
+            let data = packet.encode_response().unwrap();
+            assert_eq!(data.len(), 5 + CONTROL_LEN);
+            assert_eq!(Packet::decode_response(&data, 4).unwrap(), packet);
+
+            let (api_code, data) = packet.encode_query(&api).unwrap();
+            assert_eq!(api_code, api.poll);
+            assert_eq!(Packet::decode_query(&data, 4, api_code, &api).unwrap(), packet);
+        }
+
+        // A plain poll still decodes, and an empty chunk is not a control.
+        let packet = Packet{ack: ack.clone(), chunk: None, control: None};
+        let (api_code, data) = packet.encode_query(&api).unwrap();
+        assert_eq!(Packet::decode_query(&data, 4, api_code, &api).unwrap(), packet);
+        let packet = Packet{ack: ack.clone(), chunk: Some(Chunk::new(9, Vec::new())),
+            control: None};
+        let data = packet.encode_response().unwrap();
+        assert_eq!(Packet::decode_response(&data, 4).unwrap(), packet);
+
+        let packet = Packet{ack, chunk: Some(Chunk::new(9, vec![1])),
+            control: Some(Control::AckWindow{serial: 1, window: 1})};
+        assert!(packet.encode_response().is_err());
+        assert!(packet.encode_query(&api).is_err());
+    }
+
+    #[test]
+    fn padding_round_trip() {
+        let (full, empty) = (vec![7; 20], Vec::new());
+        let padded = pad_packet(&full, 30).unwrap();
+        assert_eq!(padded.len(), 30);
+        assert_eq!(&padded[..4], &[0, 20, 7, 7]);
+        assert_eq!(unpad_packet(&padded).unwrap(), full);
+        assert_eq!(pad_packet(&empty, 30).unwrap().len(), 30);
+        assert_eq!(unpad_packet(&pad_packet(&empty, 30).unwrap()).unwrap(), empty);
+
+        // Padding never truncates.
+        assert_eq!(pad_packet(&full, 10).unwrap().len(), 20 + PADDING_OVERHEAD);
+        assert!(unpad_packet(&[0, 5, 1, 2]).is_err());
+        assert!(unpad_packet(&[0]).is_err());
+    }
+
+    #[test]
+    fn framing_round_trip() {
+        let data = vec![1, 2, 3];
+        let framed = frame_packet(&data, false).unwrap();
+        assert_eq!(framed, vec![0x6d, 0x79, 1, 0, 0, 3, 1, 2, 3]);
+        assert_eq!(unframe_packet(&framed).unwrap(), (data.clone(), false));
+        assert_eq!(framed.len(), data.len() + FRAME_OVERHEAD);
+
+        for bad in &[vec![0x6d, 0x78, 1, 0, 0, 0], vec![0x6d, 0x79, 2, 0, 0, 0],
+            vec![0x6d, 0x79, 1, 2, 0, 0], vec![0x6d, 0x79, 1, 0, 0, 1],
+            vec![0x6d, 0x79, 1, 0, 0, 0, 9], vec![0x6d, 0x79, 1]]
+        {
+            assert!(unframe_packet(bad).is_err(), "accepted {:?}", bad);
+        }
+
+        for &(padded_size, framed) in &[(None, false), (None, true), (Some(20), false),
+            (Some(20), true)]
+        {
+            let wrapped = wrap_packet(data.clone(), padded_size, framed).unwrap();
+            if framed {
+                assert_eq!(&wrapped[..2], &FRAME_MAGIC);
+                assert_eq!(unframe_packet(&wrapped).unwrap().1, padded_size.is_some());
+            }
+            let unwrapped = unwrap_packet(wrapped.clone(), padded_size.is_some(), framed);
+            assert_eq!(unwrapped.unwrap(), data);
+            if framed {
+                assert!(unwrap_packet(wrapped, padded_size.is_none(), framed).is_err());
             }
-        })
+        }
     }
 }
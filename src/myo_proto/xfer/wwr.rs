@@ -1,20 +1,52 @@
+use std::collections::VecDeque;
 use std::num::Wrapping;
 
-use super::{Ack, Chunk};
+use super::{Ack, Chunk, Control};
+
+/// The fewest packets to handle between two changes to the outgoing window.
+///
+/// Proposals that arrive sooner are ignored until the cooldown is over, so
+/// a remote end can't make the window thrash, and acknowledgements of the
+/// chunks sent under the old window get a chance to come back.
+const WINDOW_CHANGE_COOLDOWN: u32 = 32;
 
 /// A finite state machine representing an endpoint's view of a WWR session.
 pub struct WwrState {
     in_win_size: u16,
     in_win_start: u32,
     in_received: Vec<Chunk>,
+    in_received_bytes: usize,
     in_eof: bool,
+    in_chunk_limit: Option<usize>,
 
     out_win_size: u16,
     out_next_seq: u32,
     out_win_start: u32,
     out_pending: Vec<Chunk>,
+    out_sent: Vec<bool>,
+    out_acked: Vec<Chunk>,
+    out_acked_bytes: usize,
+    out_backlog: VecDeque<Vec<u8>>,
+    out_backlog_bytes: usize,
+    out_chunk_limit: Option<usize>,
+    out_pending_bytes: usize,
+    out_retransmissions: u64,
+    out_byte_limit: Option<usize>,
     out_round_robin: usize,
-    out_eof: bool
+    out_eof: bool,
+
+    // The window sizes from `new` fix the size of acknowledgements, so the
+    // windows in use can shrink below them but never grow past them.
+    in_win_limit: u16,
+    out_win_limit: u16,
+    own_proposal: Option<(u8, u16)>,
+    own_serial: u8,
+    proposal_sent_last: bool,
+    remote_serial: Option<u8>,
+    pending_control_ack: Option<Control>,
+    packets_since_change: u32,
+
+    memory_limit: Option<usize>
 }
 
 impl WwrState {
@@ -25,31 +57,199 @@ impl WwrState {
     /// * `in_win_size` - The other end's outgoing window size.
     /// * `out_win_size` - Our outgoing window size.
     /// * `seq_start` - The initial sequence number for both directions.
+    ///
+    /// A window size of 0 disables that direction entirely: the stream starts
+    /// out at EOF, and packets in that direction only carry acknowledgements.
     pub fn new(in_win_size: u16, out_win_size: u16, seq_start: u32) -> WwrState {
         WwrState{
             in_win_size: in_win_size,
             in_win_start: seq_start,
             in_received: Vec::new(),
-            in_eof: false,
+            in_received_bytes: 0,
+            in_eof: in_win_size == 0,
+            in_chunk_limit: None,
 
             out_win_size: out_win_size,
             out_next_seq: seq_start,
             out_win_start: seq_start,
             out_pending: Vec::new(),
+            out_sent: Vec::new(),
+            out_acked: Vec::new(),
+            out_acked_bytes: 0,
+            out_backlog: VecDeque::new(),
+            out_backlog_bytes: 0,
+            out_chunk_limit: None,
+            out_pending_bytes: 0,
+            out_retransmissions: 0,
+            out_byte_limit: None,
             out_round_robin: 0,
-            out_eof: false
+            out_eof: out_win_size == 0,
+
+            in_win_limit: in_win_size,
+            out_win_limit: out_win_size,
+            own_proposal: None,
+            own_serial: 0,
+            proposal_sent_last: false,
+            remote_serial: None,
+            pending_control_ack: None,
+            packets_since_change: WINDOW_CHANGE_COOLDOWN,
+
+            memory_limit: None
+        }
+    }
+
+    /// Limit the number of unacknowledged outgoing bytes.
+    ///
+    /// Once the limit is reached, `send_buffer_space` reports no space even if
+    /// the window has room. A single chunk may overshoot the limit, so that
+    /// chunks larger than the limit can still be sent.
+    pub fn set_send_byte_limit(&mut self, limit: Option<usize>) {
+        self.out_byte_limit = limit;
+    }
+
+    /// Limit the size of incoming chunks, typically to the negotiated MTU.
+    ///
+    /// Larger chunks are dropped by `handle_chunk` without being acknowledged.
+    pub fn set_recv_chunk_limit(&mut self, limit: Option<usize>) {
+        self.in_chunk_limit = limit;
+    }
+
+    /// Limit the number of bytes buffered in both directions.
+    ///
+    /// Once the limit is reached, `send_buffer_space` reports no space, and
+    /// `handle_chunk` drops chunks that arrive out of order without
+    /// acknowledging them, so the remote end sends them again later. The
+    /// chunk at the start of the incoming window is always accepted, since it
+    /// is passed on right away and frees up the chunks queued behind it. Like
+    /// the send byte limit, a single chunk may overshoot the limit.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory_limit = limit;
+    }
+
+    /// Get the number of data bytes held for either direction: outgoing
+    /// chunks that may still have to be sent, and incoming chunks that
+    /// arrived out of order.
+    pub fn buffered_bytes(&self) -> usize {
+        self.out_pending_bytes + self.out_acked_bytes + self.out_backlog_bytes +
+            self.in_received_bytes
+    }
+
+    /// Ask the remote end to use an outgoing window of `window` chunks, which
+    /// is our incoming window.
+    ///
+    /// The proposal rides on outgoing packets in place of every other chunk
+    /// until the remote end acknowledges it. The remote end limits the
+    /// window to the size it was created with, and replies with the window
+    /// it actually uses, which `recv_window` reports from then on. It ignores
+    /// proposals that are older than one it already applied, and applies at
+    /// most one every `WINDOW_CHANGE_COOLDOWN` packets.
+    ///
+    /// Returns false without proposing anything if a proposal is still
+    /// waiting for its acknowledgement, or if `window` is 0 or larger than
+    /// the incoming window this state was created with.
+    pub fn propose_recv_window(&mut self, window: u16) -> bool {
+        if self.own_proposal.is_some() || window == 0 || window > self.in_win_size {
+            return false;
         }
+        self.own_serial = self.own_serial.wrapping_add(1);
+        self.own_proposal = Some((self.own_serial, window));
+        self.proposal_sent_last = false;
+        true
+    }
+
+    /// Get the outgoing window the remote end last agreed to use.
+    pub fn recv_window(&self) -> u16 {
+        self.in_win_limit
+    }
+
+    /// Get the number of chunks that may currently be in flight.
+    pub fn send_window(&self) -> u16 {
+        self.out_win_limit
+    }
+
+    /// Get a control message to send in the next packet, in place of a chunk.
+    ///
+    /// Like `next_send_chunk`, this should only be called once per packet.
+    pub fn next_send_control(&mut self) -> Option<Control> {
+        if let Some(ack) = self.pending_control_ack.take() {
+            return Some(ack);
+        }
+        match self.own_proposal {
+            // Alternate with chunks, so that a proposal the remote end is
+            // holding off on doesn't stall the stream.
+            Some((serial, window)) if !self.proposal_sent_last || self.out_pending.is_empty() => {
+                self.proposal_sent_last = true;
+                Some(Control::ProposeWindow{serial, window})
+            },
+            _ => {
+                self.proposal_sent_last = false;
+                None
+            }
+        }
+    }
+
+    /// Handle a control message from the remote end.
+    pub fn handle_control(&mut self, control: &Control) {
+        match *control {
+            Control::ProposeWindow{serial, window} => {
+                if self.remote_serial == Some(serial) {
+                    // Our acknowledgement was lost, so send it again.
+                    self.pending_control_ack = Some(Control::AckWindow{
+                        serial,
+                        window: self.out_win_limit
+                    });
+                    return;
+                }
+                if let Some(last) = self.remote_serial {
+                    if (serial.wrapping_sub(last) as i8) < 0 {
+                        return;
+                    }
+                }
+                if self.packets_since_change < WINDOW_CHANGE_COOLDOWN {
+                    return;
+                }
+                self.out_win_limit = window.max(1).min(self.out_win_size);
+                self.remote_serial = Some(serial);
+                self.packets_since_change = 0;
+                self.pending_control_ack = Some(Control::AckWindow{
+                    serial,
+                    window: self.out_win_limit
+                });
+                self.fill_from_backlog();
+            },
+            Control::AckWindow{serial, window} => {
+                if self.own_proposal.map(|x| x.0) == Some(serial) {
+                    self.in_win_limit = window.min(self.in_win_size);
+                    self.own_proposal = None;
+                }
+            }
+        }
+    }
+
+    /// Get the number of outgoing bytes which have not been acknowledged.
+    pub fn send_buffer_bytes(&self) -> usize {
+        self.out_pending_bytes
+    }
+
+    /// Get the number of times an outgoing chunk has been sent again.
+    pub fn retransmissions(&self) -> u64 {
+        self.out_retransmissions
     }
 
     /// Check if both the incoming and outgoing streams have EOF'd.
     pub fn is_done(&self) -> bool {
-        self.in_eof && self.out_eof && self.out_pending.len() == 0
+        self.in_eof && self.out_eof && self.out_pending.len() == 0 && self.out_backlog.is_empty()
+    }
+
+    /// Check if the outgoing stream has EOF'd and every chunk was acknowledged.
+    pub fn is_send_done(&self) -> bool {
+        self.out_eof && self.out_pending.is_empty() && self.out_backlog.is_empty()
     }
 
     /// Get the current acknowledgement packet.
     pub fn next_send_ack(&self) -> Ack {
         let mut bit_mask = Vec::new();
-        for _ in 0..(self.in_win_size - 1) {
+        for _ in 0..self.in_win_size.saturating_sub(1) {
             bit_mask.push(false);
         }
         for chunk in &self.in_received {
@@ -74,15 +274,33 @@ impl WwrState {
             self.out_round_robin = 0;
         }
         let chunk = self.out_pending[self.out_round_robin].clone();
+        if self.out_sent[self.out_round_robin] {
+            self.out_retransmissions += 1;
+        }
+        self.out_sent[self.out_round_robin] = true;
         self.out_round_robin += 1;
         Some(chunk)
     }
 
+    /// Check if `next_send_chunk` would return a chunk.
+    pub fn has_send_chunk(&self) -> bool {
+        !self.out_pending.is_empty()
+    }
+
     /// Get the number of chunks that can be pushed by `push_send_buffer`.
     pub fn send_buffer_space(&self) -> usize {
-        let win_used = (Wrapping(self.out_next_seq) - Wrapping(self.out_win_start)).0;
-        assert!((win_used as usize) <= (self.out_win_size as usize));
-        (self.out_win_size as usize) - (win_used as usize)
+        if !self.out_backlog.is_empty() {
+            return 0;
+        }
+        if let Some(limit) = self.out_byte_limit {
+            if self.out_pending_bytes >= limit {
+                return 0;
+            }
+        }
+        if self.is_over_memory_limit(0) {
+            return 0;
+        }
+        self.window_space()
     }
 
     /// Add an outgoing chunk to the end of the outgoing data stream.
@@ -90,12 +308,20 @@ impl WwrState {
     /// You should check `send_buffer_space` before calling this.
     /// You should not call this after calling `push_eof`.
     /// You should not pass an empty chunk.
+    ///
+    /// Data larger than the limit set by `restart_send` is split into several
+    /// chunks, which enter the window as it frees up.
     pub fn push_send_buffer(&mut self, data: Vec<u8>) {
         assert!(!self.out_eof);
         assert!(self.send_buffer_space() > 0);
-        let chunk = Chunk{seq: self.out_next_seq, data: data};
-        self.out_next_seq = (Wrapping(self.out_next_seq) + Wrapping(1)).0;
-        self.out_pending.push(chunk);
+        match self.out_chunk_limit {
+            Some(limit) if data.len() > limit => {
+                self.out_backlog_bytes += data.len();
+                self.out_backlog.extend(data.chunks(limit).map(|x| x.to_vec()));
+                self.fill_from_backlog();
+            },
+            _ => self.push_chunk(data)
+        }
     }
 
     /// Push an EOF to the end of the outgoing data stream.
@@ -112,11 +338,17 @@ impl WwrState {
 
     /// Handle an acknowledgement from the remote end.
     pub fn handle_ack(&mut self, ack: &Ack) {
-        assert_eq!(ack.window_mask.len(), (self.out_win_size - 1) as usize);
+        assert_eq!(ack.window_mask.len(), self.out_win_size.saturating_sub(1) as usize);
+        self.packets_since_change = self.packets_since_change.saturating_add(1);
 
         if ack.window_start == self.out_next_seq {
             self.out_pending.clear();
+            self.out_sent.clear();
+            self.out_acked.clear();
+            self.out_acked_bytes = 0;
+            self.out_pending_bytes = 0;
             self.out_win_start = self.out_next_seq;
+            self.fill_from_backlog();
             return;
         }
         let residual = (Wrapping(ack.window_start) - Wrapping(self.out_win_start)).0;
@@ -131,12 +363,86 @@ impl WwrState {
             self.remove_out_seq(residual_seq);
         }
         self.out_win_start = ack.window_start;
+        let win_used = (Wrapping(self.out_next_seq) - Wrapping(self.out_win_start)).0;
+        let win_start = self.out_win_start;
+        let mut freed = 0;
+        self.out_acked.retain(|x| {
+            let keep = (Wrapping(x.seq) - Wrapping(win_start)).0 < win_used;
+            if !keep {
+                freed += x.data.len();
+            }
+            keep
+        });
+        self.out_acked_bytes -= freed;
         for (i, b) in (&ack.window_mask).into_iter().enumerate() {
             if *b {
-                self.remove_out_seq((Wrapping(ack.window_start) + Wrapping(i as u32) +
-                    Wrapping(1)).0);
+                let seq = (Wrapping(ack.window_start) + Wrapping(i as u32) + Wrapping(1)).0;
+                if let Some(chunk) = self.remove_out_seq(seq) {
+                    // Kept in case the stream is restarted before the window passes it.
+                    self.out_acked_bytes += chunk.data.len();
+                    self.out_acked.push(chunk);
+                }
             }
         }
+        self.fill_from_backlog();
+    }
+
+    /// Restart the outgoing stream at `window_start`, using chunks of at most
+    /// `chunk_limit` bytes from now on.
+    ///
+    /// This is for when the remote end throws away every chunk from
+    /// `window_start` on, including ones it acknowledged out of order, and
+    /// wants smaller chunks (e.g. after the response MTU is renegotiated).
+    /// The data from `window_start` on is split again and renumbered starting
+    /// at `window_start`.
+    ///
+    /// Returns false without changing anything if `window_start` is outside
+    /// of the current window.
+    pub fn restart_send(&mut self, window_start: u32, chunk_limit: usize) -> bool {
+        assert!(chunk_limit > 0);
+        let win_used = (Wrapping(self.out_next_seq) - Wrapping(self.out_win_start)).0;
+        let offset = (Wrapping(window_start) - Wrapping(self.out_win_start)).0;
+        if offset > win_used {
+            return false;
+        }
+        let mut chunks: Vec<Chunk> = self.out_pending.drain(..).chain(self.out_acked.drain(..))
+            .filter(|x| (Wrapping(x.seq) - Wrapping(window_start)).0 < win_used - offset)
+            .collect();
+        chunks.sort_by_key(|x| (Wrapping(x.seq) - Wrapping(window_start)).0);
+        let old_backlog = self.out_backlog.drain(..).collect::<Vec<_>>();
+        self.out_acked_bytes = 0;
+        self.out_backlog_bytes = 0;
+        for data in chunks.into_iter().map(|x| x.data).chain(old_backlog) {
+            self.out_backlog_bytes += data.len();
+            if data.is_empty() {
+                self.out_backlog.push_back(data);
+            } else {
+                self.out_backlog.extend(data.chunks(chunk_limit).map(|x| x.to_vec()));
+            }
+        }
+        self.out_sent.clear();
+        self.out_pending_bytes = 0;
+        self.out_round_robin = 0;
+        self.out_win_start = window_start;
+        self.out_next_seq = window_start;
+        self.out_chunk_limit = Some(chunk_limit);
+        self.fill_from_backlog();
+        true
+    }
+
+    /// Forget the incoming chunks that were received out of order.
+    ///
+    /// The remote end must be told to `restart_send` at the current window
+    /// start, since the chunks after it will be numbered differently.
+    pub fn restart_recv(&mut self) {
+        self.in_received.clear();
+        self.in_received_bytes = 0;
+    }
+
+    /// Get the start of the incoming window, i.e. the next chunk that will be
+    /// passed on.
+    pub fn recv_window_start(&self) -> u32 {
+        self.in_win_start
     }
 
     /// Handle an incoming chunk from the remote end.
@@ -148,9 +454,15 @@ impl WwrState {
     /// If an empty chunk is included in the result, it is the last chunk and
     /// signals an EOF.
     pub fn handle_chunk(&mut self, chunk: Chunk) -> Vec<Chunk> {
-        if self.in_eof {
+        if self.in_eof || !chunk.is_valid() {
+            // A corrupted chunk is treated as lost, so it will be retransmitted.
             return Vec::new();
         }
+        if let Some(limit) = self.in_chunk_limit {
+            if chunk.data.len() > limit {
+                return Vec::new();
+            }
+        }
 
         let chunk_offset = (Wrapping(chunk.seq) - Wrapping(self.in_win_start)).0;
         if chunk_offset >= self.in_win_size as u32 {
@@ -160,6 +472,10 @@ impl WwrState {
         if (&self.in_received).into_iter().any(|x| x.seq == chunk.seq) {
             return Vec::new();
         }
+        if chunk_offset > 0 && self.is_over_memory_limit(chunk.data.len()) {
+            return Vec::new();
+        }
+        self.in_received_bytes += chunk.data.len();
         self.in_received.push(chunk);
 
         let mut result = Vec::new();
@@ -169,6 +485,7 @@ impl WwrState {
             for i in 0..self.in_received.len() {
                 if self.in_received[i].seq == self.in_win_start {
                     let chunk = self.in_received.swap_remove(i);
+                    self.in_received_bytes -= chunk.data.len();
                     let is_eof = chunk.data.len() == 0;
                     result.push(chunk);
                     self.in_win_start = (Wrapping(self.in_win_start) + Wrapping(1)).0;
@@ -184,16 +501,57 @@ impl WwrState {
         result
     }
 
-    fn remove_out_seq(&mut self, seq: u32) {
+    /// Check if buffering `extra` more bytes would exceed the memory limit.
+    ///
+    /// With no extra bytes, this checks if the limit is already reached.
+    fn is_over_memory_limit(&self, extra: usize) -> bool {
+        match self.memory_limit {
+            Some(limit) if extra == 0 => self.buffered_bytes() >= limit,
+            Some(limit) => self.buffered_bytes() > 0 && self.buffered_bytes() + extra > limit,
+            None => false
+        }
+    }
+
+    fn window_space(&self) -> usize {
+        let win_used = (Wrapping(self.out_next_seq) - Wrapping(self.out_win_start)).0;
+        assert!((win_used as usize) <= (self.out_win_size as usize));
+        // Chunks sent before the window shrank may still overfill it.
+        (self.out_win_limit as usize).saturating_sub(win_used as usize)
+    }
+
+    fn push_chunk(&mut self, data: Vec<u8>) {
+        let chunk = Chunk::new(self.out_next_seq, data);
+        self.out_next_seq = (Wrapping(self.out_next_seq) + Wrapping(1)).0;
+        self.out_pending_bytes += chunk.data.len();
+        self.out_pending.push(chunk);
+        self.out_sent.push(false);
+    }
+
+    fn fill_from_backlog(&mut self) {
+        while self.window_space() > 0 {
+            match self.out_backlog.pop_front() {
+                Some(data) => {
+                    self.out_backlog_bytes -= data.len();
+                    self.push_chunk(data)
+                },
+                None => break
+            }
+        }
+    }
+
+    fn remove_out_seq(&mut self, seq: u32) -> Option<Chunk> {
         for i in (0..self.out_pending.len()).into_iter().rev() {
             if self.out_pending[i].seq == seq {
-                self.out_pending.remove(i);
+                let chunk = self.out_pending.remove(i);
+                self.out_sent.remove(i);
+                self.out_pending_bytes -= chunk.data.len();
                 if self.out_round_robin > i {
                     self.out_round_robin -= 1;
                 }
-                return;
+                return Some(chunk);
             }
         }
+        None
     }
 }
 
@@ -293,6 +651,275 @@ mod tests {
         assert_eq!(state.send_buffer_space(), 5);
     }
 
+    #[test]
+    fn window_proposals() {
+        let (mut client, mut server) = (WwrState::new(8, 4, 0), WwrState::new(4, 8, 0));
+        assert!(!client.propose_recv_window(0));
+        assert!(!client.propose_recv_window(9));
+        assert!(client.propose_recv_window(2));
+        assert!(!client.propose_recv_window(3));
+
+        for i in 0..8 {
+            server.push_send_buffer(vec![i]);
+        }
+        let proposal = client.next_send_control().unwrap();
+        assert_eq!(proposal, Control::ProposeWindow{serial: 1, window: 2});
+        server.handle_control(&proposal);
+        assert_eq!(server.send_window(), 2);
+        let ack = server.next_send_control().unwrap();
+        assert_eq!(ack, Control::AckWindow{serial: 1, window: 2});
+        assert_eq!(server.next_send_control(), None);
+        assert_eq!(client.recv_window(), 8);
+        client.handle_control(&ack);
+        assert_eq!(client.recv_window(), 2);
+
+        // Chunks sent before the window shrank stay in flight, but no new
+        // ones are let in until the window has room.
+        let chunks: Vec<_> = (0..8).map(|_| server.next_send_chunk().unwrap()).collect();
+        for chunk in chunks.into_iter().skip(1) {
+            client.handle_chunk(chunk);
+        }
+        server.handle_ack(&client.next_send_ack());
+        assert_eq!(server.send_buffer_space(), 0);
+
+        // The ack is sent again if the proposal comes again.
+        server.handle_control(&proposal);
+        assert_eq!(server.next_send_control(), Some(ack));
+
+        // Proposals right after a change wait for the cooldown.
+        assert!(client.propose_recv_window(8));
+        let proposal = client.next_send_control().unwrap();
+        server.handle_control(&proposal);
+        assert_eq!(server.next_send_control(), None);
+        assert_eq!(server.send_window(), 2);
+        for _ in 0..WINDOW_CHANGE_COOLDOWN {
+            server.handle_ack(&client.next_send_ack());
+        }
+        server.handle_control(&proposal);
+        assert_eq!(server.send_window(), 8);
+        client.handle_control(&server.next_send_control().unwrap());
+        assert_eq!(client.recv_window(), 8);
+
+        // A delayed copy of an older proposal can't undo a newer one.
+        for _ in 0..WINDOW_CHANGE_COOLDOWN {
+            server.handle_ack(&client.next_send_ack());
+        }
+        server.handle_control(&Control::ProposeWindow{serial: 1, window: 2});
+        assert_eq!(server.next_send_control(), None);
+        assert_eq!(server.send_window(), 8);
+    }
+
+    #[test]
+    fn send_byte_limit() {
+        let mut state = WwrState::new(4, 8, 0);
+        state.set_send_byte_limit(Some(10));
+        for i in 0..5 {
+            assert!(state.send_buffer_space() > 0);
+            state.push_send_buffer(vec![i, i]);
+        }
+        assert_eq!(state.send_buffer_bytes(), 10);
+        assert_eq!(state.send_buffer_space(), 0);
+
+        // Acknowledging a chunk frees up its bytes.
+        state.handle_ack(&Ack{
+            window_start: 0,
+            window_mask: vec![true, false, false, false, false, false, false]
+        });
+        assert_eq!(state.send_buffer_bytes(), 8);
+        assert_eq!(state.send_buffer_space(), 3);
+
+        // A large chunk may overshoot the limit.
+        state.push_send_buffer(vec![0; 100]);
+        assert_eq!(state.send_buffer_space(), 0);
+    }
+
+    /// Check the running byte counts against the buffers they track.
+    fn check_byte_counts(state: &WwrState) {
+        let acked: usize = state.out_acked.iter().map(|x| x.data.len()).sum();
+        let backlog: usize = state.out_backlog.iter().map(|x| x.len()).sum();
+        let received: usize = state.in_received.iter().map(|x| x.data.len()).sum();
+        assert_eq!(state.out_acked_bytes, acked);
+        assert_eq!(state.out_backlog_bytes, backlog);
+        assert_eq!(state.in_received_bytes, received);
+    }
+
+    #[test]
+    fn buffered_byte_counts() {
+        let (mut sender, mut receiver) = (WwrState::new(8, 8, 0), WwrState::new(8, 8, 0));
+        assert!(sender.restart_send(0, 7));
+        let mut pushed = 0;
+        for round in 0..40 {
+            while pushed < 40 && sender.send_buffer_space() > 0 {
+                sender.push_send_buffer(vec![pushed as u8; 5 + pushed % 11]);
+                check_byte_counts(&sender);
+                pushed += 1;
+            }
+            let mut chunks: Vec<Chunk> = (0..8).filter_map(|_| sender.next_send_chunk())
+                .collect();
+            check_byte_counts(&sender);
+            // Deliver out of order and lose one chunk now and then.
+            chunks.reverse();
+            if round % 3 == 0 && chunks.len() > 1 {
+                chunks.remove(1);
+            }
+            for chunk in chunks {
+                receiver.handle_chunk(chunk);
+                check_byte_counts(&receiver);
+            }
+            sender.handle_ack(&receiver.next_send_ack());
+            check_byte_counts(&sender);
+            if round % 5 == 4 {
+                let start = receiver.next_send_ack().window_start;
+                assert!(sender.restart_send(start, 4));
+                receiver.restart_recv();
+                check_byte_counts(&sender);
+                check_byte_counts(&receiver);
+            }
+        }
+        assert_eq!(receiver.in_received_bytes, 0);
+    }
+
+    #[test]
+    fn memory_limit_under_load() {
+        let limit = 64;
+        let (mut sender, mut receiver) = (WwrState::new(32, 32, 0), WwrState::new(32, 32, 0));
+        receiver.set_memory_limit(Some(limit));
+        let data: Vec<u8> = (0..2000).map(|x| x as u8).collect();
+        let mut to_send = data.chunks(10);
+        let mut received = Vec::new();
+        let mut peak = 0;
+        for round in 0..1000 {
+            while sender.send_buffer_space() > 0 {
+                match to_send.next() {
+                    Some(x) => sender.push_send_buffer(x.to_vec()),
+                    None => break
+                }
+            }
+            // The receiver also has data of its own to send, which counts
+            // against the same budget.
+            while receiver.send_buffer_space() > 0 {
+                receiver.push_send_buffer(vec![0; 10]);
+            }
+            let mut chunks: Vec<Chunk> = (0..32).filter_map(|_| sender.next_send_chunk())
+                .collect();
+            // Deliver the window backwards, so every chunk but the first is
+            // out of order.
+            chunks.reverse();
+            for chunk in chunks {
+                for x in receiver.handle_chunk(chunk) {
+                    received.extend(x.data);
+                }
+                peak = ::std::cmp::max(peak, receiver.buffered_bytes());
+            }
+            sender.handle_ack(&receiver.next_send_ack());
+            if round % 4 == 0 {
+                // Now and then, the sender acknowledges the receiver's data.
+                receiver.handle_ack(&sender.next_send_ack());
+            }
+            if received.len() == data.len() {
+                break;
+            }
+        }
+        assert_eq!(received, data);
+        assert!(peak <= limit + 10);
+    }
+
+    #[test]
+    fn corrupted_chunk() {
+        let (mut sender, mut receiver) = (WwrState::new(2, 2, 0), WwrState::new(2, 2, 0));
+        sender.push_send_buffer(vec![1, 2, 3]);
+
+        let mut chunk = sender.next_send_chunk().unwrap();
+        assert_eq!(sender.retransmissions(), 0);
+        chunk.data[1] ^= 0x10;
+        assert!(!chunk.is_valid());
+        assert_eq!(receiver.handle_chunk(chunk), Vec::new());
+
+        // The corrupted chunk is not acknowledged, so it gets retransmitted.
+        let ack = receiver.next_send_ack();
+        assert_eq!(ack.window_start, 0);
+        sender.handle_ack(&ack);
+        let chunk = sender.next_send_chunk().unwrap();
+        assert_eq!(chunk.seq, 0);
+        assert_eq!(sender.retransmissions(), 1);
+        assert_eq!(receiver.handle_chunk(chunk.clone()), vec![chunk]);
+    }
+
+    #[test]
+    fn oversized_chunk() {
+        let mut state = WwrState::new(2, 2, 0);
+        state.set_recv_chunk_limit(Some(4));
+        assert_eq!(state.handle_chunk(Chunk::new(0, vec![1, 2, 3, 4, 5])), Vec::new());
+        assert_eq!(state.next_send_ack().window_start, 0);
+        assert_eq!(state.next_send_ack().window_mask, vec![false]);
+
+        let chunk = Chunk::new(0, vec![1, 2, 3, 4]);
+        assert_eq!(state.handle_chunk(chunk.clone()), vec![chunk]);
+    }
+
+    #[test]
+    fn ack_only_downstream() {
+        let (mut client, mut server) = (WwrState::new(0, 2, 0), WwrState::new(2, 0, 0));
+        assert_eq!(server.send_buffer_space(), 0);
+        assert!(server.next_send_chunk().is_none());
+        assert_eq!(client.next_send_ack().window_mask, Vec::new());
+
+        client.push_send_buffer(vec![1, 2, 3]);
+        let chunk = client.next_send_chunk().unwrap();
+        assert_eq!(server.handle_chunk(chunk.clone()), vec![chunk]);
+        client.handle_ack(&server.next_send_ack());
+        assert_eq!(client.send_buffer_bytes(), 0);
+
+        // Stray downstream chunks are ignored.
+        assert_eq!(client.handle_chunk(Chunk::new(0, vec![4])), Vec::new());
+
+        client.push_eof();
+        server.push_eof();
+        let chunk = client.next_send_chunk().unwrap();
+        assert_eq!(server.handle_chunk(chunk.clone()), vec![chunk]);
+        client.handle_ack(&server.next_send_ack());
+        assert!(client.is_done());
+        assert!(server.is_done());
+    }
+
+    #[test]
+    fn restart_with_smaller_chunks() {
+        let (mut sender, mut receiver) = (WwrState::new(2, 5, 10), WwrState::new(5, 2, 10));
+        for i in 0..4 {
+            sender.push_send_buffer(vec![i; 6]);
+        }
+        sender.push_eof();
+        let chunks: Vec<Chunk> = (0..5).map(|_| sender.next_send_chunk().unwrap()).collect();
+
+        // The second chunk is lost, and the third is acknowledged out of order.
+        assert_eq!(receiver.handle_chunk(chunks[0].clone()).len(), 1);
+        assert_eq!(receiver.handle_chunk(chunks[2].clone()).len(), 0);
+        sender.handle_ack(&receiver.next_send_ack());
+        assert!(!sender.restart_send(100, 4));
+
+        receiver.restart_recv();
+        assert!(sender.restart_send(receiver.recv_window_start(), 4));
+        assert_eq!(sender.send_buffer_space(), 0);
+        let mut data = Vec::new();
+        let mut finished = false;
+        for _ in 0..100 {
+            let chunk = sender.next_send_chunk().unwrap();
+            assert!(chunk.data.len() <= 4);
+            for chunk in receiver.handle_chunk(chunk) {
+                finished = chunk.data.is_empty();
+                data.extend(chunk.data);
+            }
+            sender.handle_ack(&receiver.next_send_ack());
+            if finished {
+                break;
+            }
+        }
+        assert!(finished);
+        assert_eq!(data, [vec![1; 6], vec![2; 6], vec![3; 6]].concat());
+        assert_eq!(receiver.recv_window_start(), 11 + 3 * 2 + 1);
+        assert_eq!(sender.send_buffer_bytes(), 0);
+    }
+
     fn trivial_endpoint() -> WwrState {
         WwrState::new(1, 1, 0)
     }
@@ -342,10 +969,7 @@ mod tests {
     }
 
     fn basic_eof(endpoint: &mut WwrState) {
-        let empty_chunk = Chunk{
-            seq: endpoint.next_send_ack().window_start,
-            data: Vec::new()
-        };
+        let empty_chunk = Chunk::new(endpoint.next_send_ack().window_start, Vec::new());
         assert_eq!(endpoint.handle_chunk(empty_chunk.clone()), vec![empty_chunk]);
 
         // We've gotten an EOF, but still haven't sent an EOF.
@@ -357,6 +981,7 @@ mod tests {
 
         // We've sent an EOF, but haven't gotten an ACK.
         assert!(!endpoint.is_done());
+        assert!(!endpoint.is_send_done());
 
         let ack = Ack{
             window_start: (Wrapping(next_chunk.seq) + Wrapping(1)).0,
@@ -364,20 +989,18 @@ mod tests {
         };
         endpoint.handle_ack(&ack);
         assert!(endpoint.is_done());
+        assert!(endpoint.is_send_done());
     }
 
     fn windowed_eof(endpoint: &mut WwrState) {
-        let empty_chunk = Chunk{
-            seq: (Wrapping(endpoint.next_send_ack().window_start) + Wrapping(1)).0,
-            data: Vec::new()
-        };
+        let empty_chunk = Chunk::new(
+            (Wrapping(endpoint.next_send_ack().window_start) + Wrapping(1)).0,
+            Vec::new()
+        );
         assert_eq!(endpoint.handle_chunk(empty_chunk.clone()).len(), 0);
         assert!(!endpoint.is_done());
 
-        let data_chunk = Chunk{
-            seq: endpoint.next_send_ack().window_start,
-            data: vec![1, 2, 3]
-        };
+        let data_chunk = Chunk::new(endpoint.next_send_ack().window_start, vec![1, 2, 3]);
         assert_eq!(endpoint.handle_chunk(data_chunk.clone()), vec![data_chunk, empty_chunk]);
 
         // We've gotten an EOF, but still haven't sent an EOF.
@@ -417,10 +1040,10 @@ mod tests {
         let mut final_chunks = Vec::new();
         let mut window_mask = endpoint.next_send_ack().window_mask;
         for i in (0u32..win_size).into_iter().rev() {
-            let chunk = Chunk{
-                seq: (Wrapping(start_seq) + Wrapping(i)).0,
-                data: vec![((i + 17) & 0xff) as u8]
-            };
+            let chunk = Chunk::new(
+                (Wrapping(start_seq) + Wrapping(i)).0,
+                vec![((i + 17) & 0xff) as u8]
+            );
             final_chunks.insert(0, chunk.clone());
             let chunks = endpoint.handle_chunk(chunk);
             if i != 0 {
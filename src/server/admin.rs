@@ -0,0 +1,167 @@
+//! An administrative interface for listing and closing sessions at runtime.
+//!
+//! Administrators connect to a Unix socket and send two lines: the admin
+//! password, followed by a command. The server writes a response and closes
+//! the connection. The supported commands are:
+//!
+//! * `list` - describe every active session, one per line.
+//! * `close <id>` - close the session with the given ID.
+
+use std::str::FromStr;
+use std::sync::mpsc::{Receiver, Sender};
+
+use myodine::server::Server;
+
+/// A command sent over the admin interface.
+#[derive(Debug, PartialEq)]
+pub enum AdminCommand {
+    List,
+    Close(u16)
+}
+
+impl FromStr for AdminCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AdminCommand, String> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        match words.as_slice() {
+            ["list"] => Ok(AdminCommand::List),
+            ["close", id] => id.parse().map(AdminCommand::Close)
+                .map_err(|_| format!("invalid session ID: {}", id)),
+            _ => Err(format!("unknown command: {}", s.trim()))
+        }
+    }
+}
+
+/// An authenticated command, along with a channel for the response.
+pub struct AdminRequest {
+    pub command: AdminCommand,
+    pub response: Sender<String>
+}
+
+/// Run an admin command against the server and send back the result.
+pub fn handle_request(server: &mut Server, request: AdminRequest) {
+    let response = match request.command {
+        AdminCommand::List => {
+            let sessions = server.list_sessions();
+            if sessions.is_empty() {
+                "no active sessions\n".to_owned()
+            } else {
+                sessions.iter().map(|x| format!("{}\n", x)).collect()
+            }
+        },
+        AdminCommand::Close(id) => {
+            if server.close_session(id) {
+                println!("admin closed session {}", id);
+                format!("closed session {}\n", id)
+            } else {
+                format!("error: no session with ID {}\n", id)
+            }
+        }
+    };
+    request.response.send(response).ok();
+}
+
+/// Listen for admin connections on a Unix socket.
+///
+/// The socket is only accessible to its owner. Authenticated requests are
+/// sent to the returned channel, which the server should poll regularly.
+#[cfg(unix)]
+pub fn listen(path: &str, password: String) -> Result<Receiver<AdminRequest>, String> {
+    use std::fs::{Permissions, metadata, remove_file, set_permissions};
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::os::unix::net::UnixListener;
+    use std::sync::mpsc::channel;
+    use std::thread::spawn;
+
+    if let Ok(info) = metadata(path) {
+        // Remove a socket left behind by a previous run.
+        if info.file_type().is_socket() {
+            remove_file(path).map_err(|e| format!("remove {}: {}", path, e))?;
+        }
+    }
+    let listener = UnixListener::bind(path).map_err(|e| format!("admin listen failed: {}", e))?;
+    set_permissions(path, Permissions::from_mode(0o600))
+        .map_err(|e| format!("chmod {}: {}", path, e))?;
+    let (sender, receiver) = channel();
+    spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = handle_connection(stream, &password, &sender) {
+                eprintln!("admin connection error: {}", e);
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+/// Listen for admin connections on a Unix socket.
+///
+/// This is only supported on Unix systems.
+#[cfg(not(unix))]
+pub fn listen(_path: &str, _password: String) -> Result<Receiver<AdminRequest>, String> {
+    Err("the admin socket is only supported on Unix".to_owned())
+}
+
+#[cfg(unix)]
+fn handle_connection(
+    stream: ::std::os::unix::net::UnixStream,
+    password: &str,
+    requests: &Sender<AdminRequest>
+) -> Result<(), String> {
+    use std::io::{BufRead, BufReader, Write};
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    stream.set_read_timeout(Some(Duration::from_secs(5))).map_err(|e| format!("{}", e))?;
+    let mut reader = BufReader::new(stream.try_clone().map_err(|e| format!("{}", e))?);
+    let mut read_line = || -> Result<String, String> {
+        let mut line = String::new();
+        reader.read_line(&mut line).map_err(|e| format!("{}", e))?;
+        Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+    };
+    let given_password = read_line()?;
+    let response = if !constant_time_eq(given_password.as_bytes(), password.as_bytes()) {
+        "error: invalid password\n".to_owned()
+    } else {
+        match read_line()?.parse() {
+            Ok(command) => {
+                let (sender, receiver) = channel();
+                requests.send(AdminRequest{command, response: sender})
+                    .map_err(|_| "server is not running".to_owned())?;
+                receiver.recv_timeout(Duration::from_secs(10))
+                    .map_err(|_| "server did not respond".to_owned())?
+            },
+            Err(e) => format!("error: {}\n", e)
+        }
+    };
+    (&stream).write_all(response.as_bytes()).map_err(|e| format!("{}", e))
+}
+
+/// Compare two byte strings in time independent of where they differ.
+fn constant_time_eq(x: &[u8], y: &[u8]) -> bool {
+    if x.len() != y.len() {
+        return false;
+    }
+    x.iter().zip(y).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!("list".parse(), Ok(AdminCommand::List));
+        assert_eq!(" close  12 ".parse(), Ok(AdminCommand::Close(12)));
+        assert!("close".parse::<AdminCommand>().is_err());
+        assert!("close abc".parse::<AdminCommand>().is_err());
+        assert!("shutdown".parse::<AdminCommand>().is_err());
+    }
+
+    #[test]
+    fn password_comparison() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+    }
+}
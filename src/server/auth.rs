@@ -0,0 +1,65 @@
+//! Deciding which establishment queries may open a session.
+//!
+//! The server asks an `Authenticator` about every new or resumed session.
+//! `ProofAuthenticator` checks the password proof from the wire format, and
+//! deployments with other schemes (tokens, certificates, client allowlists)
+//! can pass their own implementation to `Server::with_authenticator`.
+
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use myo_proto::establish::{EstablishQuery, proof_failure_message};
+
+use super::flags::Flags;
+
+/// A scheme for accepting or rejecting establishment queries.
+pub trait Authenticator: Send {
+    /// Check an establishment query that was sent from `source`.
+    ///
+    /// On rejection, the error is sent back to the client as the failure
+    /// message.
+    fn authenticate(&self, query: &EstablishQuery, source: SocketAddr) -> Result<(), String>;
+}
+
+/// The default `Authenticator`, which checks the query's password proof.
+pub struct ProofAuthenticator {
+    password: String,
+    proof_window: u64,
+    allow_no_auth: bool,
+    report_time: bool
+}
+
+impl ProofAuthenticator {
+    /// Create an authenticator for the password and proof settings in the
+    /// flags.
+    pub fn new(flags: &Flags) -> ProofAuthenticator {
+        ProofAuthenticator{
+            password: flags.password.clone(),
+            proof_window: flags.proof_window,
+            allow_no_auth: flags.allow_no_auth,
+            report_time: flags.report_time
+        }
+    }
+}
+
+impl Authenticator for ProofAuthenticator {
+    fn authenticate(&self, query: &EstablishQuery, _: SocketAddr) -> Result<(), String> {
+        let epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        if query.proof.is_none() {
+            if query.resume.is_some() {
+                // Only a fresh proof can take over a session, even one that
+                // was opened without one.
+                Err("resuming a session requires a proof".to_owned())
+            } else if self.allow_no_auth {
+                Ok(())
+            } else {
+                Err("authentication required".to_owned())
+            }
+        } else if query.check_proof(&self.password, epoch, self.proof_window) {
+            Ok(())
+        } else {
+            let server_time = if self.report_time { Some(epoch) } else { None };
+            Err(proof_failure_message(server_time, self.proof_window))
+        }
+    }
+}
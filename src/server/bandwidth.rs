@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a session counts as competing for bandwidth after it last had
+/// data to send.
+const ACTIVE_TIME: Duration = Duration::from_secs(1);
+
+/// A server-wide limit on the bytes of outgoing response data.
+///
+/// The rate is split evenly between the sessions that have data waiting, and
+/// each of them gets a token bucket that refills at its share of the rate and
+/// holds up to one second of it. A session only sends a chunk while its
+/// bucket is not empty, so a response may overshoot it by one chunk, which
+/// later responses pay back.
+pub struct BandwidthLimiter {
+    rate: f64,
+    buckets: HashMap<u16, Bucket>
+}
+
+struct Bucket {
+    tokens: f64,
+    last_update: Instant,
+    last_wanted: Instant
+}
+
+impl BandwidthLimiter {
+    /// Create a limiter that allows `rate` bytes per second across every
+    /// session.
+    pub fn new(rate: u32) -> BandwidthLimiter {
+        BandwidthLimiter{rate: rate as f64, buckets: HashMap::new()}
+    }
+
+    /// Check if a session with data waiting may send a chunk now.
+    ///
+    /// The session counts as competing for bandwidth for a while after this
+    /// is called, even if the answer is no.
+    pub fn allow(&mut self, session: u16) -> bool {
+        let now = Instant::now();
+        self.buckets.entry(session).or_insert(Bucket{
+            tokens: 0.0,
+            last_update: now,
+            last_wanted: now
+        }).last_wanted = now;
+        let share = self.share();
+        let bucket = self.buckets.get_mut(&session).unwrap();
+        let secs = duration_secs(now.duration_since(bucket.last_update));
+        bucket.tokens = (bucket.tokens + secs * share).min(share);
+        bucket.last_update = now;
+        bucket.tokens > 0.0
+    }
+
+    /// Record that a session sent `bytes` bytes in a response.
+    pub fn charge(&mut self, session: u16, bytes: usize) {
+        if let Some(bucket) = self.buckets.get_mut(&session) {
+            bucket.tokens -= bytes as f64;
+        }
+    }
+
+    /// Forget about sessions that stopped competing and aren't in debt.
+    pub fn garbage_collect(&mut self) {
+        self.buckets.retain(|_, x| x.last_wanted.elapsed() < ACTIVE_TIME || x.tokens < 0.0);
+    }
+
+    /// Get each competing session's share of the rate.
+    fn share(&self) -> f64 {
+        let active = self.buckets.values().filter(|x| x.last_wanted.elapsed() < ACTIVE_TIME)
+            .count();
+        self.rate / active.max(1) as f64
+    }
+}
+
+fn duration_secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + (d.subsec_nanos() as f64) / 1e9
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    #[test]
+    fn fair_shares() {
+        let mut limiter = BandwidthLimiter::new(10000);
+        // A new session starts with an empty bucket.
+        assert!(!limiter.allow(1));
+        assert!(!limiter.allow(2));
+        sleep(Duration::from_millis(200));
+
+        // Both sessions compete, so each earns about 1000 bytes, and can't
+        // save up more than half of the rate.
+        for &id in &[1, 2] {
+            assert!(limiter.allow(id));
+            limiter.charge(id, 500);
+            assert!(limiter.allow(id));
+            limiter.charge(id, 5000);
+            assert!(!limiter.allow(id));
+        }
+
+        // A session that stops asking no longer takes a share, so the other
+        // one can save up the whole rate.
+        sleep(ACTIVE_TIME * 2);
+        assert!(limiter.allow(1));
+        limiter.charge(1, 8000);
+        assert!(limiter.allow(1));
+    }
+
+    #[test]
+    fn garbage_collect() {
+        let mut limiter = BandwidthLimiter::new(10000);
+        limiter.allow(1);
+        limiter.allow(2);
+        limiter.charge(2, 100);
+        sleep(ACTIVE_TIME);
+        limiter.garbage_collect();
+        assert_eq!(limiter.buckets.keys().collect::<Vec<_>>(), vec![&2]);
+    }
+}
@@ -1,19 +1,101 @@
+extern crate clap;
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
 use std::time::Duration;
 
-use clap::{App, Arg};
+use self::clap::{App, Arg};
+
+use client::ClientConfig;
+use dns_proto::{Domain, RecordClass};
+use myo_proto::api_chars::ApiChars;
+use myo_proto::name_template::NameTemplate;
+use myo_proto::util::domain_ends_with;
+
+use super::reject_log::{ALL_REJECT_KINDS, RejectKind, parse_reject_kinds};
 
-use myodine::dns_proto::Domain;
+/// The environment variable to read the admin password from, so that it
+/// doesn't show up in the process list.
+pub const ADMIN_PASSWORD_VAR: &str = "MYODINE_ADMIN_PASSWORD";
 
 pub struct Flags {
     pub listen_addr: String,
     pub password: String,
-    pub host: Domain,
+    pub hosts: Vec<Domain>,
     pub conn_timeout: Duration,
     pub session_timeout: Duration,
-    pub proof_window: u64
+    pub proof_window: u64,
+    pub max_mtu: u16,
+    pub max_inflight_bytes: Option<usize>,
+    pub max_session_memory: Option<usize>,
+    pub allowed_dests: Vec<Destination>,
+    pub unix_dests: Vec<UnixDest>,
+    pub rate_limit: Option<u32>,
+    pub max_bandwidth: Option<u32>,
+    pub log_rejections: Vec<RejectKind>,
+    pub shuffle_answers: bool,
+    pub tcp_nodelay: bool,
+    pub ttl: u32,
+    pub ttl_jitter: u32,
+    pub max_ttl: u32,
+    pub report_time: bool,
+    pub allow_no_auth: bool,
+    pub allow_udp: bool,
+    pub authoritative: bool,
+    pub api_chars: ApiChars,
+    pub name_template: NameTemplate,
+    pub admin_socket: Option<String>,
+    pub metrics_addr: Option<String>,
+    pub capture: Option<String>,
+    pub relay: Option<ClientConfig>,
+    pub record_class: RecordClass,
+    pub admin_password: String,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>
 }
 
 impl Flags {
+    /// Create flags with default settings for the given root domain names.
+    pub fn new(hosts: Vec<Domain>) -> Flags {
+        Flags{
+            listen_addr: "0.0.0.0:53".to_owned(),
+            password: String::new(),
+            hosts,
+            conn_timeout: Duration::from_secs(5),
+            session_timeout: Duration::from_secs(60),
+            proof_window: 120,
+            max_mtu: 1024,
+            max_inflight_bytes: None,
+            max_session_memory: None,
+            allowed_dests: Vec::new(),
+            unix_dests: Vec::new(),
+            rate_limit: None,
+            max_bandwidth: None,
+            log_rejections: ALL_REJECT_KINDS.to_vec(),
+            shuffle_answers: false,
+            tcp_nodelay: false,
+            ttl: 0,
+            ttl_jitter: 0,
+            max_ttl: 86400,
+            report_time: false,
+            allow_no_auth: false,
+            allow_udp: false,
+            authoritative: true,
+            api_chars: ApiChars::default(),
+            name_template: NameTemplate::default(),
+            admin_socket: None,
+            metrics_addr: None,
+            capture: None,
+            relay: None,
+            record_class: RecordClass::IN,
+            admin_password: String::new(),
+            uid: None,
+            gid: None
+        }
+    }
+
     pub fn parse() -> Result<Flags, String> {
         let matches = App::new("myodine-server")
             .arg(Arg::with_name("addr")
@@ -28,6 +110,12 @@ impl Flags {
                 .value_name("VALUE")
                 .help("Set the server password")
                 .takes_value(true))
+            .arg(Arg::with_name("password-file")
+                .long("password-file")
+                .value_name("PATH")
+                .help("Read the server password from a file")
+                .conflicts_with("password")
+                .takes_value(true))
             .arg(Arg::with_name("proof-win")
                 .short("w")
                 .long("proof-win")
@@ -46,9 +134,152 @@ impl Flags {
                 .value_name("INT")
                 .help("Set the session timeout")
                 .takes_value(true))
+            .arg(Arg::with_name("max-mtu")
+                .long("max-mtu")
+                .value_name("INT")
+                .help("Set the largest response MTU the server will accept")
+                .takes_value(true))
+            .arg(Arg::with_name("max-inflight-bytes")
+                .long("max-inflight-bytes")
+                .value_name("INT")
+                .help("Set the maximum unacknowledged outgoing bytes per session")
+                .takes_value(true))
+            .arg(Arg::with_name("max-session-memory")
+                .long("max-session-memory")
+                .value_name("INT")
+                .help("Set the maximum bytes of data buffered per session (applies backpressure)")
+                .takes_value(true))
+            .arg(Arg::with_name("allow")
+                .long("allow")
+                .value_name("HOST[:PORT]")
+                .help("Only allow proxying to this destination (may be repeated)")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+            .arg(Arg::with_name("unix-dest")
+                .long("unix-dest")
+                .value_name("NAME=PATH")
+                .help("Let clients proxy to the Unix socket at PATH by NAME (may be repeated)")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+            .arg(Arg::with_name("rate-limit")
+                .long("rate-limit")
+                .value_name("INT")
+                .help("Set the maximum queries per second from a single client")
+                .takes_value(true))
+            .arg(Arg::with_name("max-bandwidth")
+                .long("max-bandwidth")
+                .value_name("BYTES")
+                .help("Set the maximum bytes per second of response data across all sessions")
+                .takes_value(true))
+            .arg(Arg::with_name("log-rejections")
+                .long("log-rejections")
+                .value_name("KINDS")
+                .help("Log rejected queries of these kinds: auth, destination, malformed, \
+                       error, all, or none (default all)")
+                .takes_value(true))
+            .arg(Arg::with_name("shuffle-answers")
+                .long("shuffle-answers")
+                .help("Randomize answer order in responses split across several answers"))
+            .arg(Arg::with_name("tcp-nodelay")
+                .long("tcp-nodelay")
+                .help("Disable Nagle's algorithm on destination connections (lower latency)"))
+            .arg(Arg::with_name("ttl")
+                .long("ttl")
+                .value_name("INT")
+                .help("Set the TTL of answers (in seconds)")
+                .takes_value(true))
+            .arg(Arg::with_name("ttl-jitter")
+                .long("ttl-jitter")
+                .value_name("INT")
+                .help("Randomly vary answer TTLs by up to this many seconds")
+                .takes_value(true))
+            .arg(Arg::with_name("max-ttl")
+                .long("max-ttl")
+                .value_name("INT")
+                .help("Set the largest TTL that jitter may produce")
+                .takes_value(true))
+            .arg(Arg::with_name("report-time")
+                .long("report-time")
+                .help("Include the server time in proof failures to help diagnose clock skew"))
+            .arg(Arg::with_name("allow-no-auth")
+                .long("allow-no-auth")
+                .help("Accept sessions without a password proof (trusted networks only)"))
+            .arg(Arg::with_name("allow-udp")
+                .long("allow-udp")
+                .help("Accept sessions to UDP destinations"))
+            .arg(Arg::with_name("no-authoritative")
+                .long("no-authoritative")
+                .help("Clear the authoritative answer (AA) bit in responses"))
+            .arg(Arg::with_name("api-chars")
+                .long("api-chars")
+                .value_name("CHARS")
+                .help("Set the establish, transfer, and poll API characters (must match the client)")
+                .takes_value(true))
+            .arg(Arg::with_name("name-template")
+                .long("name-template")
+                .value_name("TEMPLATE")
+                .help("Set the layout of transfer query names (must match the client)")
+                .takes_value(true))
+            .arg(Arg::with_name("record-class")
+                .long("record-class")
+                .value_name("CLASS")
+                .help("Set the DNS class that queries must use (advanced; must match the client)")
+                .takes_value(true))
+            .arg(Arg::with_name("admin-socket")
+                .long("admin-socket")
+                .value_name("PATH")
+                .help("Serve the admin interface on a Unix socket (Unix only)")
+                .takes_value(true))
+            .arg(Arg::with_name("metrics-addr")
+                .long("metrics-addr")
+                .value_name("ADDR:PORT")
+                .help("Serve Prometheus metrics over HTTP at /metrics")
+                .takes_value(true))
+            .arg(Arg::with_name("capture")
+                .long("capture")
+                .value_name("FILE")
+                .help("Write every DNS message sent or received to a pcap file")
+                .takes_value(true))
+            .arg(Arg::with_name("relay")
+                .long("relay")
+                .value_name("ADDR:PORT")
+                .help("Forward sessions through another myodine server via this DNS server")
+                .requires("relay-host")
+                .takes_value(true))
+            .arg(Arg::with_name("relay-host")
+                .long("relay-host")
+                .value_name("DOMAIN")
+                .help("Set the root domain name of the relay server")
+                .requires("relay")
+                .takes_value(true))
+            .arg(Arg::with_name("relay-password")
+                .long("relay-password")
+                .value_name("VALUE")
+                .help("Set the password for the relay server")
+                .requires("relay")
+                .takes_value(true))
+            .arg(Arg::with_name("admin-password-file")
+                .long("admin-password-file")
+                .value_name("PATH")
+                .help("Read the admin password from a file instead of $MYODINE_ADMIN_PASSWORD")
+                .takes_value(true))
+            .arg(Arg::with_name("uid")
+                .long("uid")
+                .value_name("INT")
+                .help("Set the user ID to switch to after binding (Unix only)")
+                .requires("gid")
+                .takes_value(true))
+            .arg(Arg::with_name("gid")
+                .long("gid")
+                .value_name("INT")
+                .help("Set the group ID to switch to after binding (Unix only)")
+                .takes_value(true))
             .arg(Arg::with_name("host")
-                .help("Set the root domain name of the proxy")
+                .help("Set the root domain name(s) of the proxy")
                 .required(true)
+                .multiple(true)
                 .index(1))
             .get_matches();
 
@@ -59,13 +290,183 @@ impl Flags {
             }
         }
 
+        let password = match matches.value_of("password-file") {
+            Some(path) => read_password_file("password-file", path)?,
+            None => matches.value_of("password").unwrap_or("").to_owned()
+        };
+        let proof_window = parse_arg!("proof-win", "120")?;
+        if proof_window == 0 {
+            return Err("bad proof-win argument: must be at least 1".to_owned());
+        }
+        let max_mtu = parse_arg!("max-mtu", "1024")?;
+        if max_mtu == 0 {
+            return Err("bad max-mtu argument: must be at least 1".to_owned());
+        }
+        let admin_password = match matches.value_of("admin-password-file") {
+            Some(path) => read_password_file("admin-password-file", path)?,
+            None => env::var(ADMIN_PASSWORD_VAR).unwrap_or_default()
+        };
+        if matches.is_present("admin-socket") && admin_password.is_empty() {
+            return Err(format!("admin-socket requires a password from admin-password-file or ${}",
+                ADMIN_PASSWORD_VAR));
+        }
+        let ttl: u32 = parse_arg!("ttl", "0")?;
+        let max_ttl = parse_arg!("max-ttl", "86400")?;
+        if ttl > max_ttl {
+            return Err("bad ttl argument: must not exceed max-ttl".to_owned());
+        }
+        let rate_limit = parse_optional(matches.value_of("rate-limit"))?;
+        if rate_limit == Some(0) {
+            return Err("bad rate-limit argument: must be at least 1".to_owned());
+        }
+        let max_bandwidth = parse_optional(matches.value_of("max-bandwidth"))?;
+        if max_bandwidth == Some(0) {
+            return Err("bad max-bandwidth argument: must be at least 1".to_owned());
+        }
+        let max_session_memory = parse_optional(matches.value_of("max-session-memory"))?;
+        if max_session_memory == Some(0) {
+            return Err("bad max-session-memory argument: must be at least 1".to_owned());
+        }
+        let relay = match matches.value_of("relay") {
+            Some(addr) => {
+                let host = Domain::from_user_input(matches.value_of("relay-host").unwrap_or(""))
+                    .map_err(|e| format!("bad relay-host argument: {}", e))?;
+                let mut config = ClientConfig::new(addr, host);
+                config.password = matches.value_of("relay-password").unwrap_or("").to_owned();
+                config.validate()?;
+                Some(config)
+            },
+            None => None
+        };
         Ok(Flags{
             listen_addr: matches.value_of("addr").unwrap_or("0.0.0.0:53").to_owned(),
-            password: matches.value_of("password").unwrap_or("").to_owned(),
-            host: parse_arg!("host", "")?,
+            password,
+            hosts: parse_hosts(matches.values_of("host"))?,
             conn_timeout: Duration::from_secs(parse_arg!("conn-timeout", "5")?),
             session_timeout: Duration::from_secs(parse_arg!("sess-timeout", "60")?),
-            proof_window: parse_arg!("proof-win", "120")?
+            proof_window,
+            max_mtu,
+            max_inflight_bytes: parse_optional(matches.value_of("max-inflight-bytes"))?,
+            max_session_memory,
+            allowed_dests: parse_all(matches.values_of("allow"))?,
+            unix_dests: parse_all(matches.values_of("unix-dest"))?,
+            rate_limit,
+            max_bandwidth,
+            log_rejections: parse_reject_kinds(matches.value_of("log-rejections").unwrap_or("all"))
+                .map_err(|e| format!("bad log-rejections argument: {}", e))?,
+            shuffle_answers: matches.is_present("shuffle-answers"),
+            tcp_nodelay: matches.is_present("tcp-nodelay"),
+            ttl,
+            ttl_jitter: parse_arg!("ttl-jitter", "0")?,
+            max_ttl,
+            report_time: matches.is_present("report-time"),
+            allow_no_auth: matches.is_present("allow-no-auth"),
+            allow_udp: matches.is_present("allow-udp"),
+            authoritative: !matches.is_present("no-authoritative"),
+            api_chars: parse_arg!("api-chars", "etp")?,
+            name_template: parse_arg!("name-template", "{api}{session}.{data}")?,
+            admin_socket: matches.value_of("admin-socket").map(|x| x.to_owned()),
+            metrics_addr: matches.value_of("metrics-addr").map(|x| x.to_owned()),
+            capture: matches.value_of("capture").map(|x| x.to_owned()),
+            relay,
+            record_class: parse_arg!("record-class", "IN")?,
+            admin_password,
+            uid: parse_optional(matches.value_of("uid"))?,
+            gid: parse_optional(matches.value_of("gid"))?
         })
     }
+
+    /// Check if the allowlist permits proxying to a destination.
+    pub fn is_allowed_dest(&self, host: &Domain, port: u16) -> bool {
+        self.allowed_dests.is_empty() ||
+            self.allowed_dests.iter().any(|x| x.matches(host, port))
+    }
+
+    /// Get the path of the Unix socket destination with the given name.
+    pub fn unix_path(&self, name: &Domain) -> Option<&str> {
+        self.unix_dests.iter().find(|x| domain_ends_with(name, &x.name) &&
+            name.raw_parts().len() == x.name.raw_parts().len()).map(|x| x.path.as_str())
+    }
+}
+
+/// An entry in the destination allowlist.
+pub struct Destination {
+    pub host: Domain,
+    pub port: Option<u16>
+}
+
+impl Destination {
+    /// Check if a requested destination is covered by this entry.
+    pub fn matches(&self, host: &Domain, port: u16) -> bool {
+        if self.port.is_some() && self.port != Some(port) {
+            return false;
+        }
+        self.host.raw_parts().len() == host.raw_parts().len() &&
+            self.host.raw_parts().iter().zip(host.raw_parts()).all(|(x, y)| x.eq_ignore_ascii_case(y))
+    }
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Destination, String> {
+        let mut pieces = s.splitn(2, ':');
+        let host = pieces.next().unwrap().parse()?;
+        let port = match pieces.next() {
+            Some(p) => Some(p.parse().map_err(|_| format!("invalid port: {}", p))?),
+            None => None
+        };
+        Ok(Destination{host, port})
+    }
+}
+
+/// A Unix socket that clients can proxy to by name.
+pub struct UnixDest {
+    pub name: Domain,
+    pub path: String
+}
+
+impl FromStr for UnixDest {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<UnixDest, String> {
+        let mut pieces = s.splitn(2, '=');
+        let name = pieces.next().unwrap().parse()?;
+        match pieces.next() {
+            Some(path) if !path.is_empty() => Ok(UnixDest{name, path: path.to_owned()}),
+            _ => Err("expected NAME=PATH".to_owned())
+        }
+    }
+}
+
+fn read_password_file(name: &str, path: &str) -> Result<String, String> {
+    let mut contents = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|e| format!("bad {} argument: {}", name, e))?;
+    Ok(contents.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+fn parse_optional<T: FromStr>(x: Option<&str>) -> Result<Option<T>, String> {
+    match x {
+        Some(s) => s.parse().map_err(|_| format!("bad argument: {}", s)).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn parse_hosts<'a, I: Iterator<Item = &'a str>>(x: Option<I>) -> Result<Vec<Domain>, String> {
+    match x {
+        Some(values) => values.map(|s| Domain::from_user_input(s)
+            .map_err(|e| format!("bad argument {}: {}", s, e))).collect(),
+        None => Ok(Vec::new())
+    }
+}
+
+fn parse_all<'a, T, I>(x: Option<I>) -> Result<Vec<T>, String>
+    where T: FromStr, T::Err: ::std::fmt::Display, I: Iterator<Item = &'a str>
+{
+    match x {
+        Some(values) => values.map(|s| s.parse().map_err(|e| format!("bad argument {}: {}", s, e)))
+            .collect(),
+        None => Ok(Vec::new())
+    }
 }
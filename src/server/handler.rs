@@ -0,0 +1,1180 @@
+use std::cmp;
+use std::iter::Iterator;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use myo_proto::discovery;
+use myo_proto::establish;
+use myo_proto::xfer;
+use dns_proto::{Domain, Message, RecordType, ResponseCode};
+use myo_proto::util::{domain_ends_with, jittered_ttl};
+
+use super::auth::{Authenticator, ProofAuthenticator};
+use super::bandwidth::BandwidthLimiter;
+use super::flags::Flags;
+use super::lifecycle::{SessionListener, TRAFFIC_INTERVAL};
+use super::metrics::Metrics;
+use super::reject_log::{RejectKind, RejectLog};
+use super::session::Session;
+
+/// A stateful server.
+pub struct Server {
+    flags: Flags,
+    authenticator: Box<dyn Authenticator>,
+    listeners: Vec<Box<dyn SessionListener>>,
+    last_traffic_report: Instant,
+    sessions: Vec<Session>,
+    recent_establishes: Vec<RecentEstablish>,
+    pending_establishes: Vec<PendingEstablish>,
+    bandwidth: Option<BandwidthLimiter>,
+    metrics: Arc<Metrics>,
+    reject_log: RejectLog
+}
+
+/// A recent establishment that carried a nonce or early data.
+///
+/// Repeats of the same query (from client or resolver retries, or replays)
+/// get the original response, so they don't open extra sessions and the
+/// early data is only delivered once.
+struct RecentEstablish {
+    domain: Domain,
+    time: Instant,
+    id: u16,
+    response: establish::EstablishResponse
+}
+
+/// A split establishment query that is waiting for its destination.
+struct PendingEstablish {
+    time: Instant,
+    query: establish::EstablishQuery
+}
+
+/// The most split establishment queries that may wait for their destination
+/// at once. Anyone can send them, so the oldest are dropped beyond this.
+const MAX_PENDING_ESTABLISHES: usize = 256;
+
+/// How long a split establishment query waits for its destination.
+const PENDING_ESTABLISH_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl Server {
+    /// Create a new server with the configuration flags.
+    ///
+    /// Establishment queries are checked with a `ProofAuthenticator`.
+    pub fn new(flags: Flags) -> Server {
+        let authenticator = ProofAuthenticator::new(&flags);
+        Server::with_authenticator(flags, Box::new(authenticator))
+    }
+
+    /// Create a new server that checks establishment queries with a custom
+    /// authenticator.
+    pub fn with_authenticator(flags: Flags, authenticator: Box<dyn Authenticator>) -> Server {
+        let reject_log = RejectLog::new(flags.log_rejections.clone());
+        let bandwidth = flags.max_bandwidth.map(BandwidthLimiter::new);
+        Server{
+            flags,
+            authenticator,
+            listeners: Vec::new(),
+            last_traffic_report: Instant::now(),
+            sessions: Vec::new(),
+            recent_establishes: Vec::new(),
+            pending_establishes: Vec::new(),
+            bandwidth,
+            metrics: Arc::new(Metrics::default()),
+            reject_log
+        }
+    }
+
+    /// Add a listener to be told about session lifecycle events.
+    ///
+    /// See the `lifecycle` module for the order events arrive in.
+    pub fn add_listener(&mut self, listener: Box<dyn SessionListener>) {
+        self.listeners.push(listener);
+    }
+
+    /// Get the server's counters, which may be shared with other threads.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Log a rejected query from `source`, if rejections of its kind are
+    /// logged and it isn't suppressed as a repeat.
+    pub fn log_rejection(&mut self, kind: RejectKind, source: SocketAddr, reason: &str) {
+        self.reject_log.log(kind, source, reason);
+    }
+
+    /// Remove all closed or timed-out sessions, and report recent traffic
+    /// to the listeners.
+    pub fn garbage_collect(&mut self) {
+        if !self.listeners.is_empty() && self.last_traffic_report.elapsed() >= TRAFFIC_INTERVAL {
+            self.last_traffic_report = Instant::now();
+            for session in &mut self.sessions {
+                report_traffic(&self.listeners, session);
+            }
+        }
+        let timeout = self.flags.session_timeout;
+        let mut expired = Vec::new();
+        for session in &mut self.sessions {
+            // An errored session may read the last of its destination's data.
+            let before = session.traffic();
+            if session.is_done(timeout) {
+                expired.push(session.session_id());
+            }
+            add_traffic(&self.metrics, before, session.traffic());
+        }
+        for id in expired {
+            self.close_session(id);
+        }
+        if let Some(ref mut bandwidth) = self.bandwidth {
+            bandwidth.garbage_collect();
+        }
+    }
+
+    /// Close the session with the given ID, if it exists.
+    ///
+    /// The session's connection is closed and its ID may be reused.
+    pub fn close_session(&mut self, id: u16) -> bool {
+        let index = match self.sessions.iter().position(|x| x.session_id() == id) {
+            Some(index) => index,
+            None => return false
+        };
+        let mut session = self.sessions.remove(index);
+        self.update_active_sessions();
+        report_traffic(&self.listeners, &mut session);
+        let info = session.info();
+        drop(session);
+        for listener in &self.listeners {
+            listener.session_closed(&info);
+        }
+        true
+    }
+
+    /// Get a description of every active session.
+    pub fn list_sessions(&self) -> Vec<String> {
+        self.sessions.iter().map(|x| x.summary()).collect()
+    }
+
+    /// Serve the API for the incoming message, which was sent from `source`.
+    ///
+    /// Responses have the authoritative (AA) bit set unless it was disabled
+    /// in the flags, since the server owns the zones it answers for. Answer
+    /// TTLs are set from the TTL flags.
+    ///
+    /// This should not block for very long.
+    pub fn handle_message(
+        &mut self,
+        message: Message,
+        source: SocketAddr
+    ) -> Result<Message, String> {
+        Metrics::add(&self.metrics.queries_total, 1);
+        let authoritative = self.flags.authoritative;
+        let mut response = self.respond(message, source)?;
+        response.header.authoritative = authoritative;
+        for answer in &mut response.answers {
+            answer.header.ttl = jittered_ttl(self.flags.ttl, self.flags.ttl_jitter,
+                self.flags.max_ttl);
+        }
+        Ok(response)
+    }
+
+    fn respond(&mut self, message: Message, source: SocketAddr) -> Result<Message, String> {
+        if message.questions.iter().any(|x| x.record_type.is_zone_transfer()) {
+            let mut response = empty_response(message);
+            response.header.response_code = ResponseCode::Refused;
+            return Ok(response);
+        } else if !self.has_record_class(&message) {
+            return Ok(empty_response(message));
+        }
+        // Transfer queries come first, since a custom name template may make
+        // them look like discovery or establishment queries.
+        if let Some(host) = self.query_host(&message) {
+            let id = xfer::xfer_query_session_id(&message, &host, &self.flags.api_chars,
+                &self.flags.name_template);
+            if let Some(id) = id {
+                let mut some_sess = self.sessions.iter_mut()
+                    .find(|x| x.session_id() == id);
+                if let Some(ref mut session) = some_sess {
+                    let before = session.traffic();
+                    let result = session.handle_message(message, &host,
+                        self.bandwidth.as_mut());
+                    add_traffic(&self.metrics, before, session.traffic());
+                    return result;
+                }
+            }
+        }
+        if discovery::is_domain_hash_query(&message) {
+            return discovery::domain_hash_response(&message);
+        } else if discovery::is_download_gen_query(&message) {
+            return discovery::download_gen_response(&message);
+        } else if let Some(host) = self.query_host(&message) {
+            if establish::is_destination_query(&message, &self.flags.api_chars) {
+                return self.handle_destination(message, &host, source);
+            } else if establish::is_establish_query(&message, &self.flags.api_chars) {
+                return self.handle_establish(message, &host, source);
+            }
+        }
+        Ok(empty_response(message))
+    }
+
+    fn handle_establish(
+        &mut self,
+        message: Message,
+        host: &Domain,
+        source: SocketAddr
+    ) -> Result<Message, String> {
+        let api = self.flags.api_chars;
+        let query = establish::EstablishQuery::from_query(&message, host, &api)?;
+        if query.split {
+            // Nothing is checked until the destination arrives, since the
+            // authenticator may depend on it.
+            self.pending_establishes.retain(|x| x.query.nonce != query.nonce);
+            if self.pending_establishes.len() >= MAX_PENDING_ESTABLISHES {
+                self.pending_establishes.remove(0);
+            }
+            self.pending_establishes.push(PendingEstablish{time: Instant::now(), query});
+            let response = establish::EstablishResponse::Pending;
+            return establish::establish_response(&message, host, &api, response);
+        }
+        let response = self.establish(&query, &message, source);
+        establish::establish_response(&message, host, &api, response)
+    }
+
+    /// Handle the follow-up to a split establishment query, which completes
+    /// the query with its destination.
+    fn handle_destination(
+        &mut self,
+        message: Message,
+        host: &Domain,
+        source: SocketAddr
+    ) -> Result<Message, String> {
+        let dest = establish::DestinationQuery::from_query(&message, host,
+            &self.flags.api_chars)?;
+        self.pending_establishes.retain(|x| x.time.elapsed() <= PENDING_ESTABLISH_TIMEOUT);
+        // The split query stays pending after this, so that a retry of the
+        // follow-up can be answered in the same encoding.
+        let mut query = self.pending_establishes.iter()
+            .find(|x| x.query.nonce == Some(dest.nonce))
+            .map(|x| x.query.clone())
+            .ok_or("no pending establishment for destination".to_owned())?;
+        query.host = dest.host;
+        let response = self.establish(&query, &message, source);
+        establish::destination_response(&message, &query, response)
+    }
+
+    /// Open (or resume) a session for an establishment query, which came in
+    /// `message`.
+    ///
+    /// Sessions opened by queries with a nonce or early data are remembered
+    /// under the message's domain, so repeats get the same response.
+    fn establish(
+        &mut self,
+        query: &establish::EstablishQuery,
+        message: &Message,
+        source: SocketAddr
+    ) -> establish::EstablishResponse {
+        // Without a nonce, two clients can send the same query, so a repeat
+        // can't be told from a new session. Repeated early data is still
+        // never delivered twice.
+        let remember = query.resume.is_none() &&
+            (query.nonce.is_some() || !query.early_data.is_empty());
+        if remember {
+            if let Some(response) = self.repeated_establish(&message.questions[0].domain) {
+                return response;
+            }
+        }
+        if let Err(msg) = self.authenticator.authenticate(query, source) {
+            if query.proof.is_some() {
+                Metrics::add(&self.metrics.proof_failures_total, 1);
+            }
+            self.reject_log.log(RejectKind::Auth, source, &msg);
+            establish::EstablishResponse::Failure(msg)
+        } else if let Err(msg) = establish::check_name_encoding(&query.name_encoding) {
+            establish::EstablishResponse::Failure(msg)
+        } else if query.datagram && !self.flags.allow_udp {
+            self.reject_log.log(RejectKind::Destination, source,
+                &format!("UDP destination {} not allowed", query.destination()));
+            establish::EstablishResponse::Failure("UDP destinations not allowed".to_owned())
+        } else if let Some((old_id, window_start, token)) = query.resume {
+            self.resume_session(query, message.questions[0].record_type, old_id, window_start,
+                token)
+        } else if !self.flags.is_allowed_dest(&query.host, query.port) ||
+            (query.unix && self.flags.unix_path(&query.host).is_none())
+        {
+            self.reject_log.log(RejectKind::Destination, source,
+                &format!("destination {} not allowed", query.destination()));
+            establish::EstablishResponse::Failure("destination not allowed".to_owned())
+        } else if let Some(id) = self.unused_session_id() {
+            // TODO: randomize seq_start.
+            let seq_start = 0;
+            let mtu = self.response_mtu(query, message.questions[0].record_type);
+            let sess_res = Session::new(id, source, seq_start, mtu,
+                message.questions[0].record_type, query, &self.flags);
+            match sess_res {
+                Ok(mut sess) => {
+                    let response = sess.success_response(seq_start);
+                    if !query.early_data.is_empty() {
+                        sess.handle_early_data(query.early_data.clone());
+                        add_traffic(&self.metrics, (0, 0, 0), sess.traffic());
+                    }
+                    if remember {
+                        self.recent_establishes.push(RecentEstablish{
+                            domain: message.questions[0].domain.clone(),
+                            time: Instant::now(),
+                            id,
+                            response: response.clone()
+                        });
+                    }
+                    let info = sess.info();
+                    self.sessions.push(sess);
+                    Metrics::add(&self.metrics.sessions_total, 1);
+                    self.update_active_sessions();
+                    for listener in &self.listeners {
+                        listener.session_authenticated(query, &info);
+                        listener.session_created(&info);
+                    }
+                    response
+                },
+                Err(msg) => establish::EstablishResponse::Failure(msg)
+            }
+        } else {
+            establish::EstablishResponse::Failure("no free session IDs".to_owned())
+        }
+    }
+
+    /// Move an existing session to a new ID with a new response MTU.
+    ///
+    /// The session must have the same destination and resume token. A
+    /// repeated query for a session that was already moved gets the same
+    /// response again.
+    fn resume_session(
+        &mut self,
+        query: &establish::EstablishQuery,
+        record_type: RecordType,
+        old_id: u16,
+        window_start: u32,
+        token: u32
+    ) -> establish::EstablishResponse {
+        let destination = query.destination();
+        let repeated = self.sessions.iter().find(|x| {
+            x.resumed_from() == Some((old_id, window_start)) && x.destination() == destination &&
+                x.resume_token() == token
+        });
+        if let Some(session) = repeated {
+            return session.success_response(window_start);
+        }
+        let id = match self.unused_session_id() {
+            Some(id) => id,
+            None => return establish::EstablishResponse::Failure("no free session IDs".to_owned())
+        };
+        let mtu = self.response_mtu(query, record_type);
+        // A wrong token looks like a missing session, so it can't be used to
+        // find out which sessions exist.
+        let session = self.sessions.iter_mut().find(|x| {
+            x.session_id() == old_id && x.destination() == destination &&
+                x.resume_token() == token
+        });
+        match session.map(|x| x.resume(id, window_start, mtu).map(|_| x)) {
+            Some(Ok(session)) => {
+                let info = session.info();
+                for listener in &self.listeners {
+                    listener.session_authenticated(query, &info);
+                    listener.session_resumed(old_id, &info);
+                }
+                session.success_response(window_start)
+            },
+            Some(Err(msg)) => establish::EstablishResponse::Failure(msg),
+            None => establish::EstablishResponse::Failure("no such session".to_owned())
+        }
+    }
+
+    /// Get the response MTU for a session: the one the client asked for,
+    /// unless it exceeds `max_mtu` or doesn't fit in a DNS message.
+    fn response_mtu(&self, query: &establish::EstablishQuery, record_type: RecordType) -> u16 {
+        let limit = xfer::Packet::max_response_mtu(record_type, &query.response_encoding,
+            query.query_window, query.padded, query.framed, query.answers);
+        cmp::min(cmp::min(query.mtu, self.flags.max_mtu) as usize, limit) as u16
+    }
+
+    /// Find the response to an earlier establishment with the same domain,
+    /// ignoring case, if its session still exists.
+    ///
+    /// Entries are forgotten once their proof could no longer be accepted.
+    fn repeated_establish(&mut self, domain: &Domain) -> Option<establish::EstablishResponse> {
+        // A proof is valid for proof_window seconds on either side.
+        let max_age = Duration::from_secs(self.flags.proof_window.saturating_mul(2));
+        self.recent_establishes.retain(|x| x.time.elapsed() <= max_age);
+        let entry = self.recent_establishes.iter().find(|x| {
+            x.domain.raw_parts().len() == domain.raw_parts().len() &&
+                domain_ends_with(&x.domain, domain)
+        })?;
+        if self.sessions.iter().any(|x| x.session_id() == entry.id) {
+            Some(entry.response.clone())
+        } else {
+            Some(establish::EstablishResponse::Failure("session already closed".to_owned()))
+        }
+    }
+
+    fn update_active_sessions(&self) {
+        self.metrics.sessions_active.store(self.sessions.len() as u64, Ordering::Relaxed);
+    }
+
+    fn has_record_class(&self, message: &Message) -> bool {
+        message.questions.iter().all(|x| x.record_class == self.flags.record_class)
+    }
+
+    fn query_host(&self, message: &Message) -> Option<Domain> {
+        let domain = &message.questions.first()?.domain;
+        self.flags.hosts.iter().find(|x| domain_ends_with(domain, x)).cloned()
+    }
+
+    fn unused_session_id(&self) -> Option<u16> {
+        (0..establish::RESERVED_SESSION_ID)
+            .find(|&i| !self.sessions.iter().any(|x| x.session_id() == i))
+    }
+}
+
+fn add_traffic(metrics: &Metrics, before: (u64, u64, u64), after: (u64, u64, u64)) {
+    Metrics::add(&metrics.bytes_in_total, after.0 - before.0);
+    Metrics::add(&metrics.bytes_out_total, after.1 - before.1);
+    Metrics::add(&metrics.retransmissions_total, after.2 - before.2);
+}
+
+/// Tell the listeners about a session's traffic since the last report.
+fn report_traffic(listeners: &[Box<dyn SessionListener>], session: &mut Session) {
+    if listeners.is_empty() {
+        return;
+    }
+    if let Some((bytes_in, bytes_out)) = session.take_unreported_traffic() {
+        let info = session.info();
+        for listener in listeners {
+            listener.data_transferred(&info, bytes_in, bytes_out);
+        }
+    }
+}
+
+fn empty_response(message: Message) -> Message {
+    let mut response = message;
+    response.header.is_response = true;
+    response.header.response_code = ResponseCode::NoError;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, UdpSocket};
+    use std::sync::Mutex;
+    use std::thread::sleep;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use dns_coding::dns_decode;
+    use dns_proto::{Question, RecordBody, RecordClass, RecordType};
+    use myo_proto::api_chars::ApiChars;
+    use myo_proto::name_code::get_name_code;
+    use myo_proto::name_template::NameTemplate;
+    use myo_proto::record_code::{decode_answers, get_record_code};
+
+    use super::super::lifecycle::SessionInfo;
+
+    #[test]
+    fn session_id_reuse() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let host: Domain = "proxy.com".parse().unwrap();
+        let mut flags = Flags::new(vec![host.clone()]);
+        flags.password = "pw".to_owned();
+        let mut server = Server::new(flags);
+
+        assert_eq!(establish_id(&mut server, &host, port), 0);
+        let (mut conn, _) = listener.accept().unwrap();
+        assert_eq!(establish_id(&mut server, &host, port), 1);
+        listener.accept().unwrap();
+
+        assert_eq!(server.list_sessions().len(), 2);
+        assert!(server.list_sessions()[1].starts_with("id=1 source=127.0.0.1:5353 "));
+
+        assert!(server.close_session(0));
+        assert!(!server.close_session(0));
+        let mut buf = [0u8; 16];
+        assert_eq!(conn.read(&mut buf).unwrap(), 0);
+        assert_eq!(establish_id(&mut server, &host, port), 0);
+    }
+
+    #[test]
+    fn session_resume() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let host: Domain = "proxy.com".parse().unwrap();
+        let mut flags = Flags::new(vec![host.clone()]);
+        flags.password = "pw".to_owned();
+        flags.allow_no_auth = true;
+        let mut server = Server::new(flags);
+        let epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let mut query = test_query(Some(establish::password_proof("pw", epoch)), port);
+        let token = match send_establish(&mut server, &host, &query) {
+            establish::EstablishResponse::Success{id: 0, resume_token, ..} => resume_token,
+            x => panic!("unexpected response: {:?}", x)
+        };
+        listener.accept().unwrap();
+
+        let no_session = establish::EstablishResponse::Failure("no such session".to_owned());
+        query.mtu = 32;
+        query.resume = Some((0, 0, token.wrapping_add(1)));
+        assert_eq!(send_establish(&mut server, &host, &query), no_session);
+        query.resume = Some((0, 0, token));
+        query.proof = None;
+        let no_proof = "resuming a session requires a proof".to_owned();
+        assert_eq!(send_establish(&mut server, &host, &query),
+            establish::EstablishResponse::Failure(no_proof));
+
+        query.proof = Some(establish::password_proof("pw", epoch));
+        let expected = establish::EstablishResponse::Success{id: 1, seq: 0, query_window: 4,
+            response_window: 4, query_mtu: 0, response_mtu: 32, resume_token: token};
+        assert_eq!(send_establish(&mut server, &host, &query), expected);
+        assert!(server.list_sessions()[0].starts_with("id=1 "));
+
+        // A repeated query gets the same response.
+        assert_eq!(send_establish(&mut server, &host, &query), expected);
+        assert_eq!(server.list_sessions().len(), 1);
+        query.resume = Some((0, 0, token ^ 1));
+        assert_eq!(send_establish(&mut server, &host, &query), no_session);
+
+        query.resume = Some((1, 100, token));
+        assert!(send_establish(&mut server, &host, &query) != expected);
+        query.resume = Some((5, 0, token));
+        assert_eq!(send_establish(&mut server, &host, &query), no_session);
+        query.proof = Some(0);
+        query.resume = Some((1, 0, token));
+        assert!(send_establish(&mut server, &host, &query) != expected);
+    }
+
+    #[test]
+    fn authoritative_bit() {
+        let host: Domain = "proxy.com".parse().unwrap();
+        let query = Message::new_query(Question{
+            domain: "p1.abcd.proxy.com".parse().unwrap(),
+            record_type: RecordType::TXT,
+            record_class: RecordClass::IN
+        });
+        let source = "127.0.0.1:5353".parse().unwrap();
+        let mut server = Server::new(Flags::new(vec![host.clone()]));
+        assert!(server.handle_message(query.clone(), source).unwrap().header.authoritative);
+
+        let mut flags = Flags::new(vec![host]);
+        flags.authoritative = false;
+        let mut server = Server::new(flags);
+        assert!(!server.handle_message(query, source).unwrap().header.authoritative);
+    }
+
+    #[test]
+    fn zone_transfer_refused() {
+        let host: Domain = "proxy.com".parse().unwrap();
+        let mut server = Server::new(Flags::new(vec![host]));
+        let query = Message::new_query(Question{
+            domain: "proxy.com".parse().unwrap(),
+            record_type: RecordType::AXFR,
+            record_class: RecordClass::IN
+        });
+        let response = server.handle_message(query, "127.0.0.1:5353".parse().unwrap()).unwrap();
+        assert!(response.header.is_response);
+        assert_eq!(response.header.response_code, ResponseCode::Refused);
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn zero_window_rejected() {
+        let host: Domain = "proxy.com".parse().unwrap();
+        let mut flags = Flags::new(vec![host.clone()]);
+        flags.allow_no_auth = true;
+        let mut server = Server::new(flags);
+        let mut query = test_query(None, 22);
+        query.query_window = 0;
+        match send_establish(&mut server, &host, &query) {
+            establish::EstablishResponse::Failure(msg) => assert!(msg.contains("window")),
+            x => panic!("unexpected response: {:?}", x)
+        }
+    }
+
+    #[test]
+    fn unknown_name_encoding_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let host: Domain = "proxy.com".parse().unwrap();
+        let mut flags = Flags::new(vec![host.clone()]);
+        flags.allow_no_auth = true;
+        let mut server = Server::new(flags);
+        let expected = establish::EstablishResponse::Failure(
+            "unsupported name encoding: b99 (supported: b16)".to_owned());
+
+        let mut query = test_query(None, port);
+        query.name_encoding = "b99".to_owned();
+        assert_eq!(send_establish(&mut server, &host, &query), expected);
+
+        // Early data can't be decoded, but the query still gets a failure.
+        query.name_encoding = "b16".to_owned();
+        query.early_data = b"hello".to_vec();
+        let domain = query.to_domain(&host, &ApiChars::default()).unwrap();
+        let mut parts = domain.parts().to_vec();
+        parts[2] = "b99".to_owned();
+        let message = Message::new_query(Question{
+            domain: Domain::from_parts(parts).unwrap(),
+            record_type: RecordType::TXT,
+            record_class: RecordClass::IN
+        });
+        let response = server.handle_message(message, "127.0.0.1:5353".parse().unwrap()).unwrap();
+        let code = get_record_code(RecordType::TXT, "raw").unwrap();
+        let body = code.decode_body(&response.answers[0].body).unwrap();
+        assert_eq!(dns_decode::<establish::EstablishResponse>(body).unwrap(), expected);
+        assert!(server.list_sessions().is_empty());
+    }
+
+    #[test]
+    fn custom_authenticator() {
+        struct Allowlist(SocketAddr);
+
+        impl Authenticator for Allowlist {
+            fn authenticate(
+                &self,
+                _: &establish::EstablishQuery,
+                source: SocketAddr
+            ) -> Result<(), String> {
+                if source == self.0 {
+                    Ok(())
+                } else {
+                    Err(format!("{} is not allowed", source))
+                }
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let host: Domain = "proxy.com".parse().unwrap();
+        let allowed = "127.0.0.1:5353".parse().unwrap();
+        let mut server = Server::with_authenticator(Flags::new(vec![host.clone()]),
+            Box::new(Allowlist(allowed)));
+        // The authenticator replaces the proof check entirely.
+        let query = test_query(None, port);
+        match send_establish(&mut server, &host, &query) {
+            establish::EstablishResponse::Success{..} => (),
+            x => panic!("unexpected response: {:?}", x)
+        }
+        let message = Message::new_query(Question{
+            domain: query.to_domain(&host, &ApiChars::default()).unwrap(),
+            record_type: RecordType::TXT,
+            record_class: RecordClass::IN
+        });
+        let response = server.handle_message(message, "10.0.0.1:53".parse().unwrap()).unwrap();
+        let code = get_record_code(RecordType::TXT, "raw").unwrap();
+        let body = code.decode_body(&response.answers[0].body).unwrap();
+        assert_eq!(dns_decode::<establish::EstablishResponse>(body).unwrap(),
+            establish::EstablishResponse::Failure("10.0.0.1:53 is not allowed".to_owned()));
+        assert_eq!(server.metrics().proof_failures_total.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn lifecycle_events() {
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+
+        impl SessionListener for Recorder {
+            fn session_authenticated(&self, _: &establish::EstablishQuery, info: &SessionInfo) {
+                self.0.lock().unwrap().push(format!("authenticated {} {}", info.id, info.source));
+            }
+
+            fn session_created(&self, info: &SessionInfo) {
+                self.0.lock().unwrap().push(format!("created {}", info.id));
+            }
+
+            fn data_transferred(&self, info: &SessionInfo, bytes_in: u64, bytes_out: u64) {
+                self.0.lock().unwrap().push(format!("transferred {} {} {}", info.id, bytes_in,
+                    bytes_out));
+            }
+
+            fn session_closed(&self, info: &SessionInfo) {
+                self.0.lock().unwrap().push(format!("closed {} {}", info.id, info.bytes_in));
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let host: Domain = "proxy.com".parse().unwrap();
+        let mut flags = Flags::new(vec![host.clone()]);
+        flags.allow_no_auth = true;
+        let mut server = Server::new(flags);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        server.add_listener(Box::new(Recorder(events.clone())));
+        let mut query = test_query(None, port);
+
+        // A query that passes authentication but opens no session is not
+        // reported.
+        query.name_encoding = "b99".to_owned();
+        send_establish(&mut server, &host, &query);
+        assert!(events.lock().unwrap().is_empty());
+
+        query.name_encoding = "b16".to_owned();
+        query.early_data = b"hello".to_vec();
+        send_establish(&mut server, &host, &query);
+
+        // Traffic is only reported once the interval has passed.
+        server.garbage_collect();
+        server.last_traffic_report = Instant::now() - TRAFFIC_INTERVAL;
+        server.garbage_collect();
+        server.garbage_collect();
+        assert!(server.close_session(0));
+
+        // Unreported traffic is flushed before the session is closed.
+        query.early_data = b"hi".to_vec();
+        send_establish(&mut server, &host, &query);
+        assert!(server.close_session(0));
+
+        assert_eq!(*events.lock().unwrap(), vec![
+            "authenticated 0 127.0.0.1:5353",
+            "created 0",
+            "transferred 0 5 0",
+            "closed 0 5",
+            "authenticated 0 127.0.0.1:5353",
+            "created 0",
+            "transferred 0 2 0",
+            "closed 0 2"
+        ]);
+    }
+
+    #[test]
+    fn shuffled_answers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let host: Domain = "proxy.com".parse().unwrap();
+        let mut flags = Flags::new(vec![host.clone()]);
+        flags.allow_no_auth = true;
+        flags.shuffle_answers = true;
+        let mut server = Server::new(flags);
+        let mut query = test_query(None, port);
+        query.answers = 4;
+        let id = match send_establish(&mut server, &host, &query) {
+            establish::EstablishResponse::Success{id, ..} => id,
+            x => panic!("unexpected response: {:?}", x)
+        };
+        let (mut conn, _) = listener.accept().unwrap();
+        conn.write_all(b"hello, world").unwrap();
+        let chunk = poll_chunk(&mut server, &host, id, 4);
+        assert_eq!(chunk.data, b"hello, world");
+    }
+
+    #[test]
+    fn errored_session_drained() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let host: Domain = "proxy.com".parse().unwrap();
+        let mut flags = Flags::new(vec![host.clone()]);
+        flags.allow_no_auth = true;
+        let mut server = Server::new(flags);
+        let mut query = test_query(None, port);
+        query.early_data = b"unread".to_vec();
+        send_establish(&mut server, &host, &query);
+
+        // Closing with unread data resets the connection, after the server
+        // has read what was sent before.
+        let (mut conn, _) = listener.accept().unwrap();
+        conn.write_all(b"goodbye").unwrap();
+        sleep(Duration::from_millis(100));
+        drop(conn);
+        for _ in 0..100 {
+            if server.list_sessions()[0].ends_with("conn=errored") {
+                break;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        assert!(server.list_sessions()[0].ends_with("conn=errored"));
+        server.garbage_collect();
+        assert_eq!(server.list_sessions().len(), 1);
+
+        let chunk = poll_chunk(&mut server, &host, 0, 1);
+        assert_eq!(chunk.data, b"goodbye");
+        // Acknowledge everything, including an EOF if one was read.
+        let mut next_seq = chunk.seq + 1;
+        while let Some(chunk) = send_poll(&mut server, &host, 0, 1, next_seq).chunk {
+            next_seq = chunk.seq + 1;
+        }
+        server.garbage_collect();
+        assert!(server.list_sessions().is_empty());
+    }
+
+    #[test]
+    fn early_data_delivered_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let host: Domain = "proxy.com".parse().unwrap();
+        let mut flags = Flags::new(vec![host.clone()]);
+        flags.allow_no_auth = true;
+        let mut server = Server::new(flags);
+        let mut query = test_query(None, port);
+        query.early_data = b"hello".to_vec();
+
+        let first = send_establish(&mut server, &host, &query);
+        let (mut conn, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 5];
+        conn.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // A repeated query gets the same session instead of a new one.
+        assert_eq!(send_establish(&mut server, &host, &query), first);
+        assert_eq!(server.list_sessions().len(), 1);
+
+        let metrics = server.metrics();
+        assert_eq!(metrics.queries_total.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.sessions_total.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.sessions_active.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.bytes_in_total.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn retried_establish() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let host: Domain = "proxy.com".parse().unwrap();
+        let mut flags = Flags::new(vec![host.clone()]);
+        flags.password = "pw".to_owned();
+        let mut server = Server::new(flags);
+        let epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let mut query = test_query(Some(establish::password_proof("pw", epoch)), port);
+        query.nonce = Some(0x1234);
+
+        let first = send_establish(&mut server, &host, &query);
+        listener.accept().unwrap();
+        assert_eq!(send_establish(&mut server, &host, &query), first);
+        assert_eq!(server.list_sessions().len(), 1);
+
+        // The same proof with another nonce is another client.
+        query.nonce = Some(0x5678);
+        match send_establish(&mut server, &host, &query) {
+            establish::EstablishResponse::Success{id: 1, ..} => (),
+            x => panic!("unexpected response: {:?}", x)
+        }
+        assert_eq!(server.list_sessions().len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_destination() {
+        use std::os::unix::net::UnixListener;
+        use super::super::flags::UnixDest;
+
+        let path = ::std::env::temp_dir().join(format!("myodine-test-{}.sock",
+            ::std::process::id()));
+        let listener = UnixListener::bind(&path).unwrap();
+        let host: Domain = "proxy.com".parse().unwrap();
+        let mut flags = Flags::new(vec![host.clone()]);
+        flags.allow_no_auth = true;
+        flags.unix_dests.push(UnixDest{
+            name: "db".parse().unwrap(),
+            path: path.to_str().unwrap().to_owned()
+        });
+        flags.allowed_dests.push("db".parse().unwrap());
+        let mut server = Server::new(flags);
+        let mut query = test_query(None, 0);
+        query.unix = true;
+        query.host = "db".parse().unwrap();
+        query.early_data = b"hello".to_vec();
+
+        match send_establish(&mut server, &host, &query) {
+            establish::EstablishResponse::Success{id: 0, ..} => (),
+            x => panic!("unexpected response: {:?}", x)
+        }
+        let (mut conn, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 5];
+        conn.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        assert!(server.list_sessions()[0].contains("unix:db"));
+
+        // Names have to be configured and allowed.
+        let not_allowed = establish::EstablishResponse::Failure(
+            "destination not allowed".to_owned());
+        query.early_data = Vec::new();
+        query.host = "cache".parse().unwrap();
+        assert_eq!(send_establish(&mut server, &host, &query), not_allowed);
+        server.flags.allowed_dests.push("cache".parse().unwrap());
+        assert_eq!(send_establish(&mut server, &host, &query), not_allowed);
+        server.flags.allowed_dests.clear();
+        query.host = "db".parse().unwrap();
+        match send_establish(&mut server, &host, &query) {
+            establish::EstablishResponse::Success{id: 1, ..} => (),
+            x => panic!("unexpected response: {:?}", x)
+        }
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn split_destination() {
+        use std::os::unix::net::UnixListener;
+        use super::super::flags::{Destination, UnixDest};
+
+        let path = ::std::env::temp_dir().join(format!("myodine-split-{}.sock",
+            ::std::process::id()));
+        let listener = UnixListener::bind(&path).unwrap();
+        let host: Domain = "proxy.com".parse().unwrap();
+        let name: Domain = format!("{}.{}.{}.{}.db", "a".repeat(60), "b".repeat(60),
+            "c".repeat(60), "d".repeat(30)).parse().unwrap();
+        let mut flags = Flags::new(vec![host.clone()]);
+        flags.allow_no_auth = true;
+        flags.unix_dests.push(UnixDest{
+            name: name.clone(),
+            path: path.to_str().unwrap().to_owned()
+        });
+        flags.allowed_dests.push(Destination{host: name.clone(), port: None});
+        let mut server = Server::new(flags);
+        let mut query = test_query(None, 0);
+        query.unix = true;
+        query.nonce = Some(0x1234);
+        query.host = name.clone();
+        query.early_data = b"hello".to_vec();
+        assert!(query.to_domain(&host, &ApiChars::default()).is_err());
+
+        let dest = establish::DestinationQuery{nonce: 0x1234, host: name.clone()};
+        let unknown = establish::DestinationQuery{nonce: 0x5678, host: name.clone()};
+        assert!(send_destination(&mut server, &host, &query, &unknown).is_err());
+
+        query.split = true;
+        assert_eq!(send_establish(&mut server, &host, &query),
+            establish::EstablishResponse::Pending);
+        assert!(server.list_sessions().is_empty());
+        let first = send_destination(&mut server, &host, &query, &dest).unwrap();
+        match first {
+            establish::EstablishResponse::Success{id: 0, ..} => (),
+            ref x => panic!("unexpected response: {:?}", x)
+        }
+        let (mut conn, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 5];
+        conn.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // A retried follow-up gets the same session.
+        assert_eq!(send_destination(&mut server, &host, &query, &dest).unwrap(), first);
+        assert_eq!(server.list_sessions().len(), 1);
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_client_echo() {
+        use std::io::copy;
+        use std::net::{Shutdown, TcpStream};
+        use std::thread::spawn;
+
+        use client::{ClientConfig, RawLogger, handle_connection_async};
+        use dns_coding::dns_encode;
+
+        let echo = TcpListener::bind("127.0.0.1:0").unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        spawn(move || {
+            let (conn, _) = echo.accept().unwrap();
+            copy(&mut conn.try_clone().unwrap(), &mut &conn).unwrap();
+        });
+        let host: Domain = "proxy.com".parse().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let mut flags = Flags::new(vec![host.clone()]);
+        flags.allow_no_auth = true;
+        spawn(move || {
+            let mut server = Server::new(flags);
+            loop {
+                let mut buf = [0; 2048];
+                let (size, addr) = socket.recv_from(&mut buf).unwrap();
+                let message = dns_decode::<Message>(buf[..size].to_vec()).unwrap();
+                if let Ok(response) = server.handle_message(message, addr) {
+                    socket.send_to(&dns_encode(&response).unwrap(), addr).unwrap();
+                }
+            }
+        });
+
+        let mut config = ClientConfig::new(&server_addr.to_string(), host);
+        config.no_auth = true;
+        config.remote_port = echo_port;
+        let local = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(local.local_addr().unwrap()).unwrap();
+        let (conn, _) = local.accept().unwrap();
+        let client = spawn(move || handle_connection_async(config, conn, &RawLogger::new()));
+        let data: Vec<u8> = (0..2000).map(|x| x as u8).collect();
+        stream.write_all(&data).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut echoed = Vec::new();
+        stream.read_to_end(&mut echoed).unwrap();
+        assert_eq!(echoed, data);
+        assert_eq!(client.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn oversized_chunk_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let host: Domain = "proxy.com".parse().unwrap();
+        let mut flags = Flags::new(vec![host.clone()]);
+        flags.allow_no_auth = true;
+        let mut server = Server::new(flags);
+        let mut query = test_query(None, port);
+        query.query_mtu = Some(4);
+        query.early_data = b"early".to_vec();
+        send_establish(&mut server, &host, &query);
+        let (mut conn, _) = listener.accept().unwrap();
+
+        // Early data isn't held to the query MTU, but later chunks are.
+        let chunk = xfer::Chunk::new(1, b"large".to_vec());
+        let response = send_chunk(&mut server, &host, 0, 1, 0, Some(chunk));
+        assert_eq!(response.ack.window_start, 1);
+        let chunk = xfer::Chunk::new(1, b"fits".to_vec());
+        let response = send_chunk(&mut server, &host, 0, 1, 0, Some(chunk));
+        assert_eq!(response.ack.window_start, 2);
+        let mut buf = [0u8; 9];
+        conn.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"earlyfits");
+    }
+
+    #[test]
+    fn udp_needs_flag() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let host: Domain = "proxy.com".parse().unwrap();
+        let mut query = test_query(None, port);
+        query.datagram = true;
+
+        let mut flags = Flags::new(vec![host.clone()]);
+        flags.allow_no_auth = true;
+        let mut server = Server::new(flags);
+        match send_establish(&mut server, &host, &query) {
+            establish::EstablishResponse::Failure(msg) => {
+                assert_eq!(msg, "UDP destinations not allowed");
+            },
+            x => panic!("unexpected response: {:?}", x)
+        }
+
+        let mut flags = Flags::new(vec![host.clone()]);
+        flags.allow_no_auth = true;
+        flags.allow_udp = true;
+        let mut server = Server::new(flags);
+        match send_establish(&mut server, &host, &query) {
+            establish::EstablishResponse::Success{..} => (),
+            x => panic!("unexpected response: {:?}", x)
+        }
+    }
+
+    fn establish_id(server: &mut Server, host: &Domain, port: u16) -> u16 {
+        let epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let query = test_query(Some(establish::password_proof("pw", epoch)), port);
+        match send_establish(server, host, &query) {
+            establish::EstablishResponse::Success{id, ..} => id,
+            x => panic!("unexpected response: {:?}", x)
+        }
+    }
+
+    fn test_query(proof: Option<u64>, port: u16) -> establish::EstablishQuery {
+        establish::EstablishQuery{
+            response_encoding: "raw".to_owned(),
+            mtu: 64,
+            query_mtu: None,
+            name_encoding: "b16".to_owned(),
+            query_window: 4,
+            response_window: 4,
+            proof,
+            nonce: None,
+            port,
+            host: "127.0.0.1".parse().unwrap(),
+            early_data: Vec::new(),
+            resume: None,
+            padded: false,
+            framed: false,
+            datagram: false,
+            unix: false,
+            answers: 1,
+            split: false
+        }
+    }
+
+    /// Send a transfer query with no chunk, acknowledging chunks before
+    /// `window_start`, and decode the response.
+    fn send_poll(
+        server: &mut Server,
+        host: &Domain,
+        id: u16,
+        answers: usize,
+        window_start: u32
+    ) -> xfer::Packet {
+        send_chunk(server, host, id, answers, window_start, None)
+    }
+
+    /// Send a transfer query with an optional chunk, acknowledging chunks
+    /// before `window_start`, and decode the response.
+    fn send_chunk(
+        server: &mut Server,
+        host: &Domain,
+        id: u16,
+        answers: usize,
+        window_start: u32,
+        chunk: Option<xfer::Chunk>
+    ) -> xfer::Packet {
+        let (api_char, data) = xfer::Packet{
+            ack: xfer::Ack{window_start, window_mask: vec![false; 3]},
+            chunk,
+            control: None
+        }.encode_query(&ApiChars::default()).unwrap();
+        let name_code = get_name_code("b16").unwrap();
+        let message = Message::new_query(Question{
+            domain: NameTemplate::default().encode(&*name_code, api_char, id, &data, host, 0)
+                .unwrap(),
+            record_type: RecordType::TXT,
+            record_class: RecordClass::IN
+        });
+        let response = server.handle_message(message, "127.0.0.1:5353".parse().unwrap()).unwrap();
+        assert_eq!(response.answers.len(), answers);
+        let code = get_record_code(RecordType::TXT, "raw").unwrap();
+        let bodies: Vec<&RecordBody> = response.answers.iter().map(|x| &x.body).collect();
+        let data = decode_answers(&*code, &bodies, answers).unwrap();
+        xfer::Packet::decode_response(&data, 4).unwrap()
+    }
+
+    /// Poll until the session sends a chunk from its destination.
+    fn poll_chunk(server: &mut Server, host: &Domain, id: u16, answers: usize) -> xfer::Chunk {
+        for _ in 0..100 {
+            if let Some(chunk) = send_poll(server, host, id, answers, 0).chunk {
+                return chunk;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        panic!("no data received");
+    }
+
+    fn send_establish(
+        server: &mut Server,
+        host: &Domain,
+        query: &establish::EstablishQuery
+    ) -> establish::EstablishResponse {
+        let message = Message::new_query(Question{
+            domain: query.to_domain(host, &ApiChars::default()).unwrap(),
+            record_type: RecordType::TXT,
+            record_class: RecordClass::IN
+        });
+        let response = server.handle_message(message, "127.0.0.1:5353".parse().unwrap()).unwrap();
+        assert!(response.header.authoritative);
+        let code = get_record_code(RecordType::TXT, "raw").unwrap();
+        let body = code.decode_body(&response.answers[0].body).unwrap();
+        dns_decode(body).unwrap()
+    }
+
+    /// Send the follow-up to a split establishment query.
+    fn send_destination(
+        server: &mut Server,
+        host: &Domain,
+        query: &establish::EstablishQuery,
+        dest: &establish::DestinationQuery
+    ) -> Result<establish::EstablishResponse, String> {
+        let message = Message::new_query(Question{
+            domain: dest.to_domain(host, &ApiChars::default()).unwrap(),
+            record_type: RecordType::TXT,
+            record_class: RecordClass::IN
+        });
+        let response = server.handle_message(message, "127.0.0.1:5353".parse().unwrap())?;
+        let code = get_record_code(RecordType::TXT, &query.response_encoding).unwrap();
+        let body = code.decode_body(&response.answers[0].body).unwrap();
+        Ok(dns_decode(body).unwrap())
+    }
+}
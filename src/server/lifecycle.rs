@@ -0,0 +1,83 @@
+//! Hooks for watching sessions come and go.
+//!
+//! Listeners passed to `Server::add_listener` are called on the server's
+//! thread, so they should return quickly; anything slow (writing to a
+//! database, calling out to another service) belongs on a channel to a
+//! thread of its own.
+//!
+//! For each session, events arrive in this order:
+//!
+//! 1. `session_authenticated` and then `session_created`, once the query was
+//!    accepted and the destination is connected.
+//! 2. Any number of `data_transferred` events, and pairs of
+//!    `session_authenticated` and `session_resumed` events.
+//! 3. `session_closed`, exactly once, after which the ID may be reused.
+//!
+//! Traffic is reported in batches at most once per `TRAFFIC_INTERVAL`, not
+//! per packet, and any unreported traffic is flushed before the session is
+//! closed, so the `data_transferred` counts always add up to the totals in
+//! `session_closed`. Resumed sessions use their new ID from the
+//! `session_authenticated` event on. Queries that don't open or resume a
+//! session, whether they fail authentication or a later check, don't produce
+//! events, and neither do repeated queries that get a cached response.
+//! Sessions still open when the server is dropped are never reported as
+//! closed.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use myo_proto::establish::EstablishQuery;
+
+/// The shortest time between traffic reports for a session.
+pub const TRAFFIC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Metadata about a session, as of an event.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionInfo {
+    pub id: u16,
+    pub source: SocketAddr,
+    /// The destination, as `host:port` or `unix:<name>`.
+    pub destination: String,
+    /// The bytes written to the destination so far.
+    pub bytes_in: u64,
+    /// The bytes read from the destination so far.
+    pub bytes_out: u64
+}
+
+/// A receiver of session lifecycle events.
+///
+/// Every method does nothing by default, so implementations only need to
+/// handle the events they care about.
+pub trait SessionListener: Send {
+    /// An establishment query passed authentication and opened or resumed
+    /// the session in `info`.
+    ///
+    /// This comes right before `session_created` or `session_resumed`.
+    fn session_authenticated(&self, _query: &EstablishQuery, _info: &SessionInfo) {}
+
+    /// A new session was opened.
+    fn session_created(&self, _info: &SessionInfo) {}
+
+    /// A session was moved from `old_id` to a new ID.
+    fn session_resumed(&self, _old_id: u16, _info: &SessionInfo) {}
+
+    /// A session wrote `bytes_in` bytes to its destination and read
+    /// `bytes_out` bytes from it since the last report.
+    fn data_transferred(&self, _info: &SessionInfo, _bytes_in: u64, _bytes_out: u64) {}
+
+    /// A session was closed or timed out.
+    fn session_closed(&self, _info: &SessionInfo) {}
+}
+
+/// A listener that prints when sessions open and close.
+pub struct LogListener;
+
+impl SessionListener for LogListener {
+    fn session_created(&self, info: &SessionInfo) {
+        println!("new session {} from {} to {}", info.id, info.source, info.destination);
+    }
+
+    fn session_closed(&self, info: &SessionInfo) {
+        println!("removing session {} (in={} out={})", info.id, info.bytes_in, info.bytes_out);
+    }
+}
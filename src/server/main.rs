@@ -1,18 +1,22 @@
-extern crate clap;
 extern crate myodine;
 
-mod flags;
-mod session;
-mod server;
+mod admin;
+mod privileges;
+mod rate_limit;
 
+use std::cmp::min;
 use std::net::UdpSocket;
 use std::process::exit;
+use std::time::Duration;
 
+use myodine::conn::{capture_received, capture_sent, start_capture};
 use myodine::dns_coding::{dns_decode, dns_encode};
 use myodine::dns_proto::Message;
 
-use flags::Flags;
-use server::Server;
+use myodine::server::{Flags, LogListener, RejectKind, Server, listen_metrics};
+
+use privileges::{check_privilege_flags, drop_privileges};
+use rate_limit::RateLimiter;
 
 fn main() {
     if let Err(msg) = main_or_err() {
@@ -23,38 +27,80 @@ fn main() {
 
 fn main_or_err() -> Result<(), String> {
     let flags = Flags::parse()?;
+    // Fail before binding, rather than after doing it as root.
+    check_privilege_flags(flags.uid, flags.gid)?;
 
     let socket = UdpSocket::bind(&flags.listen_addr)
         .map_err(|e| format!("listen failed: {}", e))?;
-    socket.set_read_timeout(Some(flags.session_timeout / 2))
+    let admin_requests = match flags.admin_socket {
+        Some(ref path) => Some(admin::listen(path, flags.admin_password.clone())?),
+        None => None
+    };
+    // Wake up regularly to serve admin requests.
+    let read_timeout = if admin_requests.is_some() {
+        min(flags.session_timeout / 2, Duration::from_secs(1))
+    } else {
+        flags.session_timeout / 2
+    };
+    socket.set_read_timeout(Some(read_timeout))
         .map_err(|e| format!("socket error: {}", e))?;
-
+    if let Some(ref path) = flags.capture {
+        start_capture(path)?;
+    }
+    let (uid, gid) = (flags.uid, flags.gid);
+    let mut limiter = flags.rate_limit.map(RateLimiter::new);
+    let metrics_addr = flags.metrics_addr.clone();
     let mut server = Server::new(flags);
+    server.add_listener(Box::new(LogListener));
+    if let Some(addr) = metrics_addr {
+        listen_metrics(&addr, server.metrics())?;
+    }
+    drop_privileges(uid, gid)?;
     loop {
         server.garbage_collect();
         let mut buf = [0; 2048];
         let result = socket.recv_from(&mut buf);
         server.garbage_collect();
+        if let Some(ref mut limiter) = limiter {
+            limiter.garbage_collect();
+        }
+        if let Some(ref requests) = admin_requests {
+            while let Ok(request) = requests.try_recv() {
+                admin::handle_request(&mut server, request);
+            }
+        }
         if result.is_err() {
             continue;
         }
         let (size, sender_addr) = result.unwrap();
-        if let Ok(mut message) = dns_decode::<Message>(buf[0..size].to_vec()) {
-            if message.additional.len() > 0 {
-                message.additional.clear();
-                message.header.additional_count = 0;
+        capture_received(&socket, Some(sender_addr), &buf[..size]);
+        if let Some(ref mut limiter) = limiter {
+            if !limiter.allow(sender_addr.ip()) {
+                continue;
             }
-            match server.handle_message(message) {
-                Ok(response) => match dns_encode(&response) {
-                    Ok(out_buf) => {
-                        if socket.send_to(&out_buf, &sender_addr).is_err() {
-                            eprintln!("send to {} failed", sender_addr);
-                        }
-                    },
-                    Err(err) => eprintln!("error encoding response to {}: {}", sender_addr, err)
-                }
-                Err(err) => eprintln!("error processing query from {}: {}", sender_addr, err)
+        }
+        let mut message = match dns_decode::<Message>(buf[0..size].to_vec()) {
+            Ok(message) => message,
+            Err(err) => {
+                server.log_rejection(RejectKind::Malformed, sender_addr, &err);
+                continue;
+            }
+        };
+        if message.additional.len() > 0 {
+            message.additional.clear();
+            message.header.additional_count = 0;
+        }
+        match server.handle_message(message, sender_addr) {
+            Ok(response) => match dns_encode(&response) {
+                Ok(out_buf) => {
+                    capture_sent(&socket, Some(sender_addr), &out_buf);
+                    if socket.send_to(&out_buf, &sender_addr).is_err() {
+                        eprintln!("send to {} failed", sender_addr);
+                    }
+                },
+                Err(err) => eprintln!("error encoding response to {}: {}", sender_addr, err)
             }
+            Err(err) => server.log_rejection(RejectKind::Error, sender_addr, &err)
         }
     }
 }
@@ -0,0 +1,134 @@
+//! Server counters, exposed in the Prometheus text format over HTTP.
+//!
+//! Counters are plain atomics, so updating them on the hot path is cheap.
+//! The HTTP endpoint runs on its own thread and only reads them.
+
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::spawn;
+use std::time::Duration;
+
+/// How long a scraper may take to send its request or read the response.
+///
+/// Connections are handled one at a time, so a stuck one holds up the rest
+/// until this runs out.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Counters describing the server's activity.
+#[derive(Default)]
+pub struct Metrics {
+    pub sessions_active: AtomicU64,
+    pub sessions_total: AtomicU64,
+    pub queries_total: AtomicU64,
+    pub bytes_in_total: AtomicU64,
+    pub bytes_out_total: AtomicU64,
+    pub retransmissions_total: AtomicU64,
+    pub proof_failures_total: AtomicU64
+}
+
+impl Metrics {
+    /// Add to a counter.
+    pub fn add(counter: &AtomicU64, amount: u64) {
+        counter.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut result = String::new();
+        let entries = [
+            ("sessions_active", "gauge", "Number of open sessions.", &self.sessions_active),
+            ("sessions_total", "counter", "Sessions created.", &self.sessions_total),
+            ("queries_total", "counter", "DNS queries handled.", &self.queries_total),
+            ("bytes_in_total", "counter", "Bytes written to destinations.",
+                &self.bytes_in_total),
+            ("bytes_out_total", "counter", "Bytes read from destinations.",
+                &self.bytes_out_total),
+            ("retransmissions_total", "counter", "Chunks sent to clients more than once.",
+                &self.retransmissions_total),
+            ("proof_failures_total", "counter", "Establishment queries with a bad proof.",
+                &self.proof_failures_total)
+        ];
+        for &(name, kind, help, value) in entries.iter() {
+            write!(result, "# HELP myodine_{} {}\n# TYPE myodine_{} {}\nmyodine_{} {}\n",
+                name, help, name, kind, name, value.load(Ordering::Relaxed)).unwrap();
+        }
+        result
+    }
+}
+
+/// Serve the metrics at `/metrics` on the given address.
+///
+/// The listener runs on a background thread.
+pub fn listen(addr: &str, metrics: Arc<Metrics>) -> Result<(), String> {
+    let listener = TcpListener::bind(addr)
+        .map_err(|e| format!("metrics listen failed: {}", e))?;
+    spawn(move || {
+        for stream in listener.incoming().flatten() {
+            handle_connection(stream, &metrics).ok();
+        }
+    });
+    Ok(())
+}
+
+fn handle_connection(mut stream: TcpStream, metrics: &Metrics) -> ::std::io::Result<()> {
+    stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+    let mut buffer = [0u8; 1024];
+    let size = stream.read(&mut buffer)?;
+    let request = String::from_utf8_lossy(&buffer[..size]);
+    let (status, body) = if is_metrics_request(&request) {
+        ("200 OK", metrics.render())
+    } else {
+        ("404 Not Found", "not found\n".to_owned())
+    };
+    write!(stream, "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+        Content-Length: {}\r\n\r\n{}", status, body.len(), body)
+}
+
+fn is_metrics_request(request: &str) -> bool {
+    let mut words = request.split_whitespace();
+    words.next() == Some("GET") && words.next() == Some("/metrics")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_format() {
+        let metrics = Metrics::default();
+        Metrics::add(&metrics.queries_total, 3);
+        metrics.sessions_active.store(2, Ordering::Relaxed);
+        let text = metrics.render();
+        assert!(text.contains("# TYPE myodine_sessions_active gauge\nmyodine_sessions_active 2\n"));
+        assert!(text.contains("# TYPE myodine_queries_total counter\nmyodine_queries_total 3\n"));
+        assert_eq!(text.lines().filter(|x| !x.starts_with('#')).count(), 7);
+    }
+
+    #[test]
+    fn idle_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        listen(&addr.to_string(), Arc::new(Metrics::default())).unwrap();
+
+        // A client that never sends a request doesn't block the next one.
+        let _idle = TcpStream::connect(addr).unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(CONNECTION_TIMEOUT * 3)).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+    }
+
+    #[test]
+    fn metrics_path() {
+        assert!(is_metrics_request("GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n"));
+        assert!(!is_metrics_request("GET / HTTP/1.1\r\n\r\n"));
+        assert!(!is_metrics_request("POST /metrics HTTP/1.1\r\n\r\n"));
+    }
+}
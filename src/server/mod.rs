@@ -0,0 +1,23 @@
+//! A server that proxies sessions from myodine clients to their destinations.
+//!
+//! A `Server` turns each DNS query into a response, and leaves the socket to
+//! the caller, so it can be embedded behind any transport. Deployments can
+//! plug in their own `Authenticator` to decide which sessions may open, and
+//! `SessionListener`s to watch sessions come and go.
+
+mod auth;
+mod bandwidth;
+mod flags;
+mod handler;
+mod lifecycle;
+mod metrics;
+mod reject_log;
+mod relay;
+mod session;
+
+pub use self::auth::{Authenticator, ProofAuthenticator};
+pub use self::flags::{Destination, Flags, UnixDest};
+pub use self::lifecycle::{LogListener, SessionInfo, SessionListener};
+pub use self::metrics::{Metrics, listen as listen_metrics};
+pub use self::reject_log::RejectKind;
+pub use self::handler::Server;
@@ -0,0 +1,68 @@
+#[cfg(unix)]
+extern crate libc;
+
+/// Check that a privilege drop switches the group whenever it switches the
+/// user.
+///
+/// Switching only the user would keep the root group, which can still read
+/// and write everything that group owns.
+pub fn check_privilege_flags(uid: Option<u32>, gid: Option<u32>) -> Result<(), String> {
+    if uid.is_some() && gid.is_none() {
+        Err("--uid requires --gid".to_owned())
+    } else {
+        Ok(())
+    }
+}
+
+/// Switch to the given group and user IDs.
+///
+/// Supplementary groups are cleared first, then the group is changed, since
+/// changing the user usually removes the permission to do either. A user
+/// without a group is rejected (see `check_privilege_flags`).
+#[cfg(unix)]
+pub fn drop_privileges(uid: Option<u32>, gid: Option<u32>) -> Result<(), String> {
+    check_privilege_flags(uid, gid)?;
+    if uid.is_none() && gid.is_none() {
+        return Ok(());
+    }
+    if unsafe { libc::setgroups(0, ::std::ptr::null()) } != 0 {
+        return Err(format!("setgroups failed: {}", ::std::io::Error::last_os_error()));
+    }
+    if let Some(gid) = gid {
+        if unsafe { libc::setgid(gid as libc::gid_t) } != 0 {
+            return Err(format!("setgid failed: {}", ::std::io::Error::last_os_error()));
+        }
+    }
+    if let Some(uid) = uid {
+        if unsafe { libc::setuid(uid as libc::uid_t) } != 0 {
+            return Err(format!("setuid failed: {}", ::std::io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+/// Switch to the given group and user IDs.
+///
+/// This is only supported on Unix systems.
+#[cfg(not(unix))]
+pub fn drop_privileges(uid: Option<u32>, gid: Option<u32>) -> Result<(), String> {
+    if uid.is_some() || gid.is_some() {
+        Err("dropping privileges is only supported on Unix".to_owned())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uid_requires_gid() {
+        assert!(check_privilege_flags(Some(1000), None).is_err());
+        assert!(drop_privileges(Some(1000), None).is_err());
+        assert!(check_privilege_flags(Some(1000), Some(1000)).is_ok());
+        assert!(check_privilege_flags(None, Some(1000)).is_ok());
+        assert!(check_privilege_flags(None, None).is_ok());
+    }
+}
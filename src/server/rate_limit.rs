@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// A per-client token bucket rate limiter.
+pub struct RateLimiter {
+    rate: f64,
+    buckets: HashMap<IpAddr, Bucket>
+}
+
+struct Bucket {
+    tokens: f64,
+    last_update: Instant
+}
+
+impl RateLimiter {
+    /// Create a rate limiter that allows `rate` queries per second per
+    /// client, with bursts of up to `rate` queries.
+    pub fn new(rate: u32) -> RateLimiter {
+        RateLimiter{rate: rate as f64, buckets: HashMap::new()}
+    }
+
+    /// Check if a query from the client should be served, consuming a token
+    /// if so.
+    pub fn allow(&mut self, client: IpAddr) -> bool {
+        let rate = self.rate;
+        let now = Instant::now();
+        let bucket = self.buckets.entry(client).or_insert(Bucket{
+            tokens: rate,
+            last_update: now
+        });
+        let elapsed = now.duration_since(bucket.last_update);
+        let secs = elapsed.as_secs() as f64 + (elapsed.subsec_nanos() as f64) / 1e9;
+        bucket.tokens = (bucket.tokens + secs * rate).min(rate);
+        bucket.last_update = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forget about clients whose buckets have completely refilled.
+    pub fn garbage_collect(&mut self) {
+        let rate = self.rate;
+        self.buckets.retain(|_, bucket| {
+            let elapsed = bucket.last_update.elapsed();
+            let secs = elapsed.as_secs() as f64 + (elapsed.subsec_nanos() as f64) / 1e9;
+            bucket.tokens + secs * rate < rate
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_limit() {
+        let mut limiter = RateLimiter::new(3);
+        let client1: IpAddr = "10.0.0.1".parse().unwrap();
+        let client2: IpAddr = "10.0.0.2".parse().unwrap();
+        for _ in 0..3 {
+            assert!(limiter.allow(client1));
+        }
+        assert!(!limiter.allow(client1));
+        assert!(limiter.allow(client2));
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How long identical rejection lines are suppressed for after one is logged.
+const REPEAT_INTERVAL_SECS: u64 = 10;
+
+/// The most rejection lines to log per interval, whatever their reasons.
+///
+/// Reasons for malformed queries may contain data from the query itself, so
+/// suppressing repeats alone would let a flood of distinct junk through.
+const MAX_LINES_PER_INTERVAL: usize = 20;
+
+/// A category of rejected query.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum RejectKind {
+    /// An establishment query that failed authentication.
+    Auth,
+
+    /// An establishment query for a destination outside the allowlist.
+    Destination,
+
+    /// A query that could not be decoded.
+    Malformed,
+
+    /// A query that was decoded, but that the server failed to handle.
+    Error
+}
+
+/// Every kind of rejection, which are all logged by default.
+pub const ALL_REJECT_KINDS: [RejectKind; 4] = [RejectKind::Auth, RejectKind::Destination,
+    RejectKind::Malformed, RejectKind::Error];
+
+impl RejectKind {
+    fn name(&self) -> &'static str {
+        match *self {
+            RejectKind::Auth => "auth",
+            RejectKind::Destination => "destination",
+            RejectKind::Malformed => "malformed",
+            RejectKind::Error => "error"
+        }
+    }
+}
+
+/// Parse a comma-separated list of rejection kinds, or "all" or "none".
+pub fn parse_reject_kinds(s: &str) -> Result<Vec<RejectKind>, String> {
+    match s {
+        "all" => Ok(ALL_REJECT_KINDS.to_vec()),
+        "none" => Ok(Vec::new()),
+        _ => s.split(',').map(|name| {
+            ALL_REJECT_KINDS.iter().find(|x| x.name() == name).cloned()
+                .ok_or_else(|| format!("unknown rejection kind: {}", name))
+        }).collect()
+    }
+}
+
+/// A log of rejected queries that can't be used to flood the server's output.
+///
+/// A line is only logged if no line with the same kind and reason was logged
+/// in the last few seconds, and only a limited number of lines are logged in
+/// that time. The next line with a suppressed reason counts the repeats.
+pub struct RejectLog {
+    kinds: Vec<RejectKind>,
+    interval: Duration,
+    interval_start: Instant,
+    lines_in_interval: usize,
+    recent: HashMap<(RejectKind, String), RecentReason>
+}
+
+struct RecentReason {
+    time: Instant,
+    suppressed: u64
+}
+
+impl RejectLog {
+    /// Create a log that only includes the given kinds of rejections.
+    pub fn new(kinds: Vec<RejectKind>) -> RejectLog {
+        RejectLog::with_interval(kinds, Duration::from_secs(REPEAT_INTERVAL_SECS))
+    }
+
+    fn with_interval(kinds: Vec<RejectKind>, interval: Duration) -> RejectLog {
+        RejectLog{
+            kinds,
+            interval,
+            interval_start: Instant::now(),
+            lines_in_interval: 0,
+            recent: HashMap::new()
+        }
+    }
+
+    /// Print a rejected query, unless it is suppressed.
+    ///
+    /// Errors from handling a query go to stderr, and the rest to stdout.
+    pub fn log(&mut self, kind: RejectKind, source: SocketAddr, reason: &str) {
+        if let Some(line) = self.line(kind, source, reason) {
+            if kind == RejectKind::Error {
+                eprintln!("{}", line);
+            } else {
+                println!("{}", line);
+            }
+        }
+    }
+
+    /// Get the line to log for a rejected query, if any.
+    fn line(&mut self, kind: RejectKind, source: SocketAddr, reason: &str) -> Option<String> {
+        if !self.kinds.contains(&kind) {
+            return None;
+        }
+        let now = Instant::now();
+        if now.duration_since(self.interval_start) >= self.interval {
+            let interval = self.interval;
+            self.interval_start = now;
+            self.lines_in_interval = 0;
+            // Counts of suppressed lines are dropped after a while, since
+            // they are only reported when the same reason comes up again.
+            self.recent.retain(|_, x| now.duration_since(x.time) < interval * 2);
+        }
+        let key = (kind, reason.to_owned());
+        let suppressed = match self.recent.get_mut(&key) {
+            Some(recent) if now.duration_since(recent.time) < self.interval => {
+                recent.suppressed += 1;
+                return None;
+            },
+            Some(recent) => recent.suppressed,
+            None => 0
+        };
+        if self.lines_in_interval >= MAX_LINES_PER_INTERVAL {
+            // Only remember reasons that were logged before, so that distinct
+            // junk can't grow the map.
+            if let Some(recent) = self.recent.get_mut(&key) {
+                recent.suppressed += 1;
+            }
+            return None;
+        }
+        self.lines_in_interval += 1;
+        self.recent.insert(key, RecentReason{time: now, suppressed: 0});
+        let mut line = if kind == RejectKind::Error {
+            format!("error processing query from {}: {}", source, reason)
+        } else {
+            format!("rejected {} query from {}: {}", kind.name(), source, reason)
+        };
+        if suppressed > 0 {
+            line.push_str(&format!(" ({} similar suppressed)", suppressed));
+        }
+        Some(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    #[test]
+    fn kinds_and_repeats() {
+        assert_eq!(parse_reject_kinds("auth,malformed"),
+            Ok(vec![RejectKind::Auth, RejectKind::Malformed]));
+        assert_eq!(parse_reject_kinds("none"), Ok(Vec::new()));
+        assert_eq!(parse_reject_kinds("all").unwrap().len(), 4);
+        assert!(parse_reject_kinds("auth,bogus").is_err());
+
+        let source: SocketAddr = "10.0.0.1:5353".parse().unwrap();
+        let mut log = RejectLog::with_interval(vec![RejectKind::Auth, RejectKind::Malformed],
+            Duration::from_millis(100));
+        assert_eq!(log.line(RejectKind::Auth, source, "bad proof"),
+            Some("rejected auth query from 10.0.0.1:5353: bad proof".to_owned()));
+        assert_eq!(log.line(RejectKind::Auth, source, "bad proof"), None);
+        assert_eq!(log.line(RejectKind::Auth, source, "bad proof"), None);
+        assert_eq!(log.line(RejectKind::Destination, source, "not allowed"), None);
+        assert!(log.line(RejectKind::Malformed, source, "bad proof").is_some());
+        assert_eq!(log.line(RejectKind::Error, source, "bad packet"), None);
+        sleep(Duration::from_millis(150));
+        assert_eq!(log.line(RejectKind::Auth, source, "bad proof"),
+            Some("rejected auth query from 10.0.0.1:5353: bad proof \
+                (2 similar suppressed)".to_owned()));
+    }
+
+    #[test]
+    fn handler_errors() {
+        let source: SocketAddr = "10.0.0.1:5353".parse().unwrap();
+        let mut log = RejectLog::new(parse_reject_kinds("error").unwrap());
+        assert_eq!(log.line(RejectKind::Error, source, "bad packet"),
+            Some("error processing query from 10.0.0.1:5353: bad packet".to_owned()));
+        assert_eq!(log.line(RejectKind::Error, source, "bad packet"), None);
+    }
+
+    #[test]
+    fn distinct_reason_flood() {
+        let source: SocketAddr = "10.0.0.1:5353".parse().unwrap();
+        let mut log = RejectLog::new(vec![RejectKind::Malformed]);
+        let logged = (0..1000)
+            .filter_map(|i| log.line(RejectKind::Malformed, source, &format!("junk {}", i)))
+            .count();
+        assert_eq!(logged, MAX_LINES_PER_INTERVAL);
+        assert_eq!(log.recent.len(), MAX_LINES_PER_INTERVAL);
+    }
+}
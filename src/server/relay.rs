@@ -0,0 +1,94 @@
+//! Forwarding sessions through another myodine server.
+//!
+//! When a relay is configured, each session's destination connection is a
+//! client session to the next server instead of a direct TCP connection. The
+//! destination host and port from the establishment query are passed on as
+//! the next hop's destination. Everything else (MTUs, windows, codecs) is
+//! negotiated separately on each hop.
+
+use std::net::{TcpListener, TcpStream};
+use std::thread::spawn;
+
+use client::{ClientConfig, Health, RawLogger, handle_connection};
+use dns_proto::Domain;
+
+/// Open a connection whose other end is proxied through the relay to the
+/// given destination.
+///
+/// The relay session is established in the background. If it fails, the
+/// returned stream is closed.
+pub fn connect(relay: &ClientConfig, host: &Domain, port: u16) -> Result<TcpStream, String> {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .map_err(|e| format!("relay listen error: {}", e))?;
+    let addr = listener.local_addr().map_err(|e| format!("relay listen error: {}", e))?;
+    let stream = TcpStream::connect(addr).map_err(|e| format!("relay connect error: {}", e))?;
+    let local_addr = stream.local_addr().map_err(|e| format!("relay connect error: {}", e))?;
+    // Another local process may connect to the listener first, so only the
+    // connection from our own stream is used.
+    let relay_end = loop {
+        let (conn, peer) = listener.accept()
+            .map_err(|e| format!("relay accept error: {}", e))?;
+        if peer == local_addr {
+            break conn;
+        }
+    };
+    let mut config = relay.clone();
+    config.remote_host = host.clone();
+    config.remote_port = port;
+    let dest = format!("{}:{}", host, port);
+    spawn(move || {
+        let logger = RawLogger::new();
+        if let Err(msg) = handle_connection(config, relay_end, &logger, &Health::new()) {
+            logger.log(format!("relay error for {}: {}", dest, msg));
+        }
+    });
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write, copy};
+    use std::net::UdpSocket;
+
+    use dns_coding::{dns_decode, dns_encode};
+    use dns_proto::Message;
+
+    use super::super::flags::Flags;
+    use super::super::handler::Server;
+
+    #[test]
+    fn relayed_echo() {
+        let echo = TcpListener::bind("127.0.0.1:0").unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        spawn(move || {
+            let (conn, _) = echo.accept().unwrap();
+            copy(&mut conn.try_clone().unwrap(), &mut &conn).unwrap();
+        });
+
+        let host: Domain = "relay.com".parse().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let mut flags = Flags::new(vec![host.clone()]);
+        flags.allow_no_auth = true;
+        spawn(move || {
+            let mut server = Server::new(flags);
+            loop {
+                let mut buf = [0; 2048];
+                let (size, addr) = socket.recv_from(&mut buf).unwrap();
+                let message = dns_decode::<Message>(buf[..size].to_vec()).unwrap();
+                if let Ok(response) = server.handle_message(message, addr) {
+                    socket.send_to(&dns_encode(&response).unwrap(), addr).unwrap();
+                }
+            }
+        });
+
+        let mut config = ClientConfig::new(&server_addr.to_string(), host);
+        config.no_auth = true;
+        let mut stream = connect(&config, &"127.0.0.1".parse().unwrap(), echo_port).unwrap();
+        stream.write_all(b"hello").unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
@@ -1,52 +1,113 @@
-use std::net::TcpStream;
+use std::cmp;
+use std::net::{Shutdown, SocketAddr, TcpStream, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
 use std::time::{Duration, Instant};
 
-use myodine::conn::TcpChunker;
-use myodine::dns_proto::{Domain, Message, Record, RecordHeader, RecordType};
-use myodine::myo_proto::establish::EstablishQuery;
-use myodine::myo_proto::name_code::{NameCode, get_name_code};
-use myodine::myo_proto::record_code::{RecordCode, get_record_code};
-use myodine::myo_proto::xfer::{Packet, WwrState, handle_packet_in, next_packet_out};
+use conn::{Chunker, ConnState, DATAGRAM_BUFFER, Stream, StreamChunker, UdpChunker};
+use dns_proto::{Domain, Message, Record, RecordHeader, RecordType};
+use myo_proto::api_chars::ApiChars;
+use myo_proto::establish::{EstablishQuery, EstablishResponse, new_resume_token};
+use myo_proto::name_code::{NameCode, get_name_code};
+use myo_proto::name_template::NameTemplate;
+use myo_proto::record_code::{MAX_ANSWERS, RecordCode, encode_answers,
+    get_record_code};
+use myo_proto::util::shuffle_answers;
+use myo_proto::xfer::{Chunk, Packet, WwrState, fill_send_buffer, handle_packet_in,
+    next_packet_out_limited, unwrap_packet, wrap_packet};
+
+use super::bandwidth::BandwidthLimiter;
+use super::flags::Flags;
+use super::lifecycle::SessionInfo;
+use super::relay;
 
 /// The state of a single session.
 pub struct Session {
     id: u16,
+    source: SocketAddr,
+    destination: String,
+    created: Instant,
     last_used: Instant,
+    bytes_in: u64,
+    bytes_out: u64,
+    reported_traffic: (u64, u64),
     state: WwrState,
     name_code: Box<NameCode>,
+    name_template: NameTemplate,
     record_code: Box<RecordCode>,
-    conn: TcpChunker,
-    response_window: u16
+    conn: Box<dyn Chunker>,
+    query_window: u16,
+    response_window: u16,
+    query_mtu: u16,
+    response_mtu: u16,
+    answers: usize,
+    resumed_from: Option<(u16, u32)>,
+    resume_token: u32,
+    padded: bool,
+    framed: bool,
+    shuffle_answers: bool,
+    api_chars: ApiChars
 }
 
 impl Session {
     /// Establish a new session.
+    ///
+    /// The `source` is the address that sent the establishment query.
     pub fn new(
         id: u16,
+        source: SocketAddr,
         seq_start: u32,
+        mtu: u16,
         query_type: RecordType,
         query: &EstablishQuery,
-        timeout: Duration
+        flags: &Flags
     ) -> Result<Session, String> {
+        if query.query_window == 0 {
+            return Err("query window must be at least 1".to_owned());
+        } else if query.answers == 0 || query.answers > MAX_ANSWERS {
+            return Err(format!("answers must be from 1 to {}", MAX_ANSWERS));
+        }
         let name_code = get_name_code(&query.name_encoding)
             .ok_or(format!("bad name code: {}", query.name_encoding))?;
         let record_code = get_record_code(query_type, &query.response_encoding)
             .ok_or("bad record code".to_owned())?;
         let addr_str = format!("{}:{}", query.host, query.port);
-        let addr = addr_str.parse().map_err(|e| format!("parse {}: {}", addr_str, e))?;
-        let stream = TcpStream::connect_timeout(&addr, timeout)
-            .map_err(|e| format!("connect error: {}", e))?;
-        // TCP buffer sizes are chosen rather arbitrarily.
-        let conn = TcpChunker::new(stream, query.mtu as usize, query.response_window as usize,
-                query.query_window as usize).map_err(|e| format!("chunker error: {}", e))?;
+        let conn = if query.unix {
+            open_unix(mtu, query, flags)?
+        } else if query.datagram {
+            open_udp(&addr_str, mtu, query, flags)?
+        } else {
+            open_tcp(&addr_str, mtu, query, flags)?
+        };
+        let mut state = WwrState::new(query.query_window, query.response_window, seq_start);
+        state.set_send_byte_limit(flags.max_inflight_bytes);
+        state.set_memory_limit(flags.max_session_memory);
+        state.set_recv_chunk_limit(query.query_mtu.map(|x| x as usize));
         Ok(Session{
             id: id,
+            source,
+            destination: query.destination(),
+            created: Instant::now(),
             last_used: Instant::now(),
-            state: WwrState::new(query.query_window, query.response_window, seq_start),
+            bytes_in: 0,
+            bytes_out: 0,
+            reported_traffic: (0, 0),
+            state,
             name_code: name_code,
+            name_template: flags.name_template.clone(),
             record_code: record_code,
             conn: conn,
-            response_window: query.response_window
+            query_window: query.query_window,
+            response_window: query.response_window,
+            query_mtu: query.query_mtu.unwrap_or(0),
+            response_mtu: mtu,
+            answers: query.answers,
+            resumed_from: None,
+            resume_token: new_resume_token(),
+            padded: query.padded,
+            framed: query.framed,
+            shuffle_answers: flags.shuffle_answers,
+            api_chars: flags.api_chars
         })
     }
 
@@ -55,10 +116,105 @@ impl Session {
         self.id
     }
 
+    /// Get the destination, as `host:port` or `unix:<name>`.
+    pub fn destination(&self) -> &str {
+        &self.destination
+    }
+
+    /// Get the successful establishment response for this session, with
+    /// the parameters it uses and the given sequence number.
+    pub fn success_response(&self, seq: u32) -> EstablishResponse {
+        EstablishResponse::Success{
+            id: self.id,
+            seq,
+            query_window: self.query_window,
+            response_window: self.response_window,
+            query_mtu: self.query_mtu,
+            response_mtu: self.response_mtu,
+            resume_token: self.resume_token
+        }
+    }
+
+    /// Get the token a client must present to resume this session.
+    pub fn resume_token(&self) -> u32 {
+        self.resume_token
+    }
+
+    /// Get the session ID and window start that this session was resumed
+    /// from, if it was.
+    pub fn resumed_from(&self) -> Option<(u16, u32)> {
+        self.resumed_from
+    }
+
+    /// Move the session to a new ID, and restart the outgoing stream at the
+    /// client's `window_start` with chunks of at most `mtu` bytes.
+    ///
+    /// Queries for the old ID are ignored from then on, so that a delayed
+    /// query can't acknowledge chunks that have since been numbered again.
+    pub fn resume(&mut self, id: u16, window_start: u32, mtu: u16) -> Result<(), String> {
+        if mtu == 0 {
+            return Err("invalid response MTU".to_owned());
+        } else if !self.state.restart_send(window_start, mtu as usize) {
+            return Err("window start out of range".to_owned());
+        }
+        self.resumed_from = Some((self.id, window_start));
+        self.id = id;
+        self.response_mtu = mtu;
+        self.last_used = Instant::now();
+        Ok(())
+    }
+
+    /// Get a one-line, human-readable description of the session.
+    pub fn summary(&self) -> String {
+        format!("id={} source={} dest={} in={} out={} age={}s idle={}s conn={}", self.id,
+            self.source, self.destination, self.bytes_in, self.bytes_out,
+            self.created.elapsed().as_secs(), self.last_used.elapsed().as_secs(),
+            self.conn.state())
+    }
+
+    /// Get the bytes written to the destination, the bytes read from it, and
+    /// the number of chunks sent more than once.
+    pub fn traffic(&self) -> (u64, u64, u64) {
+        (self.bytes_in, self.bytes_out, self.state.retransmissions())
+    }
+
+    /// Get the session's metadata for lifecycle listeners.
+    pub fn info(&self) -> SessionInfo {
+        SessionInfo{
+            id: self.id,
+            source: self.source,
+            destination: self.destination.clone(),
+            bytes_in: self.bytes_in,
+            bytes_out: self.bytes_out
+        }
+    }
+
+    /// Get the bytes written to and read from the destination since the
+    /// last call, if there were any.
+    pub fn take_unreported_traffic(&mut self) -> Option<(u64, u64)> {
+        let (bytes_in, bytes_out) = self.reported_traffic;
+        if (bytes_in, bytes_out) == (self.bytes_in, self.bytes_out) {
+            return None;
+        }
+        self.reported_traffic = (self.bytes_in, self.bytes_out);
+        Some((self.bytes_in - bytes_in, self.bytes_out - bytes_out))
+    }
+
     /// Check if the session is ready to be cleaned up.
-    pub fn is_done(&self, timeout: Duration) -> bool {
+    ///
+    /// Sessions whose destination connection failed are cleaned up once the
+    /// data already read from the destination has been acknowledged, since
+    /// nothing more can be delivered.
+    pub fn is_done(&mut self, timeout: Duration) -> bool {
+        if Instant::now() - self.last_used > timeout {
+            return true;
+        }
         // For now, don't check self.state.is_done() because of an EOF ack issue.
-        Instant::now() - self.last_used > timeout
+        if self.conn.state() != ConnState::Errored {
+            return false;
+        }
+        self.bytes_out += fill_send_buffer(&mut self.state, &mut *self.conn) as u64;
+        !self.state.has_send_chunk() && self.state.send_buffer_space() > 0
     }
 
     /// Handle a message that was directed to the session.
@@ -67,30 +223,178 @@ impl Session {
     ///
     /// * `message` - The message that was received.
     /// * `host` - The root domain name of the server.
-    pub fn handle_message(&mut self, message: Message, host: &Domain) -> Result<Message, String> {
-        let (api, _, data) = self.name_code.decode_domain(&message.questions[0].domain, host)?;
-        let in_packet = Packet::decode_query(&data, self.response_window, api)?;
-        let response_packet = self.handle_packet(in_packet);
-        let mut response = message;
-        let record = Record{
-            header: RecordHeader{
-                domain: response.questions[0].domain.clone(),
-                record_type: response.questions[0].record_type,
-                record_class: response.questions[0].record_class,
-                ttl: 0,
-            },
-            body: self.record_code.encode_body(&response_packet.encode_response()?)?
+    /// * `bandwidth` - The server-wide limit on response data, if there is
+    ///   one. Responses leave out their chunk while the session is over its
+    ///   share.
+    pub fn handle_message(
+        &mut self,
+        message: Message,
+        host: &Domain,
+        mut bandwidth: Option<&mut BandwidthLimiter>
+    ) -> Result<Message, String> {
+        let (api, _, data) = self.name_template.decode(&*self.name_code,
+            &message.questions[0].domain, host)?;
+        let data = unwrap_packet(data, self.padded, self.framed)?;
+        let in_packet = Packet::decode_query(&data, self.response_window, api,
+            &self.api_chars)?;
+        let response_packet = self.handle_packet(in_packet, bandwidth.as_deref_mut());
+        let padded_size = if self.padded {
+            Some(Packet::padded_size(self.query_window, self.response_mtu))
+        } else {
+            None
         };
-        response.answers.push(record);
+        let response_data = wrap_packet(response_packet.encode_response()?, padded_size,
+            self.framed)?;
+        if let (Some(bandwidth), Some(_)) = (bandwidth, response_packet.chunk.as_ref()) {
+            bandwidth.charge(self.id, response_data.len());
+        }
+        let mut response = message;
+        for body in encode_answers(&*self.record_code, &response_data, self.answers)? {
+            response.answers.push(Record{
+                header: RecordHeader{
+                    domain: response.questions[0].domain.clone(),
+                    record_type: response.questions[0].record_type,
+                    record_class: response.questions[0].record_class,
+                    ttl: 0,
+                },
+                body
+            });
+        }
         response.header.is_response = true;
-        response.header.answer_count = 1;
+        response.header.answer_count = response.answers.len() as u16;
+        // Split responses carry an index in each answer, so their order
+        // doesn't matter.
+        if self.shuffle_answers && self.answers > 1 {
+            shuffle_answers(&mut response);
+        }
         Ok(response)
     }
 
-    fn handle_packet(&mut self, packet: Packet) -> Packet {
+    /// Deliver data that the client sent along with the establishment query.
+    ///
+    /// It is treated as the first incoming chunk.
+    pub fn handle_early_data(&mut self, data: Vec<u8>) {
+        // Early data fills the establishment query, so it may exceed the
+        // query MTU that limits later chunks.
+        self.state.set_recv_chunk_limit(None);
+        let seq = self.state.next_send_ack().window_start;
+        for chunk in self.state.handle_chunk(Chunk::new(seq, data)) {
+            self.bytes_in += chunk.data.len() as u64;
+            self.conn.send(chunk.data);
+        }
+        let limit = Some(self.query_mtu as usize).filter(|&x| x > 0);
+        self.state.set_recv_chunk_limit(limit);
+    }
+
+    fn handle_packet(
+        &mut self,
+        packet: Packet,
+        bandwidth: Option<&mut BandwidthLimiter>
+    ) -> Packet {
         // TODO: verify packet using sequence number!
         self.last_used = Instant::now();
-        handle_packet_in(packet, &mut self.state, &mut self.conn);
-        next_packet_out(&mut self.state, &mut self.conn).0
+        self.bytes_in += handle_packet_in(packet, &mut self.state, &mut *self.conn) as u64;
+        let id = self.id;
+        let (packet, bytes_out) = next_packet_out_limited(&mut self.state, &mut *self.conn,
+            || match bandwidth {
+                Some(limiter) => limiter.allow(id),
+                None => true
+            });
+        self.bytes_out += bytes_out as u64;
+        packet
+    }
+}
+
+/// Connect to a TCP destination, directly or through the relay.
+fn open_tcp(
+    addr_str: &str,
+    mtu: u16,
+    query: &EstablishQuery,
+    flags: &Flags
+) -> Result<Box<dyn Chunker>, String> {
+    let stream = if let Some(ref relay_config) = flags.relay {
+        relay::connect(relay_config, &query.host, query.port)?
+    } else {
+        let addr = addr_str.parse().map_err(|e| format!("parse {}: {}", addr_str, e))?;
+        TcpStream::connect_timeout(&addr, flags.conn_timeout)
+            .map_err(|e| format!("connect error: {}", e))?
+    };
+    if flags.tcp_nodelay {
+        stream.set_nodelay(true).map_err(|e| format!("socket error: {}", e))?;
+    }
+    open_stream(stream, mtu, query, flags)
+}
+
+/// Connect to a Unix socket destination, which is looked up by name.
+///
+/// The socket is local, so it is never relayed.
+#[cfg(unix)]
+fn open_unix(mtu: u16, query: &EstablishQuery, flags: &Flags) -> Result<Box<dyn Chunker>, String> {
+    let path = flags.unix_path(&query.host)
+        .ok_or_else(|| format!("no Unix destination named {}", query.host))?;
+    let stream = UnixStream::connect(path).map_err(|e| format!("connect error: {}", e))?;
+    open_stream(stream, mtu, query, flags)
+}
+
+#[cfg(not(unix))]
+fn open_unix(_: u16, _: &EstablishQuery, _: &Flags) -> Result<Box<Chunker>, String> {
+    Err("Unix destinations are not supported on this platform".to_owned())
+}
+
+/// Wrap a connected stream destination in a chunker.
+fn open_stream<S: Stream>(
+    stream: S,
+    mtu: u16,
+    query: &EstablishQuery,
+    flags: &Flags
+) -> Result<Box<dyn Chunker>, String> {
+    if query.response_window == 0 {
+        // Push-only session: nothing read from the destination could be sent.
+        stream.shutdown(Shutdown::Read).ok();
+    }
+    // Stream buffer sizes are chosen rather arbitrarily, except that data read
+    // ahead from the destination counts against the session's memory.
+    let mut in_buf = cmp::max(1, query.response_window as usize);
+    if let Some(limit) = flags.max_session_memory {
+        in_buf = cmp::max(1, cmp::min(in_buf, limit / mtu as usize));
+    }
+    let conn = StreamChunker::new(stream, mtu as usize, in_buf, query.query_window as usize)
+        .map_err(|e| format!("chunker error: {}", e))?;
+    Ok(Box::new(conn))
+}
+
+/// Create a UDP socket for a datagram session's destination.
+///
+/// Datagrams from the destination that don't fit in one response chunk are
+/// dropped.
+fn open_udp(
+    addr_str: &str,
+    mtu: u16,
+    query: &EstablishQuery,
+    flags: &Flags
+) -> Result<Box<dyn Chunker>, String> {
+    if flags.relay.is_some() {
+        return Err("UDP destinations cannot be relayed".to_owned());
+    }
+    let addr: SocketAddr = addr_str.parse().map_err(|e| format!("parse {}: {}", addr_str, e))?;
+    let bind_addr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind_addr).map_err(|e| format!("socket error: {}", e))?;
+    socket.connect(addr).map_err(|e| format!("connect error: {}", e))?;
+    // Like streams, datagrams read ahead count against the session's memory.
+    let mut in_buf = DATAGRAM_BUFFER;
+    if let Some(limit) = flags.max_session_memory {
+        in_buf = cmp::max(1, cmp::min(in_buf, limit / mtu as usize));
+    }
+    let mut conn = UdpChunker::connect(socket, mtu as usize, in_buf)
+        .map_err(|e| format!("chunker error: {}", e))?;
+    if query.response_window == 0 {
+        conn.stop_reading();
+    }
+    Ok(Box::new(conn))
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.conn.close();
     }
 }
@@ -0,0 +1,366 @@
+//! JavaScript bindings for the client side of the protocol.
+//!
+//! The bindings only turn a stream into DNS messages and back. The caller
+//! sends the messages to the server, e.g. over DoH, and supplies the time and
+//! random values, since `wasm32-unknown-unknown` has neither. The encodings
+//! are the client's defaults: `raw` TXT responses and the first name
+//! encoding, without padding, framing, or decoy labels.
+//!
+//! See `examples/wasm` for a handshake driven from Node.
+
+extern crate rand;
+extern crate wasm_bindgen;
+use self::rand::{SeedableRng, XorShiftRng};
+use self::wasm_bindgen::prelude::*;
+
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::mem;
+
+use client::check_success;
+use conn::{ChunkSink, ChunkSource};
+use dns_coding::{dns_decode, dns_encode};
+use dns_proto::{Domain, Message, Question, RecordClass, RecordType};
+use myo_proto::api_chars::ApiChars;
+use myo_proto::establish::{DestinationQuery, EstablishQuery, EstablishResponse,
+    password_proof};
+use myo_proto::name_code::{NameCode, get_name_code, supported_name_encodings};
+use myo_proto::name_template::NameTemplate;
+use myo_proto::record_code::{RecordCode, get_record_code};
+use myo_proto::util::payload_answer;
+use myo_proto::xfer::{Packet, WwrState, handle_packet_in, next_packet_out};
+
+/// The window sizes to ask for, which are the client's defaults.
+const WINDOW_SIZE: u16 = 4;
+
+/// The largest MTU to ask for, which is the client's default.
+const MAX_MTU: u16 = 64;
+
+/// An establishment in progress.
+#[wasm_bindgen]
+pub struct Handshake {
+    host: Domain,
+    api: ApiChars,
+    query: EstablishQuery,
+    /// Set once the server is waiting for the destination of a split query.
+    pending: bool
+}
+
+#[wasm_bindgen]
+impl Handshake {
+    /// Start a handshake through the server for `host`, to the TCP
+    /// destination `remote_host:remote_port`.
+    ///
+    /// Without a password, the handshake asks for a session without
+    /// authentication. `epoch` is the current time in seconds, and `nonce` is
+    /// a random value that tells this client's queries apart from others.
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        host: &str,
+        remote_host: &str,
+        remote_port: u16,
+        password: Option<String>,
+        epoch: f64,
+        nonce: u32
+    ) -> Result<Handshake, JsValue> {
+        let host: Domain = host.parse().map_err(js_error)?;
+        let api = ApiChars::default();
+        let name_encoding = supported_name_encodings()[0];
+        let name_code = get_name_code(name_encoding).unwrap();
+        let query_mtu = NameTemplate::default().max_data(&*name_code, api.transfer, &host, 0)
+            .saturating_sub(Packet::query_overhead(WINDOW_SIZE));
+        if query_mtu == 0 {
+            return Err(js_error(format!("host name {} leaves no room for data", host)));
+        }
+        let response_mtu = Packet::max_response_mtu(RecordType::TXT, "raw", WINDOW_SIZE, false,
+            false, 1);
+        let mut query = EstablishQuery{
+            response_encoding: "raw".to_owned(),
+            mtu: min(MAX_MTU as usize, response_mtu) as u16,
+            query_mtu: Some(min(MAX_MTU as usize, query_mtu) as u16),
+            name_encoding: name_encoding.to_owned(),
+            query_window: WINDOW_SIZE,
+            response_window: WINDOW_SIZE,
+            proof: password.map(|x| password_proof(&x, epoch as u64)),
+            nonce: Some(nonce),
+            port: remote_port,
+            host: remote_host.parse().map_err(js_error)?,
+            early_data: Vec::new(),
+            resume: None,
+            padded: false,
+            framed: false,
+            datagram: false,
+            unix: false,
+            answers: 1,
+            split: false
+        };
+        // A destination that doesn't fit follows in a second query.
+        query.split = query.to_domain(&host, &api).is_err();
+        Ok(Handshake{host, api, query, pending: false})
+    }
+
+    /// Encode the query to send next, as a DNS message.
+    ///
+    /// A query whose response was lost can be sent again.
+    pub fn query(&self) -> Result<Vec<u8>, JsValue> {
+        let domain = if self.pending {
+            let follow_up = DestinationQuery{
+                nonce: self.query.nonce.unwrap(),
+                host: self.query.host.clone()
+            };
+            follow_up.to_domain(&self.host, &self.api)
+        } else {
+            self.query.to_domain(&self.host, &self.api)
+        };
+        encode_query(domain.map_err(js_error)?).map_err(js_error)
+    }
+
+    /// Handle the response to the last query.
+    ///
+    /// Returns the new session, or nothing if another query has to be sent
+    /// first.
+    pub fn handle_response(&mut self, response: &[u8]) -> Result<Option<Transfer>, JsValue> {
+        let message = decode_message(response).map_err(js_error)?;
+        let body = get_record_code(RecordType::TXT, "raw").unwrap()
+            .decode_body(payload_answer(&message).map_err(js_error)?).map_err(js_error)?;
+        let response: EstablishResponse = dns_decode(body).map_err(js_error)?;
+        match response {
+            EstablishResponse::Pending if self.query.split && !self.pending => {
+                self.pending = true;
+                Ok(None)
+            },
+            EstablishResponse::Success{id, seq, query_window, response_window, query_mtu,
+                response_mtu, resume_token} =>
+            {
+                check_success(&self.query, &response).map_err(js_error)?;
+                let mut state = WwrState::new(response_window, query_window, seq);
+                state.set_recv_chunk_limit(Some(response_mtu as usize));
+                let seed = [self.query.nonce.unwrap(), seq, resume_token, id as u32 | 1];
+                Ok(Some(Transfer{
+                    host: self.host.clone(),
+                    api: self.api,
+                    name_code: get_name_code(&self.query.name_encoding).unwrap(),
+                    record_code: get_record_code(RecordType::TXT, "raw").unwrap(),
+                    session_id: id,
+                    query_mtu,
+                    query_window,
+                    state,
+                    buffers: Buffers{
+                        outgoing: VecDeque::new(),
+                        incoming: Vec::new(),
+                        finished: false
+                    },
+                    rng: XorShiftRng::from_seed(seed)
+                }))
+            },
+            EstablishResponse::Failure(msg) => Err(js_error(msg)),
+            EstablishResponse::Pending => {
+                Err(js_error("unexpected pending establishment response".to_owned()))
+            },
+            EstablishResponse::Unknown(x) => {
+                Err(js_error(format!("unknown establishment response type: {}", x)))
+            }
+        }
+    }
+}
+
+/// An open session, which carries a stream to and from the destination.
+///
+/// The caller keeps a number of transfer queries in flight, like the lanes
+/// of the native client.
+#[wasm_bindgen]
+pub struct Transfer {
+    host: Domain,
+    api: ApiChars,
+    name_code: Box<dyn NameCode>,
+    record_code: Box<dyn RecordCode>,
+    session_id: u16,
+    query_mtu: u16,
+    query_window: u16,
+    state: WwrState,
+    buffers: Buffers,
+    /// The source of the random bytes in polls, which only have to keep
+    /// caches from answering them, so it is seeded from the handshake.
+    rng: XorShiftRng
+}
+
+#[wasm_bindgen]
+impl Transfer {
+    /// Get the ID that the server assigned to the session.
+    pub fn session_id(&self) -> u16 {
+        self.session_id
+    }
+
+    /// Queue data to send to the destination.
+    pub fn write(&mut self, data: &[u8]) {
+        for chunk in data.chunks(self.query_mtu as usize) {
+            self.buffers.outgoing.push_back(chunk.to_vec());
+        }
+    }
+
+    /// Queue the end of the outgoing stream.
+    pub fn close(&mut self) {
+        self.buffers.outgoing.push_back(Vec::new());
+    }
+
+    /// Take the data that arrived from the destination so far.
+    pub fn read(&mut self) -> Vec<u8> {
+        mem::take(&mut self.buffers.incoming)
+    }
+
+    /// Check if the destination closed its side of the stream.
+    pub fn is_finished(&self) -> bool {
+        self.buffers.finished
+    }
+
+    /// Check if both sides of the stream ended and were acknowledged.
+    pub fn is_done(&self) -> bool {
+        self.state.is_done()
+    }
+
+    /// Encode the next transfer query, as a DNS message.
+    ///
+    /// Chunks in queries that get no response are sent again later.
+    pub fn next_query(&mut self) -> Result<Vec<u8>, JsValue> {
+        let (packet, _) = next_packet_out(&mut self.state, &mut self.buffers);
+        let (api_code, data) = packet.encode_query_with_rng(&self.api, &mut self.rng)
+            .map_err(js_error)?;
+        let domain = NameTemplate::default().encode(&*self.name_code, api_code,
+            self.session_id, &data, &self.host, 0).map_err(js_error)?;
+        encode_query(domain).map_err(js_error)
+    }
+
+    /// Handle the response to a transfer query.
+    pub fn handle_response(&mut self, response: &[u8]) -> Result<(), JsValue> {
+        let message = decode_message(response).map_err(js_error)?;
+        let body = payload_answer(&message).map_err(js_error)?;
+        let data = self.record_code.decode_body(body).map_err(js_error)?;
+        let packet = Packet::decode_response(&data, self.query_window).map_err(js_error)?;
+        handle_packet_in(packet, &mut self.state, &mut self.buffers);
+        Ok(())
+    }
+}
+
+/// The local end of a `Transfer`, which JS reads and writes.
+struct Buffers {
+    outgoing: VecDeque<Vec<u8>>,
+    incoming: Vec<u8>,
+    finished: bool
+}
+
+impl ChunkSource for Buffers {
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.outgoing.pop_front()
+    }
+}
+
+impl ChunkSink for Buffers {
+    fn can_send(&mut self) -> bool {
+        true
+    }
+
+    fn send(&mut self, chunk: Vec<u8>) {
+        self.incoming.extend(chunk);
+    }
+
+    fn send_finished(&mut self) {
+        self.finished = true;
+    }
+}
+
+fn encode_query(domain: Domain) -> Result<Vec<u8>, String> {
+    dns_encode(&Message::new_query(Question{
+        domain,
+        record_type: RecordType::TXT,
+        record_class: RecordClass::IN
+    }))
+}
+
+fn decode_message(data: &[u8]) -> Result<Message, String> {
+    let message: Message = dns_decode(data.to_vec())?;
+    if message.header.truncated {
+        Err("truncated response".to_owned())
+    } else {
+        Ok(message)
+    }
+}
+
+fn js_error(msg: String) -> JsValue {
+    JsValue::from_str(&msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use myo_proto::establish::establish_response;
+    use myo_proto::xfer::{Ack, Chunk, xfer_query_session_id};
+
+    #[test]
+    fn handshake_and_transfer() {
+        let host: Domain = "proxy.com".parse().unwrap();
+        let api = ApiChars::default();
+        let mut handshake = Handshake::new("proxy.com", "127.0.0.1", 22,
+            Some("pw".to_owned()), 1000.0, 0x1234).unwrap();
+        let query: Message = dns_decode(handshake.query().unwrap()).unwrap();
+        let establish = EstablishQuery::from_query(&query, &host, &api).unwrap();
+        assert_eq!(establish.proof, Some(password_proof("pw", 1000)));
+        assert_eq!(establish.nonce, Some(0x1234));
+        let response = establish_response(&query, &host, &api, EstablishResponse::Success{
+            id: 7,
+            seq: 100,
+            query_window: 4,
+            response_window: 4,
+            query_mtu: establish.query_mtu.unwrap(),
+            response_mtu: establish.mtu,
+            resume_token: 5
+        }).unwrap();
+        let mut transfer = handshake.handle_response(&dns_encode(&response).unwrap()).unwrap()
+            .unwrap();
+        assert_eq!(transfer.session_id(), 7);
+
+        transfer.write(b"hi");
+        let query: Message = dns_decode(transfer.next_query().unwrap()).unwrap();
+        assert_eq!(xfer_query_session_id(&query, &host, &api, &NameTemplate::default()), Some(7));
+        let packet = Packet{
+            ack: Ack{window_start: 101, window_mask: vec![false; 3]},
+            chunk: Some(Chunk::new(100, b"hello".to_vec())),
+            control: None
+        };
+        let mut response = Message::new_query(query.questions[0].clone());
+        response.header.is_response = true;
+        response.header.answer_count = 1;
+        response.answers.push(::dns_proto::Record{
+            header: ::dns_proto::RecordHeader{
+                domain: query.questions[0].domain.clone(),
+                record_type: RecordType::TXT,
+                record_class: RecordClass::IN,
+                ttl: 0
+            },
+            body: get_record_code(RecordType::TXT, "raw").unwrap()
+                .encode_body(&packet.encode_response().unwrap()).unwrap()
+        });
+        transfer.handle_response(&dns_encode(&response).unwrap()).unwrap();
+        assert_eq!(transfer.read(), b"hello".to_vec());
+        assert!(transfer.read().is_empty());
+        assert!(!transfer.is_finished());
+    }
+
+    #[test]
+    fn split_handshake() {
+        let host: Domain = "proxy.com".parse().unwrap();
+        let api = ApiChars::default();
+        let remote = format!("{}.{}.{}.{}", "a".repeat(60), "b".repeat(60), "c".repeat(60),
+            "d".repeat(30));
+        let mut handshake = Handshake::new("proxy.com", &remote, 22, None, 0.0, 0x1234).unwrap();
+        let query: Message = dns_decode(handshake.query().unwrap()).unwrap();
+        let establish = EstablishQuery::from_query(&query, &host, &api).unwrap();
+        assert!(establish.split);
+        let response = establish_response(&query, &host, &api, EstablishResponse::Pending)
+            .unwrap();
+        assert!(handshake.handle_response(&dns_encode(&response).unwrap()).unwrap().is_none());
+        let follow_up: Message = dns_decode(handshake.query().unwrap()).unwrap();
+        let dest = DestinationQuery::from_query(&follow_up, &host, &api).unwrap();
+        assert_eq!(dest.nonce, 0x1234);
+        assert_eq!(dest.host.to_string(), remote);
+    }
+}